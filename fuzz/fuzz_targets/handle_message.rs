@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Feeds a sequence of fuzzed FUSE requests through `Server::handle_message()`.
+//!
+//! Every iteration starts from a fresh in-memory file system, negotiates the protocol with fuzzed
+//! INIT flags (so that e.g. the request extensions get parsed), and then sends fuzzed requests
+//! over fuzzed descriptor chains.  The harness checks that the server neither panics nor produces
+//! replies that exceed the writable part of the chain.
+
+#![no_main]
+
+use std::mem::size_of;
+
+use libfuzzer_sys::arbitrary::{Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+use virtiofsd::descriptor_utils::DescriptorType;
+use virtiofsd::fuse::{
+    InHeader, InitInCompat, InitInExt, OutHeader, KERNEL_MINOR_VERSION, KERNEL_VERSION,
+};
+use virtiofsd::server::Server;
+use virtiofsd_fuzz::memfs::MemFs;
+use virtiofsd_fuzz::{handle_message, NoopCacheReqHandler};
+use vm_memory::ByteValued;
+
+/// Highest opcode we generate; one past `FUSE_TMPFILE` to also cover unknown opcodes.
+const MAX_OPCODE: u32 = 52;
+/// Largest request body generated for a single message.
+const MAX_BODY_LEN: usize = 0x2000;
+/// Largest size of a single descriptor buffer.
+const MAX_DESC_LEN: u32 = 0x4000;
+/// Maximum number of readable and writable descriptors each.
+const MAX_DESCS: usize = 3;
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let _ = run(&mut u);
+});
+
+fn run(u: &mut Unstructured) -> Result<()> {
+    let server = Server::new(MemFs::new());
+    let mut cache = NoopCacheReqHandler;
+    let mut unique = 0;
+
+    init(&server, &mut cache, u.arbitrary()?);
+
+    while !u.is_empty() {
+        unique += 1;
+        let body_len = u.int_in_range(0..=MAX_BODY_LEN)?.min(u.len());
+        let body = u.bytes(body_len)?;
+
+        let header = InHeader {
+            len: if u.ratio(1, 8)? {
+                u.arbitrary()?
+            } else {
+                (size_of::<InHeader>() + body.len()) as u32
+            },
+            opcode: u.int_in_range(1..=MAX_OPCODE)?,
+            unique,
+            nodeid: u.int_in_range(0..=8)?,
+            uid: u.arbitrary()?,
+            gid: u.arbitrary()?,
+            pid: u.arbitrary()?,
+            total_extlen: u.int_in_range(0..=16)?,
+            padding: 0,
+        };
+
+        let mut request = header.as_slice().to_vec();
+        request.extend_from_slice(body);
+
+        let descriptors = descriptors(u, request.len())?;
+        handle_message(&server, &mut cache, &descriptors, &request);
+    }
+
+    Ok(())
+}
+
+/// Negotiates the protocol, offering the fuzzed feature `flags` to the file system.
+fn init(server: &Server<MemFs>, cache: &mut NoopCacheReqHandler, flags: u64) {
+    let init_in = InitInCompat {
+        major: KERNEL_VERSION,
+        minor: KERNEL_MINOR_VERSION,
+        max_readahead: 0x20000,
+        flags: flags as u32,
+    };
+    let init_in_ext = InitInExt {
+        flags2: (flags >> 32) as u32,
+        unused: [0; 11],
+    };
+    let header = InHeader {
+        len: (size_of::<InHeader>() + size_of::<InitInCompat>() + size_of::<InitInExt>()) as u32,
+        opcode: 26, // FUSE_INIT
+        ..Default::default()
+    };
+
+    let mut request = header.as_slice().to_vec();
+    request.extend_from_slice(init_in.as_slice());
+    request.extend_from_slice(init_in_ext.as_slice());
+
+    let descriptors = [
+        (DescriptorType::Readable, request.len() as u32),
+        (DescriptorType::Writable, 0x1000),
+    ];
+    let reply = handle_message(server, cache, &descriptors, &request).expect("INIT failed");
+    assert!(reply.len() > size_of::<OutHeader>(), "INIT was rejected");
+}
+
+/// Generates a descriptor chain layout whose readable part can hold `request_len` bytes (unless
+/// the fuzzer decides to truncate it) followed by up to `MAX_DESCS` writable descriptors.
+fn descriptors(u: &mut Unstructured, request_len: usize) -> Result<Vec<(DescriptorType, u32)>> {
+    let mut descriptors = Vec::new();
+
+    let readable = u.int_in_range(1..=MAX_DESCS)?;
+    let mut remaining = request_len as u32;
+    for i in 0..readable {
+        let len = if i + 1 == readable {
+            remaining
+        } else {
+            u.int_in_range(0..=remaining)?
+        };
+        let len = if u.ratio(1, 16)? {
+            u.int_in_range(0..=MAX_DESC_LEN)?
+        } else {
+            len
+        };
+        remaining = remaining.saturating_sub(len);
+        descriptors.push((DescriptorType::Readable, len));
+    }
+
+    for _ in 0..u.int_in_range(0..=MAX_DESCS)? {
+        descriptors.push((DescriptorType::Writable, u.int_in_range(0..=MAX_DESC_LEN)?));
+    }
+
+    Ok(descriptors)
+}
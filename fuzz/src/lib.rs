@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Shared helpers for the virtiofsd fuzz targets.
+
+pub mod memfs;
+
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::RawFd;
+
+use virtiofsd::descriptor_utils::{create_descriptor_chain, DescriptorType, Reader, Writer};
+use virtiofsd::filesystem::FileSystem;
+use virtiofsd::fs_cache_req_handler::FsCacheReqHandler;
+use virtiofsd::fuse::{InHeader, OutHeader, RemovemappingOne};
+use virtiofsd::server::Server;
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
+
+/// Where the descriptor table is placed in guest memory.
+const DESC_TABLE_ADDR: u64 = 0;
+/// Where the first descriptor buffer is placed in guest memory.
+const BUFFERS_ADDR: u64 = 0x1_0000;
+
+/// `FsCacheReqHandler` that accepts every DAX mapping request without doing anything.
+pub struct NoopCacheReqHandler;
+
+impl FsCacheReqHandler for NoopCacheReqHandler {
+    fn map(
+        &mut self,
+        _foffset: u64,
+        _moffset: u64,
+        _len: u64,
+        _flags: u64,
+        _fd: RawFd,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn unmap(&mut self, _requests: Vec<RemovemappingOne>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Places `request` into the readable descriptors of a chain laid out as described by
+/// `descriptors`, passes the chain to `Server::handle_message()`, and checks that the reply stays
+/// within the writable part of the chain and is consistent with its `OutHeader`.
+///
+/// Returns the reply bytes, or `None` if `handle_message()` rejected the request.
+pub fn handle_message<F: FileSystem + Sync>(
+    server: &Server<F>,
+    cache: &mut NoopCacheReqHandler,
+    descriptors: &[(DescriptorType, u32)],
+    request: &[u8],
+) -> Option<Vec<u8>> {
+    let buffers_len: u64 = descriptors.iter().map(|(_, len)| u64::from(*len)).sum();
+    let mem = GuestMemoryMmap::<()>::from_ranges(&[(
+        GuestAddress(0),
+        (BUFFERS_ADDR + buffers_len + 1) as usize,
+    )])
+    .unwrap();
+
+    // Copy the request into the readable buffers and remember where the writable ones are.
+    let mut addr = BUFFERS_ADDR;
+    let mut remaining = request;
+    let mut writable = Vec::new();
+    for (type_, len) in descriptors {
+        match type_ {
+            DescriptorType::Readable => {
+                let n = remaining.len().min(*len as usize);
+                mem.write_slice(&remaining[..n], GuestAddress(addr))
+                    .unwrap();
+                remaining = &remaining[n..];
+            }
+            DescriptorType::Writable => writable.push((addr, *len as usize)),
+        }
+        addr += u64::from(*len);
+    }
+    let capacity: usize = writable.iter().map(|(_, len)| len).sum();
+
+    let chain = create_descriptor_chain(
+        &mem,
+        GuestAddress(DESC_TABLE_ADDR),
+        GuestAddress(BUFFERS_ADDR),
+        descriptors.to_vec(),
+        0,
+    )
+    .ok()?;
+    let reader = Reader::new(&mem, chain.clone()).ok()?;
+    let writer = Writer::new(&mem, chain).ok()?;
+
+    let len = server.handle_message(reader, writer, Some(cache)).ok()?;
+    assert!(
+        len <= capacity,
+        "reply of {len} bytes does not fit into {capacity} writable bytes"
+    );
+    if len == 0 {
+        return Some(Vec::new());
+    }
+
+    let mut reply = vec![0u8; capacity];
+    let mut pos = 0;
+    for (addr, len) in writable {
+        mem.read_slice(&mut reply[pos..pos + len], GuestAddress(addr))
+            .unwrap();
+        pos += len;
+    }
+    reply.truncate(len);
+
+    assert!(len >= size_of::<OutHeader>(), "short reply: {len} bytes");
+    let out = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+    assert_eq!(out.len as usize, len, "OutHeader.len does not match reply");
+    assert!(out.error <= 0, "positive error value {}", out.error);
+    if out.error != 0 {
+        assert_eq!(len, size_of::<OutHeader>(), "error reply carries a payload");
+    }
+    if let Some(in_header) = request
+        .get(..size_of::<InHeader>())
+        .and_then(InHeader::from_slice)
+    {
+        assert_eq!(out.unique, in_header.unique, "reply for the wrong request");
+    }
+
+    Some(reply)
+}
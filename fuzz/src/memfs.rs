@@ -0,0 +1,701 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! A small in-memory `FileSystem` used as the backend of the fuzz targets.
+//!
+//! Running the fuzzer against `PassthroughFs` is not an option: outside of the sandbox nothing
+//! stops a fuzzed name like `../../etc` from escaping the shared directory.  This file system keeps
+//! its whole tree in memory and stores regular file contents in `memfd`s, so that the zero-copy
+//! `read`/`write` paths and DAX mappings can still be exercised with real file descriptors.
+
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use virtiofsd::filesystem::{
+    Context, DirEntry, DirectoryIterator, Entry, Extensions, FileSystem, FsOptions, GetxattrReply,
+    ListxattrReply, OpenOptions, RemovemappingOne, SetattrValid, SetxattrFlags, ZeroCopyReader,
+    ZeroCopyWriter,
+};
+use virtiofsd::fs_cache_req_handler::FsCacheReqHandler;
+use virtiofsd::fuse::ROOT_ID;
+
+/// Upper bound for the size of a single file, so the fuzzer cannot exhaust memory.
+const MAX_FILE_SIZE: u64 = 1 << 20;
+
+/// Upper bound for the number of inodes that may exist at the same time.
+const MAX_INODES: usize = 1024;
+
+fn err(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+enum Kind {
+    Dir(BTreeMap<CString, u64>),
+    File(File),
+    Symlink(Vec<u8>),
+    Special,
+}
+
+struct Node {
+    attr: libc::stat64,
+    kind: Kind,
+    xattrs: BTreeMap<CString, Vec<u8>>,
+}
+
+struct State {
+    nodes: BTreeMap<u64, Node>,
+    next_inode: u64,
+}
+
+impl State {
+    fn node(&self, inode: u64) -> io::Result<&Node> {
+        self.nodes.get(&inode).ok_or_else(|| err(libc::EBADF))
+    }
+
+    fn node_mut(&mut self, inode: u64) -> io::Result<&mut Node> {
+        self.nodes.get_mut(&inode).ok_or_else(|| err(libc::EBADF))
+    }
+
+    fn dir(&self, inode: u64) -> io::Result<&BTreeMap<CString, u64>> {
+        match &self.node(inode)?.kind {
+            Kind::Dir(entries) => Ok(entries),
+            _ => Err(err(libc::ENOTDIR)),
+        }
+    }
+
+    fn dir_mut(&mut self, inode: u64) -> io::Result<&mut BTreeMap<CString, u64>> {
+        match &mut self.node_mut(inode)?.kind {
+            Kind::Dir(entries) => Ok(entries),
+            _ => Err(err(libc::ENOTDIR)),
+        }
+    }
+
+    fn file(&self, inode: u64) -> io::Result<&File> {
+        match &self.node(inode)?.kind {
+            Kind::File(f) => Ok(f),
+            Kind::Dir(_) => Err(err(libc::EISDIR)),
+            _ => Err(err(libc::EINVAL)),
+        }
+    }
+
+    fn lookup(&self, parent: u64, name: &CStr) -> io::Result<u64> {
+        self.dir(parent)?
+            .get(name)
+            .copied()
+            .ok_or_else(|| err(libc::ENOENT))
+    }
+
+    fn entry(&self, inode: u64) -> io::Result<Entry> {
+        Ok(Entry {
+            inode,
+            generation: 0,
+            attr: self.node(inode)?.attr,
+            attr_flags: 0,
+            attr_timeout: Duration::ZERO,
+            entry_timeout: Duration::ZERO,
+        })
+    }
+
+    fn add(
+        &mut self,
+        ctx: Context,
+        parent: u64,
+        name: &CStr,
+        mode: u32,
+        kind: Kind,
+    ) -> io::Result<u64> {
+        if self.nodes.len() >= MAX_INODES {
+            return Err(err(libc::ENOSPC));
+        }
+        if self.dir(parent)?.contains_key(name) {
+            return Err(err(libc::EEXIST));
+        }
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.nodes.insert(inode, Node::new(inode, ctx, mode, kind));
+        self.dir_mut(parent)?.insert(name.to_owned(), inode);
+        Ok(inode)
+    }
+
+    fn remove(&mut self, parent: u64, name: &CStr, want_dir: bool) -> io::Result<()> {
+        let inode = self.lookup(parent, name)?;
+        match (&self.node(inode)?.kind, want_dir) {
+            (Kind::Dir(entries), true) if !entries.is_empty() => return Err(err(libc::ENOTEMPTY)),
+            (Kind::Dir(_), false) => return Err(err(libc::EISDIR)),
+            (Kind::Dir(_), true) => {}
+            (_, true) => return Err(err(libc::ENOTDIR)),
+            (_, false) => {}
+        }
+
+        self.dir_mut(parent)?.remove(name);
+        let node = self.node_mut(inode)?;
+        node.attr.st_nlink = node.attr.st_nlink.saturating_sub(1);
+        if node.attr.st_nlink == 0 {
+            self.nodes.remove(&inode);
+        }
+        Ok(())
+    }
+
+    fn refresh_size(&mut self, inode: u64) -> io::Result<()> {
+        let len = self.file(inode)?.metadata()?.len();
+        self.node_mut(inode)?.attr.st_size = len as i64;
+        Ok(())
+    }
+}
+
+impl Node {
+    fn new(inode: u64, ctx: Context, mode: u32, kind: Kind) -> Node {
+        // SAFETY: `stat64` only contains plain integer fields, all-zero is a valid value.
+        let mut attr: libc::stat64 = unsafe { mem::zeroed() };
+        attr.st_ino = inode;
+        attr.st_mode = mode;
+        attr.st_nlink = 1;
+        attr.st_uid = ctx.uid;
+        attr.st_gid = ctx.gid;
+        attr.st_blksize = 4096;
+        if let Kind::Symlink(target) = &kind {
+            attr.st_size = target.len() as i64;
+        }
+
+        Node {
+            attr,
+            kind,
+            xattrs: BTreeMap::new(),
+        }
+    }
+}
+
+pub struct MemDirIter {
+    entries: Vec<(u64, u32, CString)>,
+    pos: usize,
+}
+
+impl DirectoryIterator for MemDirIter {
+    fn next(&mut self) -> Option<DirEntry<'_>> {
+        let (ino, type_, name) = self.entries.get(self.pos)?;
+        self.pos += 1;
+        Some(DirEntry {
+            ino: *ino,
+            offset: self.pos as u64,
+            type_: *type_,
+            name,
+        })
+    }
+}
+
+/// In-memory file system.  Handles are simply the inode numbers of the opened nodes.
+pub struct MemFs {
+    state: Mutex<State>,
+}
+
+impl Default for MemFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemFs {
+    pub fn new() -> MemFs {
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let mut nodes = BTreeMap::new();
+        let mut root = Node::new(
+            ROOT_ID,
+            ctx,
+            libc::S_IFDIR | 0o755,
+            Kind::Dir(BTreeMap::new()),
+        );
+        root.attr.st_nlink = 2;
+        nodes.insert(ROOT_ID, root);
+
+        MemFs {
+            state: Mutex::new(State {
+                nodes,
+                next_inode: ROOT_ID + 1,
+            }),
+        }
+    }
+
+    fn create_file(&self, ctx: Context, parent: u64, name: &CStr, mode: u32) -> io::Result<u64> {
+        // SAFETY: `memfd_create()` only reads the nul-terminated name we pass in.
+        let fd = unsafe { libc::memfd_create(c"memfs".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just returned by `memfd_create()` and is not owned by anything else.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let mode = libc::S_IFREG | (mode & 0o7777);
+        self.state
+            .lock()
+            .unwrap()
+            .add(ctx, parent, name, mode, Kind::File(file))
+    }
+}
+
+impl FileSystem for MemFs {
+    type Inode = u64;
+    type Handle = u64;
+    type DirIter = MemDirIter;
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        // Accept everything the guest offers, so that all optional request formats (e.g. the
+        // security context and supplementary group extensions) are reachable.
+        Ok(capable)
+    }
+
+    fn lookup(&self, _ctx: Context, parent: u64, name: &CStr) -> io::Result<Entry> {
+        let state = self.state.lock().unwrap();
+        let inode = state.lookup(parent, name)?;
+        state.entry(inode)
+    }
+
+    fn getattr(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: Option<u64>,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let state = self.state.lock().unwrap();
+        Ok((state.node(inode)?.attr, Duration::ZERO))
+    }
+
+    fn setattr(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        attr: libc::stat64,
+        _handle: Option<u64>,
+        valid: SetattrValid,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let mut state = self.state.lock().unwrap();
+        if valid.contains(SetattrValid::SIZE) {
+            if attr.st_size as u64 > MAX_FILE_SIZE {
+                return Err(err(libc::EFBIG));
+            }
+            state.file(inode)?.set_len(attr.st_size as u64)?;
+            state.refresh_size(inode)?;
+        }
+
+        let node = state.node_mut(inode)?;
+        if valid.contains(SetattrValid::MODE) {
+            node.attr.st_mode = (node.attr.st_mode & libc::S_IFMT) | (attr.st_mode & 0o7777);
+        }
+        if valid.contains(SetattrValid::UID) {
+            node.attr.st_uid = attr.st_uid;
+        }
+        if valid.contains(SetattrValid::GID) {
+            node.attr.st_gid = attr.st_gid;
+        }
+        if valid.contains(SetattrValid::ATIME) {
+            node.attr.st_atime = attr.st_atime;
+            node.attr.st_atime_nsec = attr.st_atime_nsec;
+        }
+        if valid.contains(SetattrValid::MTIME) {
+            node.attr.st_mtime = attr.st_mtime;
+            node.attr.st_mtime_nsec = attr.st_mtime_nsec;
+        }
+        Ok((node.attr, Duration::ZERO))
+    }
+
+    fn readlink(&self, _ctx: Context, inode: u64) -> io::Result<Vec<u8>> {
+        match &self.state.lock().unwrap().node(inode)?.kind {
+            Kind::Symlink(target) => Ok(target.clone()),
+            _ => Err(err(libc::EINVAL)),
+        }
+    }
+
+    fn symlink(
+        &self,
+        ctx: Context,
+        linkname: &CStr,
+        parent: u64,
+        name: &CStr,
+        _extensions: Extensions,
+    ) -> io::Result<Entry> {
+        let mut state = self.state.lock().unwrap();
+        let kind = Kind::Symlink(linkname.to_bytes().to_vec());
+        let inode = state.add(ctx, parent, name, libc::S_IFLNK | 0o777, kind)?;
+        state.entry(inode)
+    }
+
+    fn mknod(
+        &self,
+        ctx: Context,
+        parent: u64,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+        _extensions: Extensions,
+    ) -> io::Result<Entry> {
+        let mode = mode & !umask;
+        let inode = if mode & libc::S_IFMT == libc::S_IFREG {
+            self.create_file(ctx, parent, name, mode)?
+        } else {
+            let mut state = self.state.lock().unwrap();
+            let inode = state.add(ctx, parent, name, mode, Kind::Special)?;
+            state.node_mut(inode)?.attr.st_rdev = rdev.into();
+            inode
+        };
+        self.state.lock().unwrap().entry(inode)
+    }
+
+    fn mkdir(
+        &self,
+        ctx: Context,
+        parent: u64,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+        _extensions: Extensions,
+    ) -> io::Result<Entry> {
+        let mut state = self.state.lock().unwrap();
+        let mode = libc::S_IFDIR | (mode & !umask & 0o7777);
+        let inode = state.add(ctx, parent, name, mode, Kind::Dir(BTreeMap::new()))?;
+        state.node_mut(inode)?.attr.st_nlink = 2;
+        state.entry(inode)
+    }
+
+    fn unlink(&self, _ctx: Context, parent: u64, name: &CStr) -> io::Result<()> {
+        self.state.lock().unwrap().remove(parent, name, false)
+    }
+
+    fn rmdir(&self, _ctx: Context, parent: u64, name: &CStr) -> io::Result<()> {
+        self.state.lock().unwrap().remove(parent, name, true)
+    }
+
+    fn rename(
+        &self,
+        _ctx: Context,
+        olddir: u64,
+        oldname: &CStr,
+        newdir: u64,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        if flags != 0 {
+            return Err(err(libc::EINVAL));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let inode = state.lookup(olddir, oldname)?;
+        // Refuse to move a directory below itself, this would detach it from the tree.
+        if inode == newdir {
+            return Err(err(libc::EINVAL));
+        }
+        state.dir(newdir)?;
+        if state.lookup(newdir, newname).is_ok() {
+            let is_dir = matches!(state.node(inode)?.kind, Kind::Dir(_));
+            state.remove(newdir, newname, is_dir)?;
+        }
+
+        state.dir_mut(olddir)?.remove(oldname);
+        state.dir_mut(newdir)?.insert(newname.to_owned(), inode);
+        Ok(())
+    }
+
+    fn link(&self, _ctx: Context, inode: u64, newparent: u64, newname: &CStr) -> io::Result<Entry> {
+        let mut state = self.state.lock().unwrap();
+        if let Kind::Dir(_) = state.node(inode)?.kind {
+            return Err(err(libc::EPERM));
+        }
+        if state.dir(newparent)?.contains_key(newname) {
+            return Err(err(libc::EEXIST));
+        }
+
+        state.dir_mut(newparent)?.insert(newname.to_owned(), inode);
+        state.node_mut(inode)?.attr.st_nlink += 1;
+        state.entry(inode)
+    }
+
+    fn open(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _kill_priv: bool,
+        _flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        self.state.lock().unwrap().file(inode)?;
+        Ok((Some(inode), OpenOptions::empty()))
+    }
+
+    fn create(
+        &self,
+        ctx: Context,
+        parent: u64,
+        name: &CStr,
+        mode: u32,
+        _kill_priv: bool,
+        _flags: u32,
+        umask: u32,
+        _extensions: Extensions,
+    ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+        let inode = self.create_file(ctx, parent, name, mode & !umask)?;
+        let entry = self.state.lock().unwrap().entry(inode)?;
+        Ok((entry, Some(inode), OpenOptions::empty()))
+    }
+
+    fn read<W: io::Write + ZeroCopyWriter>(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        handle: u64,
+        mut w: W,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        let state = self.state.lock().unwrap();
+        w.write_from(state.file(handle)?, size as usize, offset)
+    }
+
+    fn write<R: io::Read + ZeroCopyReader>(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        handle: u64,
+        mut r: R,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        _kill_priv: bool,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        if offset.saturating_add(size.into()) > MAX_FILE_SIZE {
+            return Err(err(libc::EFBIG));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let count = r.read_to(state.file(handle)?, size as usize, offset, None)?;
+        state.refresh_size(handle)?;
+        Ok(count)
+    }
+
+    fn flush(&self, _ctx: Context, _inode: u64, handle: u64, _lock_owner: u64) -> io::Result<()> {
+        self.state.lock().unwrap().node(handle).map(|_| ())
+    }
+
+    fn fsync(&self, _ctx: Context, _inode: u64, _datasync: bool, handle: u64) -> io::Result<()> {
+        self.state.lock().unwrap().node(handle).map(|_| ())
+    }
+
+    fn fallocate(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        handle: u64,
+        _mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        let end = offset
+            .checked_add(length)
+            .ok_or_else(|| err(libc::EINVAL))?;
+        if end > MAX_FILE_SIZE {
+            return Err(err(libc::EFBIG));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let file = state.file(handle)?;
+        if file.metadata()?.len() < end {
+            file.set_len(end)?;
+        }
+        state.refresh_size(handle)
+    }
+
+    fn release(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _flags: u32,
+        _handle: u64,
+        _flush: bool,
+        _flock_release: bool,
+        _lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn setxattr(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+        _extra_flags: SetxattrFlags,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let node = state.node_mut(inode)?;
+        let exists = node.xattrs.contains_key(name);
+        if flags as i32 & libc::XATTR_CREATE != 0 && exists {
+            return Err(err(libc::EEXIST));
+        }
+        if flags as i32 & libc::XATTR_REPLACE != 0 && !exists {
+            return Err(err(libc::ENODATA));
+        }
+
+        node.xattrs.insert(name.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn getxattr(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        let state = self.state.lock().unwrap();
+        let value = state
+            .node(inode)?
+            .xattrs
+            .get(name)
+            .ok_or_else(|| err(libc::ENODATA))?;
+
+        if size == 0 {
+            Ok(GetxattrReply::Count(value.len() as u32))
+        } else if value.len() > size as usize {
+            Err(err(libc::ERANGE))
+        } else {
+            Ok(GetxattrReply::Value(value.clone()))
+        }
+    }
+
+    fn listxattr(&self, _ctx: Context, inode: u64, size: u32) -> io::Result<ListxattrReply> {
+        let state = self.state.lock().unwrap();
+        let names: Vec<u8> = state
+            .node(inode)?
+            .xattrs
+            .keys()
+            .flat_map(|name| name.to_bytes_with_nul().iter().copied())
+            .collect();
+
+        if size == 0 {
+            Ok(ListxattrReply::Count(names.len() as u32))
+        } else if names.len() > size as usize {
+            Err(err(libc::ERANGE))
+        } else {
+            Ok(ListxattrReply::Names(names))
+        }
+    }
+
+    fn removexattr(&self, _ctx: Context, inode: u64, name: &CStr) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.node_mut(inode)?.xattrs.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(err(libc::ENODATA)),
+        }
+    }
+
+    fn opendir(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        self.state.lock().unwrap().dir(inode)?;
+        Ok((Some(inode), OpenOptions::empty()))
+    }
+
+    fn readdir(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        handle: u64,
+        _size: u32,
+        offset: u64,
+    ) -> io::Result<MemDirIter> {
+        let state = self.state.lock().unwrap();
+        let entries = state
+            .dir(handle)?
+            .iter()
+            .map(|(name, inode)| {
+                let mode = state.nodes.get(inode).map_or(0, |n| n.attr.st_mode);
+                (*inode, (mode & libc::S_IFMT) >> 12, name.clone())
+            })
+            .collect::<Vec<_>>();
+
+        Ok(MemDirIter {
+            pos: (offset as usize).min(entries.len()),
+            entries,
+        })
+    }
+
+    fn fsyncdir(&self, _ctx: Context, _inode: u64, _datasync: bool, handle: u64) -> io::Result<()> {
+        self.state.lock().unwrap().dir(handle).map(|_| ())
+    }
+
+    fn releasedir(&self, _ctx: Context, _inode: u64, _flags: u32, _handle: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn setupmapping<T: FsCacheReqHandler>(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: u64,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut T,
+    ) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        let fd = state.file(inode)?.as_raw_fd();
+        vu_req.map(foffset, moffset, len, flags, fd)
+    }
+
+    fn removemapping<T: FsCacheReqHandler>(
+        &self,
+        _ctx: Context,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut T,
+    ) -> io::Result<()> {
+        vu_req.unmap(requests)
+    }
+
+    fn access(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _mask: u32,
+        _extensions: Extensions,
+    ) -> io::Result<()> {
+        self.state.lock().unwrap().node(inode).map(|_| ())
+    }
+
+    fn lseek(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        handle: u64,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        let state = self.state.lock().unwrap();
+        let len = state.file(handle)?.metadata()?.len();
+        match whence as i32 {
+            libc::SEEK_DATA if offset < len => Ok(offset),
+            libc::SEEK_HOLE if offset < len => Ok(len),
+            libc::SEEK_DATA | libc::SEEK_HOLE => Err(err(libc::ENXIO)),
+            _ => Err(err(libc::EINVAL)),
+        }
+    }
+
+    fn syncfs(&self, _ctx: Context, _inode: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
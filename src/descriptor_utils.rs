@@ -13,12 +13,18 @@ use std::{cmp, result};
 use virtio_queue::DescriptorChain;
 use vm_memory::bitmap::{Bitmap, BitmapSlice};
 use vm_memory::{
-    Address, ByteValued, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion, Le16,
-    Le32, Le64, VolatileMemory, VolatileMemoryError, VolatileSlice,
+    Address, ByteValued, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+    VolatileMemory, VolatileMemoryError, VolatileSlice,
 };
 
 use crate::file_traits::FileReadWriteAtVolatile;
 use crate::oslib;
+use crate::uring::GuestIovecs;
+
+#[cfg(any(test, feature = "fuzzing"))]
+use virtio_queue::{Queue, QueueOwnedT, QueueT};
+#[cfg(any(test, feature = "fuzzing"))]
+use vm_memory::{Bytes, GuestAddress, Le16, Le32, Le64};
 
 #[derive(Debug)]
 pub enum Error {
@@ -138,6 +144,39 @@ impl<'a, B: BitmapSlice> DescriptorChainConsumer<'a, B> {
         Ok(bytes_consumed)
     }
 
+    /// Returns the guest buffers making up at most `count` bytes at the front of the buffer,
+    /// without consuming them.
+    ///
+    /// # Safety
+    ///
+    /// The guest memory must stay mapped for as long as the returned object is used.
+    unsafe fn iovecs(&self, count: usize, writable: bool) -> GuestIovecs
+    where
+        B: Send + 'static,
+    {
+        let mut rem = count;
+        let mut iovecs = GuestIovecs::default();
+        for vs in &self.buffers {
+            if rem == 0 {
+                break;
+            }
+
+            let len = cmp::min(rem, vs.len());
+            if writable {
+                let bitmap = vs.bitmap().clone();
+                iovecs.push_writable(
+                    vs.ptr_guard_mut(),
+                    len,
+                    Box::new(move |count| bitmap.mark_dirty(0, count)),
+                );
+            } else {
+                iovecs.push_readable(vs.ptr_guard(), len);
+            }
+            rem -= len;
+        }
+        iovecs
+    }
+
     fn split_at(&mut self, offset: usize) -> Result<DescriptorChainConsumer<'a, B>> {
         let mut rem = offset;
         let pos = self.buffers.iter().position(|vs| {
@@ -281,6 +320,20 @@ impl<'a, B: Bitmap + BitmapSlice + 'static> Reader<'a, B> {
     pub fn split_at(&mut self, offset: usize) -> Result<Reader<'a, B>> {
         self.buffer.split_at(offset).map(|buffer| Reader { buffer })
     }
+
+    /// Returns the guest buffers making up at most the next `count` bytes to be read, so that
+    /// they can be handed to the kernel directly.  Nothing is consumed.
+    ///
+    /// # Safety
+    ///
+    /// The guest memory this `Reader` was created from must stay mapped for as long as the
+    /// returned object is used.
+    pub unsafe fn iovecs(&self, count: usize) -> GuestIovecs
+    where
+        B: Send,
+    {
+        self.buffer.iovecs(count, false)
+    }
 }
 
 impl<'a, B: BitmapSlice> io::Read for Reader<'a, B> {
@@ -398,6 +451,36 @@ impl<'a, B: Bitmap + BitmapSlice + 'static> Writer<'a, B> {
     pub fn split_at(&mut self, offset: usize) -> Result<Writer<'a, B>> {
         self.buffer.split_at(offset).map(|buffer| Writer { buffer })
     }
+
+    /// Returns the guest buffers making up at most the next `count` bytes to be written, so that
+    /// they can be handed to the kernel directly.  Nothing is consumed.  Bytes written through
+    /// them are not marked dirty until `GuestIovecs::mark_dirty()` is called.
+    ///
+    /// # Safety
+    ///
+    /// The guest memory this `Writer` was created from must stay mapped for as long as the
+    /// returned object is used.
+    pub unsafe fn iovecs(&self, count: usize) -> GuestIovecs
+    where
+        B: Send,
+    {
+        self.buffer.iovecs(count, true)
+    }
+
+    /// Returns a copy of the first `count` bytes of the descriptor chain buffer (or less, if
+    /// fewer bytes are available).  Since writes through a `Writer` do not affect its clones,
+    /// this can be used on a clone to retrieve the data that was written through the original.
+    pub fn read_back(&self, count: usize) -> Vec<u8> {
+        let mut data = vec![0u8; cmp::min(count, self.available_bytes())];
+        let mut pos = 0;
+        for buf in &self.buffer.buffers {
+            if pos == data.len() {
+                break;
+            }
+            pos += buf.copy_to(&mut data[pos..]);
+        }
+        data
+    }
 }
 
 impl<'a, B: BitmapSlice> io::Write for Writer<'a, B> {
@@ -435,6 +518,14 @@ pub enum DescriptorType {
     Writable,
 }
 
+#[cfg(any(test, feature = "fuzzing"))]
+const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+#[cfg(any(test, feature = "fuzzing"))]
+const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+#[cfg(any(test, feature = "fuzzing"))]
+const MAX_QUEUE_SIZE: u16 = 32768;
+
+#[cfg(any(test, feature = "fuzzing"))]
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 struct virtq_desc {
@@ -445,8 +536,10 @@ struct virtq_desc {
 }
 
 // Safe because it only has data and has no implicit padding.
+#[cfg(any(test, feature = "fuzzing"))]
 unsafe impl ByteValued for virtq_desc {}
 
+#[cfg(any(test, feature = "fuzzing"))]
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 struct virtq_avail {
@@ -456,79 +549,73 @@ struct virtq_avail {
 }
 
 // Safe because it only has data and has no implicit padding.
+#[cfg(any(test, feature = "fuzzing"))]
 unsafe impl ByteValued for virtq_avail {}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use virtio_queue::{Queue, QueueOwnedT, QueueT};
-    use vm_memory::{Bytes, GuestAddress};
-
-    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
-    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
-    const MAX_QUEUE_SIZE: u16 = 32768;
-
-    /// Test utility function to create a descriptor chain in guest memory.
-    pub fn create_descriptor_chain(
-        memory: &GuestMemoryMmap,
-        descriptor_array_addr: GuestAddress,
-        mut buffers_start_addr: GuestAddress,
-        descriptors: Vec<(DescriptorType, u32)>,
-        spaces_between_regions: u32,
-    ) -> Result<DescriptorChain<&GuestMemoryMmap>> {
-        let descriptors_len = descriptors.len();
-        for (index, (type_, size)) in descriptors.into_iter().enumerate() {
-            let mut flags = 0;
-            if let DescriptorType::Writable = type_ {
-                flags |= VIRTQ_DESC_F_WRITE;
-            }
-            if index + 1 < descriptors_len {
-                flags |= VIRTQ_DESC_F_NEXT;
-            }
-
-            let index = index as u16;
-            let desc = virtq_desc {
-                addr: buffers_start_addr.raw_value().into(),
-                len: size.into(),
-                flags: flags.into(),
-                next: (index + 1).into(),
-            };
-
-            let offset = size + spaces_between_regions;
-            buffers_start_addr = buffers_start_addr
-                .checked_add(u64::from(offset))
-                .ok_or(Error::InvalidChain)?;
-
-            let _ = memory.write_obj(
-                desc,
-                descriptor_array_addr
-                    .checked_add(u64::from(index) * std::mem::size_of::<virtq_desc>() as u64)
-                    .ok_or(Error::InvalidChain)?,
-            );
+/// Test utility function to create a descriptor chain in guest memory.
+#[cfg(any(test, feature = "fuzzing"))]
+pub fn create_descriptor_chain(
+    memory: &GuestMemoryMmap,
+    descriptor_array_addr: GuestAddress,
+    mut buffers_start_addr: GuestAddress,
+    descriptors: Vec<(DescriptorType, u32)>,
+    spaces_between_regions: u32,
+) -> Result<DescriptorChain<&GuestMemoryMmap>> {
+    let descriptors_len = descriptors.len();
+    for (index, (type_, size)) in descriptors.into_iter().enumerate() {
+        let mut flags = 0;
+        if let DescriptorType::Writable = type_ {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+        if index + 1 < descriptors_len {
+            flags |= VIRTQ_DESC_F_NEXT;
         }
 
-        let avail_ring = descriptor_array_addr
-            .checked_add(
-                u64::from(descriptors_len as u16) * std::mem::size_of::<virtq_desc>() as u64,
-            )
-            .ok_or(Error::InvalidChain)?;
-        let avail = virtq_avail {
-            flags: 0.into(),
-            idx: 1.into(),
-            ring: 0.into(),
+        let index = index as u16;
+        let desc = virtq_desc {
+            addr: buffers_start_addr.raw_value().into(),
+            len: size.into(),
+            flags: flags.into(),
+            next: (index + 1).into(),
         };
-        let _ = memory.write_obj(avail, avail_ring);
 
-        let mut queue: Queue = Queue::new(MAX_QUEUE_SIZE).unwrap();
-        queue
-            .try_set_desc_table_address(descriptor_array_addr)
-            .unwrap();
-        queue.try_set_avail_ring_address(avail_ring).unwrap();
-        queue.set_ready(true);
-        let desc = queue.iter(memory).unwrap().next().unwrap();
-        Ok(desc.clone())
+        let offset = size + spaces_between_regions;
+        buffers_start_addr = buffers_start_addr
+            .checked_add(u64::from(offset))
+            .ok_or(Error::InvalidChain)?;
+
+        let _ = memory.write_obj(
+            desc,
+            descriptor_array_addr
+                .checked_add(u64::from(index) * std::mem::size_of::<virtq_desc>() as u64)
+                .ok_or(Error::InvalidChain)?,
+        );
     }
 
+    let avail_ring = descriptor_array_addr
+        .checked_add(u64::from(descriptors_len as u16) * std::mem::size_of::<virtq_desc>() as u64)
+        .ok_or(Error::InvalidChain)?;
+    let avail = virtq_avail {
+        flags: 0.into(),
+        idx: 1.into(),
+        ring: 0.into(),
+    };
+    let _ = memory.write_obj(avail, avail_ring);
+
+    let mut queue: Queue = Queue::new(MAX_QUEUE_SIZE).unwrap();
+    queue
+        .try_set_desc_table_address(descriptor_array_addr)
+        .unwrap();
+    queue.try_set_avail_ring_address(avail_ring).unwrap();
+    queue.set_ready(true);
+    let desc = queue.iter(memory).unwrap().next().unwrap();
+    Ok(desc.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
     #[test]
     fn reader_test_simple_chain() {
         use DescriptorType::*;
@@ -553,7 +640,7 @@ mod tests {
         assert_eq!(reader.available_bytes(), 106);
         assert_eq!(reader.bytes_read(), 0);
 
-        let mut buffer = [0 as u8; 64];
+        let mut buffer = [0u8; 64];
         if let Err(e) = reader.read_exact(&mut buffer) {
             panic!("read_exact should not fail here: {:?}", e);
         }
@@ -594,7 +681,7 @@ mod tests {
         assert_eq!(writer.available_bytes(), 106);
         assert_eq!(writer.bytes_written(), 0);
 
-        let buffer = [0 as u8; 64];
+        let buffer = [0u8; 64];
         if let Err(e) = writer.write_all(&buffer) {
             panic!("write_all should not fail here: {:?}", e);
         }
@@ -939,7 +1026,7 @@ mod tests {
         .expect("create_descriptor_chain failed");
         let mut reader = Reader::new(&memory, chain).expect("failed to create Reader");
 
-        let mut buf = vec![0u8; 64];
+        let mut buf = [0u8; 64];
         assert_eq!(
             reader.read(&mut buf[..]).expect("failed to read to buffer"),
             48
@@ -963,7 +1050,7 @@ mod tests {
         .expect("create_descriptor_chain failed");
         let mut writer = Writer::new(&memory, chain).expect("failed to create Writer");
 
-        let buf = vec![0xdeu8; 64];
+        let buf = [0xdeu8; 64];
         assert_eq!(
             writer.write(&buf[..]).expect("failed to write from buffer"),
             48
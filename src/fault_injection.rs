@@ -0,0 +1,1370 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! A `Layer` that injects errors and delays into the operations of another file system, e.g. to
+//! test how a guest copes with a full disk or a flaky network file system.
+//!
+//! ## Rules
+//!
+//! Faults are described by a list of rules, one per line.  Empty lines and lines starting with
+//! `#` are ignored.  A rule consists of whitespace-separated `key=value` pairs:
+//!
+//! | key | description |
+//! | - | - |
+//! | `op` | Comma-separated list of the operations the rule applies to, named after the `FileSystem` methods (e.g. `write,fsync`).  Default: all operations. |
+//! | `path` | Glob matched against the path of the file the operation refers to, relative to the shared directory and starting with `/`.  `*` and `?` do not match `/`, `**` matches anything.  Default: all paths. |
+//! | `error` | Error to fail the operation with, either a name (e.g. `ENOSPC`) or a number. |
+//! | `delay` | Time to wait before performing (or failing) the operation, e.g. `100ms`, `2s` or `500us`. |
+//! | `probability` | Probability with which the rule applies to a matching operation, between 0 and 1.  Default: 1. |
+//! | `count` | Maximum number of times the rule applies.  Default: unlimited. |
+//!
+//! Every rule needs at least an `error` or a `delay`.  For each operation, the rules are checked
+//! in order, and only the first one that applies takes effect.
+//!
+//! A line of the form `seed=<number>` sets the seed for the random numbers behind `probability`.
+//! With the same seed and the same sequence of operations, the same faults are injected, so tests
+//! can be deterministic (as long as requests are processed serially, i.e. without thread pool).
+//!
+//! Example:
+//!
+//! ```text
+//! # The first three writes below /data fail as if the disk was full.
+//! op=write,create,mkdir path=/data/** error=ENOSPC count=3
+//! # One in ten metadata operations takes a second.
+//! op=lookup,getattr delay=1s probability=0.1
+//! ```
+//!
+//! Paths are only known for inodes that were looked up through the wrapper.  Operations that do
+//! not refer to a file (e.g. `init`, `removemapping`) only match rules without `path`.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::filesystem::{
+    AsyncIoOp, AsyncIoTarget, Context, Entry, Extensions, FileSystem, FsOptions, GetxattrReply,
+    ListxattrReply, OpenOptions, RemovemappingOne, SetattrValid, SetxattrFlags, ZeroCopyReader,
+    ZeroCopyWriter, ROOT_ID,
+};
+use crate::fs_cache_req_handler::FsCacheReqHandler;
+use crate::layer::{InnerDirIter, InnerHandle, InnerInode, Layer};
+use crate::oslib;
+
+/// Names of the operations that rules can refer to.
+const OPS: &[&str] = &[
+    "init",
+    "lookup",
+    "getattr",
+    "setattr",
+    "readlink",
+    "symlink",
+    "mknod",
+    "mkdir",
+    "unlink",
+    "rmdir",
+    "rename",
+    "link",
+    "open",
+    "create",
+    "read",
+    "write",
+    "flush",
+    "fsync",
+    "fallocate",
+    "release",
+    "statfs",
+    "setxattr",
+    "getxattr",
+    "listxattr",
+    "removexattr",
+    "opendir",
+    "readdir",
+    "fsyncdir",
+    "releasedir",
+    "setupmapping",
+    "removemapping",
+    "access",
+    "lseek",
+    "copyfilerange",
+    "syncfs",
+];
+
+/// Errors that can be given by name in a rule.
+const ERRNO_NAMES: &[(&str, i32)] = &[
+    ("EACCES", libc::EACCES),
+    ("EAGAIN", libc::EAGAIN),
+    ("EBUSY", libc::EBUSY),
+    ("EDQUOT", libc::EDQUOT),
+    ("EEXIST", libc::EEXIST),
+    ("EFBIG", libc::EFBIG),
+    ("EINTR", libc::EINTR),
+    ("EINVAL", libc::EINVAL),
+    ("EIO", libc::EIO),
+    ("EMFILE", libc::EMFILE),
+    ("ENFILE", libc::ENFILE),
+    ("ENOENT", libc::ENOENT),
+    ("ENOMEM", libc::ENOMEM),
+    ("ENOSPC", libc::ENOSPC),
+    ("ENOTSUP", libc::ENOTSUP),
+    ("EPERM", libc::EPERM),
+    ("EROFS", libc::EROFS),
+    ("ESTALE", libc::ESTALE),
+    ("ETIMEDOUT", libc::ETIMEDOUT),
+];
+
+/// How often the rules file is checked for changes.
+const RULES_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Errors that can occur while parsing fault injection rules.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// A token is not of the form `key=value`.
+    InvalidToken(String),
+    /// The key of a `key=value` pair is unknown.
+    UnknownKey(String),
+    /// An operation named in `op` does not exist.
+    UnknownOp(String),
+    /// The value of `error` is neither a known error name nor a positive number.
+    InvalidError(String),
+    /// The value of `delay` is not a number followed by `s`, `ms` or `us`.
+    InvalidDelay(String),
+    /// The value of `probability` is not a number between 0 and 1.
+    InvalidProbability(String),
+    /// The value of `count` or `seed` is not a number.
+    InvalidNumber(String),
+    /// The rule has neither `error` nor `delay`.
+    NoEffect,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ErrorKind::*;
+        match self {
+            InvalidToken(t) => write!(f, "expected key=value, found '{t}'"),
+            UnknownKey(k) => write!(f, "unknown key '{k}'"),
+            UnknownOp(o) => write!(f, "unknown operation '{o}'"),
+            InvalidError(e) => write!(f, "invalid error '{e}'"),
+            InvalidDelay(d) => write!(f, "invalid delay '{d}'"),
+            InvalidProbability(p) => write!(f, "invalid probability '{p}'"),
+            InvalidNumber(n) => write!(f, "invalid number '{n}'"),
+            NoEffect => write!(f, "rule has neither an error nor a delay"),
+        }
+    }
+}
+
+/// An error in the fault injection rules, with the (1-based) number of the culpable line.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Error {
+    pub cause: ErrorKind,
+    pub line: usize,
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.cause)
+    }
+}
+
+/// A single fault injection rule, see the module documentation.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rule {
+    ops: Option<Vec<&'static str>>,
+    path: Option<Vec<u8>>,
+    errno: Option<i32>,
+    delay: Duration,
+    probability: f64,
+    count: Option<u64>,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Result<Self, ErrorKind> {
+        let mut rule = Rule {
+            probability: 1.0,
+            ..Default::default()
+        };
+
+        for token in line.split_whitespace() {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or_else(|| ErrorKind::InvalidToken(token.to_owned()))?;
+            match key {
+                "op" => {
+                    let ops = value
+                        .split(',')
+                        .map(|op| {
+                            OPS.iter()
+                                .find(|o| **o == op)
+                                .copied()
+                                .ok_or_else(|| ErrorKind::UnknownOp(op.to_owned()))
+                        })
+                        .collect::<Result<_, _>>()?;
+                    rule.ops = Some(ops);
+                }
+                "path" => rule.path = Some(value.as_bytes().to_vec()),
+                "error" => rule.errno = Some(parse_errno(value)?),
+                "delay" => rule.delay = parse_delay(value)?,
+                "probability" => {
+                    rule.probability = value
+                        .parse()
+                        .ok()
+                        .filter(|p| (0.0..=1.0).contains(p))
+                        .ok_or_else(|| ErrorKind::InvalidProbability(value.to_owned()))?;
+                }
+                "count" => rule.count = Some(parse_number(value)?),
+                _ => return Err(ErrorKind::UnknownKey(key.to_owned())),
+            }
+        }
+
+        if rule.errno.is_none() && rule.delay.is_zero() {
+            return Err(ErrorKind::NoEffect);
+        }
+        Ok(rule)
+    }
+
+    fn matches_op(&self, op: &str) -> bool {
+        match &self.ops {
+            Some(ops) => ops.contains(&op),
+            None => true,
+        }
+    }
+}
+
+fn parse_number(value: &str) -> Result<u64, ErrorKind> {
+    value
+        .parse()
+        .map_err(|_| ErrorKind::InvalidNumber(value.to_owned()))
+}
+
+fn parse_errno(value: &str) -> Result<i32, ErrorKind> {
+    if let Some((_, errno)) = ERRNO_NAMES.iter().find(|(name, _)| *name == value) {
+        return Ok(*errno);
+    }
+    value
+        .parse()
+        .ok()
+        .filter(|e| *e > 0)
+        .ok_or_else(|| ErrorKind::InvalidError(value.to_owned()))
+}
+
+fn parse_delay(value: &str) -> Result<Duration, ErrorKind> {
+    let invalid = || ErrorKind::InvalidDelay(value.to_owned());
+    let (number, to_duration): (&str, fn(u64) -> Duration) =
+        if let Some(n) = value.strip_suffix("ms") {
+            (n, Duration::from_millis)
+        } else if let Some(n) = value.strip_suffix("us") {
+            (n, Duration::from_micros)
+        } else if let Some(n) = value.strip_suffix('s') {
+            (n, Duration::from_secs)
+        } else {
+            return Err(invalid());
+        };
+    number.parse().map(to_duration).map_err(|_| invalid())
+}
+
+/// A list of fault injection rules, see the module documentation for the syntax.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    seed: u64,
+}
+
+impl FromStr for Rules {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Rules::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let res = match line.strip_prefix("seed=") {
+                Some(seed) => parse_number(seed).map(|seed| rules.seed = seed),
+                None => Rule::parse(line).map(|rule| rules.rules.push(rule)),
+            };
+            res.map_err(|cause| Error { cause, line: i + 1 })?;
+        }
+        Ok(rules)
+    }
+}
+
+/// Device, inode, size and modification time (seconds and nanoseconds) of a file.
+type FileVersion = (u64, u64, u64, i64, i64);
+
+/// A file with fault injection rules that is re-read whenever it changes.
+///
+/// The file is looked up relative to its parent directory, which is kept open, so that it can
+/// still be found after entering the sandbox.
+pub struct RulesFile {
+    dir: File,
+    name: CString,
+    /// Version of the file when it was last read.
+    version: Mutex<Option<FileVersion>>,
+}
+
+impl RulesFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EISDIR))?;
+
+        let dir = CString::new(dir.as_os_str().as_bytes())?;
+        let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+        // SAFETY: `dir` is a valid, nul-terminated string.
+        let fd = unsafe { libc::open(dir.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(RulesFile {
+            // SAFETY: We just opened `fd` and nothing else owns it.
+            dir: unsafe { File::from_raw_fd(fd) },
+            name: CString::new(name.as_bytes())?,
+            version: Mutex::new(None),
+        })
+    }
+
+    /// Reads and parses the file, unless it has not changed since the last call.
+    pub fn read_if_changed(&self) -> io::Result<Option<Rules>> {
+        let fd = oslib::openat(
+            &self.dir,
+            &self.name,
+            libc::O_RDONLY | libc::O_CLOEXEC,
+            None,
+        )?;
+        // SAFETY: We just opened `fd` and nothing else owns it.
+        let mut file = unsafe { File::from_raw_fd(fd) };
+
+        let md = file.metadata()?;
+        let version = Some((md.dev(), md.ino(), md.size(), md.mtime(), md.mtime_nsec()));
+        let mut last_version = self.version.lock().unwrap();
+        if *last_version == version {
+            return Ok(None);
+        }
+
+        let mut rules = String::new();
+        file.read_to_string(&mut rules)?;
+        let rules = rules
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        *last_version = version;
+        Ok(Some(rules))
+    }
+}
+
+struct RuleState {
+    rule: Rule,
+    /// Number of times the rule has been applied.
+    applied: u64,
+}
+
+struct State {
+    rules: Vec<RuleState>,
+    /// State of the xorshift64* generator behind `Rule::probability`.
+    rng: u64,
+}
+
+impl State {
+    fn new(rules: Rules) -> Self {
+        State {
+            rules: rules
+                .rules
+                .into_iter()
+                .map(|rule| RuleState { rule, applied: 0 })
+                .collect(),
+            // xorshift64* must not be seeded with 0.
+            rng: match rules.seed ^ 0x9e37_79b9_7f4a_7c15 {
+                0 => 0x9e37_79b9_7f4a_7c15,
+                rng => rng,
+            },
+        }
+    }
+
+    /// Returns a random number in `[0, 1)`.
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let r = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (r >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The name under which an inode was last seen.
+struct InodeName {
+    parent: u64,
+    name: CString,
+    /// Lookup count, so that the name can be dropped together with the inode.
+    refcount: u64,
+}
+
+/// Matches `path` against `glob`, where `*` and `?` do not match `/`, and `**` matches anything.
+fn glob_match(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let end = path.iter().position(|c| *c == b'/').unwrap_or(path.len());
+            (0..=end).any(|i| glob_match(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => matches!(path, [c, p @ ..] if *c != b'/' && glob_match(rest, p)),
+        [g, rest @ ..] => matches!(path, [c, p @ ..] if c == g && glob_match(rest, p)),
+    }
+}
+
+/// Wraps a `FileSystem` and injects faults into its operations according to `Rules`.
+pub struct FaultInjectionFs<F: FileSystem> {
+    inner: F,
+    /// Whether to inject faults at all, see `disabled()`.
+    enabled: bool,
+    state: Mutex<State>,
+    rules_file: Option<RulesFile>,
+    next_rules_check: Mutex<Instant>,
+    names: RwLock<HashMap<u64, InodeName>>,
+}
+
+impl<F: FileSystem> FaultInjectionFs<F> {
+    pub fn new(inner: F, rules: Rules) -> Self {
+        FaultInjectionFs {
+            inner,
+            enabled: true,
+            state: Mutex::new(State::new(rules)),
+            rules_file: None,
+            next_rules_check: Mutex::new(Instant::now()),
+            names: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a `FaultInjectionFs` whose rules are read from `rules_file`, and are replaced
+    /// whenever the file changes.
+    pub fn with_rules_file(inner: F, rules_file: RulesFile) -> io::Result<Self> {
+        let rules = rules_file.read_if_changed()?.unwrap_or_default();
+        Ok(FaultInjectionFs {
+            rules_file: Some(rules_file),
+            next_rules_check: Mutex::new(Instant::now() + RULES_FILE_CHECK_INTERVAL),
+            ..Self::new(inner, rules)
+        })
+    }
+
+    /// Creates a `FaultInjectionFs` that passes all operations through unchanged, without the
+    /// bookkeeping needed for rules.
+    pub fn disabled(inner: F) -> Self {
+        FaultInjectionFs {
+            enabled: false,
+            ..Self::new(inner, Rules::default())
+        }
+    }
+
+    /// Replaces the rules, which also resets their counts and the random number generator.
+    pub fn set_rules(&self, rules: Rules) {
+        *self.state.lock().unwrap() = State::new(rules);
+    }
+
+    fn check_rules_file(&self) {
+        let rules_file = match &self.rules_file {
+            Some(rules_file) => rules_file,
+            None => return,
+        };
+
+        {
+            let now = Instant::now();
+            let mut next_check = self.next_rules_check.lock().unwrap();
+            if now < *next_check {
+                return;
+            }
+            *next_check = now + RULES_FILE_CHECK_INTERVAL;
+        }
+
+        match rules_file.read_if_changed() {
+            Ok(Some(rules)) => {
+                info!("Loaded new fault injection rules");
+                self.set_rules(rules);
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to read fault injection rules, keeping the old ones: {e}"),
+        }
+    }
+
+    /// Applies the first rule matching the operation `op` on `path`, i.e. waits for its delay
+    /// and returns its error.  `path` is only called if there is a rule that needs it.
+    fn inject<P: FnOnce() -> Option<Vec<u8>>>(&self, op: &str, path: P) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        self.check_rules_file();
+
+        let mut path = Some(path);
+        let mut resolved_path = None;
+        let (delay, errno) = {
+            let mut state = self.state.lock().unwrap();
+            let mut effect = None;
+            for i in 0..state.rules.len() {
+                let rule = &state.rules[i].rule;
+                if !rule.matches_op(op) || rule.count.is_some_and(|c| state.rules[i].applied >= c) {
+                    continue;
+                }
+                if let Some(glob) = &rule.path {
+                    if let Some(path) = path.take() {
+                        resolved_path = path();
+                    }
+                    match &resolved_path {
+                        Some(p) if glob_match(glob, p) => {}
+                        _ => continue,
+                    }
+                }
+                let rule = rule.clone();
+                if rule.probability < 1.0 && state.random() >= rule.probability {
+                    continue;
+                }
+
+                state.rules[i].applied += 1;
+                effect = Some((rule.delay, rule.errno));
+                break;
+            }
+            match effect {
+                Some(effect) => effect,
+                None => return Ok(()),
+            }
+        };
+
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        match errno {
+            Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+            None => Ok(()),
+        }
+    }
+
+    fn path(&self, inode: u64) -> Option<Vec<u8>> {
+        let names = self.names.read().unwrap();
+        let mut components = Vec::new();
+        let mut inode = inode;
+        while inode != ROOT_ID {
+            let name = names.get(&inode)?;
+            components.push(name.name.as_bytes());
+            inode = name.parent;
+            // Avoid looping forever in case of inconsistent names.
+            if components.len() > libc::PATH_MAX as usize {
+                return None;
+            }
+        }
+
+        if components.is_empty() {
+            return Some(b"/".to_vec());
+        }
+        let mut path = Vec::new();
+        for component in components.iter().rev() {
+            path.push(b'/');
+            path.extend_from_slice(component);
+        }
+        Some(path)
+    }
+
+    fn child_path(&self, parent: u64, name: &CStr) -> Option<Vec<u8>> {
+        let mut path = self.path(parent)?;
+        if path != b"/" {
+            path.push(b'/');
+        }
+        path.extend_from_slice(name.to_bytes());
+        Some(path)
+    }
+
+    /// Remembers the name of the inode returned by a successful operation that increased its
+    /// lookup count.
+    fn remember(&self, parent: u64, name: &CStr, entry: Option<&Entry>) {
+        if !self.enabled {
+            return;
+        }
+        let inode = match entry {
+            Some(entry) if entry.inode != 0 => entry.inode,
+            _ => return,
+        };
+        let mut names = self.names.write().unwrap();
+        let refcount = names.get(&inode).map_or(0, |n| n.refcount);
+        names.insert(
+            inode,
+            InodeName {
+                parent,
+                name: name.to_owned(),
+                refcount: refcount + 1,
+            },
+        );
+    }
+
+    fn forget_name(&self, inode: u64, count: u64) {
+        if !self.enabled {
+            return;
+        }
+        let mut names = self.names.write().unwrap();
+        if let Some(name) = names.get_mut(&inode) {
+            name.refcount = name.refcount.saturating_sub(count);
+            if name.refcount == 0 {
+                names.remove(&inode);
+            }
+        }
+    }
+}
+
+impl<F: FileSystem> Layer for FaultInjectionFs<F> {
+    type Inner = F;
+
+    fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        self.inject("init", || None)?;
+        self.inner.init(capable)
+    }
+
+    fn destroy(&self) {
+        self.inner.destroy();
+        self.names.write().unwrap().clear();
+    }
+
+    fn lookup(&self, ctx: Context, parent: InnerInode<Self>, name: &CStr) -> io::Result<Entry> {
+        let parent = parent.into();
+        self.inject("lookup", || self.child_path(parent, name))?;
+        let res = self.inner.lookup(ctx, parent.into(), name);
+        self.remember(parent, name, res.as_ref().ok());
+        res
+    }
+
+    fn forget(&self, ctx: Context, inode: InnerInode<Self>, count: u64) {
+        let inode = inode.into();
+        self.inner.forget(ctx, inode.into(), count);
+        self.forget_name(inode, count);
+    }
+
+    fn batch_forget(&self, ctx: Context, requests: Vec<(InnerInode<Self>, u64)>) {
+        let requests: Vec<(u64, u64)> = requests
+            .into_iter()
+            .map(|(inode, count)| (inode.into(), count))
+            .collect();
+        self.inner.batch_forget(
+            ctx,
+            requests
+                .iter()
+                .map(|(inode, count)| ((*inode).into(), *count))
+                .collect(),
+        );
+        for (inode, count) in requests {
+            self.forget_name(inode, count);
+        }
+    }
+
+    fn getattr(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: Option<InnerHandle<Self>>,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let inode = inode.into();
+        self.inject("getattr", || self.path(inode))?;
+        self.inner.getattr(ctx, inode.into(), handle)
+    }
+
+    fn setattr(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        attr: libc::stat64,
+        handle: Option<InnerHandle<Self>>,
+        valid: SetattrValid,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let inode = inode.into();
+        self.inject("setattr", || self.path(inode))?;
+        self.inner.setattr(ctx, inode.into(), attr, handle, valid)
+    }
+
+    fn readlink(&self, ctx: Context, inode: InnerInode<Self>) -> io::Result<Vec<u8>> {
+        let inode = inode.into();
+        self.inject("readlink", || self.path(inode))?;
+        self.inner.readlink(ctx, inode.into())
+    }
+
+    fn symlink(
+        &self,
+        ctx: Context,
+        linkname: &CStr,
+        parent: InnerInode<Self>,
+        name: &CStr,
+        extensions: Extensions,
+    ) -> io::Result<Entry> {
+        let parent = parent.into();
+        self.inject("symlink", || self.child_path(parent, name))?;
+        let res = self
+            .inner
+            .symlink(ctx, linkname, parent.into(), name, extensions);
+        self.remember(parent, name, res.as_ref().ok());
+        res
+    }
+
+    fn mknod(
+        &self,
+        ctx: Context,
+        parent: InnerInode<Self>,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<Entry> {
+        let parent = parent.into();
+        self.inject("mknod", || self.child_path(parent, name))?;
+        let res = self
+            .inner
+            .mknod(ctx, parent.into(), name, mode, rdev, umask, extensions);
+        self.remember(parent, name, res.as_ref().ok());
+        res
+    }
+
+    fn mkdir(
+        &self,
+        ctx: Context,
+        parent: InnerInode<Self>,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<Entry> {
+        let parent = parent.into();
+        self.inject("mkdir", || self.child_path(parent, name))?;
+        let res = self
+            .inner
+            .mkdir(ctx, parent.into(), name, mode, umask, extensions);
+        self.remember(parent, name, res.as_ref().ok());
+        res
+    }
+
+    fn unlink(&self, ctx: Context, parent: InnerInode<Self>, name: &CStr) -> io::Result<()> {
+        let parent = parent.into();
+        self.inject("unlink", || self.child_path(parent, name))?;
+        self.inner.unlink(ctx, parent.into(), name)
+    }
+
+    fn rmdir(&self, ctx: Context, parent: InnerInode<Self>, name: &CStr) -> io::Result<()> {
+        let parent = parent.into();
+        self.inject("rmdir", || self.child_path(parent, name))?;
+        self.inner.rmdir(ctx, parent.into(), name)
+    }
+
+    fn rename(
+        &self,
+        ctx: Context,
+        olddir: InnerInode<Self>,
+        oldname: &CStr,
+        newdir: InnerInode<Self>,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        let (olddir, newdir) = (olddir.into(), newdir.into());
+        self.inject("rename", || self.child_path(olddir, oldname))?;
+        self.inner
+            .rename(ctx, olddir.into(), oldname, newdir.into(), newname, flags)?;
+
+        // Keep the paths of the renamed inode and its children up to date.  With
+        // RENAME_EXCHANGE, the inode at the new name moves to the old name.
+        let mut names = self.names.write().unwrap();
+        let mut moved = Vec::new();
+        for (inode, name) in names.iter() {
+            if name.parent == olddir && name.name.as_c_str() == oldname {
+                moved.push((*inode, newdir, newname.to_owned()));
+            } else if flags & libc::RENAME_EXCHANGE != 0
+                && name.parent == newdir
+                && name.name.as_c_str() == newname
+            {
+                moved.push((*inode, olddir, oldname.to_owned()));
+            }
+        }
+        for (inode, parent, new_name) in moved {
+            if let Some(name) = names.get_mut(&inode) {
+                name.parent = parent;
+                name.name = new_name;
+            }
+        }
+        Ok(())
+    }
+
+    fn link(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        newparent: InnerInode<Self>,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        let (inode, newparent) = (inode.into(), newparent.into());
+        self.inject("link", || self.child_path(newparent, newname))?;
+        let res = self
+            .inner
+            .link(ctx, inode.into(), newparent.into(), newname);
+        self.remember(newparent, newname, res.as_ref().ok());
+        res
+    }
+
+    fn open(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        kill_priv: bool,
+        flags: u32,
+    ) -> io::Result<(Option<InnerHandle<Self>>, OpenOptions)> {
+        let inode = inode.into();
+        self.inject("open", || self.path(inode))?;
+        self.inner.open(ctx, inode.into(), kill_priv, flags)
+    }
+
+    fn create(
+        &self,
+        ctx: Context,
+        parent: InnerInode<Self>,
+        name: &CStr,
+        mode: u32,
+        kill_priv: bool,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<InnerHandle<Self>>, OpenOptions)> {
+        let parent = parent.into();
+        self.inject("create", || self.child_path(parent, name))?;
+        let res = self.inner.create(
+            ctx,
+            parent.into(),
+            name,
+            mode,
+            kill_priv,
+            flags,
+            umask,
+            extensions,
+        );
+        self.remember(parent, name, res.as_ref().ok().map(|(entry, _, _)| entry));
+        res
+    }
+
+    fn read<W: io::Write + ZeroCopyWriter>(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        w: W,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        let inode = inode.into();
+        self.inject("read", || self.path(inode))?;
+        self.inner.read(
+            ctx,
+            inode.into(),
+            handle,
+            w,
+            size,
+            offset,
+            lock_owner,
+            flags,
+        )
+    }
+
+    fn write<R: io::Read + ZeroCopyReader>(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        r: R,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        kill_priv: bool,
+        flags: u32,
+    ) -> io::Result<usize> {
+        let inode = inode.into();
+        self.inject("write", || self.path(inode))?;
+        self.inner.write(
+            ctx,
+            inode.into(),
+            handle,
+            r,
+            size,
+            offset,
+            lock_owner,
+            delayed_write,
+            kill_priv,
+            flags,
+        )
+    }
+
+    fn flush(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        lock_owner: u64,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("flush", || self.path(inode))?;
+        self.inner.flush(ctx, inode.into(), handle, lock_owner)
+    }
+
+    fn fsync(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        datasync: bool,
+        handle: InnerHandle<Self>,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("fsync", || self.path(inode))?;
+        self.inner.fsync(ctx, inode.into(), datasync, handle)
+    }
+
+    fn async_io(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        op: AsyncIoOp,
+    ) -> io::Result<AsyncIoTarget> {
+        let inode = inode.into();
+        let name = match op {
+            AsyncIoOp::Read { .. } => "read",
+            AsyncIoOp::Write { .. } => "write",
+            AsyncIoOp::Fsync { .. } => "fsync",
+        };
+        self.inject(name, || self.path(inode))?;
+        self.inner.async_io(ctx, inode.into(), handle, op)
+    }
+
+    fn fallocate(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("fallocate", || self.path(inode))?;
+        self.inner
+            .fallocate(ctx, inode.into(), handle, mode, offset, length)
+    }
+
+    fn release(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        flags: u32,
+        handle: InnerHandle<Self>,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("release", || self.path(inode))?;
+        self.inner.release(
+            ctx,
+            inode.into(),
+            flags,
+            handle,
+            flush,
+            flock_release,
+            lock_owner,
+        )
+    }
+
+    fn statfs(&self, ctx: Context, inode: InnerInode<Self>) -> io::Result<libc::statvfs64> {
+        let inode = inode.into();
+        self.inject("statfs", || self.path(inode))?;
+        self.inner.statfs(ctx, inode.into())
+    }
+
+    fn setxattr(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+        extra_flags: SetxattrFlags,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("setxattr", || self.path(inode))?;
+        self.inner
+            .setxattr(ctx, inode.into(), name, value, flags, extra_flags)
+    }
+
+    fn getxattr(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        let inode = inode.into();
+        self.inject("getxattr", || self.path(inode))?;
+        self.inner.getxattr(ctx, inode.into(), name, size)
+    }
+
+    fn listxattr(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        size: u32,
+    ) -> io::Result<ListxattrReply> {
+        let inode = inode.into();
+        self.inject("listxattr", || self.path(inode))?;
+        self.inner.listxattr(ctx, inode.into(), size)
+    }
+
+    fn removexattr(&self, ctx: Context, inode: InnerInode<Self>, name: &CStr) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("removexattr", || self.path(inode))?;
+        self.inner.removexattr(ctx, inode.into(), name)
+    }
+
+    fn opendir(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        flags: u32,
+    ) -> io::Result<(Option<InnerHandle<Self>>, OpenOptions)> {
+        let inode = inode.into();
+        self.inject("opendir", || self.path(inode))?;
+        self.inner.opendir(ctx, inode.into(), flags)
+    }
+
+    fn readdir(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        size: u32,
+        offset: u64,
+    ) -> io::Result<InnerDirIter<Self>> {
+        let inode = inode.into();
+        self.inject("readdir", || self.path(inode))?;
+        self.inner.readdir(ctx, inode.into(), handle, size, offset)
+    }
+
+    fn fsyncdir(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        datasync: bool,
+        handle: InnerHandle<Self>,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("fsyncdir", || self.path(inode))?;
+        self.inner.fsyncdir(ctx, inode.into(), datasync, handle)
+    }
+
+    fn releasedir(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        flags: u32,
+        handle: InnerHandle<Self>,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("releasedir", || self.path(inode))?;
+        self.inner.releasedir(ctx, inode.into(), flags, handle)
+    }
+
+    fn setupmapping<T: FsCacheReqHandler>(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut T,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("setupmapping", || self.path(inode))?;
+        self.inner.setupmapping(
+            ctx,
+            inode.into(),
+            handle,
+            foffset,
+            len,
+            flags,
+            moffset,
+            vu_req,
+        )
+    }
+
+    fn removemapping<T: FsCacheReqHandler>(
+        &self,
+        ctx: Context,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut T,
+    ) -> io::Result<()> {
+        self.inject("removemapping", || None)?;
+        self.inner.removemapping(ctx, requests, vu_req)
+    }
+
+    fn access(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        mask: u32,
+        extensions: Extensions,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("access", || self.path(inode))?;
+        self.inner.access(ctx, inode.into(), mask, extensions)
+    }
+
+    fn lseek(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        let inode = inode.into();
+        self.inject("lseek", || self.path(inode))?;
+        self.inner.lseek(ctx, inode.into(), handle, offset, whence)
+    }
+
+    fn copyfilerange(
+        &self,
+        ctx: Context,
+        inode_in: InnerInode<Self>,
+        handle_in: InnerHandle<Self>,
+        offset_in: u64,
+        inode_out: InnerInode<Self>,
+        handle_out: InnerHandle<Self>,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        let (inode_in, inode_out) = (inode_in.into(), inode_out.into());
+        self.inject("copyfilerange", || self.path(inode_out))?;
+        self.inner.copyfilerange(
+            ctx,
+            inode_in.into(),
+            handle_in,
+            offset_in,
+            inode_out.into(),
+            handle_out,
+            offset_out,
+            len,
+            flags,
+        )
+    }
+
+    fn syncfs(&self, ctx: Context, inode: InnerInode<Self>) -> io::Result<()> {
+        let inode = inode.into();
+        self.inject("syncfs", || self.path(inode))?;
+        self.inner.syncfs(ctx, inode.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuse;
+    use crate::passthrough::{Config, PassthroughFs};
+    use crate::test_client::{FuseClient, TempDir};
+    use std::fs;
+
+    fn errno(e: io::Error) -> i32 {
+        e.raw_os_error().unwrap()
+    }
+
+    fn setup(rules: &str) -> (TempDir, FuseClient<FaultInjectionFs<PassthroughFs>>) {
+        let dir = TempDir::new();
+        let cfg = Config {
+            root_dir: dir.path().to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let fs = FaultInjectionFs::new(PassthroughFs::new(cfg).unwrap(), rules.parse().unwrap());
+        let mut client = FuseClient::new(fs);
+        client.init(FsOptions::empty()).unwrap();
+        (dir, client)
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules: Rules = "
+            # comment
+            seed=42
+            op=write,fsync path=/data/** error=ENOSPC count=3
+            delay=10ms probability=0.5 error=5
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(rules.seed, 42);
+        assert_eq!(
+            rules.rules,
+            [
+                Rule {
+                    ops: Some(vec!["write", "fsync"]),
+                    path: Some(b"/data/**".to_vec()),
+                    errno: Some(libc::ENOSPC),
+                    delay: Duration::ZERO,
+                    probability: 1.0,
+                    count: Some(3),
+                },
+                Rule {
+                    ops: None,
+                    path: None,
+                    errno: Some(libc::EIO),
+                    delay: Duration::from_millis(10),
+                    probability: 0.5,
+                    count: None,
+                },
+            ]
+        );
+
+        let err = |s: &str| s.parse::<Rules>().unwrap_err();
+        assert_eq!(
+            err("error=EIO\nop=frobnicate error=EIO"),
+            Error {
+                cause: ErrorKind::UnknownOp("frobnicate".to_owned()),
+                line: 2
+            }
+        );
+        assert_eq!(err("op=write").cause, ErrorKind::NoEffect);
+        assert_eq!(
+            err("error=EIO foo").cause,
+            ErrorKind::InvalidToken("foo".to_owned())
+        );
+        assert_eq!(
+            err("error=EWHATEVER").cause,
+            ErrorKind::InvalidError("EWHATEVER".to_owned())
+        );
+        assert_eq!(
+            err("delay=10").cause,
+            ErrorKind::InvalidDelay("10".to_owned())
+        );
+        assert_eq!(
+            err("error=EIO probability=2").cause,
+            ErrorKind::InvalidProbability("2".to_owned())
+        );
+    }
+
+    #[test]
+    fn random_any_seed() {
+        // No seed may get the generator stuck at 0.
+        let rules = Rules {
+            seed: 0x9e37_79b9_7f4a_7c15,
+            ..Default::default()
+        };
+        let mut state = State::new(rules);
+        assert!((0..4).any(|_| state.random() > 0.0));
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(b"/data/*", b"/data/file"));
+        assert!(!glob_match(b"/data/*", b"/data/dir/file"));
+        assert!(glob_match(b"/data/**", b"/data/dir/file"));
+        assert!(glob_match(b"/**/*.log", b"/a/b/c.log"));
+        assert!(glob_match(b"/file?", b"/file1"));
+        assert!(!glob_match(b"/file?", b"/file"));
+        assert!(glob_match(b"/*", b"/"));
+    }
+
+    #[test]
+    fn inject_errors() {
+        let (dir, mut client) = setup("op=create,mkdir path=/full/** error=ENOSPC count=2");
+        fs::create_dir(dir.path().join("full")).unwrap();
+        let full = client.lookup(fuse::ROOT_ID, "full").unwrap();
+
+        // Only paths matching the glob are affected, ...
+        client.mkdir(fuse::ROOT_ID, "other", 0o755).unwrap();
+        let err = client.mkdir(full.nodeid, "a", 0o755).unwrap_err();
+        assert_eq!(errno(err), libc::ENOSPC);
+        let err = client
+            .create(full.nodeid, "b", 0o644, libc::O_WRONLY as u32)
+            .unwrap_err();
+        assert_eq!(errno(err), libc::ENOSPC);
+
+        // ... and only as often as given by `count`.
+        client.mkdir(full.nodeid, "a", 0o755).unwrap();
+        assert!(dir.path().join("full/a").is_dir());
+    }
+
+    #[test]
+    fn paths_follow_renames() {
+        let (dir, mut client) = setup("op=getattr path=/new/file error=EIO");
+        fs::create_dir(dir.path().join("old")).unwrap();
+        fs::write(dir.path().join("old/file"), b"").unwrap();
+
+        let old = client.lookup(fuse::ROOT_ID, "old").unwrap();
+        let file = client.lookup(old.nodeid, "file").unwrap();
+        client.getattr(file.nodeid).unwrap();
+
+        client
+            .rename(fuse::ROOT_ID, "old", fuse::ROOT_ID, "new", 0)
+            .unwrap();
+        let err = client.getattr(file.nodeid).unwrap_err();
+        assert_eq!(errno(err), libc::EIO);
+    }
+
+    #[test]
+    fn probability_is_deterministic() {
+        let run = || {
+            let (_dir, mut client) = setup("seed=1\nop=statfs probability=0.5 error=EIO");
+            (0..64)
+                .map(|_| client.statfs(fuse::ROOT_ID).is_ok())
+                .collect::<Vec<_>>()
+        };
+        let results = run();
+        assert!(results.contains(&true) && results.contains(&false));
+        assert_eq!(results, run());
+    }
+
+    #[test]
+    fn reload_rules_file() {
+        let rules_dir = TempDir::new();
+        let path = rules_dir.path().join("rules");
+        fs::write(&path, "op=statfs error=EIO\n").unwrap();
+
+        let dir = TempDir::new();
+        let cfg = Config {
+            root_dir: dir.path().to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let rules_file = RulesFile::open(&path).unwrap();
+        let fs = FaultInjectionFs::with_rules_file(PassthroughFs::new(cfg).unwrap(), rules_file)
+            .unwrap();
+        let mut client = FuseClient::new(fs);
+        client.init(FsOptions::empty()).unwrap();
+        assert_eq!(errno(client.statfs(fuse::ROOT_ID).unwrap_err()), libc::EIO);
+
+        // Replace the file, as an editor would.
+        let tmp = rules_dir.path().join("rules.tmp");
+        fs::write(&tmp, "op=statfs error=EDQUOT\n").unwrap();
+        fs::rename(&tmp, &path).unwrap();
+        thread::sleep(RULES_FILE_CHECK_INTERVAL);
+        assert_eq!(
+            errno(client.statfs(fuse::ROOT_ID).unwrap_err()),
+            libc::EDQUOT
+        );
+    }
+}
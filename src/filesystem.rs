@@ -5,6 +5,8 @@
 use std::convert::TryInto;
 use std::ffi::{CStr, CString};
 use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{io, mem};
 
@@ -204,7 +206,7 @@ pub trait ZeroCopyReader {
     }
 }
 
-impl<'a, R: ZeroCopyReader> ZeroCopyReader for &'a mut R {
+impl<R: ZeroCopyReader> ZeroCopyReader for &mut R {
     fn read_to(
         &mut self,
         f: &File,
@@ -314,7 +316,7 @@ pub trait ZeroCopyWriter {
     }
 }
 
-impl<'a, W: ZeroCopyWriter> ZeroCopyWriter for &'a mut W {
+impl<W: ZeroCopyWriter> ZeroCopyWriter for &mut W {
     fn write_from(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
         (**self).write_from(f, count, off)
     }
@@ -366,12 +368,38 @@ pub struct SecContext {
     pub secctx: Vec<u8>,
 }
 
+/// A READ, WRITE or FSYNC request that a server would like to perform asynchronously. See
+/// `FileSystem::async_io` for more details.
+#[derive(Clone, Copy, Debug)]
+pub enum AsyncIoOp {
+    /// Read `size` bytes of data from the file at `offset`.
+    Read { offset: u64, size: u32 },
+    /// Write data to the file. The fields have the same meaning as the corresponding parameters of
+    /// `FileSystem::write`.
+    Write {
+        delayed_write: bool,
+        kill_priv: bool,
+        flags: u32,
+    },
+    /// Synchronize the file contents. Only the data needs to be flushed if `datasync` is true.
+    Fsync { datasync: bool },
+}
+
+/// The host file on which to perform an `AsyncIoOp`.
+pub struct AsyncIoTarget {
+    /// The file to perform the operation on. It is kept open until the operation has completed.
+    pub file: Arc<dyn AsRawFd + Send + Sync>,
+
+    /// Flags for the read or write, as for `preadv2(2)` and `pwritev2(2)` (e.g. `RWF_APPEND`).
+    pub rw_flags: i32,
+}
+
 /// A trait for iterating over the contents of a directory. This trait is needed because rust
 /// doesn't support generic associated types, which means that it's not possible to implement a
 /// regular iterator that yields a `DirEntry` due to its generic lifetime parameter.
 pub trait DirectoryIterator {
     /// Returns the next entry in the directory or `None` if there are no more.
-    fn next(&mut self) -> Option<DirEntry>;
+    fn next(&mut self) -> Option<DirEntry<'_>>;
 }
 
 /// The main trait that connects a file system with a transport.
@@ -836,6 +864,26 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Prepare a READ, WRITE or FSYNC request on an open file to be performed asynchronously.
+    ///
+    /// Servers that support asynchronous I/O call this method instead of `read`, `write` or
+    /// `fsync` and, if it succeeds, perform `op` directly on the returned file, transferring data
+    /// straight between the file and the guest's buffers. File systems must therefore only succeed
+    /// if that is equivalent to calling the corresponding method. Any side effect that method would
+    /// have before doing the I/O (e.g., clearing file capabilities on write) must be applied here.
+    ///
+    /// If this method returns an error then the request is handled synchronously instead, so the
+    /// error is never returned to the kernel.
+    fn async_io(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        op: AsyncIoOp,
+    ) -> io::Result<AsyncIoTarget> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Allocate requested space for file data.
     ///
     /// If this function returns success, then the file system must guarantee that it is possible to
@@ -1110,7 +1158,13 @@ pub trait FileSystem {
     /// If this method returns an `ENOSYS` error, then the kernel will treat it as a permanent
     /// success: all future calls to `access` will return success without being forwarded to the
     /// file system.
-    fn access(&self, ctx: Context, inode: Self::Inode, mask: u32) -> io::Result<()> {
+    fn access(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        mask: u32,
+        extensions: Extensions,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
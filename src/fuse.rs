@@ -172,7 +172,6 @@ const EXPLICIT_INVAL_DATA: u64 = 1 << 25;
 /// init_out.map_alignment contains log2(byte alignment) for
 /// foffset and moffset fields in struct fuse_setupmapping_out and
 /// fuse_removemapping_one
-#[allow(dead_code)]
 const MAP_ALIGNMENT: u64 = 1 << 26;
 
 /// Kernel supports auto-mounting directory submounts
@@ -404,6 +403,12 @@ bitflags! {
         /// This feature is not currently supported.
         const EXPLICIT_INVAL_DATA = EXPLICIT_INVAL_DATA;
 
+        /// Indicates that the `map_alignment` field of the INIT reply is valid, i.e. the
+        /// alignment that the offsets of DAX mappings must have.
+        ///
+        /// This feature is enabled when a non-zero alignment has been configured.
+        const MAP_ALIGNMENT = MAP_ALIGNMENT;
+
         /// Indicates that the kernel supports the FUSE_ATTR_SUBMOUNT flag.
         ///
         /// Setting (or not setting) this flag in the `FsOptions` returned from the `init` method
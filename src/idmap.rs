@@ -1,5 +1,7 @@
 // SPDX-License-Identifier: BSD-3-Clause
+use std::ffi::CStr;
 use std::fmt;
+use std::fs;
 use std::num::ParseIntError;
 use std::str::FromStr;
 
@@ -12,6 +14,14 @@ pub enum IdMapError {
     IncompleteMap,
     /// Wraps the cause of parsing an integer failing.
     InvalidValue(ParseIntError),
+    /// A range is empty or extends beyond the largest id.
+    InvalidRange,
+    /// Two ranges overlap on the inside or on the outside.
+    OverlappingRanges,
+    /// `auto` has been combined with other maps.
+    AutoWithOtherMaps,
+    /// The subordinate ids of the current user cannot be determined.
+    SubordinateIds(String),
 }
 impl std::error::Error for IdMapError {}
 
@@ -27,6 +37,14 @@ impl fmt::Display for IdMapError {
                 "The map is empty or incorrect number of values are provided"
             ),
             IdMapError::InvalidValue(err) => write!(f, "{}", err),
+            IdMapError::InvalidRange => {
+                write!(f, "A range is empty or extends beyond the largest id")
+            }
+            IdMapError::OverlappingRanges => write!(f, "The ranges of the map overlap"),
+            IdMapError::AutoWithOtherMaps => write!(f, "auto cannot be combined with other maps"),
+            IdMapError::SubordinateIds(msg) => {
+                write!(f, "Cannot determine the subordinate ids: {}", msg)
+            }
         }
     }
 }
@@ -99,6 +117,272 @@ impl fmt::Display for GidMap {
     }
 }
 
+impl UidMap {
+    /// Checks that `maps` can be set up together, i.e. that they are valid and do not overlap.
+    pub fn check(maps: &[UidMap]) -> std::result::Result<(), IdMapError> {
+        check_ranges(maps.iter().map(|m| (m.inside_uid, m.outside_uid, m.count)))
+    }
+
+    /// Returns the maps for `auto`: The effective uid mapped to 0, followed by the subordinate
+    /// uids of the current user from `/etc/subuid`, like rootless podman does.
+    pub fn auto() -> std::result::Result<Vec<UidMap>, IdMapError> {
+        // SAFETY: `geteuid()` has no preconditions and cannot fail.
+        let uid = unsafe { libc::geteuid() };
+        let maps = auto_ranges(uid, "/etc/subuid")?
+            .map(|(inside_uid, outside_uid, count)| UidMap {
+                inside_uid,
+                outside_uid,
+                count,
+            })
+            .collect();
+        Ok(maps)
+    }
+
+    /// Returns the maps given by the `--uid-map` values `options`, checked to be usable
+    /// together.
+    pub fn resolve(
+        options: Vec<IdMapOption<UidMap>>,
+    ) -> std::result::Result<Vec<UidMap>, IdMapError> {
+        let maps = IdMapOption::resolve(options, UidMap::auto)?;
+        UidMap::check(&maps)?;
+        Ok(maps)
+    }
+}
+
+impl GidMap {
+    /// Checks that `maps` can be set up together, i.e. that they are valid and do not overlap.
+    pub fn check(maps: &[GidMap]) -> std::result::Result<(), IdMapError> {
+        check_ranges(maps.iter().map(|m| (m.inside_gid, m.outside_gid, m.count)))
+    }
+
+    /// Returns the maps for `auto`: The effective gid mapped to 0, followed by the subordinate
+    /// gids of the current user from `/etc/subgid`, like rootless podman does.
+    pub fn auto() -> std::result::Result<Vec<GidMap>, IdMapError> {
+        // SAFETY: `getegid()` has no preconditions and cannot fail.
+        let gid = unsafe { libc::getegid() };
+        let maps = auto_ranges(gid, "/etc/subgid")?
+            .map(|(inside_gid, outside_gid, count)| GidMap {
+                inside_gid,
+                outside_gid,
+                count,
+            })
+            .collect();
+        Ok(maps)
+    }
+
+    /// Returns the maps given by the `--gid-map` values `options`, checked to be usable
+    /// together.
+    pub fn resolve(
+        options: Vec<IdMapOption<GidMap>>,
+    ) -> std::result::Result<Vec<GidMap>, IdMapError> {
+        let maps = IdMapOption::resolve(options, GidMap::auto)?;
+        GidMap::check(&maps)?;
+        Ok(maps)
+    }
+}
+
+/// A value of `--uid-map` or `--gid-map`: Either a single map, or `auto`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdMapOption<T> {
+    Map(T),
+    Auto,
+}
+
+impl<T: FromStr<Err = IdMapError>> FromStr for IdMapOption<T> {
+    type Err = IdMapError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "auto" {
+            Ok(IdMapOption::Auto)
+        } else {
+            s.parse().map(IdMapOption::Map)
+        }
+    }
+}
+
+impl<T> IdMapOption<T> {
+    fn resolve(
+        options: Vec<Self>,
+        auto: impl FnOnce() -> std::result::Result<Vec<T>, IdMapError>,
+    ) -> std::result::Result<Vec<T>, IdMapError> {
+        if !options.iter().any(|o| matches!(o, IdMapOption::Auto)) {
+            return Ok(options
+                .into_iter()
+                .filter_map(|o| match o {
+                    IdMapOption::Map(map) => Some(map),
+                    IdMapOption::Auto => None,
+                })
+                .collect());
+        }
+        if options.len() > 1 {
+            return Err(IdMapError::AutoWithOtherMaps);
+        }
+        auto()
+    }
+}
+
+/// Returns the `(inside, outside, count)` ranges mapping `own_id` to 0, followed by the
+/// subordinate ids of the current user from `subid_file` (see `subuid(5)`).
+fn auto_ranges(
+    own_id: u32,
+    subid_file: &str,
+) -> std::result::Result<impl Iterator<Item = (u32, u32, u32)>, IdMapError> {
+    let user = current_user_name()?;
+    let contents = fs::read_to_string(subid_file)
+        .map_err(|e| IdMapError::SubordinateIds(format!("{}: {}", subid_file, e)))?;
+    // SAFETY: `geteuid()` has no preconditions and cannot fail.
+    let uid = unsafe { libc::geteuid() };
+    let subids = parse_subids(&contents, &user, uid);
+    if subids.is_empty() {
+        return Err(IdMapError::SubordinateIds(format!(
+            "{} has no entry for {}",
+            subid_file, user
+        )));
+    }
+
+    let mut next_inside = 1u32;
+    let mut ranges = vec![(0, own_id, 1)];
+    for (start, count) in subids {
+        ranges.push((next_inside, start, count));
+        next_inside = next_inside
+            .checked_add(count)
+            .ok_or(IdMapError::InvalidRange)?;
+    }
+    Ok(ranges.into_iter())
+}
+
+/// Returns the `(start, count)` ranges that `contents` of `/etc/subuid` or `/etc/subgid` assign
+/// to the user with the name `user` or the uid `uid`.
+fn parse_subids(contents: &str, user: &str, uid: u32) -> Vec<(u32, u32)> {
+    let uid = uid.to_string();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().split(':');
+            let owner = fields.next()?;
+            let start = fields.next()?.parse().ok()?;
+            let count = fields.next()?.parse().ok()?;
+            (owner == user || owner == uid).then_some((start, count))
+        })
+        .collect()
+}
+
+/// Returns the name of the user with the effective uid.
+fn current_user_name() -> std::result::Result<String, IdMapError> {
+    // SAFETY: `geteuid()` has no preconditions and cannot fail, and `getpwuid()` is only
+    // called during startup, so no other thread can overwrite the returned entry.
+    let pw = unsafe { libc::getpwuid(libc::geteuid()) };
+    if pw.is_null() {
+        return Err(IdMapError::SubordinateIds(
+            "the current user has no passwd entry".to_string(),
+        ));
+    }
+    // SAFETY: `pw` is a valid passwd entry, whose name is a nul-terminated string.
+    let name = unsafe { CStr::from_ptr((*pw).pw_name) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// A range of ids in the guest, and the range of host ids it corresponds to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdRange {
+    pub guest: u32,
+    pub host: u32,
+    pub count: u32,
+}
+
+impl FromStr for IdRange {
+    type Err = IdMapError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let fields = parse_idmap(s, 3)?;
+
+        Ok(IdRange {
+            guest: fields[0],
+            host: fields[1],
+            count: fields[2],
+        })
+    }
+}
+
+impl fmt::Display for IdRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, ":{}:{}:{}:", self.guest, self.host, self.count)
+    }
+}
+
+/// Translates uids or gids between the guest and the host without a user namespace.  Like in a
+/// user namespace, ids outside of all ranges become the overflow id (65534) on the other side.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdTranslation {
+    ranges: Vec<IdRange>,
+}
+
+impl IdTranslation {
+    /// The id that ids without a counterpart on the other side are translated to.
+    pub const OVERFLOW_ID: u32 = 65534;
+
+    /// Creates a translation from `ranges`, which must neither be empty nor overlap.
+    pub fn new(ranges: Vec<IdRange>) -> std::result::Result<Self, IdMapError> {
+        check_ranges(ranges.iter().map(|r| (r.guest, r.host, r.count)))?;
+        Ok(IdTranslation { ranges })
+    }
+
+    pub fn ranges(&self) -> &[IdRange] {
+        &self.ranges
+    }
+
+    /// Translates an id of the guest into the corresponding host id.
+    pub fn to_host(&self, id: u32) -> u32 {
+        self.try_to_host(id).unwrap_or(Self::OVERFLOW_ID)
+    }
+
+    /// Translates an id of the host into the corresponding guest id.
+    pub fn to_guest(&self, id: u32) -> u32 {
+        self.try_to_guest(id).unwrap_or(Self::OVERFLOW_ID)
+    }
+
+    /// Like `to_host`, but returns `None` for ids outside of all ranges.
+    pub fn try_to_host(&self, id: u32) -> Option<u32> {
+        self.ranges
+            .iter()
+            .find(|r| id >= r.guest && id - r.guest < r.count)
+            .map(|r| r.host + (id - r.guest))
+    }
+
+    /// Like `to_guest`, but returns `None` for ids outside of all ranges.
+    pub fn try_to_guest(&self, id: u32) -> Option<u32> {
+        self.ranges
+            .iter()
+            .find(|r| id >= r.host && id - r.host < r.count)
+            .map(|r| r.guest + (id - r.host))
+    }
+}
+
+/// Checks that the `(inside, outside, count)` ranges are valid, and that they overlap neither on
+/// the inside nor on the outside.
+fn check_ranges(
+    ranges: impl Iterator<Item = (u32, u32, u32)> + Clone,
+) -> std::result::Result<(), IdMapError> {
+    let ends = |start: u32, count: u32| -> std::result::Result<(u64, u64), IdMapError> {
+        if count == 0 || u64::from(start) + u64::from(count) > u64::from(u32::MAX) + 1 {
+            return Err(IdMapError::InvalidRange);
+        }
+        Ok((start.into(), u64::from(start) + u64::from(count)))
+    };
+    let overlap = |a: (u64, u64), b: (u64, u64)| a.0 < b.1 && b.0 < a.1;
+
+    for (i, (inside, outside, count)) in ranges.clone().enumerate() {
+        let (a_in, a_out) = (ends(inside, count)?, ends(outside, count)?);
+        for (inside, outside, count) in ranges.clone().take(i) {
+            let (b_in, b_out) = (ends(inside, count)?, ends(outside, count)?);
+            if overlap(a_in, b_in) || overlap(a_out, b_out) {
+                return Err(IdMapError::OverlappingRanges);
+            }
+        }
+    }
+    Ok(())
+}
+
 fn parse_idmap(s: &str, expected_len: usize) -> std::result::Result<Vec<u32>, IdMapError> {
     let mut s = String::from(s);
     let delimiter = s.pop().ok_or(IdMapError::IncompleteMap)?;
@@ -128,3 +412,46 @@ pub(crate) enum IdMapSetUpPipeMessage {
     Request = 0x1,
     Done = 0x2,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subids() {
+        let contents = "alice:100000:65536\nbob:165536:65536\n1000:300000:1000\n# comment\n";
+        assert_eq!(
+            parse_subids(contents, "alice", 1000),
+            vec![(100000, 65536), (300000, 1000)]
+        );
+        assert_eq!(parse_subids(contents, "carol", 1001), vec![]);
+    }
+
+    #[test]
+    fn check_maps() {
+        let map = |inside_uid, outside_uid, count| UidMap {
+            inside_uid,
+            outside_uid,
+            count,
+        };
+
+        assert!(UidMap::check(&[]).is_ok());
+        assert!(UidMap::check(&[map(0, 1000, 1), map(1, 100000, 65536)]).is_ok());
+        assert_eq!(
+            UidMap::check(&[map(0, 1000, 1), map(0, 100000, 65536)]),
+            Err(IdMapError::OverlappingRanges)
+        );
+        assert_eq!(
+            UidMap::check(&[map(0, 100000, 65536), map(70000, 165535, 1)]),
+            Err(IdMapError::OverlappingRanges)
+        );
+        assert_eq!(
+            UidMap::check(&[map(0, 1000, 0)]),
+            Err(IdMapError::InvalidRange)
+        );
+        assert_eq!(
+            UidMap::check(&[map(u32::MAX, 0, 2)]),
+            Err(IdMapError::InvalidRange)
+        );
+    }
+}
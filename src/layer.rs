@@ -0,0 +1,1064 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Support for stacking file systems on top of each other.
+//!
+//! A `Layer` wraps another `FileSystem` (e.g. `PassthroughFs`) and forwards every operation to it
+//! by default, so a layer that adds some behavior (policy enforcement, auditing, statistics, ...)
+//! only needs to override the methods it is interested in.  Every `Layer` is a `FileSystem` itself
+//! and can therefore be wrapped by further layers, and be passed to `Server`.
+//!
+//! Layers share the `Inode`, `Handle` and `DirIter` types of the file system they wrap.  File
+//! systems that need different types have to implement `FileSystem` directly.
+//!
+//! Note that since a `Layer` implements both traits, calls like `self.lookup(...)` inside a
+//! layer are ambiguous; use `self.inner().lookup(...)` to call into the wrapped file system, or
+//! `Layer::lookup(self, ...)` for the layer's own implementation.
+//!
+//! Note that data transferred through `async_io` bypasses the layer's `read`, `write` and
+//! `fsync`, so layers that need to see these operations have to override `async_io`, too.
+//!
+//! ```ignore
+//! struct ReadOnly<F>(F);
+//!
+//! impl<F: FileSystem> Layer for ReadOnly<F> {
+//!     type Inner = F;
+//!
+//!     fn inner(&self) -> &F {
+//!         &self.0
+//!     }
+//!
+//!     fn unlink(&self, _ctx: Context, _parent: InnerInode<Self>, _name: &CStr) -> io::Result<()> {
+//!         Err(io::Error::from_raw_os_error(libc::EROFS))
+//!     }
+//! }
+//!
+//! let fs = LayerBuilder::new(PassthroughFs::new(cfg)?)
+//!     .layer(ReadOnly)
+//!     .layer(|fs| FaultInjectionFs::new(fs, rules))
+//!     .build();
+//! ```
+
+use std::ffi::CStr;
+use std::io;
+use std::time::Duration;
+
+use crate::filesystem::{
+    AsyncIoOp, AsyncIoTarget, Context, Entry, Extensions, FileSystem, FsOptions, GetxattrReply,
+    ListxattrReply, OpenOptions, RemovemappingOne, SetattrValid, SetxattrFlags, ZeroCopyReader,
+    ZeroCopyWriter,
+};
+use crate::fs_cache_req_handler::FsCacheReqHandler;
+
+/// The `Inode` type of the file system wrapped by layer `L`.
+pub type InnerInode<L> = <<L as Layer>::Inner as FileSystem>::Inode;
+/// The `Handle` type of the file system wrapped by layer `L`.
+pub type InnerHandle<L> = <<L as Layer>::Inner as FileSystem>::Handle;
+/// The `DirIter` type of the file system wrapped by layer `L`.
+pub type InnerDirIter<L> = <<L as Layer>::Inner as FileSystem>::DirIter;
+
+/// A file system that wraps another one.  All methods forward to the same method of the wrapped
+/// file system unless overridden; see the documentation of `FileSystem` for their semantics.
+///
+/// `batch_forget` is forwarded as is, so layers that override `forget` usually need to override
+/// `batch_forget`, too.
+pub trait Layer {
+    /// The wrapped file system.
+    type Inner: FileSystem;
+
+    /// Returns the wrapped file system.
+    fn inner(&self) -> &Self::Inner;
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        self.inner().init(capable)
+    }
+
+    fn destroy(&self) {
+        self.inner().destroy()
+    }
+
+    fn lookup(&self, ctx: Context, parent: InnerInode<Self>, name: &CStr) -> io::Result<Entry> {
+        self.inner().lookup(ctx, parent, name)
+    }
+
+    fn forget(&self, ctx: Context, inode: InnerInode<Self>, count: u64) {
+        self.inner().forget(ctx, inode, count)
+    }
+
+    fn batch_forget(&self, ctx: Context, requests: Vec<(InnerInode<Self>, u64)>) {
+        self.inner().batch_forget(ctx, requests)
+    }
+
+    fn getattr(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: Option<InnerHandle<Self>>,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        self.inner().getattr(ctx, inode, handle)
+    }
+
+    fn setattr(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        attr: libc::stat64,
+        handle: Option<InnerHandle<Self>>,
+        valid: SetattrValid,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        self.inner().setattr(ctx, inode, attr, handle, valid)
+    }
+
+    fn readlink(&self, ctx: Context, inode: InnerInode<Self>) -> io::Result<Vec<u8>> {
+        self.inner().readlink(ctx, inode)
+    }
+
+    fn symlink(
+        &self,
+        ctx: Context,
+        linkname: &CStr,
+        parent: InnerInode<Self>,
+        name: &CStr,
+        extensions: Extensions,
+    ) -> io::Result<Entry> {
+        self.inner()
+            .symlink(ctx, linkname, parent, name, extensions)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn mknod(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<Entry> {
+        self.inner()
+            .mknod(ctx, inode, name, mode, rdev, umask, extensions)
+    }
+
+    fn mkdir(
+        &self,
+        ctx: Context,
+        parent: InnerInode<Self>,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<Entry> {
+        self.inner()
+            .mkdir(ctx, parent, name, mode, umask, extensions)
+    }
+
+    fn unlink(&self, ctx: Context, parent: InnerInode<Self>, name: &CStr) -> io::Result<()> {
+        self.inner().unlink(ctx, parent, name)
+    }
+
+    fn rmdir(&self, ctx: Context, parent: InnerInode<Self>, name: &CStr) -> io::Result<()> {
+        self.inner().rmdir(ctx, parent, name)
+    }
+
+    fn rename(
+        &self,
+        ctx: Context,
+        olddir: InnerInode<Self>,
+        oldname: &CStr,
+        newdir: InnerInode<Self>,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.inner()
+            .rename(ctx, olddir, oldname, newdir, newname, flags)
+    }
+
+    fn link(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        newparent: InnerInode<Self>,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        self.inner().link(ctx, inode, newparent, newname)
+    }
+
+    fn open(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        kill_priv: bool,
+        flags: u32,
+    ) -> io::Result<(Option<InnerHandle<Self>>, OpenOptions)> {
+        self.inner().open(ctx, inode, kill_priv, flags)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        &self,
+        ctx: Context,
+        parent: InnerInode<Self>,
+        name: &CStr,
+        mode: u32,
+        kill_priv: bool,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<InnerHandle<Self>>, OpenOptions)> {
+        self.inner()
+            .create(ctx, parent, name, mode, kill_priv, flags, umask, extensions)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn read<W: io::Write + ZeroCopyWriter>(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        w: W,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        self.inner()
+            .read(ctx, inode, handle, w, size, offset, lock_owner, flags)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write<R: io::Read + ZeroCopyReader>(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        r: R,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        kill_priv: bool,
+        flags: u32,
+    ) -> io::Result<usize> {
+        self.inner().write(
+            ctx,
+            inode,
+            handle,
+            r,
+            size,
+            offset,
+            lock_owner,
+            delayed_write,
+            kill_priv,
+            flags,
+        )
+    }
+
+    fn flush(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        lock_owner: u64,
+    ) -> io::Result<()> {
+        self.inner().flush(ctx, inode, handle, lock_owner)
+    }
+
+    fn fsync(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        datasync: bool,
+        handle: InnerHandle<Self>,
+    ) -> io::Result<()> {
+        self.inner().fsync(ctx, inode, datasync, handle)
+    }
+
+    fn async_io(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        op: AsyncIoOp,
+    ) -> io::Result<AsyncIoTarget> {
+        self.inner().async_io(ctx, inode, handle, op)
+    }
+
+    fn fallocate(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        self.inner()
+            .fallocate(ctx, inode, handle, mode, offset, length)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn release(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        flags: u32,
+        handle: InnerHandle<Self>,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        self.inner()
+            .release(ctx, inode, flags, handle, flush, flock_release, lock_owner)
+    }
+
+    fn statfs(&self, ctx: Context, inode: InnerInode<Self>) -> io::Result<libc::statvfs64> {
+        self.inner().statfs(ctx, inode)
+    }
+
+    fn setxattr(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+        extra_flags: SetxattrFlags,
+    ) -> io::Result<()> {
+        self.inner()
+            .setxattr(ctx, inode, name, value, flags, extra_flags)
+    }
+
+    fn getxattr(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        self.inner().getxattr(ctx, inode, name, size)
+    }
+
+    fn listxattr(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        size: u32,
+    ) -> io::Result<ListxattrReply> {
+        self.inner().listxattr(ctx, inode, size)
+    }
+
+    fn removexattr(&self, ctx: Context, inode: InnerInode<Self>, name: &CStr) -> io::Result<()> {
+        self.inner().removexattr(ctx, inode, name)
+    }
+
+    fn opendir(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        flags: u32,
+    ) -> io::Result<(Option<InnerHandle<Self>>, OpenOptions)> {
+        self.inner().opendir(ctx, inode, flags)
+    }
+
+    fn readdir(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        size: u32,
+        offset: u64,
+    ) -> io::Result<InnerDirIter<Self>> {
+        self.inner().readdir(ctx, inode, handle, size, offset)
+    }
+
+    fn fsyncdir(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        datasync: bool,
+        handle: InnerHandle<Self>,
+    ) -> io::Result<()> {
+        self.inner().fsyncdir(ctx, inode, datasync, handle)
+    }
+
+    fn releasedir(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        flags: u32,
+        handle: InnerHandle<Self>,
+    ) -> io::Result<()> {
+        self.inner().releasedir(ctx, inode, flags, handle)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn setupmapping<T: FsCacheReqHandler>(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut T,
+    ) -> io::Result<()> {
+        self.inner()
+            .setupmapping(ctx, inode, handle, foffset, len, flags, moffset, vu_req)
+    }
+
+    fn removemapping<T: FsCacheReqHandler>(
+        &self,
+        ctx: Context,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut T,
+    ) -> io::Result<()> {
+        self.inner().removemapping(ctx, requests, vu_req)
+    }
+
+    fn access(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        mask: u32,
+        extensions: Extensions,
+    ) -> io::Result<()> {
+        self.inner().access(ctx, inode, mask, extensions)
+    }
+
+    fn lseek(
+        &self,
+        ctx: Context,
+        inode: InnerInode<Self>,
+        handle: InnerHandle<Self>,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        self.inner().lseek(ctx, inode, handle, offset, whence)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn copyfilerange(
+        &self,
+        ctx: Context,
+        inode_in: InnerInode<Self>,
+        handle_in: InnerHandle<Self>,
+        offset_in: u64,
+        inode_out: InnerInode<Self>,
+        handle_out: InnerHandle<Self>,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        self.inner().copyfilerange(
+            ctx, inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len, flags,
+        )
+    }
+
+    fn syncfs(&self, ctx: Context, inode: InnerInode<Self>) -> io::Result<()> {
+        self.inner().syncfs(ctx, inode)
+    }
+
+    fn getlk(&self) -> io::Result<()> {
+        self.inner().getlk()
+    }
+
+    fn setlk(&self) -> io::Result<()> {
+        self.inner().setlk()
+    }
+
+    fn setlkw(&self) -> io::Result<()> {
+        self.inner().setlkw()
+    }
+
+    fn ioctl(&self) -> io::Result<()> {
+        self.inner().ioctl()
+    }
+
+    fn bmap(&self) -> io::Result<()> {
+        self.inner().bmap()
+    }
+
+    fn poll(&self) -> io::Result<()> {
+        self.inner().poll()
+    }
+
+    fn notify_reply(&self) -> io::Result<()> {
+        self.inner().notify_reply()
+    }
+
+    fn tmpfile(&self) -> io::Result<(Entry, Option<InnerHandle<Self>>, OpenOptions)> {
+        self.inner().tmpfile()
+    }
+}
+
+impl<L: Layer> FileSystem for L {
+    type Inode = InnerInode<L>;
+    type Handle = InnerHandle<L>;
+    type DirIter = InnerDirIter<L>;
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        Layer::init(self, capable)
+    }
+
+    fn destroy(&self) {
+        Layer::destroy(self)
+    }
+
+    fn lookup(&self, ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
+        Layer::lookup(self, ctx, parent, name)
+    }
+
+    fn forget(&self, ctx: Context, inode: Self::Inode, count: u64) {
+        Layer::forget(self, ctx, inode, count)
+    }
+
+    fn batch_forget(&self, ctx: Context, requests: Vec<(Self::Inode, u64)>) {
+        Layer::batch_forget(self, ctx, requests)
+    }
+
+    fn getattr(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        Layer::getattr(self, ctx, inode, handle)
+    }
+
+    fn setattr(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        attr: libc::stat64,
+        handle: Option<Self::Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        Layer::setattr(self, ctx, inode, attr, handle, valid)
+    }
+
+    fn readlink(&self, ctx: Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        Layer::readlink(self, ctx, inode)
+    }
+
+    fn symlink(
+        &self,
+        ctx: Context,
+        linkname: &CStr,
+        parent: Self::Inode,
+        name: &CStr,
+        extensions: Extensions,
+    ) -> io::Result<Entry> {
+        Layer::symlink(self, ctx, linkname, parent, name, extensions)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn mknod(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<Entry> {
+        Layer::mknod(self, ctx, inode, name, mode, rdev, umask, extensions)
+    }
+
+    fn mkdir(
+        &self,
+        ctx: Context,
+        parent: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<Entry> {
+        Layer::mkdir(self, ctx, parent, name, mode, umask, extensions)
+    }
+
+    fn unlink(&self, ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        Layer::unlink(self, ctx, parent, name)
+    }
+
+    fn rmdir(&self, ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        Layer::rmdir(self, ctx, parent, name)
+    }
+
+    fn rename(
+        &self,
+        ctx: Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        Layer::rename(self, ctx, olddir, oldname, newdir, newname, flags)
+    }
+
+    fn link(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        newparent: Self::Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        Layer::link(self, ctx, inode, newparent, newname)
+    }
+
+    fn open(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        kill_priv: bool,
+        flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        Layer::open(self, ctx, inode, kill_priv, flags)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        &self,
+        ctx: Context,
+        parent: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        kill_priv: bool,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        Layer::create(
+            self, ctx, parent, name, mode, kill_priv, flags, umask, extensions,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn read<W: io::Write + ZeroCopyWriter>(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        w: W,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        Layer::read(self, ctx, inode, handle, w, size, offset, lock_owner, flags)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write<R: io::Read + ZeroCopyReader>(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        r: R,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        kill_priv: bool,
+        flags: u32,
+    ) -> io::Result<usize> {
+        Layer::write(
+            self,
+            ctx,
+            inode,
+            handle,
+            r,
+            size,
+            offset,
+            lock_owner,
+            delayed_write,
+            kill_priv,
+            flags,
+        )
+    }
+
+    fn flush(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        lock_owner: u64,
+    ) -> io::Result<()> {
+        Layer::flush(self, ctx, inode, handle, lock_owner)
+    }
+
+    fn fsync(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        Layer::fsync(self, ctx, inode, datasync, handle)
+    }
+
+    fn async_io(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        op: AsyncIoOp,
+    ) -> io::Result<AsyncIoTarget> {
+        Layer::async_io(self, ctx, inode, handle, op)
+    }
+
+    fn fallocate(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        Layer::fallocate(self, ctx, inode, handle, mode, offset, length)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn release(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        Layer::release(
+            self,
+            ctx,
+            inode,
+            flags,
+            handle,
+            flush,
+            flock_release,
+            lock_owner,
+        )
+    }
+
+    fn statfs(&self, ctx: Context, inode: Self::Inode) -> io::Result<libc::statvfs64> {
+        Layer::statfs(self, ctx, inode)
+    }
+
+    fn setxattr(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+        extra_flags: SetxattrFlags,
+    ) -> io::Result<()> {
+        Layer::setxattr(self, ctx, inode, name, value, flags, extra_flags)
+    }
+
+    fn getxattr(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        Layer::getxattr(self, ctx, inode, name, size)
+    }
+
+    fn listxattr(&self, ctx: Context, inode: Self::Inode, size: u32) -> io::Result<ListxattrReply> {
+        Layer::listxattr(self, ctx, inode, size)
+    }
+
+    fn removexattr(&self, ctx: Context, inode: Self::Inode, name: &CStr) -> io::Result<()> {
+        Layer::removexattr(self, ctx, inode, name)
+    }
+
+    fn opendir(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        Layer::opendir(self, ctx, inode, flags)
+    }
+
+    fn readdir(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+    ) -> io::Result<Self::DirIter> {
+        Layer::readdir(self, ctx, inode, handle, size, offset)
+    }
+
+    fn fsyncdir(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        Layer::fsyncdir(self, ctx, inode, datasync, handle)
+    }
+
+    fn releasedir(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        Layer::releasedir(self, ctx, inode, flags, handle)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn setupmapping<T: FsCacheReqHandler>(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut T,
+    ) -> io::Result<()> {
+        Layer::setupmapping(
+            self, ctx, inode, handle, foffset, len, flags, moffset, vu_req,
+        )
+    }
+
+    fn removemapping<T: FsCacheReqHandler>(
+        &self,
+        ctx: Context,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut T,
+    ) -> io::Result<()> {
+        Layer::removemapping(self, ctx, requests, vu_req)
+    }
+
+    fn access(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        mask: u32,
+        extensions: Extensions,
+    ) -> io::Result<()> {
+        Layer::access(self, ctx, inode, mask, extensions)
+    }
+
+    fn lseek(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        Layer::lseek(self, ctx, inode, handle, offset, whence)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn copyfilerange(
+        &self,
+        ctx: Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        Layer::copyfilerange(
+            self, ctx, inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len,
+            flags,
+        )
+    }
+
+    fn syncfs(&self, ctx: Context, inode: Self::Inode) -> io::Result<()> {
+        Layer::syncfs(self, ctx, inode)
+    }
+
+    fn getlk(&self) -> io::Result<()> {
+        Layer::getlk(self)
+    }
+
+    fn setlk(&self) -> io::Result<()> {
+        Layer::setlk(self)
+    }
+
+    fn setlkw(&self) -> io::Result<()> {
+        Layer::setlkw(self)
+    }
+
+    fn ioctl(&self) -> io::Result<()> {
+        Layer::ioctl(self)
+    }
+
+    fn bmap(&self) -> io::Result<()> {
+        Layer::bmap(self)
+    }
+
+    fn poll(&self) -> io::Result<()> {
+        Layer::poll(self)
+    }
+
+    fn notify_reply(&self) -> io::Result<()> {
+        Layer::notify_reply(self)
+    }
+
+    fn tmpfile(&self) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        Layer::tmpfile(self)
+    }
+}
+
+/// Builds a stack of layers on top of a file system, from the bottom to the top.
+pub struct LayerBuilder<F: FileSystem> {
+    fs: F,
+}
+
+impl<F: FileSystem> LayerBuilder<F> {
+    /// Starts a stack with `fs` at the bottom.
+    pub fn new(fs: F) -> Self {
+        LayerBuilder { fs }
+    }
+
+    /// Puts the layer created by `make` from the current stack on top of it.
+    pub fn layer<L: FileSystem, M: FnOnce(F) -> L>(self, make: M) -> LayerBuilder<L> {
+        LayerBuilder { fs: make(self.fs) }
+    }
+
+    /// Returns the topmost layer.
+    pub fn build(self) -> F {
+        self.fs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuse::ROOT_ID;
+    use crate::passthrough::{Config, PassthroughFs};
+    use crate::test_client::{FuseClient, TempDir};
+    use crate::uring::Uring;
+    use std::fs;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// Counts the lookups that reach it.
+    struct CountLookups<F> {
+        inner: F,
+        lookups: Arc<AtomicU64>,
+    }
+
+    impl<F: FileSystem> Layer for CountLookups<F> {
+        type Inner = F;
+
+        fn inner(&self) -> &F {
+            &self.inner
+        }
+
+        fn lookup(&self, ctx: Context, parent: InnerInode<Self>, name: &CStr) -> io::Result<Entry> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            self.inner.lookup(ctx, parent, name)
+        }
+    }
+
+    /// Denies unlinking, and hides files named "hidden".
+    struct Protect<F>(F);
+
+    impl<F: FileSystem> Layer for Protect<F> {
+        type Inner = F;
+
+        fn inner(&self) -> &F {
+            &self.0
+        }
+
+        fn lookup(&self, ctx: Context, parent: InnerInode<Self>, name: &CStr) -> io::Result<Entry> {
+            if name.to_bytes() == b"hidden" {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            self.0.lookup(ctx, parent, name)
+        }
+
+        fn unlink(&self, _ctx: Context, _parent: InnerInode<Self>, _name: &CStr) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::EPERM))
+        }
+    }
+
+    #[test]
+    fn stack() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("file"), b"data").unwrap();
+        fs::write(dir.path().join("hidden"), b"").unwrap();
+        let cfg = Config {
+            root_dir: dir.path().to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+
+        let lookups = Arc::new(AtomicU64::new(0));
+        let fs = LayerBuilder::new(PassthroughFs::new(cfg).unwrap())
+            .layer(|fs| CountLookups {
+                inner: fs,
+                lookups: lookups.clone(),
+            })
+            .layer(Protect)
+            .build();
+        let mut client = FuseClient::new(fs);
+        client.init(FsOptions::empty()).unwrap();
+
+        // Operations that are not overridden reach the bottom of the stack.
+        let entry = client.lookup(ROOT_ID, "file").unwrap();
+        let open = client.open(entry.nodeid, libc::O_RDONLY as u32).unwrap();
+        assert_eq!(client.read(entry.nodeid, open.fh, 0, 100).unwrap(), b"data");
+        client.release(entry.nodeid, open.fh).unwrap();
+
+        // Overridden ones are handled by the topmost layer that overrides them.
+        let err = client.unlink(ROOT_ID, "file").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        assert!(dir.path().join("file").exists());
+        let err = client.lookup(ROOT_ID, "hidden").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        // The hidden file's lookup never reached the lower layer.
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn async_io() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("file"), b"data").unwrap();
+        let cfg = Config {
+            root_dir: dir.path().to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+
+        let fs = LayerBuilder::new(PassthroughFs::new(cfg).unwrap())
+            .layer(Protect)
+            .build();
+        let mut client = FuseClient::new(fs);
+        client.init(FsOptions::empty()).unwrap();
+        match Uring::new(16) {
+            Ok(uring) => client.set_uring(uring),
+            Err(e) => {
+                eprintln!("Skipping test, io_uring is not available: {}", e);
+                return;
+            }
+        }
+
+        // Layers do not keep the wrapped file system from doing I/O asynchronously.
+        let entry = client.lookup(ROOT_ID, "file").unwrap();
+        let open = client.open(entry.nodeid, libc::O_RDONLY as u32).unwrap();
+        assert_eq!(client.read(entry.nodeid, open.fh, 0, 100).unwrap(), b"data");
+        assert_eq!(client.async_replies, 1);
+    }
+}
@@ -6,19 +6,25 @@
 extern crate log;
 
 pub mod descriptor_utils;
+pub mod fault_injection;
 pub mod file_traits;
 pub mod filesystem;
 pub mod fs_cache_req_handler;
 pub mod fuse;
 pub mod idmap;
+pub mod layer;
 pub mod limits;
 pub mod macros;
 pub mod oslib;
 pub mod passthrough;
 pub mod read_dir;
+pub mod record;
 pub mod sandbox;
 pub mod seccomp;
 pub mod server;
+#[cfg(test)]
+pub mod test_client;
+pub mod uring;
 pub mod util;
 
 use std::ffi::{FromBytesWithNulError, FromVecWithNulError};
@@ -9,13 +9,16 @@ use passthrough::xattrmap::XattrMap;
 use std::collections::HashSet;
 use std::convert::{self, TryFrom, TryInto};
 use std::ffi::CString;
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::{env, error, fmt, io, process};
-use virtiofsd::idmap::{GidMap, UidMap};
+use virtiofsd::idmap::{GidMap, IdMapOption, IdRange, IdTranslation, UidMap};
 
 use clap::{CommandFactory, Parser};
 
@@ -28,13 +31,18 @@ use virtio_bindings::bindings::virtio_config::*;
 use virtio_bindings::bindings::virtio_ring::{
     VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
 };
-use virtio_queue::{DescriptorChain, QueueOwnedT};
+use virtio_queue::{DescriptorChain, QueueOwnedT, QueueT};
 use virtiofsd::descriptor_utils::{Error as VufDescriptorError, Reader, Writer};
+use virtiofsd::fault_injection::{FaultInjectionFs, RulesFile};
 use virtiofsd::filesystem::FileSystem;
+use virtiofsd::passthrough::credentials::Squash;
+use virtiofsd::passthrough::read_hints::ReadHints;
 use virtiofsd::passthrough::{self, CachePolicy, InodeFileHandlesMode, PassthroughFs};
+use virtiofsd::record::{self, Recorder};
 use virtiofsd::sandbox::{Sandbox, SandboxMode};
 use virtiofsd::seccomp::{enable_seccomp, SeccompAction};
-use virtiofsd::server::Server;
+use virtiofsd::server::{InitLimits, RequestKind, Server};
+use virtiofsd::uring::Uring;
 use virtiofsd::util::write_pid_file;
 use virtiofsd::{limits, oslib, Error as VhostUserFsError};
 use vm_memory::{
@@ -51,10 +59,23 @@ const REQUEST_QUEUES: u32 = 1;
 // notification queue.
 const NUM_QUEUES: usize = REQUEST_QUEUES as usize + 1;
 
-// The guest queued an available buffer for the high priority queue.
-const HIPRIO_QUEUE_EVENT: u16 = 0;
-// The guest queued an available buffer for the request queue.
-const REQ_QUEUE_EVENT: u16 = 1;
+// Each queue is handled by a thread of its own, whose index is the index of the queue.  The high
+// priority queue only has FORGET and INTERRUPT requests, which are handled right away on its
+// thread, so that they are never stuck behind other requests.
+const HIPRIO_QUEUE: usize = 0;
+const REQ_QUEUE: usize = 1;
+
+// The guest queued an available buffer for the queue of the thread.
+const QUEUE_EVENT: u16 = 0;
+// Requests submitted to the io_uring have completed. (`NUM_QUEUES` is the exit event.)
+const URING_EVENT: u16 = NUM_QUEUES as u16 + 1;
+// Requests have finished after the request queue was left alone because of `--max-in-flight`.
+const RESUME_EVENT: u16 = NUM_QUEUES as u16 + 2;
+// The statistics of the request queue should be logged (see `SIGUSR1` in `set_signal_handlers()`).
+const STATS_EVENT: u16 = NUM_QUEUES as u16 + 3;
+
+// The eventfd that `SIGUSR1` writes to (`VhostUserFsThread::stats_evt`), or -1 before there is one.
+static STATS_EVENT_FD: AtomicI32 = AtomicI32::new(-1);
 
 const MAX_TAG_LEN: usize = 36;
 
@@ -70,6 +91,12 @@ enum Error {
     CreateKillEventFd(io::Error),
     /// Failed to create thread pool.
     CreateThreadPool(io::Error),
+    /// Failed to create io_uring.
+    CreateUring(io::Error),
+    /// Failed to create the eventfd for resuming the request queue.
+    CreateResumeEventFd(io::Error),
+    /// Failed to create the eventfd for logging statistics.
+    CreateStatsEventFd(io::Error),
     /// Failed to handle event other than input event.
     HandleEventNotEpollIn,
     /// Failed to handle unknown event.
@@ -115,7 +142,7 @@ impl error::Error for Error {}
 
 impl convert::From<Error> for io::Error {
     fn from(e: Error) -> Self {
-        io::Error::new(io::ErrorKind::Other, e)
+        io::Error::other(e)
     }
 }
 
@@ -127,6 +154,61 @@ struct VhostUserFsThread<F: FileSystem + Send + Sync + 'static> {
     vu_req: Option<Backend>,
     event_idx: bool,
     pool: Option<ThreadPool>,
+    // Handles data requests instead of `pool`, so that they cannot delay metadata requests.
+    data_pool: Option<ThreadPool>,
+    uring: Option<Arc<Uring<UringRequest>>>,
+    in_flight: Arc<InFlight>,
+    stats_evt: Arc<EventFd>,
+    // Where `handle_event_stats()` writes the statistics to, besides the log.
+    stats_file: Option<Arc<File>>,
+}
+
+/// Keeps track of the requests from the request queue that have been taken from the queue, but not
+/// yet returned to the guest.  Once there are `max` of them, no more requests are taken from the
+/// queue until one has finished, so that a guest cannot make us queue up an arbitrary amount of
+/// work.  (Requests from the high priority queue are handled right away.)
+struct InFlight {
+    count: AtomicUsize,
+    max: usize,
+    // Becomes readable when the count drops below `max` again.
+    resume_evt: EventFd,
+}
+
+impl InFlight {
+    fn new(max: usize) -> Result<Self> {
+        Ok(InFlight {
+            count: AtomicUsize::new(0),
+            max,
+            resume_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateResumeEventFd)?,
+        })
+    }
+
+    fn has_room(&self) -> bool {
+        self.room() > 0
+    }
+
+    /// Returns how many more requests may be started.
+    fn room(&self) -> usize {
+        self.max.saturating_sub(self.count.load(Ordering::Acquire))
+    }
+
+    fn start(&self) {
+        self.count.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn finish(&self) {
+        if self.count.fetch_sub(1, Ordering::AcqRel) == self.max {
+            // Failing to write only means that there already is a pending event.
+            let _ = self.resume_evt.write(1);
+        }
+    }
+}
+
+/// A request from the request queue whose I/O has been submitted to the io_uring.
+struct UringRequest {
+    head_index: u16,
+    // Keeps the guest memory that the I/O is performed on mapped until it has completed.
+    _mem: GuestMemoryLoadGuard<GuestMemoryMmap>,
 }
 
 impl<F: FileSystem + Send + Sync + 'static> Clone for VhostUserFsThread<F> {
@@ -138,12 +220,37 @@ impl<F: FileSystem + Send + Sync + 'static> Clone for VhostUserFsThread<F> {
             vu_req: self.vu_req.clone(),
             event_idx: self.event_idx,
             pool: self.pool.clone(),
+            data_pool: self.data_pool.clone(),
+            uring: self.uring.clone(),
+            in_flight: self.in_flight.clone(),
+            stats_evt: self.stats_evt.clone(),
+            stats_file: self.stats_file.clone(),
         }
     }
 }
 
 impl<F: FileSystem + Send + Sync + 'static> VhostUserFsThread<F> {
-    fn new(fs: F, thread_pool_size: usize) -> Result<Self> {
+    fn new(
+        fs: F,
+        thread_pool_size: usize,
+        data_thread_pool_size: usize,
+        recorder: Option<Recorder>,
+        io_uring: bool,
+        max_in_flight: usize,
+        init_limits: InitLimits,
+    ) -> Result<Self> {
+        let create_pool = |size| {
+            ThreadPoolBuilder::new()
+                .after_start(|_| {
+                    // unshare FS for xattr operation
+                    let ret = unsafe { libc::unshare(libc::CLONE_FS) };
+                    assert_eq!(ret, 0); // Should not fail
+                })
+                .pool_size(size)
+                .create()
+                .map_err(Error::CreateThreadPool)
+        };
+
         let pool = if thread_pool_size > 0 {
             // Test that unshare(CLONE_FS) works, it will be called for each thread.
             // It's an unprivileged system call but some Docker/Moby versions are
@@ -156,28 +263,43 @@ impl<F: FileSystem + Send + Sync + 'static> VhostUserFsThread<F> {
                 return Err(Error::UnshareCloneFs(std::io::Error::last_os_error()));
             }
 
-            Some(
-                ThreadPoolBuilder::new()
-                    .after_start(|_| {
-                        // unshare FS for xattr operation
-                        let ret = unsafe { libc::unshare(libc::CLONE_FS) };
-                        assert_eq!(ret, 0); // Should not fail
-                    })
-                    .pool_size(thread_pool_size)
-                    .create()
-                    .map_err(Error::CreateThreadPool)?,
-            )
+            Some(create_pool(thread_pool_size)?)
         } else {
             None
         };
 
+        let data_pool = if pool.is_some() && data_thread_pool_size > 0 {
+            Some(create_pool(data_thread_pool_size)?)
+        } else {
+            None
+        };
+
+        let uring = if io_uring {
+            Some(Arc::new(
+                Uring::new(QUEUE_SIZE as u32).map_err(Error::CreateUring)?,
+            ))
+        } else {
+            None
+        };
+
+        let mut server = Server::new(fs);
+        server.set_init_limits(init_limits);
+        if let Some(recorder) = recorder {
+            server.set_recorder(recorder);
+        }
+
         Ok(VhostUserFsThread {
             mem: None,
             kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
-            server: Arc::new(Server::new(fs)),
+            server: Arc::new(server),
             vu_req: None,
             event_idx: false,
             pool,
+            data_pool,
+            uring,
+            in_flight: Arc::new(InFlight::new(max_in_flight)?),
+            stats_evt: Arc::new(EventFd::new(EFD_NONBLOCK).map_err(Error::CreateStatsEventFd)?),
+            stats_file: None,
         })
     }
 
@@ -220,13 +342,17 @@ impl<F: FileSystem + Send + Sync + 'static> VhostUserFsThread<F> {
             None => return Err(Error::NoMemoryConfigured),
         };
 
-        while let Some(avail_desc) = vring
-            .get_mut()
-            .get_queue_mut()
-            .iter(atomic_mem.memory())
-            .map_err(|_| Error::IterateQueue)?
-            .next()
-        {
+        while self.in_flight.has_room() {
+            let avail_desc = match vring
+                .get_mut()
+                .get_queue_mut()
+                .iter(atomic_mem.memory())
+                .map_err(|_| Error::IterateQueue)?
+                .next()
+            {
+                Some(avail_desc) => avail_desc,
+                None => break,
+            };
             used_any = true;
 
             // Prepare a set of objects that can be moved to the worker thread.
@@ -236,8 +362,16 @@ impl<F: FileSystem + Send + Sync + 'static> VhostUserFsThread<F> {
             let event_idx = self.event_idx;
             let worker_vring = vring.clone();
             let worker_desc = avail_desc.clone();
+            let uring = self.uring.clone();
+            let in_flight = self.in_flight.clone();
+
+            let pool = match &self.data_pool {
+                Some(data_pool) if RequestKind::of(&avail_desc) == RequestKind::Data => data_pool,
+                _ => self.pool.as_ref().unwrap(),
+            };
 
-            self.pool.as_ref().unwrap().spawn_ok(async move {
+            in_flight.start();
+            pool.spawn_ok(async move {
                 let mem = atomic_mem.memory();
                 let head_index = worker_desc.head_index();
 
@@ -248,34 +382,74 @@ impl<F: FileSystem + Send + Sync + 'static> VhostUserFsThread<F> {
                     .map_err(Error::QueueWriter)
                     .unwrap();
 
-                let len = server
-                    .handle_message(reader, writer, vu_req.as_mut())
-                    .map_err(Error::ProcessQueue)
-                    .unwrap();
+                let len = match uring {
+                    Some(uring) => {
+                        let request = UringRequest {
+                            head_index,
+                            _mem: mem.clone(),
+                        };
+                        // Safe because `request` keeps the guest memory mapped.
+                        unsafe {
+                            server.handle_message_async(
+                                reader,
+                                writer,
+                                vu_req.as_mut(),
+                                &uring,
+                                request,
+                            )
+                        }
+                    }
+                    None => server
+                        .handle_message(reader, writer, vu_req.as_mut())
+                        .map(Some),
+                }
+                .map_err(Error::ProcessQueue)
+                .unwrap();
 
-                Self::return_descriptor(&mut worker_vring.get_mut(), head_index, event_idx, len);
+                // Requests submitted to the io_uring are returned once they complete.
+                if let Some(len) = len {
+                    Self::return_descriptor(
+                        &mut worker_vring.get_mut(),
+                        head_index,
+                        event_idx,
+                        len,
+                    );
+                    in_flight.finish();
+                }
             });
         }
 
         Ok(used_any)
     }
 
-    fn process_queue_serial(&self, vring_state: &mut VringState) -> Result<bool> {
+    /// Handles the requests on `queue` one after the other on the calling thread.
+    fn process_queue_serial(&self, vring_state: &mut VringState, queue: usize) -> Result<bool> {
         let mut used_any = false;
         let mem = match &self.mem {
             Some(m) => m.memory(),
             None => return Err(Error::NoMemoryConfigured),
         };
         let mut vu_req = self.vu_req.clone();
+        // The completions of requests submitted to the io_uring are returned to the request queue,
+        // so requests from the high priority queue must not be submitted to it.
+        let (uring, in_flight) = if queue == REQ_QUEUE {
+            (self.uring.as_deref(), Some(&*self.in_flight))
+        } else {
+            (None, None)
+        };
 
         let avail_chains: Vec<DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>> = vring_state
             .get_queue_mut()
             .iter(mem.clone())
             .map_err(|_| Error::IterateQueue)?
+            .take(in_flight.map_or(usize::MAX, InFlight::room))
             .collect();
 
         for chain in avail_chains {
             used_any = true;
+            if let Some(in_flight) = in_flight {
+                in_flight.start();
+            }
 
             let head_index = chain.head_index();
 
@@ -286,34 +460,45 @@ impl<F: FileSystem + Send + Sync + 'static> VhostUserFsThread<F> {
                 .map_err(Error::QueueWriter)
                 .unwrap();
 
-            let len = self
-                .server
-                .handle_message(reader, writer, vu_req.as_mut())
-                .map_err(Error::ProcessQueue)
-                .unwrap();
-
-            Self::return_descriptor(vring_state, head_index, self.event_idx, len);
+            let len = match uring {
+                Some(uring) => {
+                    let request = UringRequest {
+                        head_index,
+                        _mem: mem.clone(),
+                    };
+                    // Safe because `request` keeps the guest memory mapped.
+                    unsafe {
+                        self.server.handle_message_async(
+                            reader,
+                            writer,
+                            vu_req.as_mut(),
+                            uring,
+                            request,
+                        )
+                    }
+                }
+                None => self
+                    .server
+                    .handle_message(reader, writer, vu_req.as_mut())
+                    .map(Some),
+            }
+            .map_err(Error::ProcessQueue)
+            .unwrap();
+
+            // Requests submitted to the io_uring are returned once they complete.
+            if let Some(len) = len {
+                Self::return_descriptor(vring_state, head_index, self.event_idx, len);
+                if let Some(in_flight) = in_flight {
+                    in_flight.finish();
+                }
+            }
         }
 
         Ok(used_any)
     }
 
-    fn handle_event_pool(
-        &self,
-        device_event: u16,
-        vrings: &[VringMutex],
-    ) -> VhostUserBackendResult<()> {
-        let idx = match device_event {
-            HIPRIO_QUEUE_EVENT => {
-                debug!("HIPRIO_QUEUE_EVENT");
-                0
-            }
-            REQ_QUEUE_EVENT => {
-                debug!("QUEUE_EVENT");
-                1
-            }
-            _ => return Err(Error::HandleEventUnknownEvent.into()),
-        };
+    fn handle_event_pool(&self, vring: &VringMutex) -> VhostUserBackendResult<()> {
+        debug!("QUEUE_EVENT");
 
         if self.event_idx {
             // vm-virtio's Queue implementation only checks avail_index
@@ -321,36 +506,30 @@ impl<F: FileSystem + Send + Sync + 'static> VhostUserFsThread<F> {
             // calling process_queue() until it stops finding new
             // requests on the queue.
             loop {
-                vrings[idx].disable_notification().unwrap();
-                self.process_queue_pool(vrings[idx].clone())?;
-                if !vrings[idx].enable_notification().unwrap() {
+                vring.disable_notification().unwrap();
+                self.process_queue_pool(vring.clone())?;
+                // Notifications stay disabled while too many requests are in flight, the queue is
+                // processed again once one of them has finished.
+                if !self.in_flight.has_room() || !vring.enable_notification().unwrap() {
                     break;
                 }
             }
         } else {
             // Without EVENT_IDX, a single call is enough.
-            self.process_queue_pool(vrings[idx].clone())?;
+            self.process_queue_pool(vring.clone())?;
         }
 
         Ok(())
     }
 
-    fn handle_event_serial(
-        &self,
-        device_event: u16,
-        vrings: &[VringMutex],
-    ) -> VhostUserBackendResult<()> {
-        let mut vring_state = match device_event {
-            HIPRIO_QUEUE_EVENT => {
-                debug!("HIPRIO_QUEUE_EVENT");
-                vrings[0].get_mut()
-            }
-            REQ_QUEUE_EVENT => {
-                debug!("QUEUE_EVENT");
-                vrings[1].get_mut()
-            }
-            _ => return Err(Error::HandleEventUnknownEvent.into()),
-        };
+    fn handle_event_serial(&self, queue: usize, vring: &VringMutex) -> VhostUserBackendResult<()> {
+        if queue == HIPRIO_QUEUE {
+            debug!("HIPRIO_QUEUE_EVENT");
+        } else {
+            debug!("QUEUE_EVENT");
+        }
+        let throttled = || queue == REQ_QUEUE && !self.in_flight.has_room();
+        let mut vring_state = vring.get_mut();
 
         if self.event_idx {
             // vm-virtio's Queue implementation only checks avail_index
@@ -359,14 +538,88 @@ impl<F: FileSystem + Send + Sync + 'static> VhostUserFsThread<F> {
             // requests on the queue.
             loop {
                 vring_state.disable_notification().unwrap();
-                self.process_queue_serial(&mut vring_state)?;
-                if !vring_state.enable_notification().unwrap() {
+                self.process_queue_serial(&mut vring_state, queue)?;
+                // See `handle_event_pool()`.
+                if throttled() || !vring_state.enable_notification().unwrap() {
                     break;
                 }
             }
         } else {
             // Without EVENT_IDX, a single call is enough.
-            self.process_queue_serial(&mut vring_state)?;
+            self.process_queue_serial(&mut vring_state, queue)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the requests whose I/O has completed to the request queue.
+    fn handle_event_uring(&self, vring: &VringMutex) -> VhostUserBackendResult<()> {
+        debug!("URING_EVENT");
+        let uring = self.uring.as_ref().ok_or(Error::HandleEventUnknownEvent)?;
+
+        for (request, len) in uring.complete() {
+            Self::return_descriptor(
+                &mut vring.get_mut(),
+                request.head_index,
+                self.event_idx,
+                len,
+            );
+            self.in_flight.finish();
+        }
+
+        Ok(())
+    }
+
+    /// Processes the request queue again after requests have finished, see `InFlight`.
+    fn handle_event_resume(&self, vring: &VringMutex) -> VhostUserBackendResult<()> {
+        debug!("RESUME_EVENT");
+        // The eventfd only wakes us up, whether there is room is checked when processing.
+        let _ = self.in_flight.resume_evt.read();
+
+        if self.pool.is_some() {
+            self.handle_event_pool(vring)
+        } else {
+            self.handle_event_serial(REQ_QUEUE, vring)
+        }
+    }
+
+    /// Logs the number of requests in flight and the number of requests waiting in the request
+    /// queue.
+    fn handle_event_stats(&self, vring: &VringMutex) -> VhostUserBackendResult<()> {
+        debug!("STATS_EVENT");
+        let _ = self.stats_evt.read();
+
+        let waiting = match &self.mem {
+            Some(mem) => {
+                let vring_state = vring.get_ref();
+                let queue = vring_state.get_queue();
+                queue
+                    .avail_idx(&*mem.memory(), Ordering::Acquire)
+                    .map(|avail_idx| (avail_idx - std::num::Wrapping(queue.next_avail())).0)
+                    .unwrap_or(0)
+            }
+            None => 0,
+        };
+
+        let in_flight = self.in_flight.count.load(Ordering::Relaxed);
+        info!(
+            "Request queue: {} requests in flight (at most {}), {} waiting in the queue",
+            in_flight, self.in_flight.max, waiting
+        );
+
+        if let Some(file) = &self.stats_file {
+            let stats = format!(
+                "in_flight {}\nmax_in_flight {}\nwaiting {}\n",
+                in_flight, self.in_flight.max, waiting
+            );
+            // Overwrite the old statistics before cutting them off, so that readers never see an
+            // empty file.
+            if let Err(e) = file
+                .write_all_at(stats.as_bytes(), 0)
+                .and_then(|()| file.set_len(stats.len() as u64))
+            {
+                warn!("Failed to write statistics file: {}", e);
+            }
         }
 
         Ok(())
@@ -396,12 +649,37 @@ unsafe impl ByteValued for VirtioFsConfig {}
 struct VhostUserFsBackend<F: FileSystem + Send + Sync + 'static> {
     thread: RwLock<VhostUserFsThread<F>>,
     tag: Option<String>,
+    // Set when a queue is set up, so that the size of the request queue is passed on to the server
+    // once it is in use.
+    queue_set_up: AtomicBool,
 }
 
 impl<F: FileSystem + Send + Sync + 'static> VhostUserFsBackend<F> {
-    fn new(fs: F, thread_pool_size: usize, tag: Option<String>) -> Result<Self> {
-        let thread = RwLock::new(VhostUserFsThread::new(fs, thread_pool_size)?);
-        Ok(VhostUserFsBackend { thread, tag })
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        fs: F,
+        thread_pool_size: usize,
+        data_thread_pool_size: usize,
+        tag: Option<String>,
+        recorder: Option<Recorder>,
+        io_uring: bool,
+        max_in_flight: usize,
+        init_limits: InitLimits,
+    ) -> Result<Self> {
+        let thread = RwLock::new(VhostUserFsThread::new(
+            fs,
+            thread_pool_size,
+            data_thread_pool_size,
+            recorder,
+            io_uring,
+            max_in_flight,
+            init_limits,
+        )?);
+        Ok(VhostUserFsBackend {
+            thread,
+            tag,
+            queue_set_up: AtomicBool::new(false),
+        })
     }
 }
 
@@ -417,6 +695,10 @@ impl<F: FileSystem + Send + Sync + 'static> VhostUserBackend for VhostUserFsBack
         QUEUE_SIZE
     }
 
+    fn queues_per_thread(&self) -> Vec<u64> {
+        vec![1 << HIPRIO_QUEUE, 1 << REQ_QUEUE]
+    }
+
     fn features(&self) -> u64 {
         1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_RING_F_INDIRECT_DESC
@@ -472,6 +754,9 @@ impl<F: FileSystem + Send + Sync + 'static> VhostUserBackend for VhostUserFsBack
     }
 
     fn set_event_idx(&self, enabled: bool) {
+        // This is called whenever a queue is set up, and events for the queue only arrive once its
+        // setup is complete.
+        self.queue_set_up.store(true, Ordering::Release);
         self.thread.write().unwrap().event_idx = enabled;
     }
 
@@ -485,18 +770,33 @@ impl<F: FileSystem + Send + Sync + 'static> VhostUserBackend for VhostUserFsBack
         device_event: u16,
         evset: EventSet,
         vrings: &[VringMutex],
-        _thread_id: usize,
+        thread_id: usize,
     ) -> VhostUserBackendResult<()> {
         if evset != EventSet::IN {
             return Err(Error::HandleEventNotEpollIn.into());
         }
 
         let thread = self.thread.read().unwrap();
+        // Every thread handles exactly one queue, see `queues_per_thread()`.
+        let vring = &vrings[0];
+        if device_event == QUEUE_EVENT
+            && thread_id == REQ_QUEUE
+            && self.queue_set_up.load(Ordering::Acquire)
+            && self.queue_set_up.swap(false, Ordering::AcqRel)
+        {
+            let queue_size = vring.get_ref().get_queue().size();
+            thread.server.set_queue_size(queue_size.into());
+        }
 
-        if thread.pool.is_some() {
-            thread.handle_event_pool(device_event, vrings)
-        } else {
-            thread.handle_event_serial(device_event, vrings)
+        match device_event {
+            URING_EVENT if thread_id == REQ_QUEUE => thread.handle_event_uring(vring),
+            RESUME_EVENT if thread_id == REQ_QUEUE => thread.handle_event_resume(vring),
+            STATS_EVENT if thread_id == REQ_QUEUE => thread.handle_event_stats(vring),
+            QUEUE_EVENT if thread_id == REQ_QUEUE && thread.pool.is_some() => {
+                thread.handle_event_pool(vring)
+            }
+            QUEUE_EVENT => thread.handle_event_serial(thread_id, vring),
+            _ => Err(Error::HandleEventUnknownEvent.into()),
         }
     }
 
@@ -532,6 +832,8 @@ enum InodeFileHandlesCommandLineMode {
     Prefer,
     /// `InodeFileHandlesMode::Mandatory`
     Mandatory,
+    /// `InodeFileHandlesMode::Adaptive`
+    Adaptive,
 }
 
 impl From<InodeFileHandlesCommandLineMode> for InodeFileHandlesMode {
@@ -541,6 +843,7 @@ impl From<InodeFileHandlesCommandLineMode> for InodeFileHandlesMode {
             InodeFileHandlesCommandLineMode::Fallback => InodeFileHandlesMode::Prefer,
             InodeFileHandlesCommandLineMode::Prefer => InodeFileHandlesMode::Prefer,
             InodeFileHandlesCommandLineMode::Mandatory => InodeFileHandlesMode::Mandatory,
+            InodeFileHandlesCommandLineMode::Adaptive => InodeFileHandlesMode::Adaptive,
         }
     }
 }
@@ -554,12 +857,35 @@ impl FromStr for InodeFileHandlesCommandLineMode {
             "fallback" => Ok(InodeFileHandlesCommandLineMode::Fallback),
             "prefer" => Ok(InodeFileHandlesCommandLineMode::Prefer),
             "mandatory" => Ok(InodeFileHandlesCommandLineMode::Mandatory),
+            "adaptive" => Ok(InodeFileHandlesCommandLineMode::Adaptive),
 
             _ => Err("invalid inode file handles mode"),
         }
     }
 }
 
+fn parse_read_hints_thresholds(src: &str) -> std::result::Result<(u32, u32), String> {
+    let (sequential, random) = src
+        .split_once(':')
+        .ok_or_else(|| "expected SEQUENTIAL:RANDOM".to_string())?;
+    let parse = |n: &str| match n.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!(
+            "invalid threshold '{n}', must be a positive number"
+        )),
+    };
+    Ok((parse(sequential)?, parse(random)?))
+}
+
+fn parse_mode_mask(src: &str) -> std::result::Result<u32, String> {
+    match u32::from_str_radix(src, 8) {
+        Ok(mask) if mask <= 0o7777 => Ok(mask),
+        _ => Err(format!(
+            "invalid mask '{src}', must be octal permission bits"
+        )),
+    }
+}
+
 fn parse_tag(tag: &str) -> Result<String> {
     if !tag.is_empty() && tag.len() <= MAX_TAG_LEN {
         Ok(tag.into())
@@ -592,11 +918,11 @@ struct Opt {
     tag: Option<String>,
 
     /// vhost-user socket path [deprecated]
-    #[arg(long, required_unless_present_any = &["fd", "socket_path", "print_capabilities"])]
+    #[arg(long, required_unless_present_any = &["fd", "socket_path", "print_capabilities", "replay"])]
     socket: Option<String>,
 
     /// vhost-user socket path
-    #[arg(long = "socket-path", required_unless_present_any = &["fd", "socket", "print_capabilities"])]
+    #[arg(long = "socket-path", required_unless_present_any = &["fd", "socket", "print_capabilities", "replay"])]
     socket_path: Option<String>,
 
     /// Name of group for the vhost-user socket
@@ -604,13 +930,29 @@ struct Opt {
     socket_group: Option<String>,
 
     /// File descriptor for the listening socket
-    #[arg(long, required_unless_present_any = &["socket", "socket_path", "print_capabilities"], conflicts_with_all = &["socket_path", "socket"])]
+    #[arg(long, required_unless_present_any = &["socket", "socket_path", "print_capabilities", "replay"], conflicts_with_all = &["socket_path", "socket"])]
     fd: Option<RawFd>,
 
     /// Maximum thread pool size. A value of "0" disables the pool
     #[arg(long, default_value = "0")]
     thread_pool_size: usize,
 
+    /// Maximum size of a separate thread pool for requests that transfer file data (read, write,
+    /// fallocate and copy_file_range), so that they cannot delay other requests. A value of "0" has
+    /// them share the pool of --thread-pool-size
+    #[arg(long, default_value = "0")]
+    data_thread_pool_size: usize,
+
+    /// Perform READ, WRITE and FSYNC requests asynchronously using io_uring, so that a few
+    /// threads suffice for high-throughput I/O
+    #[arg(long = "io-uring")]
+    io_uring: bool,
+
+    /// Maximum number of requests from the request queue that are handled at the same time.
+    /// Further requests stay in the queue until one of them has finished
+    #[arg(long = "max-in-flight", default_value_t = QUEUE_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_in_flight: usize,
+
     /// Enable support for extended attributes
     #[arg(long)]
     xattr: bool,
@@ -619,12 +961,66 @@ struct Opt {
     #[arg(long)]
     posix_acl: bool,
 
+    /// How long (in seconds) extended attributes may be cached, including the fact that an
+    /// attribute does not exist [default: 0]
+    #[arg(long = "xattr-cache-timeout", value_name = "SECONDS")]
+    xattr_cache_timeout: Option<u64>,
+
     /// Add custom rules for translating extended attributes between host and guest
     /// (e.g. :map::user.virtiofs.:)
     #[arg(long, value_parser = |s: &_| XattrMap::try_from(s))]
     xattrmap: Option<XattrMap>,
 
-    /// Sandbox mechanism to isolate the daemon process (namespace, chroot, none)
+    /// Perform requests from root (root) or from everyone (all) as --anon-uid/--anon-gid, like
+    /// the NFS export options root_squash and all_squash (none, root, all)
+    #[arg(long, default_value = "none")]
+    squash: Squash,
+
+    /// The uid squashed requests are performed as (see --squash)
+    #[arg(long = "anon-uid", default_value = "65534")]
+    anon_uid: u32,
+
+    /// The gid squashed requests are performed as (see --squash)
+    #[arg(long = "anon-gid", default_value = "65534")]
+    anon_gid: u32,
+
+    /// Translate a range of guest UIDs into host UIDs in the daemon, given as
+    /// :guest_uid:host_uid:count: (may be given multiple times)
+    ///
+    /// For example, :1000:201000:1: performs requests of guest UID 1000 as host UID 201000,
+    /// and reports files owned by host UID 201000 as owned by UID 1000. UIDs outside of all
+    /// ranges become 65534 on the other side.
+    #[arg(long = "translate-uid", value_name = "RANGE")]
+    translate_uid: Vec<IdRange>,
+
+    /// Translate a range of guest GIDs into host GIDs in the daemon, given as
+    /// :guest_gid:host_gid:count: (may be given multiple times, see --translate-uid)
+    #[arg(long = "translate-gid", value_name = "RANGE")]
+    translate_gid: Vec<IdRange>,
+
+    /// Emulate file ownership, modes and device nodes in the user.containers.override_stat
+    /// extended attribute instead of applying them to host files, for running unprivileged
+    #[arg(long = "override-stat")]
+    override_stat: bool,
+
+    /// Report all files as owned by this UID, whoever owns them on the host; requests are then
+    /// performed with the daemon's own credentials
+    #[arg(long = "present-uid")]
+    present_uid: Option<u32>,
+
+    /// Report all files as owned by this GID (see --present-uid)
+    #[arg(long = "present-gid")]
+    present_gid: Option<u32>,
+
+    /// Permission bits (octal) to clear in the mode reported for files other than directories
+    #[arg(long = "file-mode-mask", value_name = "MASK", default_value = "0", value_parser = parse_mode_mask)]
+    file_mode_mask: u32,
+
+    /// Permission bits (octal) to clear in the mode reported for directories
+    #[arg(long = "dir-mode-mask", value_name = "MASK", default_value = "0", value_parser = parse_mode_mask)]
+    dir_mode_mask: u32,
+
+    /// Sandbox mechanism to isolate the daemon process (namespace, chroot, idmap, none)
     #[arg(long, default_value = "namespace")]
     sandbox: SandboxMode,
 
@@ -641,17 +1037,21 @@ struct Opt {
     no_announce_submounts: bool,
 
     /// When to use file handles to reference inodes instead of O_PATH file descriptors (never,
-    /// prefer, mandatory)
+    /// prefer, mandatory, adaptive)
     ///
     /// - never: Never use file handles, always use O_PATH file descriptors.
     ///
     /// - prefer: Attempt to generate file handles, but fall back to O_PATH file descriptors where
-    /// the underlying filesystem does not support file handles.  Useful when there are various
-    /// different filesystems under the shared directory and some of them do not support file
-    /// handles.  ("fallback" is a deprecated alias for "prefer".)
+    ///   the underlying filesystem does not support file handles.  Useful when there are various
+    ///   different filesystems under the shared directory and some of them do not support file
+    ///   handles.  ("fallback" is a deprecated alias for "prefer".)
     ///
     /// - mandatory: Always use file handles, never fall back to O_PATH file descriptors.
     ///
+    /// - adaptive: Use O_PATH file descriptors until they take up half of the file descriptor
+    ///   limit (see --rlimit-nofile), then switch inodes over to file handles where the
+    ///   underlying filesystem supports them.
+    ///
     /// Using file handles reduces the number of file descriptors virtiofsd keeps open, which is
     /// not only helpful with resources, but may also be important in cases where virtiofsd should
     /// only have file descriptors open for files that are open in the guest, e.g. to get around
@@ -663,6 +1063,11 @@ struct Opt {
     #[arg(long, default_value = "auto")]
     cache: CachePolicy,
 
+    /// How long (in seconds) the guest may cache that a file does not exist. Has no effect with
+    /// --cache=never [default: 0]
+    #[arg(long = "negative-timeout", value_name = "SECONDS")]
+    negative_timeout: Option<u64>,
+
     /// Disable support for READDIRPLUS operations
     #[arg(long)]
     no_readdirplus: bool,
@@ -696,6 +1101,55 @@ struct Opt {
     #[arg(long = "rlimit-nofile")]
     rlimit_nofile: Option<u64>,
 
+    /// Close the file descriptors of open files that have not been used for this many seconds,
+    /// and reopen them on their next use [default: 0 (never)]
+    #[arg(long = "idle-handle-timeout", value_name = "SECONDS")]
+    idle_handle_timeout: Option<u64>,
+
+    /// Tell the host how open files are read, so that it reads ahead of sequential reads and
+    /// does not read ahead of random ones. Most useful with --cache=never
+    #[arg(long = "read-hints")]
+    read_hints: bool,
+
+    /// How far ahead of sequential reads to have the host read with --read-hints, in bytes
+    /// [default: 2097152]
+    #[arg(
+        long = "read-hints-window",
+        value_name = "BYTES",
+        requires = "read_hints"
+    )]
+    read_hints_window: Option<u64>,
+
+    /// Number of consecutive sequential and non-sequential reads, respectively, after which
+    /// --read-hints considers a file to be read sequentially or randomly [default: 2:4]
+    #[arg(long = "read-hints-thresholds", value_name = "SEQUENTIAL:RANDOM", requires = "read_hints", value_parser = parse_read_hints_thresholds)]
+    read_hints_thresholds: Option<(u32, u32)>,
+
+    /// Maximum size of the data of a single read or write request, in bytes. The guest kernel
+    /// may limit it further [default: 1048576]
+    #[arg(long = "max-write", value_name = "BYTES")]
+    max_write: Option<u32>,
+
+    /// Maximum number of background requests (e.g. readahead) the guest may have pending
+    /// [default: 65535]
+    #[arg(long = "max-background")]
+    max_background: Option<u16>,
+
+    /// Number of pending background requests at which the guest considers the file system
+    /// congested [default: 3/4 of --max-background]
+    #[arg(long = "congestion-threshold")]
+    congestion_threshold: Option<u16>,
+
+    /// Granularity of the timestamps of the shared directory, in nanoseconds (a power of 10)
+    /// [default: 1]
+    #[arg(long = "time-gran", value_name = "NANOSECONDS")]
+    time_gran: Option<u32>,
+
+    /// Log2 of the alignment in bytes that the guest must use for the offsets of DAX mappings, at
+    /// most 21 [default: 0]
+    #[arg(long = "map-alignment", value_name = "LOG2_BYTES")]
+    map_alignment: Option<u16>,
+
     /// Options in a format compatible with the legacy implementation [deprecated]
     #[arg(short = 'o')]
     compat_options: Option<Vec<String>>,
@@ -725,23 +1179,50 @@ struct Opt {
     /// :namespace_uid:host_uid:count:
     ///
     /// For example, :0:100000:65536: will map the 65536 host UIDs [100000, 165535]
-    /// into the namespace as [0, 65535].
+    /// into the namespace as [0, 65535]. May be given multiple times to map several
+    /// ranges, which must not overlap.
+    ///
+    /// auto maps the current UID to 0 and the subordinate UIDs of the current user
+    /// from /etc/subuid to [1, ...], like rootless podman.
     #[arg(long)]
-    uid_map: Option<UidMap>,
+    uid_map: Vec<IdMapOption<UidMap>>,
 
     /// Map a range of GIDs from the host into the namespace, given as
     /// :namespace_gid:host_gid:count:
     ///
     /// For example, :0:100000:65536: will map the 65536 host GIDs [100000, 165535]
-    /// into the namespace as [0, 65535].
+    /// into the namespace as [0, 65535]. May be given multiple times to map several
+    /// ranges, which must not overlap.
+    ///
+    /// auto maps the current GID to 0 and the subordinate GIDs of the current user
+    /// from /etc/subgid to [1, ...], like rootless podman.
     #[arg(long)]
-    gid_map: Option<GidMap>,
+    gid_map: Vec<IdMapOption<GidMap>>,
 
     /// Preserve O_NOATIME behavior, otherwise automatically clean up O_NOATIME flag to prevent
     /// potential permission errors when running in unprivileged mode (e.g., when accessing files
     /// without having ownership/capability to use O_NOATIME).
     #[arg(long = "preserve-noatime")]
     preserve_noatime: bool,
+
+    /// Record all FUSE requests and their replies to the given file (for use with --replay)
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<String>,
+
+    /// Also write the request queue statistics to the given file when receiving SIGUSR1, as
+    /// "<name> <value>" lines
+    #[arg(long = "stats-file", value_name = "PATH", conflicts_with = "replay")]
+    stats_file: Option<String>,
+
+    /// Replay the FUSE requests from a file written with --record against the shared directory,
+    /// report the replies that differ from the recorded ones, and exit
+    #[arg(long, conflicts_with_all = &["fd", "socket", "socket_path", "socket_group"])]
+    replay: Option<String>,
+
+    /// Inject errors and delays into file system operations according to the rules in the given
+    /// file, which is re-read when it changes (for testing only)
+    #[arg(long)]
+    fault_injection: Option<String>,
 }
 
 fn parse_compat(opt: Opt) -> Opt {
@@ -877,6 +1358,30 @@ fn set_signal_handlers() {
             process::exit(1);
         }
     }
+
+    // Logging is not async-signal-safe, so have the thread of the request queue do it.
+    extern "C" fn handle_stats_signal(
+        _: libc::c_int,
+        _: *mut libc::siginfo_t,
+        _: *mut libc::c_void,
+    ) {
+        let fd = STATS_EVENT_FD.load(Ordering::Relaxed);
+        if fd >= 0 {
+            let one = 1u64;
+            // Safe because we write a u64 from a buffer of that size.
+            unsafe {
+                libc::write(
+                    fd,
+                    &one as *const u64 as *const libc::c_void,
+                    std::mem::size_of::<u64>(),
+                )
+            };
+        }
+    }
+    if let Err(e) = signal::register_signal_handler(libc::SIGUSR1, handle_stats_signal) {
+        error!("Setting signal handlers: {}", e);
+        process::exit(1);
+    }
 }
 
 fn parse_modcaps(
@@ -964,6 +1469,38 @@ fn drop_capabilities(inode_file_handles: InodeFileHandlesMode, modcaps: Option<S
     }
 }
 
+fn parse_id_translation(option: &str, ranges: &[IdRange]) -> Option<IdTranslation> {
+    if ranges.is_empty() {
+        return None;
+    }
+    match IdTranslation::new(ranges.to_vec()) {
+        Ok(translation) => Some(translation),
+        Err(error) => {
+            error!("Invalid {}: {}", option, error);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_init_limits(opt: &Opt) -> InitLimits {
+    let default = InitLimits::default();
+    let max_background = opt.max_background.unwrap_or(default.max_background);
+    let init_limits = InitLimits {
+        max_write: opt.max_write.unwrap_or(default.max_write),
+        max_background,
+        congestion_threshold: opt.congestion_threshold.unwrap_or((max_background / 4) * 3),
+        time_gran: opt.time_gran.unwrap_or(default.time_gran),
+        map_alignment: opt.map_alignment.unwrap_or(default.map_alignment),
+    };
+
+    if let Err(error) = init_limits.validate(QUEUE_SIZE) {
+        error!("Invalid FUSE limits: {}", error);
+        process::exit(1);
+    }
+
+    init_limits
+}
+
 fn has_noatime_capability() -> bool {
     // We may not have all permissions/capabilities to use O_NOATIME with all the exported files if
     // we are running as unprivileged user and without any sandbox (e.g., --sandbox=none).
@@ -978,6 +1515,21 @@ fn has_noatime_capability() -> bool {
     uid == 0 || capng::have_capability(capng::Type::EFFECTIVE, cap)
 }
 
+fn replay<F: FileSystem + Sync>(fs: F, recording: File, init_limits: InitLimits) -> ! {
+    let mut server = Server::new(fs);
+    server.set_init_limits(init_limits);
+    let stats = record::replay(&server, recording, &mut io::stdout()).unwrap_or_else(|error| {
+        error!("Error replaying recording: {}", error);
+        process::exit(1)
+    });
+
+    info!(
+        "Replayed {} requests, {} of {} compared replies differ",
+        stats.requests, stats.mismatches, stats.compared
+    );
+    process::exit(if stats.mismatches == 0 { 0 } else { 1 })
+}
+
 fn main() {
     let opt = parse_compat(Opt::parse());
 
@@ -1019,6 +1571,25 @@ fn main() {
     let xattrmap = opt.xattrmap.clone();
     let xattr = xattrmap.is_some() || opt.posix_acl || opt.xattr;
     let thread_pool_size = opt.thread_pool_size;
+    let data_thread_pool_size = opt.data_thread_pool_size;
+    if data_thread_pool_size > 0 && thread_pool_size == 0 {
+        error!("--data-thread-pool-size requires --thread-pool-size");
+        process::exit(1);
+    }
+    let init_limits = parse_init_limits(&opt);
+    let read_hints = opt.read_hints.then(|| {
+        let default = ReadHints::default();
+        let (sequential_threshold, random_threshold) = opt
+            .read_hints_thresholds
+            .unwrap_or((default.sequential_threshold, default.random_threshold));
+        ReadHints {
+            window: opt.read_hints_window.unwrap_or(default.window),
+            sequential_threshold,
+            random_threshold,
+        }
+    });
+    let uid_translation = parse_id_translation("--translate-uid", &opt.translate_uid);
+    let gid_translation = parse_id_translation("--translate-gid", &opt.translate_gid);
     let readdirplus = match opt.cache {
         CachePolicy::Never => false,
         _ => !opt.no_readdirplus,
@@ -1031,6 +1602,14 @@ fn main() {
         CachePolicy::Always => Duration::from_secs(86400),
     };
 
+    let negative_timeout = match (&opt.cache, opt.negative_timeout) {
+        (CachePolicy::Never, Some(_)) => {
+            warn!("Ignoring '--negative-timeout' because the cache policy is 'never'");
+            Duration::ZERO
+        }
+        (_, secs) => Duration::from_secs(secs.unwrap_or(0)),
+    };
+
     let umask = if opt.socket_group.is_some() {
         libc::S_IROTH | libc::S_IWOTH | libc::S_IXOTH
     } else {
@@ -1042,10 +1621,40 @@ fn main() {
             | libc::S_IXOTH
     };
 
+    // These files must be opened before entering the sandbox, which may hide their paths.
+    let recorder = opt.record.as_ref().map(|path| {
+        File::create(path)
+            .and_then(Recorder::new)
+            .unwrap_or_else(|error| {
+                error!("Error creating recording file '{}': {}", path, error);
+                process::exit(1);
+            })
+    });
+    let stats_file = opt.stats_file.as_ref().map(|path| {
+        File::create(path).unwrap_or_else(|error| {
+            error!("Error creating statistics file '{}': {}", path, error);
+            process::exit(1);
+        })
+    });
+    let rules_file = opt.fault_injection.as_ref().map(|path| {
+        RulesFile::open(Path::new(path)).unwrap_or_else(|error| {
+            error!("Error opening fault injection rules '{}': {}", path, error);
+            process::exit(1);
+        })
+    });
+    let replay_file = opt.replay.as_ref().map(|path| {
+        File::open(path).unwrap_or_else(|error| {
+            error!("Error opening recording file '{}': {}", path, error);
+            process::exit(1);
+        })
+    });
+
     // We need to keep _pid_file around because it maintains a lock on the pid file
     // that prevents another daemon from using the same pid file.
     let (listener, socket_path, _pid_file) = match opt.fd.as_ref() {
-        Some(fd) => unsafe { (Listener::from_raw_fd(*fd), None, None) },
+        // There is no vhost-user frontend when replaying a recording.
+        _ if replay_file.is_some() => (None, None, None),
+        Some(fd) => unsafe { (Some(Listener::from_raw_fd(*fd)), None, None) },
         None => {
             // Set umask to ensure the socket is created with the right permissions
             let _umask_guard = oslib::ScopedUmask::new(umask);
@@ -1067,7 +1676,7 @@ fn main() {
                 process::exit(1);
             });
 
-            (listener, Some(socket.clone()), Some(pid_file))
+            (Some(listener), Some(socket.clone()), Some(pid_file))
         }
     };
 
@@ -1096,16 +1705,20 @@ fn main() {
         process::exit(1)
     });
 
-    let mut sandbox = Sandbox::new(
-        shared_dir.to_string(),
-        opt.sandbox,
-        opt.uid_map,
-        opt.gid_map,
-    )
-    .unwrap_or_else(|error| {
-        error!("Error creating sandbox: {}", error);
+    let uid_map = UidMap::resolve(opt.uid_map).unwrap_or_else(|error| {
+        error!("Invalid --uid-map: {}", error);
         process::exit(1)
     });
+    let gid_map = GidMap::resolve(opt.gid_map).unwrap_or_else(|error| {
+        error!("Invalid --gid-map: {}", error);
+        process::exit(1)
+    });
+
+    let mut sandbox = Sandbox::new(shared_dir.to_string(), opt.sandbox, uid_map, gid_map)
+        .unwrap_or_else(|error| {
+            error!("Error creating sandbox: {}", error);
+            process::exit(1)
+        });
 
     // Enter the sandbox, from this point the process will be isolated (or not)
     // as chosen in '--sandbox'.
@@ -1117,15 +1730,19 @@ fn main() {
     let fs_cfg = passthrough::Config {
         entry_timeout: timeout,
         attr_timeout: timeout,
+        negative_timeout,
         cache_policy: opt.cache,
         root_dir: sandbox.get_root_dir(),
         mountinfo_prefix: sandbox.get_mountinfo_prefix(),
         xattr,
+        xattr_cache_timeout: Duration::from_secs(opt.xattr_cache_timeout.unwrap_or(0)),
         xattrmap,
         proc_sfd_rawfd: sandbox.get_proc_self_fd(),
         proc_mountinfo_rawfd: sandbox.get_mountinfo_fd(),
         announce_submounts,
         inode_file_handles: opt.inode_file_handles.into(),
+        idle_handle_timeout: Duration::from_secs(opt.idle_handle_timeout.unwrap_or(0)),
+        read_hints,
         readdirplus,
         writeback: opt.writeback,
         allow_direct_io: opt.allow_direct_io,
@@ -1133,6 +1750,16 @@ fn main() {
         security_label: opt.security_label,
         posix_acl: opt.posix_acl,
         clean_noatime: !opt.preserve_noatime && !has_noatime_capability(),
+        squash: opt.squash,
+        anon_uid: opt.anon_uid,
+        anon_gid: opt.anon_gid,
+        uid_translation,
+        gid_translation,
+        override_stat: opt.override_stat,
+        present_uid: opt.present_uid,
+        present_gid: opt.present_gid,
+        file_mode_mask: opt.file_mode_mask,
+        dir_mode_mask: opt.dir_mode_mask,
         ..Default::default()
     };
 
@@ -1160,8 +1787,61 @@ fn main() {
         }
     };
 
+    let fs = match rules_file {
+        Some(rules_file) => {
+            FaultInjectionFs::with_rules_file(fs, rules_file).unwrap_or_else(|error| {
+                error!("Error reading fault injection rules: {}", error);
+                process::exit(1)
+            })
+        }
+        None => FaultInjectionFs::disabled(fs),
+    };
+
+    run(
+        fs,
+        listener,
+        replay_file,
+        thread_pool_size,
+        data_thread_pool_size,
+        opt.tag,
+        recorder,
+        opt.io_uring,
+        opt.max_in_flight,
+        init_limits,
+        stats_file,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run<F: FileSystem + Send + Sync + 'static>(
+    fs: F,
+    listener: Option<Listener>,
+    replay_file: Option<File>,
+    thread_pool_size: usize,
+    data_thread_pool_size: usize,
+    tag: Option<String>,
+    recorder: Option<Recorder>,
+    io_uring: bool,
+    max_in_flight: usize,
+    init_limits: InitLimits,
+    stats_file: Option<File>,
+) {
+    if let Some(replay_file) = replay_file {
+        replay(fs, replay_file, init_limits);
+    }
+
     let fs_backend = Arc::new(
-        VhostUserFsBackend::new(fs, thread_pool_size, opt.tag).unwrap_or_else(|error| {
+        VhostUserFsBackend::new(
+            fs,
+            thread_pool_size,
+            data_thread_pool_size,
+            tag,
+            recorder,
+            io_uring,
+            max_in_flight,
+            init_limits,
+        )
+        .unwrap_or_else(|error| {
             error!("Error creating vhost-user backend: {}", error);
             process::exit(1)
         }),
@@ -1174,9 +1854,47 @@ fn main() {
     )
     .unwrap();
 
+    if let Some(uring) = &fs_backend.thread.read().unwrap().uring {
+        // Only requests from the request queue are submitted to the ring, so the thread of that
+        // queue handles the completions.
+        if let Err(e) = daemon.get_epoll_handlers()[REQ_QUEUE].register_listener(
+            uring.as_raw_fd(),
+            EventSet::IN,
+            u64::from(URING_EVENT),
+        ) {
+            error!("Failed to register io_uring eventfd: {}", e);
+            process::exit(1);
+        }
+    }
+
+    fs_backend.thread.write().unwrap().stats_file = stats_file.map(Arc::new);
+
+    {
+        let thread = fs_backend.thread.read().unwrap();
+        let handler = &daemon.get_epoll_handlers()[REQ_QUEUE];
+        if let Err(e) = handler.register_listener(
+            thread.in_flight.resume_evt.as_raw_fd(),
+            EventSet::IN,
+            u64::from(RESUME_EVENT),
+        ) {
+            error!("Failed to register resume eventfd: {}", e);
+            process::exit(1);
+        }
+        if let Err(e) = handler.register_listener(
+            thread.stats_evt.as_raw_fd(),
+            EventSet::IN,
+            u64::from(STATS_EVENT),
+        ) {
+            error!("Failed to register statistics eventfd: {}", e);
+            process::exit(1);
+        }
+        STATS_EVENT_FD.store(thread.stats_evt.as_raw_fd(), Ordering::Relaxed);
+    }
+
     info!("Waiting for vhost-user socket connection...");
 
-    if let Err(e) = daemon.start(listener) {
+    // safe to unwrap because there is always a listener unless we are replaying
+    if let Err(e) = daemon.start(listener.unwrap()) {
         error!("Failed to start daemon: {:?}", e);
         process::exit(1);
     }
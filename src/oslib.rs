@@ -21,6 +21,7 @@ fn check_retval<T: From<i8> + PartialEq>(t: T) -> Result<T> {
 /// such as available syscalls.
 pub struct OsFacts {
     pub has_openat2: bool,
+    pub has_faccessat2: bool,
 }
 
 #[allow(clippy::new_without_default)]
@@ -52,10 +53,48 @@ impl OsFacts {
             }
         }
 
-        Self { has_openat2 }
+        // Checking for `faccessat2()` since it first appeared in Linux 5.8.
+        // SAFETY: `cwd.as_ptr()` points to a valid NUL-terminated string, and this call doesn't
+        // modify any memory.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_faccessat2,
+                libc::AT_FDCWD,
+                cwd.as_ptr(),
+                libc::F_OK,
+                libc::AT_EACCESS,
+            )
+        };
+        let has_faccessat2 =
+            ret == 0 || Error::last_os_error().raw_os_error() != Some(libc::ENOSYS);
+
+        Self {
+            has_openat2,
+            has_faccessat2,
+        }
     }
 }
 
+/// Safe wrapper for `faccessat2(2)`, checking with the effective ids whether `path` relative to
+/// `dirfd` can be accessed with `mode`
+///
+/// # Errors
+///
+/// Will return `Err(errno)` if `faccessat2(2)` fails, e.g. `EACCES` if the access is denied.
+pub fn faccessat2(dirfd: RawFd, path: &CStr, mode: i32) -> Result<()> {
+    // SAFETY: `path` is a valid C string, and this call doesn't modify any memory.
+    check_retval(unsafe {
+        libc::syscall(
+            libc::SYS_faccessat2,
+            dirfd,
+            path.as_ptr(),
+            mode,
+            libc::AT_EACCESS,
+        )
+    })?;
+    Ok(())
+}
+
 /// Safe wrapper for `mount(2)`
 ///
 /// # Errors
@@ -113,6 +152,114 @@ pub fn fchdir(fd: RawFd) -> Result<()> {
     Ok(())
 }
 
+// These are not in libc yet, see `linux/mount.h`.
+const MOUNT_ATTR_IDMAP: u64 = 0x0010_0000;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x0000_0004;
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// Safe wrapper for `open_tree(2)`, returning a detached clone of the mount tree at `path`
+///
+/// # Errors
+///
+/// Will return `Err(errno)` if `open_tree(2)` fails.
+///
+/// # Panics
+///
+/// This function panics if the string `path` contains an internal 0 byte.
+pub fn open_tree_clone(path: &str) -> Result<File> {
+    let path = CString::new(path).unwrap();
+    let flags = libc::OPEN_TREE_CLONE | libc::OPEN_TREE_CLOEXEC | libc::AT_RECURSIVE as u32;
+
+    // Safety: `path` is a valid C string pointer, and we check the return value.
+    let fd = check_retval(unsafe {
+        libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, path.as_ptr(), flags)
+    })?;
+    // Safety: we just opened this fd.
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+/// Safe wrapper for `mount_setattr(2)`, idmapping the mount tree `mount` with the user
+/// namespace `userns`
+///
+/// # Errors
+///
+/// Will return `Err(errno)` if `mount_setattr(2)` fails, e.g. because a file system in the tree
+/// does not support idmapped mounts.
+pub fn mount_setattr_idmap(mount: &impl AsRawFd, userns: &impl AsRawFd) -> Result<()> {
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns.as_raw_fd() as u64,
+    };
+    let empty = CString::new("").unwrap();
+
+    // Safety: `empty` is a valid C string pointer, `attr` is a valid `mount_attr` of the size we
+    // pass, and this call doesn't modify any memory.
+    check_retval(unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            mount.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_RECURSIVE,
+            &attr as *const MountAttr,
+            std::mem::size_of::<MountAttr>(),
+        )
+    })?;
+    Ok(())
+}
+
+/// Safe wrapper for `move_mount(2)`, attaching the detached mount tree `mount` at `target`
+///
+/// # Errors
+///
+/// Will return `Err(errno)` if `move_mount(2)` fails.
+///
+/// # Panics
+///
+/// This function panics if the string `target` contains an internal 0 byte.
+pub fn move_mount(mount: &impl AsRawFd, target: &str) -> Result<()> {
+    let empty = CString::new("").unwrap();
+    let target = CString::new(target).unwrap();
+
+    // Safety: `empty` and `target` are valid C string pointers, and this call doesn't modify any
+    // memory.
+    check_retval(unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            mount.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_FDCWD,
+            target.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    })?;
+    Ok(())
+}
+
+/// Safe wrapper for `posix_fadvise(2)`
+///
+/// # Errors
+///
+/// Will return `Err(errno)` if `posix_fadvise(2)` fails, see `posix_fadvise(2)` for details.
+pub fn fadvise(fd: &impl AsRawFd, offset: i64, len: i64, advice: i32) -> Result<()> {
+    // SAFETY: this call doesn't modify any memory.
+    let ret = unsafe { libc::posix_fadvise(fd.as_raw_fd(), offset, len, advice) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        // Unlike most calls, posix_fadvise() returns the error number instead of setting errno.
+        Err(Error::from_raw_os_error(ret))
+    }
+}
+
 /// Safe wrapper for `umask(2)`
 pub fn umask(mask: u32) -> u32 {
     // SAFETY: this call doesn't modify any memory and there is no need
@@ -165,10 +312,10 @@ pub fn openat(dir: &impl AsRawFd, pathname: &CStr, flags: i32, mode: Option<u32>
 /// An utility function that uses `openat2(2)` to restrict the how the provided pathname
 /// is resolved. It uses the following flags:
 /// - `RESOLVE_IN_ROOT`: Treat the directory referred to by dirfd as the root directory while
-/// resolving pathname. This has the effect as though virtiofsd had used chroot(2) to modify its
-/// root directory to dirfd.
+///   resolving pathname. This has the effect as though virtiofsd had used chroot(2) to modify its
+///   root directory to dirfd.
 /// - `RESOLVE_NO_MAGICLINKS`: Disallow all magic-link (i.e., proc(2) link-like files) resolution
-/// during path resolution.
+///   during path resolution.
 ///
 /// Additionally, the flags `O_NOFOLLOW` and `O_CLOEXEC` are added.
 ///
@@ -290,7 +437,6 @@ pub fn open_by_handle_at(
 mod writev {
     /// musl does not provide a wrapper for the `pwritev2(2)` system call,
     /// we need to call it using `syscall(2)`.
-
     #[cfg(target_env = "gnu")]
     pub use libc::pwritev2;
 
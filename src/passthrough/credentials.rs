@@ -3,6 +3,71 @@
 use crate::oslib;
 use crate::passthrough::util::einval;
 use std::io;
+use std::str::FromStr;
+
+/// Which requests are performed with the credentials of an anonymous user instead of their own,
+/// like the NFS export options `root_squash` and `all_squash`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Squash {
+    /// Requests keep their credentials.
+    #[default]
+    None,
+    /// Requests from uid 0 are squashed, and gid 0 is squashed in all requests.
+    Root,
+    /// All requests are squashed.
+    All,
+}
+
+impl Squash {
+    /// Returns whether requests from `uid` are squashed, i.e. are not allowed to act as `uid`.
+    pub fn applies_to(self, uid: libc::uid_t) -> bool {
+        match self {
+            Squash::None => false,
+            Squash::Root => uid == 0,
+            Squash::All => true,
+        }
+    }
+
+    /// Returns the uid to use instead of `uid`, for requests as well as for file owners.
+    pub fn uid(self, uid: libc::uid_t, anon_uid: libc::uid_t) -> libc::uid_t {
+        if self.applies_to(uid) {
+            anon_uid
+        } else {
+            uid
+        }
+    }
+
+    /// Returns the gid to use instead of `gid`, for requests as well as for file owners.
+    pub fn gid(self, gid: libc::gid_t, anon_gid: libc::gid_t) -> libc::gid_t {
+        if self.applies_to_gid(gid) {
+            anon_gid
+        } else {
+            gid
+        }
+    }
+
+    /// Returns whether `gid` is replaced by the anonymous gid.
+    pub fn applies_to_gid(self, gid: libc::gid_t) -> bool {
+        match self {
+            Squash::None => false,
+            Squash::Root => gid == 0,
+            Squash::All => true,
+        }
+    }
+}
+
+impl FromStr for Squash {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Squash::None),
+            "root" => Ok(Squash::Root),
+            "all" => Ok(Squash::All),
+            _ => Err("invalid squash mode"),
+        }
+    }
+}
 
 pub struct UnixCredentials {
     uid: libc::uid_t,
@@ -33,25 +98,45 @@ impl UnixCredentials {
         }
     }
 
+    /// Never keep the DAC_OVERRIDE capability, even if a supplementary group may be missing.
+    /// For permission checks, which would otherwise always succeed.
+    pub fn without_capability(self) -> Self {
+        UnixCredentials {
+            keep_capability: false,
+            ..self
+        }
+    }
+
     /// Changes the effective uid/gid of the current thread to `val`.  Changes
-    /// the thread's credentials back to root when the returned struct is dropped.
+    /// the thread's credentials back to root when the returned struct is dropped,
+    /// or right away if changing them fails half-way.
     pub fn set(self) -> io::Result<Option<UnixCredentialsGuard>> {
         let change_uid = self.uid != 0;
         let change_gid = self.gid != 0;
 
+        // Whatever has been changed when we return early is changed back when this is dropped.
+        let mut guard = UnixCredentialsGuard {
+            reset_uid: false,
+            reset_gid: false,
+            drop_sup_gid: false,
+        };
+
         // We have to change the gid before we change the uid because if we
         // change the uid first then we lose the capability to change the gid.
         // However changing back can happen in any order.
         if let Some(sup_gid) = self.sup_gid {
             oslib::setsupgroup(sup_gid)?;
+            guard.drop_sup_gid = true;
         }
 
         if change_gid {
             oslib::seteffgid(self.gid)?;
+            guard.reset_gid = true;
         }
 
         if change_uid {
             oslib::seteffuid(self.uid)?;
+            guard.reset_uid = true;
         }
 
         if change_uid && self.keep_capability {
@@ -66,15 +151,11 @@ impl UnixCredentials {
             }
         }
 
-        if !change_uid && !change_gid {
+        if !change_uid && !change_gid && self.sup_gid.is_none() {
             return Ok(None);
         }
 
-        Ok(Some(UnixCredentialsGuard {
-            reset_uid: change_uid,
-            reset_gid: change_gid,
-            drop_sup_gid: self.sup_gid.is_some(),
-        }))
+        Ok(Some(guard))
     }
 }
 
@@ -108,20 +189,26 @@ impl Drop for UnixCredentialsGuard {
 
 pub struct ScopedCaps {
     cap: capng::Capability,
+    // Whether the capability was raised rather than dropped, so it has to be dropped again.
+    raised: bool,
+}
+
+fn capability(cap_name: &str) -> io::Result<capng::Capability> {
+    capng::name_to_capability(cap_name).map_err(|_| {
+        let err = io::Error::last_os_error();
+        error!(
+            "couldn't get the capability id for name {}: {:?}",
+            cap_name, err
+        );
+        err
+    })
 }
 
 impl ScopedCaps {
     fn new(cap_name: &str) -> io::Result<Option<Self>> {
         use capng::{Action, CUpdate, Set, Type};
 
-        let cap = capng::name_to_capability(cap_name).map_err(|_| {
-            let err = io::Error::last_os_error();
-            error!(
-                "couldn't get the capability id for name {}: {:?}",
-                cap_name, err
-            );
-            err
-        })?;
+        let cap = capability(cap_name)?;
 
         if capng::have_capability(Type::EFFECTIVE, cap) {
             let req = vec![CUpdate {
@@ -140,11 +227,37 @@ impl ScopedCaps {
                 );
                 einval()
             })?;
-            Ok(Some(Self { cap }))
+            Ok(Some(Self { cap, raised: false }))
         } else {
             Ok(None)
         }
     }
+
+    fn raise(cap_name: &str) -> io::Result<Option<Self>> {
+        use capng::{Action, CUpdate, Set, Type};
+
+        let cap = capability(cap_name)?;
+
+        if capng::have_capability(Type::EFFECTIVE, cap) {
+            return Ok(None);
+        }
+
+        let req = vec![CUpdate {
+            action: Action::ADD,
+            cap_type: Type::EFFECTIVE,
+            capability: cap,
+        }];
+        capng::update(req).map_err(|e| {
+            error!("couldn't raise {} capability: {:?}", cap, e);
+            einval()
+        })?;
+        capng::apply(Set::CAPS).map_err(|e| {
+            error!("couldn't apply capabilities after raising {}: {:?}", cap, e);
+            // The capability is not in the permitted set.
+            io::Error::from_raw_os_error(libc::EPERM)
+        })?;
+        Ok(Some(Self { cap, raised: true }))
+    }
 }
 
 impl Drop for ScopedCaps {
@@ -152,7 +265,11 @@ impl Drop for ScopedCaps {
         use capng::{Action, CUpdate, Set, Type};
 
         let req = vec![CUpdate {
-            action: Action::ADD,
+            action: if self.raised {
+                Action::DROP
+            } else {
+                Action::ADD
+            },
             cap_type: Type::EFFECTIVE,
             capability: self.cap,
         }];
@@ -172,3 +289,9 @@ impl Drop for ScopedCaps {
 pub fn drop_effective_cap(cap_name: &str) -> io::Result<Option<ScopedCaps>> {
     ScopedCaps::new(cap_name)
 }
+
+/// Adds `cap_name` to the effective set, if it is not in there yet, until the returned guard is
+/// dropped.  The capability has to be in the permitted set.
+pub fn raise_effective_cap(cap_name: &str) -> io::Result<Option<ScopedCaps>> {
+    ScopedCaps::raise(cap_name)
+}
@@ -8,11 +8,15 @@ use std::collections::BTreeMap;
 use std::fs::File;
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::atomic::AtomicU64;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
 pub type Inode = u64;
 
+/// Number of shards the inode store is split into.  Each shard has its own lock, so operations on
+/// inodes in different shards do not contend with each other.
+const SHARDS: usize = 64;
+
 #[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
 pub struct InodeIds {
     pub ino: libc::ino64_t,
@@ -26,7 +30,7 @@ pub struct InodeData {
     pub file_or_handle: FileOrHandle,
     pub refcount: AtomicU64,
 
-    // Used as key in the `InodeStore::by_ids` map.
+    // Used as key in the `Indexes::by_ids` map.
     pub ids: InodeIds,
 
     // File type and mode
@@ -45,11 +49,22 @@ pub enum InodeFile<'inode_lifetime> {
     Ref(&'inode_lifetime File),
 }
 
-#[derive(Default)]
+/// All inodes known to the guest.  Inodes are stored in shards by their number, and can also be
+/// found by their `InodeIds` or `FileHandle` through secondary indexes.  The indexes are sharded
+/// by `InodeIds`, so that both index entries for an inode are in the same shard, and are only ever
+/// changed with that shard locked for writing.  When locking both an index shard and an inode
+/// shard, the index shard must be locked first.
 pub struct InodeStore {
-    data: BTreeMap<Inode, Arc<InodeData>>,
-    by_ids: BTreeMap<InodeIds, Inode>,
-    by_handle: BTreeMap<FileHandle, Inode>,
+    data: Vec<RwLock<BTreeMap<Inode, Arc<InodeData>>>>,
+    indexes: Vec<RwLock<Indexes>>,
+    // Number of inodes in the store that hold an `O_PATH` fd.
+    files: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Indexes {
+    by_ids: BTreeMap<InodeIds, Arc<InodeData>>,
+    by_handle: BTreeMap<FileHandle, Arc<InodeData>>,
 }
 
 impl<'a> InodeData {
@@ -109,50 +124,265 @@ impl AsRawFd for InodeFile<'_> {
     }
 }
 
-impl InodeStore {
-    pub fn insert(&mut self, data: Arc<InodeData>) {
-        self.by_ids.insert(data.ids, data.inode);
+impl Default for InodeStore {
+    fn default() -> Self {
+        InodeStore {
+            data: (0..SHARDS).map(|_| Default::default()).collect(),
+            indexes: (0..SHARDS).map(|_| Default::default()).collect(),
+            files: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Indexes {
+    fn insert(&mut self, data: &Arc<InodeData>) {
+        self.by_ids.insert(data.ids, data.clone());
         if let FileOrHandle::Handle(handle) = &data.file_or_handle {
-            self.by_handle.insert(handle.inner().clone(), data.inode);
+            self.by_handle.insert(handle.inner().clone(), data.clone());
         }
-        self.data.insert(data.inode, data);
     }
 
-    pub fn remove(&mut self, inode: &Inode) -> Option<Arc<InodeData>> {
-        let data = self.data.remove(inode);
-        if let Some(data) = data.as_ref() {
-            if let FileOrHandle::Handle(handle) = &data.file_or_handle {
+    fn remove(&mut self, data: &Arc<InodeData>) {
+        // The entries may belong to a newer inode with the same IDs or handle by now.
+        if self
+            .by_ids
+            .get(&data.ids)
+            .is_some_and(|d| Arc::ptr_eq(d, data))
+        {
+            self.by_ids.remove(&data.ids);
+        }
+        if let FileOrHandle::Handle(handle) = &data.file_or_handle {
+            if self
+                .by_handle
+                .get(handle.inner())
+                .is_some_and(|d| Arc::ptr_eq(d, data))
+            {
                 self.by_handle.remove(handle.inner());
             }
-            self.by_ids.remove(&data.ids);
         }
-        data
     }
 
-    pub fn clear(&mut self) {
-        self.data.clear();
-        self.by_handle.clear();
-        self.by_ids.clear();
+    /// Attempts to find an inode and increment its refcount.  Returns the inode number on
+    /// success and `None` on failure.  Reasons for failure can be that the inode isn't in the
+    /// indexes or that the refcount is zero.  This function will never increment a refcount
+    /// that's already zero.
+    fn claim(&self, handle: Option<&FileHandle>, ids: &InodeIds) -> Option<Inode> {
+        let data = handle.and_then(|h| self.by_handle.get(h)).or_else(|| {
+            self.by_ids.get(ids).filter(|data| {
+                // When we have to fall back to looking up an inode by its inode ID, ensure
+                // that we hit an entry that has a valid file descriptor.  Having an FD
+                // open means that the inode cannot really be deleted until the FD is
+                // closed, so that the inode ID remains valid until we evict the
+                // `InodeData`.  With no FD open (and just a file handle), the inode can be
+                // deleted while we still have our `InodeData`, and so the inode ID may be
+                // reused by a completely different new inode.  Such inodes must be looked
+                // up by file handle, because this handle contains a generation ID to
+                // differentiate between the old and the new inode.
+                matches!(data.file_or_handle, FileOrHandle::File(_))
+            })
+        })?;
+
+        // We use a CAS loop instead of `fetch_add()`, because we must never increment the
+        // refcount from zero to one.
+        let mut n = data.refcount.load(Ordering::Relaxed);
+        loop {
+            if n == 0 {
+                return None;
+            }
+
+            match data.refcount.compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(data.inode),
+                Err(old) => n = old,
+            }
+        }
+    }
+}
+
+impl InodeStore {
+    fn data_shard(&self, inode: Inode) -> &RwLock<BTreeMap<Inode, Arc<InodeData>>> {
+        &self.data[inode as usize % SHARDS]
+    }
+
+    fn index_shard(&self, ids: &InodeIds) -> &RwLock<Indexes> {
+        &self.indexes[ids.ino as usize % SHARDS]
+    }
+
+    fn count_file(&self, data: &InodeData, added: bool) {
+        if matches!(data.file_or_handle, FileOrHandle::File(_)) {
+            if added {
+                self.files.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.files.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn insert(&self, data: Arc<InodeData>) {
+        let mut indexes = self.index_shard(&data.ids).write().unwrap();
+        indexes.insert(&data);
+        self.count_file(&data, true);
+        let old = self
+            .data_shard(data.inode)
+            .write()
+            .unwrap()
+            .insert(data.inode, data);
+        if let Some(old) = old {
+            self.count_file(&old, false);
+        }
     }
 
-    pub fn get(&self, inode: &Inode) -> Option<&Arc<InodeData>> {
-        self.data.get(inode)
+    /// Looks up an existing inode by `handle` or `ids` like `claim()`, and if there is none,
+    /// inserts the one returned by `new_data`.  Returns the inode number.
+    pub fn claim_or_insert<F>(
+        &self,
+        handle: Option<&FileHandle>,
+        ids: &InodeIds,
+        new_data: F,
+    ) -> Inode
+    where
+        F: FnOnce() -> InodeData,
+    {
+        let mut indexes = self.index_shard(ids).write().unwrap();
+        if let Some(inode) = indexes.claim(handle, ids) {
+            return inode;
+        }
+
+        let data = Arc::new(new_data());
+        indexes.insert(&data);
+        self.count_file(&data, true);
+        let inode = data.inode;
+        self.data_shard(inode).write().unwrap().insert(inode, data);
+        inode
     }
 
-    pub fn get_by_ids(&self, ids: &InodeIds) -> Option<&Arc<InodeData>> {
-        self.inode_by_ids(ids).map(|inode| self.get(inode).unwrap())
+    /// Attempts to find an inode by `handle` or `ids` and increment its refcount.  See
+    /// `Indexes::claim()`.
+    pub fn claim(&self, handle: Option<&FileHandle>, ids: &InodeIds) -> Option<Inode> {
+        self.index_shard(ids).read().unwrap().claim(handle, ids)
     }
 
-    pub fn get_by_handle(&self, handle: &FileHandle) -> Option<&Arc<InodeData>> {
-        self.inode_by_handle(handle)
-            .map(|inode| self.get(inode).unwrap())
+    /// Decrements the refcount of `inode` by `count`, and removes it once that reaches zero.
+    pub fn forget(&self, inode: Inode, count: u64) {
+        let ids = match self.get(&inode) {
+            Some(data) => data.ids,
+            None => return,
+        };
+
+        // Acquiring the write lock on the index shard prevents new lookups from incrementing the
+        // refcount.
+        let mut indexes = self.index_shard(&ids).write().unwrap();
+
+        // Look the inode up again, its data may have been replaced in the meantime (see
+        // `replace_file_or_handle()`), and only the current refcount counts.
+        let data = match self.get(&inode) {
+            Some(data) => data,
+            None => return,
+        };
+        loop {
+            let refcount = data.refcount.load(Ordering::Relaxed);
+
+            // Saturating sub because it doesn't make sense for a refcount to go below zero and
+            // we don't want misbehaving clients to cause integer overflow.
+            let new_count = refcount.saturating_sub(count);
+
+            // We don't need any stronger ordering, because the refcount itself doesn't protect any
+            // data.  The maps are protected by their locks.
+            if data.refcount.compare_exchange(
+                refcount,
+                new_count,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) == Ok(refcount)
+            {
+                if new_count == 0 {
+                    // We just removed the last refcount for this inode.  Any thread that is
+                    // waiting to do a forget on the same inode will have to wait until we release
+                    // the lock on the index shard, and will then find the refcount to be zero.
+                    indexes.remove(&data);
+                    let mut shard = self.data_shard(inode).write().unwrap();
+                    if shard.get(&inode).is_some_and(|d| Arc::ptr_eq(d, &data)) {
+                        shard.remove(&inode);
+                        self.count_file(&data, false);
+                    }
+                }
+                break;
+            }
+        }
     }
 
-    pub fn inode_by_ids(&self, ids: &InodeIds) -> Option<&Inode> {
-        self.by_ids.get(ids)
+    pub fn clear(&self) {
+        for shard in &self.indexes {
+            *shard.write().unwrap() = Default::default();
+        }
+        for shard in &self.data {
+            shard.write().unwrap().clear();
+        }
+        self.files.store(0, Ordering::Relaxed);
+    }
+
+    /// Replaces the file or handle of the inode `old` refers to, keeping its number and refcount.
+    /// Threads that already hold a reference to `old` can continue to use it, its file is closed
+    /// once the last of them drops it.  Returns `false` if `old` is no longer in the store.
+    pub fn replace_file_or_handle(
+        &self,
+        old: &Arc<InodeData>,
+        file_or_handle: FileOrHandle,
+    ) -> bool {
+        // Holding the index shard lock keeps lookups and forgets from changing the refcount.
+        let mut indexes = self.index_shard(&old.ids).write().unwrap();
+        let mut shard = self.data_shard(old.inode).write().unwrap();
+        if !shard.get(&old.inode).is_some_and(|d| Arc::ptr_eq(d, old)) {
+            return false;
+        }
+
+        let new = Arc::new(InodeData {
+            inode: old.inode,
+            file_or_handle,
+            refcount: AtomicU64::new(old.refcount.load(Ordering::Relaxed)),
+            ids: old.ids,
+            mode: old.mode,
+        });
+        indexes.remove(old);
+        indexes.insert(&new);
+        self.count_file(old, false);
+        self.count_file(&new, true);
+        shard.insert(new.inode, new);
+        true
+    }
+
+    /// Returns the number of inodes that hold an `O_PATH` fd.
+    pub fn file_count(&self) -> usize {
+        self.files.load(Ordering::Relaxed)
+    }
+
+    /// Calls `f` for each inode that holds an `O_PATH` fd, one shard at a time, until it returns
+    /// `false`.  The shard is not locked while `f` runs, so `f` may modify the store.
+    pub fn for_each_file<F>(&self, mut f: F)
+    where
+        F: FnMut(Arc<InodeData>) -> bool,
+    {
+        for shard in &self.data {
+            let files: Vec<Arc<InodeData>> = shard
+                .read()
+                .unwrap()
+                .values()
+                .filter(|d| matches!(d.file_or_handle, FileOrHandle::File(_)))
+                .cloned()
+                .collect();
+            for data in files {
+                if !f(data) {
+                    return;
+                }
+            }
+        }
     }
 
-    pub fn inode_by_handle(&self, handle: &FileHandle) -> Option<&Inode> {
-        self.by_handle.get(handle)
+    pub fn get(&self, inode: &Inode) -> Option<Arc<InodeData>> {
+        self.data_shard(*inode).read().unwrap().get(inode).cloned()
     }
 }
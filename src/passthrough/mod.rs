@@ -6,22 +6,32 @@ pub mod credentials;
 pub mod file_handle;
 pub mod inode_store;
 pub mod mount_fd;
+pub mod override_stat;
+pub mod posix_acl;
+pub mod read_hints;
 pub mod stat;
 pub mod util;
+pub mod xattr_cache;
 pub mod xattrmap;
 
 use super::fs_cache_req_handler::FsCacheReqHandler;
 use crate::filesystem::{
-    Context, Entry, Extensions, FileSystem, FsOptions, GetxattrReply, ListxattrReply, OpenOptions,
-    SecContext, SetattrValid, SetxattrFlags, ZeroCopyReader, ZeroCopyWriter,
+    AsyncIoOp, AsyncIoTarget, Context, Entry, Extensions, FileSystem, FsOptions, GetxattrReply,
+    ListxattrReply, OpenOptions, SecContext, SetattrValid, SetxattrFlags, ZeroCopyReader,
+    ZeroCopyWriter,
+};
+use crate::idmap::IdTranslation;
+use crate::passthrough::credentials::{
+    drop_effective_cap, raise_effective_cap, Squash, UnixCredentials,
 };
-use crate::passthrough::credentials::{drop_effective_cap, UnixCredentials};
 use crate::passthrough::inode_store::{Inode, InodeData, InodeFile, InodeIds, InodeStore};
-use crate::passthrough::util::{ebadf, is_safe_inode, openat, reopen_fd_through_proc};
+use crate::passthrough::util::{ebadf, einval, is_safe_inode, openat, reopen_fd_through_proc};
 use crate::read_dir::ReadDir;
 use crate::{fuse, oslib};
 use file_handle::{FileHandle, FileOrHandle, OpenableFileHandle};
 use mount_fd::{MPRError, MountFds};
+use override_stat::OverrideStat;
+use read_hints::{ReadHints, ReadPattern};
 use stat::{statx, StatExt};
 use std::borrow::Cow;
 use std::collections::{btree_map, BTreeMap};
@@ -30,11 +40,13 @@ use std::fs::File;
 use std::io;
 use std::io::ErrorKind;
 use std::mem::MaybeUninit;
+use std::ops::Deref;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use xattr_cache::{CachedXattr, XattrCache};
 use xattrmap::{AppliedRule, XattrMap};
 
 const EMPTY_CSTR: &[u8] = b"\0";
@@ -43,7 +55,188 @@ type Handle = u64;
 
 struct HandleData {
     inode: Inode,
-    file: RwLock<File>,
+    // The flags the file was opened with, so it can be reopened after having been closed.
+    flags: i32,
+    // `None` while the file is closed for being idle, see `PassthroughFs::close_idle_handles()`.
+    file: RwLock<Option<File>>,
+    // A separate file to read from, if `file` could only be opened write-only, see
+    // `PassthroughFs::open_handle()`.  Closed and reopened along with `file`.
+    reader: RwLock<Option<File>>,
+    // Whether the handle has been used since the last time we looked for idle handles.
+    used: AtomicBool,
+    // Only used with `Config::read_hints`.
+    read_pattern: Mutex<ReadPattern>,
+}
+
+impl HandleData {
+    fn new(inode: Inode, flags: i32, file: File, reader: Option<File>) -> Self {
+        HandleData {
+            inode,
+            flags,
+            file: RwLock::new(Some(file)),
+            reader: RwLock::new(reader),
+            used: AtomicBool::new(true),
+            read_pattern: Default::default(),
+        }
+    }
+
+    /// Locks the file for reading.  Only use this on handles returned by
+    /// `PassthroughFs::find_handle()`, which makes sure the file is open.
+    fn read_file(&self) -> OpenFile<RwLockReadGuard<'_, Option<File>>> {
+        OpenFile(self.file.read().unwrap())
+    }
+
+    /// Locks the file for writing, see `read_file()`.
+    fn write_file(&self) -> OpenFile<RwLockWriteGuard<'_, Option<File>>> {
+        OpenFile(self.file.write().unwrap())
+    }
+
+    /// Locks the file to read data from, i.e. `reader` if there is one, and `file` otherwise.
+    /// See `read_file()`.
+    fn reader(&self) -> OpenFile<RwLockReadGuard<'_, Option<File>>> {
+        let reader = self.reader.read().unwrap();
+        if reader.is_some() {
+            OpenFile(reader)
+        } else {
+            self.read_file()
+        }
+    }
+}
+
+/// Gives out the file to read data from of a handle, see `HandleData::reader()`.
+struct HandleReader(Arc<HandleData>);
+
+impl AsRawFd for HandleReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.reader().as_raw_fd()
+    }
+}
+
+/// A locked `HandleData::file` that is known to be open.
+struct OpenFile<G>(G);
+
+impl<G: Deref<Target = Option<File>>> Deref for OpenFile<G> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        self.0
+            .as_ref()
+            .expect("idle handle used without reopening it")
+    }
+}
+
+/// Number of shards the handle store is split into, see `InodeStore` for the rationale.
+const HANDLE_SHARDS: usize = 64;
+
+/// The open files and directories, sharded by handle.
+struct HandleStore {
+    shards: Vec<RwLock<BTreeMap<Handle, Arc<HandleData>>>>,
+}
+
+impl Default for HandleStore {
+    fn default() -> Self {
+        HandleStore {
+            shards: (0..HANDLE_SHARDS).map(|_| Default::default()).collect(),
+        }
+    }
+}
+
+impl HandleStore {
+    fn shard(&self, handle: Handle) -> &RwLock<BTreeMap<Handle, Arc<HandleData>>> {
+        &self.shards[handle as usize % HANDLE_SHARDS]
+    }
+
+    fn insert(&self, handle: Handle, data: Arc<HandleData>) {
+        self.shard(handle).write().unwrap().insert(handle, data);
+    }
+
+    /// Returns the data for `handle`, if it is a handle for `inode`.
+    fn get(&self, handle: Handle, inode: Inode) -> Option<Arc<HandleData>> {
+        self.shard(handle)
+            .read()
+            .unwrap()
+            .get(&handle)
+            .filter(|hd| hd.inode == inode)
+            .cloned()
+    }
+
+    /// Removes `handle` if it is a handle for `inode`, and returns whether it did.
+    fn remove(&self, handle: Handle, inode: Inode) -> bool {
+        let mut shard = self.shard(handle).write().unwrap();
+        if let btree_map::Entry::Occupied(e) = shard.entry(handle) {
+            if e.get().inode == inode {
+                e.remove();
+                return true;
+            }
+        }
+        false
+    }
+
+    fn clear(&self) {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
+        }
+    }
+
+    /// Closes the files of all handles that have not been used since the last call and are not
+    /// in use right now.  They are reopened by `PassthroughFs::find_handle()`.  Returns the number
+    /// of files closed.
+    ///
+    /// Note that we never take locks on behalf of the guest (it handles them locally, as we do
+    /// not implement GETLK/SETLK), so closing a file cannot drop any.
+    fn close_idle(&self) -> usize {
+        // Only pick the candidates while holding the shard locks, checking whether their files
+        // have been deleted takes a syscall each.
+        let mut candidates = Vec::new();
+        for shard in &self.shards {
+            for (handle, data) in shard.read().unwrap().iter() {
+                // Handles that are in use right now are skipped without resetting `used`.
+                if Arc::strong_count(data) == 1 && !data.used.swap(false, Ordering::Relaxed) {
+                    candidates.push((*handle, data.clone()));
+                }
+            }
+        }
+
+        let mut closed = 0;
+        for (handle, data) in candidates {
+            // A deleted file may not be reopened (e.g. by file handle), so keep it open.
+            let deleted = match data.file.read().unwrap().as_ref() {
+                Some(f) => statx(f, None).map_or(true, |st| st.st.st_nlink == 0),
+                None => continue,
+            };
+            if deleted {
+                continue;
+            }
+
+            let (file, reader) = {
+                // Holding the shard lock for writing keeps others from getting a new reference, so
+                // if we have the only one besides the store's, the handle is still not in use.
+                #[allow(clippy::readonly_write_lock)]
+                let shard = self.shard(handle).write().unwrap();
+                if !shard.get(&handle).is_some_and(|d| Arc::ptr_eq(d, &data))
+                    || Arc::strong_count(&data) != 2
+                    || data.used.load(Ordering::Relaxed)
+                {
+                    continue;
+                }
+                let file = data.file.write().unwrap().take();
+                let reader = data.reader.write().unwrap().take();
+                (file, reader)
+            };
+            // Close the files outside of the lock.
+            if file.is_some() {
+                closed += 1;
+            }
+            drop((file, reader));
+        }
+        closed
+    }
+}
+
+impl AsRawFd for HandleData {
+    fn as_raw_fd(&self) -> RawFd {
+        self.read_file().as_raw_fd()
+    }
 }
 
 struct ScopedWorkingDirectory {
@@ -125,6 +318,12 @@ pub enum InodeFileHandlesMode {
 
     /// Always use file handles, never fall back to `O_PATH` file descriptors.
     Mandatory,
+
+    /// Use `O_PATH` file descriptors while there are few of them, and switch inodes over to file
+    /// handles (where the underlying filesystem supports them) once their number crosses
+    /// `Config::adaptive_fd_threshold`.  Inodes switch back to `O_PATH` file descriptors when they
+    /// are looked up again after the number has dropped.
+    Adaptive,
 }
 
 /// Options that configure the behavior of the file system.
@@ -144,6 +343,14 @@ pub struct Config {
     /// The default value for this option is 5 seconds.
     pub attr_timeout: Duration,
 
+    /// How long the FUSE client should remember that a name does not exist in a directory. If this
+    /// is not zero, a lookup that fails with `ENOENT` is replied to with a negative entry, so the
+    /// client does not send the same lookup again during this time. Like `entry_timeout`, this
+    /// should only be a large value if the file system has exclusive access.
+    ///
+    /// The default value for this option is 0, which disables negative entries.
+    pub negative_timeout: Duration,
+
     /// The caching policy the file system should use. See the documentation of `CachePolicy` for
     /// more details.
     pub cache_policy: CachePolicy,
@@ -152,13 +359,15 @@ pub struct Config {
     /// allows the FUSE client to cache and coalesce multiple writes before sending them to the file
     /// system. However, enabling this option can increase the risk of data corruption if the file
     /// contents can change without the knowledge of the FUSE client (i.e., the server does **NOT**
-    /// have exclusive access). Additionally, the file system should have read access to all files
-    /// in the directory it is serving as the FUSE client may send read requests even for files
-    /// opened with `O_WRONLY`.
+    /// have exclusive access).
+    ///
+    /// The FUSE client may send read requests even for files opened with `O_WRONLY`, so these are
+    /// opened for reading as well.  Files that may be written but not read are additionally
+    /// opened read-only with `CAP_DAC_READ_SEARCH`, which therefore has to be in the permitted set
+    /// of capabilities.  Opening such files fails with `EPERM` otherwise.
     ///
-    /// Therefore callers should only enable this option when they can guarantee that: 1) the file
-    /// system has exclusive access to the directory and 2) the file system has read permissions for
-    /// all files in that directory.
+    /// Therefore callers should only enable this option when they can guarantee that the file
+    /// system has exclusive access to the directory.
     ///
     /// The default value for this option is `false`.
     pub writeback: bool,
@@ -180,6 +389,13 @@ pub struct Config {
     /// The default value for this options is `false`.
     pub xattr: bool,
 
+    /// How long the file system may cache extended attribute values, and the fact that an
+    /// attribute does not exist.  Changes made through the file system are always visible
+    /// immediately, while changes made by others may only become visible after this time.
+    ///
+    /// The default value for this option is 0, which disables the cache.
+    pub xattr_cache_timeout: Duration,
+
     /// An optional translation layer for host<->guest Extended Attribute (xattr) names.
     pub xattrmap: Option<XattrMap>,
 
@@ -231,6 +447,26 @@ pub struct Config {
     /// The default is `Never`.
     pub inode_file_handles: InodeFileHandlesMode,
 
+    /// The number of `O_PATH` file descriptors held for inodes above which the `Adaptive` mode of
+    /// `inode_file_handles` switches inodes to file handles.  0 means half of the `RLIMIT_NOFILE`
+    /// soft limit.
+    ///
+    /// The default is 0.
+    pub adaptive_fd_threshold: usize,
+
+    /// How long an open file may go unused before its file descriptor is closed, to be reopened
+    /// transparently the next time the file is used.  Files that have been deleted are never
+    /// closed this way.
+    ///
+    /// The default is 0, which keeps all open files open.
+    pub idle_handle_timeout: Duration,
+
+    /// Heuristics for telling the host how open files are read (sequentially or randomly), so
+    /// that it can adjust its readahead.  See the `read_hints` module.
+    ///
+    /// The default is `None`, which gives no hints.
+    pub read_hints: Option<ReadHints>,
+
     /// Whether the file system should support READDIRPLUS (READDIR+LOOKUP) operations.
     ///
     /// The default is `false`.
@@ -262,6 +498,67 @@ pub struct Config {
     /// If `clean_noatime` is true automatically clean up O_NOATIME flag to prevent potential
     /// permission errors.
     pub clean_noatime: bool,
+
+    /// Which requests are performed as `anon_uid`/`anon_gid` instead of the guest's credentials.
+    /// This also applies to the owners set with chown, and squashed requests cannot set the
+    /// setuid and setgid bits on files, so that an untrusted guest root cannot create root-owned
+    /// or setuid files on the host.
+    ///
+    /// The default is `Squash::None`.
+    pub squash: Squash,
+
+    /// The uid squashed requests are performed as.
+    ///
+    /// The default is 65534 (nobody).
+    pub anon_uid: libc::uid_t,
+
+    /// The gid squashed requests are performed as.
+    ///
+    /// The default is 65534 (nogroup).
+    pub anon_gid: libc::gid_t,
+
+    /// Translates the uids of the guest into host uids, without a user namespace.  It applies
+    /// to the credentials requests are performed with and to the owners set with chown, and
+    /// ownership reported back to the guest is translated in reverse.  `anon_uid` is a host uid
+    /// and is not translated.
+    ///
+    /// The default is `None`.
+    pub uid_translation: Option<IdTranslation>,
+
+    /// Like `uid_translation`, for gids.
+    ///
+    /// The default is `None`.
+    pub gid_translation: Option<IdTranslation>,
+
+    /// Whether to emulate ownership, modes and device nodes instead of applying them to the host
+    /// files, for daemons that are not privileged enough to do so.  Files are created by the
+    /// daemon, and what the guest asked for is stored in an extended attribute instead (see
+    /// `override_stat`), which is reported back to the guest and used for `access`.
+    ///
+    /// The default is `false`.
+    pub override_stat: bool,
+
+    /// The uid all files are reported to be owned by, whoever owns them on the host.  When
+    /// ownership is presented this way, requests are performed with the daemon's own credentials,
+    /// and chown cannot change it.
+    ///
+    /// The default is `None`.
+    pub present_uid: Option<libc::uid_t>,
+
+    /// Like `present_uid`, for gids.
+    ///
+    /// The default is `None`.
+    pub present_gid: Option<libc::gid_t>,
+
+    /// Permission bits to clear in the mode reported for files other than directories.
+    ///
+    /// The default is 0.
+    pub file_mode_mask: u32,
+
+    /// Permission bits to clear in the mode reported for directories.
+    ///
+    /// The default is 0.
+    pub dir_mode_mask: u32,
 }
 
 impl Default for Config {
@@ -269,23 +566,38 @@ impl Default for Config {
         Config {
             entry_timeout: Duration::from_secs(5),
             attr_timeout: Duration::from_secs(5),
+            negative_timeout: Duration::ZERO,
             cache_policy: Default::default(),
             writeback: false,
             root_dir: String::from("/"),
             mountinfo_prefix: None,
             xattr: false,
+            xattr_cache_timeout: Duration::ZERO,
             xattrmap: None,
             xattr_security_capability: None,
             proc_sfd_rawfd: None,
             proc_mountinfo_rawfd: None,
             announce_submounts: false,
             inode_file_handles: Default::default(),
+            adaptive_fd_threshold: 0,
+            idle_handle_timeout: Duration::ZERO,
+            read_hints: None,
             readdirplus: true,
             allow_direct_io: false,
             killpriv_v2: false,
             posix_acl: false,
             security_label: false,
             clean_noatime: true,
+            squash: Squash::None,
+            anon_uid: 65534,
+            anon_gid: 65534,
+            uid_translation: None,
+            gid_translation: None,
+            override_stat: false,
+            present_uid: None,
+            present_gid: None,
+            file_mode_mask: 0,
+            dir_mode_mask: 0,
         }
     }
 }
@@ -300,14 +612,29 @@ pub struct PassthroughFs {
     // the `O_PATH` option so they cannot be used for reading or writing any data. See the
     // documentation of the `O_PATH` flag in `open(2)` for more details on what one can and cannot
     // do with an fd opened with this flag.
-    inodes: RwLock<InodeStore>,
+    inodes: InodeStore,
     next_inode: AtomicU64,
 
     // File descriptors for open files and directories. Unlike the fds in `inodes`, these _can_ be
     // used for reading and writing data.
-    handles: RwLock<BTreeMap<Handle, Arc<HandleData>>>,
+    handles: HandleStore,
     next_handle: AtomicU64,
 
+    // Extended attributes of inodes, see `Config::xattr_cache_timeout`.
+    xattr_cache: XattrCache,
+
+    // In `InodeFileHandlesMode::Adaptive`, the number of inode `O_PATH` fds at which we start
+    // switching inodes to file handles, and the number at which we look for candidates again.
+    // The lock is held while switching.  Until the threshold has first been reached, no inode
+    // uses a file handle, so lookups need not generate one.
+    fd_threshold: usize,
+    fd_pressure_mark: AtomicUsize,
+    fd_pressure_lock: Mutex<()>,
+    fd_pressure_seen: AtomicBool,
+
+    // When we last looked for idle handles, see `close_idle_handles()`.
+    idle_handle_sweep: Mutex<Instant>,
+
     // Maps mount IDs to an open FD on the respective ID for the purpose of open_by_handle_at().
     // This is set when inode_file_handles is not never, since in the 'never' case,
     // open_by_handle_at() is not called.
@@ -375,11 +702,31 @@ impl PassthroughFs {
             Some(MountFds::new(mountinfo_fd, cfg.mountinfo_prefix.clone()))
         };
 
+        let fd_threshold = match cfg.adaptive_fd_threshold {
+            0 => {
+                let mut limit = MaybeUninit::<libc::rlimit>::zeroed();
+                // Safe because this only writes to `limit`, and we check the return value.
+                if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Safe because getrlimit() succeeded.
+                let limit = unsafe { limit.assume_init() };
+                (limit.rlim_cur / 2) as usize
+            }
+            threshold => threshold,
+        };
+
         let mut fs = PassthroughFs {
-            inodes: RwLock::new(Default::default()),
+            inodes: Default::default(),
             next_inode: AtomicU64::new(fuse::ROOT_ID + 1),
-            handles: RwLock::new(BTreeMap::new()),
+            handles: Default::default(),
             next_handle: AtomicU64::new(0),
+            xattr_cache: XattrCache::new(cfg.xattr_cache_timeout),
+            fd_threshold,
+            fd_pressure_mark: AtomicUsize::new(fd_threshold),
+            fd_pressure_lock: Mutex::new(()),
+            fd_pressure_seen: AtomicBool::new(false),
+            idle_handle_sweep: Mutex::new(Instant::now()),
             mount_fds,
             proc_self_fd,
             root_fd,
@@ -431,32 +778,490 @@ impl PassthroughFs {
     }
 
     fn find_handle(&self, handle: Handle, inode: Inode) -> io::Result<Arc<HandleData>> {
-        self.handles
-            .read()
-            .unwrap()
-            .get(&handle)
-            .filter(|hd| hd.inode == inode)
-            .cloned()
-            .ok_or_else(ebadf)
+        let data = self.handles.get(handle, inode).ok_or_else(ebadf)?;
+        data.used.store(true, Ordering::Relaxed);
+
+        // Requests using a handle drive looking for idle ones.  Ours is not closed, as we hold a
+        // reference to it.
+        self.close_idle_handles();
+
+        // Reopen the file if it has been closed for being idle.  As long as we hold a reference,
+        // it will not be closed again.
+        if data.file.read().unwrap().is_none() {
+            let mut file = data.file.write().unwrap();
+            if file.is_none() {
+                let flags = data.flags & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC);
+                let (reopened, reader) = self.open_handle(inode, flags)?;
+                *data.reader.write().unwrap() = reader;
+                *file = Some(reopened);
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Returns the host uid corresponding to the guest's `uid`, according to `Config::squash`
+    /// and `Config::uid_translation`, or `None` if the translation does not cover `uid`.
+    fn try_host_uid(&self, uid: u32) -> Option<u32> {
+        if self.cfg.squash.applies_to(uid) {
+            Some(self.cfg.anon_uid)
+        } else {
+            self.cfg
+                .uid_translation
+                .as_ref()
+                .map_or(Some(uid), |t| t.try_to_host(uid))
+        }
+    }
+
+    /// Returns the host gid corresponding to the guest's `gid`, according to `Config::squash`
+    /// and `Config::gid_translation`, or `None` if the translation does not cover `gid`.
+    fn try_host_gid(&self, gid: u32) -> Option<u32> {
+        if self.cfg.squash.applies_to_gid(gid) {
+            Some(self.cfg.anon_gid)
+        } else {
+            self.cfg
+                .gid_translation
+                .as_ref()
+                .map_or(Some(gid), |t| t.try_to_host(gid))
+        }
+    }
+
+    /// Like `try_host_uid()`, but returns the overflow id for uids the translation does not cover.
+    fn host_uid(&self, uid: u32) -> u32 {
+        self.try_host_uid(uid).unwrap_or(IdTranslation::OVERFLOW_ID)
+    }
+
+    /// Like `try_host_gid()`, but returns the overflow id for gids the translation does not cover.
+    fn host_gid(&self, gid: u32) -> u32 {
+        self.try_host_gid(gid).unwrap_or(IdTranslation::OVERFLOW_ID)
+    }
+
+    /// Returns `ctx` with the guest's uid and gid replaced by the host ids requests are performed
+    /// with.
+    fn host_context(&self, ctx: &Context) -> Context {
+        Context {
+            uid: self.host_uid(ctx.uid),
+            gid: self.host_gid(ctx.gid),
+            pid: ctx.pid,
+        }
+    }
+
+    /// Implements `access()` from the mode bits of the (emulated) attributes of `inode_file`
+    /// alone.
+    fn access_by_mode(&self, ctx: Context, inode_file: &InodeFile, mode: i32) -> io::Result<()> {
+        let st = self.override_stat(inode_file, statx(inode_file, None)?.st)?;
+        // Presented attributes are checked against the guest's own credentials.
+        let (ctx, st) = if self.presents_ownership() {
+            (ctx, self.guest_stat(st))
+        } else {
+            (self.host_context(&ctx), st)
+        };
+
+        if mode == libc::F_OK {
+            // The file exists since we were able to call `stat(2)` on it.
+            return Ok(());
+        }
+
+        if (mode & libc::R_OK) != 0
+            && ctx.uid != 0
+            && (st.st_uid != ctx.uid || st.st_mode & 0o400 == 0)
+            && (st.st_gid != ctx.gid || st.st_mode & 0o040 == 0)
+            && st.st_mode & 0o004 == 0
+        {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+
+        if (mode & libc::W_OK) != 0
+            && ctx.uid != 0
+            && (st.st_uid != ctx.uid || st.st_mode & 0o200 == 0)
+            && (st.st_gid != ctx.gid || st.st_mode & 0o020 == 0)
+            && st.st_mode & 0o002 == 0
+        {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+
+        // root can only execute something if it is executable by one of the owner, the group, or
+        // everyone.
+        if (mode & libc::X_OK) != 0
+            && (ctx.uid != 0 || st.st_mode & 0o111 == 0)
+            && (st.st_uid != ctx.uid || st.st_mode & 0o100 == 0)
+            && (st.st_gid != ctx.gid || st.st_mode & 0o010 == 0)
+            && st.st_mode & 0o001 == 0
+        {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the ownership of files is replaced by `Config::present_uid` or
+    /// `Config::present_gid`.
+    fn presents_ownership(&self) -> bool {
+        self.cfg.present_uid.is_some() || self.cfg.present_gid.is_some()
+    }
+
+    /// Returns `st` as the guest is to see it: Ownership translated back into guest ids (see
+    /// `Config::uid_translation`), or replaced by the presented one, and the mode masks applied.
+    fn guest_stat(&self, mut st: libc::stat64) -> libc::stat64 {
+        if let Some(t) = &self.cfg.uid_translation {
+            st.st_uid = t.to_guest(st.st_uid);
+        }
+        if let Some(t) = &self.cfg.gid_translation {
+            st.st_gid = t.to_guest(st.st_gid);
+        }
+        st.st_uid = self.cfg.present_uid.unwrap_or(st.st_uid);
+        st.st_gid = self.cfg.present_gid.unwrap_or(st.st_gid);
+        st.st_mode &= if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            !self.cfg.dir_mode_mask
+        } else {
+            !self.cfg.file_mode_mask
+        };
+        st
+    }
+
+    /// Drops the ownership changes in `valid` that only restate the presented ownership (see
+    /// `Config::present_uid`), and fails for those that would change it.
+    fn setattr_presented_owner(
+        &self,
+        attr: &libc::stat64,
+        valid: SetattrValid,
+    ) -> io::Result<SetattrValid> {
+        let mut valid = valid;
+        if let Some(uid) = self
+            .cfg
+            .present_uid
+            .filter(|_| valid.contains(SetattrValid::UID))
+        {
+            if attr.st_uid != uid {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            valid.remove(SetattrValid::UID);
+        }
+        if let Some(gid) = self
+            .cfg
+            .present_gid
+            .filter(|_| valid.contains(SetattrValid::GID))
+        {
+            if attr.st_gid != gid {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            valid.remove(SetattrValid::GID);
+        }
+        Ok(valid)
+    }
+
+    /// Returns the credentials to create a file for `ctx` with, see `UnixCredentials`.
+    fn credentials(&self, ctx: &Context, sup_gid: Option<u32>) -> UnixCredentials {
+        // Files are created by the daemon itself, their ownership is only recorded or presented.
+        if self.cfg.override_stat || self.presents_ownership() {
+            return UnixCredentials::new(0, 0);
+        }
+        let squashed = self.cfg.squash.applies_to(ctx.uid);
+        let ctx = self.host_context(ctx);
+        // The supplementary group belongs to the guest's user, not to the anonymous one.  It is
+        // squashed and translated like any other group, and dropped if it has no host gid.
+        let sup_gid = sup_gid
+            .filter(|_| !squashed)
+            .and_then(|gid| self.try_host_gid(gid));
+        UnixCredentials::new(ctx.uid, ctx.gid).supplementary_gid(
+            squashed || self.sup_group_extension.load(Ordering::Relaxed),
+            sup_gid,
+        )
+    }
+
+    /// Removes the setuid and setgid bits from `mode` if `ctx` is squashed, see `Config::squash`.
+    fn squash_mode(&self, ctx: &Context, mode: u32) -> u32 {
+        if self.cfg.squash.applies_to(ctx.uid) && mode & libc::S_IFMT != libc::S_IFDIR {
+            mode & !(libc::S_ISUID | libc::S_ISGID)
+        } else {
+            mode
+        }
+    }
+
+    /// Returns the attributes of `file` to report to the guest, i.e. the host attributes `st`
+    /// with those recorded for `Config::override_stat` applied.
+    fn override_stat(&self, file: &impl AsRawFd, mut st: libc::stat64) -> io::Result<libc::stat64> {
+        if let Some(ov) = self.read_override_stat(file.as_raw_fd(), &st)? {
+            ov.apply(&mut st);
+        }
+        Ok(st)
+    }
+
+    /// Returns the attributes recorded for `fd`, whose host attributes are `st`, or `None` if
+    /// there are none.
+    fn read_override_stat(&self, fd: RawFd, st: &libc::stat64) -> io::Result<Option<OverrideStat>> {
+        if !self.cfg.override_stat || !override_stat::supported(st.st_mode) {
+            return Ok(None);
+        }
+
+        let procname = CString::new(format!("{fd}"))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let _working_dir_guard =
+            set_working_directory(self.proc_self_fd.as_raw_fd(), self.root_fd.as_raw_fd());
+
+        let mut buf = [0u8; 64];
+        // Safe because this will only modify the contents of `buf`.
+        let res = unsafe {
+            libc::getxattr(
+                procname.as_ptr(),
+                override_stat::xattr_name().as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+                // Valid records always fit.
+                Some(libc::ERANGE) => {
+                    warn!(
+                        "Ignoring oversized {} on fd {}",
+                        override_stat::XATTR_NAME,
+                        fd
+                    );
+                    Ok(None)
+                }
+                _ => Err(err),
+            };
+        }
+
+        let ov = OverrideStat::parse(&buf[..res as usize], st.st_mode);
+        if ov.is_none() {
+            warn!(
+                "Ignoring malformed {} on fd {}",
+                override_stat::XATTR_NAME,
+                fd
+            );
+        }
+        Ok(ov)
+    }
+
+    /// Records `ov` as the attributes of `fd`, see `Config::override_stat`.
+    fn write_override_stat(&self, fd: RawFd, ov: OverrideStat) -> io::Result<()> {
+        let procname = CString::new(format!("{fd}"))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let value = ov.to_xattr();
+        let _working_dir_guard =
+            set_working_directory(self.proc_self_fd.as_raw_fd(), self.root_fd.as_raw_fd());
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::setxattr(
+                procname.as_ptr(),
+                override_stat::xattr_name().as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Records the attributes the guest asked for on the file `name` in `parent_file` it just
+    /// created, see `Config::override_stat`.  Removes the file again if this fails.
+    fn record_new_file(
+        &self,
+        ctx: &Context,
+        parent_file: &InodeFile,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<()> {
+        if !self.cfg.override_stat {
+            return Ok(());
+        }
+
+        let umask = if self.posix_acl.load(Ordering::Relaxed) {
+            umask
+        } else {
+            0
+        };
+        let host_ctx = self.host_context(ctx);
+        let ov = OverrideStat {
+            uid: host_ctx.uid,
+            gid: host_ctx.gid,
+            mode: self.squash_mode(ctx, mode) & !umask,
+            rdev: rdev.into(),
+        };
+
+        let res = self
+            .open_relative_to(parent_file, name, libc::O_PATH, None)
+            .and_then(|fd| {
+                // Safe because we just opened this fd.
+                let file = unsafe { File::from_raw_fd(fd) };
+                self.write_override_stat(file.as_raw_fd(), ov)
+            });
+        if res.is_err() {
+            let flags = if mode & libc::S_IFMT == libc::S_IFDIR {
+                libc::AT_REMOVEDIR
+            } else {
+                0
+            };
+            // Safe because this doesn't modify any memory.
+            unsafe { libc::unlinkat(parent_file.as_raw_fd(), name.as_ptr(), flags) };
+        }
+        res
+    }
+
+    /// Records the mode and ownership changes in `valid` for `Config::override_stat` instead of
+    /// applying them to the host file, and returns the changes that are left to apply.
+    fn setattr_override_stat(
+        &self,
+        ctx: &Context,
+        file: &InodeFile,
+        attr: &libc::stat64,
+        valid: SetattrValid,
+    ) -> io::Result<SetattrValid> {
+        let recorded = SetattrValid::MODE | SetattrValid::UID | SetattrValid::GID;
+        if !self.cfg.override_stat || !valid.intersects(recorded) {
+            return Ok(valid);
+        }
+
+        let st = statx(file, None)?.st;
+        if !override_stat::supported(st.st_mode) {
+            return Ok(valid);
+        }
+
+        let mut ov = self
+            .read_override_stat(file.as_raw_fd(), &st)?
+            .unwrap_or_else(|| OverrideStat::from_stat(&st));
+        if valid.contains(SetattrValid::MODE) {
+            ov.mode = (ov.mode & libc::S_IFMT) | (self.squash_mode(ctx, attr.st_mode) & 0o7777);
+        }
+        if valid.contains(SetattrValid::UID) {
+            ov.uid = self.host_uid(attr.st_uid);
+        }
+        if valid.contains(SetattrValid::GID) {
+            ov.gid = self.host_gid(attr.st_gid);
+        }
+        self.write_override_stat(file.as_raw_fd(), ov)?;
+
+        Ok(valid - recorded)
+    }
+
+    /// Returns the mode to create a host file with for the guest's `mode`.  With
+    /// `Config::override_stat`, host files are only accessible by the daemon, and can be neither
+    /// setuid nor setgid.
+    fn host_mode(&self, ctx: &Context, mode: u32) -> u32 {
+        if !self.cfg.override_stat {
+            return self.squash_mode(ctx, mode);
+        }
+        match mode & libc::S_IFMT {
+            libc::S_IFDIR => libc::S_IFDIR | 0o700,
+            _ => libc::S_IFREG | 0o600,
+        }
+    }
+
+    /// Translates the ids in the value of the ACL extended attribute `name` from guest into host
+    /// ids, see `Config::uid_translation`.  Other extended attributes are returned as they are.
+    fn acl_to_host<'a>(&self, name: &CStr, value: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        let (uids, gids) = (&self.cfg.uid_translation, &self.cfg.gid_translation);
+        if (uids.is_none() && gids.is_none()) || !posix_acl::is_acl(name) {
+            return Ok(Cow::Borrowed(value));
+        }
+        posix_acl::translate(
+            value,
+            |uid| uids.as_ref().map_or(Some(uid), |t| t.try_to_host(uid)),
+            |gid| gids.as_ref().map_or(Some(gid), |t| t.try_to_host(gid)),
+        )
+        .map(Cow::Owned)
+    }
+
+    /// Translates the ids in the value of the ACL extended attribute `name` from host into guest
+    /// ids, the reverse of `acl_to_host()`.
+    fn acl_to_guest(&self, name: &CStr, value: Vec<u8>) -> io::Result<Vec<u8>> {
+        let (uids, gids) = (&self.cfg.uid_translation, &self.cfg.gid_translation);
+        if (uids.is_none() && gids.is_none()) || !posix_acl::is_acl(name) {
+            return Ok(value);
+        }
+        posix_acl::translate(
+            &value,
+            |uid| uids.as_ref().map_or(Some(uid), |t| t.try_to_guest(uid)),
+            |gid| gids.as_ref().map_or(Some(gid), |t| t.try_to_guest(gid)),
+        )
+    }
+
+    /// Records a read on `data` and passes the resulting hints on to the host for `file`, see
+    /// `Config::read_hints`.
+    fn give_read_hints(&self, data: &HandleData, file: &impl AsRawFd, offset: u64, size: u32) {
+        let cfg = match &self.cfg.read_hints {
+            Some(cfg) => cfg,
+            None => return,
+        };
+
+        let hints = data.read_pattern.lock().unwrap().record(cfg, offset, size);
+        for hint in hints {
+            // The hints are just that, reading works regardless.
+            if let Err(e) = hint.apply(file) {
+                debug!("Failed to apply {:?} to inode {}: {}", hint, data.inode, e);
+            }
+        }
+    }
+
+    /// Closes the files of handles that have been idle for `Config::idle_handle_timeout`, if
+    /// enough time has passed since we last looked.
+    fn close_idle_handles(&self) {
+        if self.cfg.idle_handle_timeout.is_zero() {
+            return;
+        }
+
+        // If another thread is already at it, there is nothing left to do for us.
+        let mut last_sweep = match self.idle_handle_sweep.try_lock() {
+            Ok(last_sweep) => last_sweep,
+            Err(_) => return,
+        };
+        if last_sweep.elapsed() < self.cfg.idle_handle_timeout {
+            return;
+        }
+
+        // Handles are closed if they have not been used since the last sweep, i.e. for at least
+        // the timeout.
+        let closed = self.handles.close_idle();
+        *last_sweep = Instant::now();
+        if closed > 0 {
+            debug!("Closed {closed} idle file handles");
+        }
+    }
+
+    /// Returns `flags` for opening a file that the guest opened with `flags`.  When writeback
+    /// caching is enabled, the kernel may send read requests even if the userspace program opened
+    /// the file write-only.  So we need to ensure that we open the file for reading as well as
+    /// writing.
+    fn readable_flags(&self, flags: i32) -> i32 {
+        if self.writeback.load(Ordering::Relaxed) && flags & libc::O_ACCMODE == libc::O_WRONLY {
+            (flags & !libc::O_ACCMODE) | libc::O_RDWR
+        } else {
+            flags
+        }
+    }
+
+    /// Opens `inode` for a handle the guest opened with `flags`.  Returns the file, and a
+    /// separate file to read from if the guest may read (see `readable_flags()`), but we may only
+    /// write the file.  The latter is opened with `CAP_DAC_READ_SEARCH`, as the guest's own page
+    /// cache depends on it.
+    fn open_handle(&self, inode: Inode, flags: i32) -> io::Result<(File, Option<File>)> {
+        let readable_flags = self.readable_flags(flags);
+        match self.open_inode(inode, readable_flags) {
+            Err(e) if readable_flags != flags && e.raw_os_error() == Some(libc::EACCES) => {}
+            res => return res.map(|file| (file, None)),
+        }
+
+        let file = self.open_inode(inode, flags)?;
+        let reader = {
+            let _cap_guard = raise_effective_cap("DAC_READ_SEARCH")?;
+            self.open_inode(inode, libc::O_RDONLY)?
+        };
+        Ok((file, Some(reader)))
     }
 
     fn open_inode(&self, inode: Inode, mut flags: i32) -> io::Result<File> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
-
-        // When writeback caching is enabled, the kernel may send read requests even if the
-        // userspace program opened the file write-only. So we need to ensure that we have opened
-        // the file for reading as well as writing.
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
         let writeback = self.writeback.load(Ordering::Relaxed);
-        if writeback && flags & libc::O_ACCMODE == libc::O_WRONLY {
-            flags &= !libc::O_ACCMODE;
-            flags |= libc::O_RDWR;
-        }
 
         // When writeback caching is enabled the kernel is responsible for handling `O_APPEND`.
         // However, this breaks atomicity as the file may have changed on disk, invalidating the
@@ -482,16 +1287,16 @@ impl PassthroughFs {
     /// These are the possible return values:
     /// - `Ok(Some(_))`: Success, caller should use this file handle.
     /// - `Ok(None)`: No error, but no file handle is available.  The caller should fall back to
-    ///               using an `O_PATH` FD.
+    ///   using an `O_PATH` FD.
     /// - `Err(_)`: An error occurred, the caller should return this to the guest.
     ///
     /// This function takes the chosen `self.cfg.inode_file_handles` mode into account:
     /// - `Never`: Always return `Ok(None)`.
     /// - `Prefer`: Return `Ok(None)` when file handles are not supported by this filesystem.
-    ///             Otherwise, return either `Ok(Some(_))` or `Err(_)`, depending on whether a file
-    ///             handle could be generated or not.
+    ///   Otherwise, return either `Ok(Some(_))` or `Err(_)`, depending on whether a file
+    ///   handle could be generated or not.
     /// - `Mandatory`: Never return `Ok(None)`.  When the filesystem does not support file handles,
-    ///                return an `Err(_)`.
+    ///   return an `Err(_)`.
     ///
     /// When the filesystem does not support file handles, this is logged (as a warning in
     /// `Prefer` mode, and as an error in `Mandatory` mode) one time per filesystem.
@@ -506,9 +1311,9 @@ impl PassthroughFs {
                 return Ok(None);
             }
 
-            InodeFileHandlesMode::Prefer | InodeFileHandlesMode::Mandatory => {
-                FileHandle::from_fd(fd)?
-            }
+            InodeFileHandlesMode::Prefer
+            | InodeFileHandlesMode::Mandatory
+            | InodeFileHandlesMode::Adaptive => FileHandle::from_fd(fd)?,
         };
 
         if handle.is_none() {
@@ -517,7 +1322,7 @@ impl PassthroughFs {
 
             let desc = match self.cfg.inode_file_handles {
                 InodeFileHandlesMode::Never => unreachable!(),
-                InodeFileHandlesMode::Prefer => {
+                InodeFileHandlesMode::Prefer | InodeFileHandlesMode::Adaptive => {
                     "Filesystem does not support file handles, falling back to O_PATH FDs"
                 }
                 InodeFileHandlesMode::Mandatory => "Filesystem does not support file handles",
@@ -546,7 +1351,7 @@ impl PassthroughFs {
             // a warning/error for this filesystem.)
             match self.cfg.inode_file_handles {
                 InodeFileHandlesMode::Never => unreachable!(),
-                InodeFileHandlesMode::Prefer => {
+                InodeFileHandlesMode::Prefer | InodeFileHandlesMode::Adaptive => {
                     if !err.silent() {
                         warn!("{}", err);
                     }
@@ -600,7 +1405,7 @@ impl PassthroughFs {
                 Ok(_) => (),
                 Err(e) => match self.cfg.inode_file_handles {
                     InodeFileHandlesMode::Never => unreachable!(),
-                    InodeFileHandlesMode::Prefer => {
+                    InodeFileHandlesMode::Prefer | InodeFileHandlesMode::Adaptive => {
                         warn!("Failed to open file handle for the root node: {}", e);
                         warn!("File handles do not appear safe to use, disabling file handles altogether");
                         self.cfg.inode_file_handles = InodeFileHandlesMode::Never;
@@ -619,7 +1424,7 @@ impl PassthroughFs {
             // were to forget some (future?) variant.
             match self.cfg.inode_file_handles {
                 InodeFileHandlesMode::Never => unreachable!(),
-                InodeFileHandlesMode::Prefer => {
+                InodeFileHandlesMode::Prefer | InodeFileHandlesMode::Adaptive => {
                     warn!("Failed to generate a file handle for the root node, disabling file handles altogether");
                     self.cfg.inode_file_handles = InodeFileHandlesMode::Never;
                 }
@@ -630,14 +1435,108 @@ impl PassthroughFs {
         Ok(())
     }
 
+    /// Returns whether a new inode should use a file handle instead of an `O_PATH` fd, given that
+    /// one could be generated for it.  In `Adaptive` mode, that depends on how many `O_PATH` fds
+    /// there are.
+    fn use_file_handles(&self) -> bool {
+        match self.cfg.inode_file_handles {
+            InodeFileHandlesMode::Adaptive => self.inodes.file_count() >= self.fd_threshold,
+            _ => true,
+        }
+    }
+
+    /// Returns whether a lookup needs a file handle, either to store it in a new inode or to find
+    /// an existing inode that uses one.  In `Adaptive` mode, that is only the case once the fd
+    /// threshold has been reached, so that handles are not generated needlessly before.
+    fn need_file_handles(&self) -> bool {
+        match self.cfg.inode_file_handles {
+            InodeFileHandlesMode::Adaptive => {
+                if self.fd_pressure_seen.load(Ordering::Relaxed) {
+                    return true;
+                }
+                if self.use_file_handles() {
+                    self.fd_pressure_seen.store(true, Ordering::Relaxed);
+                    return true;
+                }
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// The number of inode `O_PATH` fds that `relieve_fd_pressure()` aims for.
+    fn fd_low_watermark(&self) -> usize {
+        self.fd_threshold / 4 * 3
+    }
+
+    /// In `Adaptive` mode, switches inodes from `O_PATH` fds to file handles once there are too
+    /// many fds, until their number has dropped to the low watermark.
+    fn relieve_fd_pressure(&self) {
+        if self.cfg.inode_file_handles != InodeFileHandlesMode::Adaptive
+            || self.inodes.file_count() < self.fd_pressure_mark.load(Ordering::Relaxed)
+        {
+            return;
+        }
+
+        // If another thread is already at it, there is nothing left to do for us.
+        let _guard = match self.fd_pressure_lock.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return,
+        };
+        self.fd_pressure_seen.store(true, Ordering::Relaxed);
+
+        let target = self.fd_low_watermark();
+        let mut switched = 0;
+        self.inodes.for_each_file(|data| {
+            if self.inodes.file_count() <= target {
+                return false;
+            }
+
+            // All lookups start at the root, so keep that one cheap.
+            if data.inode == fuse::ROOT_ID {
+                return true;
+            }
+
+            match self.file_to_handle(&data) {
+                Ok(Some(handle)) => {
+                    if self
+                        .inodes
+                        .replace_file_or_handle(&data, FileOrHandle::Handle(handle))
+                    {
+                        switched += 1;
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => debug!("Cannot switch inode {} to a file handle: {}", data.inode, e),
+            }
+            true
+        });
+
+        let remaining = self.inodes.file_count();
+        debug!("Switched {switched} inodes to file handles, {remaining} O_PATH fds remain");
+
+        // Some inodes may not support file handles, do not go through all of them again on every
+        // lookup.
+        let mark = std::cmp::max(self.fd_threshold, remaining + self.fd_threshold - target);
+        self.fd_pressure_mark.store(mark, Ordering::Relaxed);
+    }
+
+    /// Generates an openable file handle for an inode that holds an `O_PATH` fd.  Returns `None`
+    /// if its filesystem does not support file handles.
+    fn file_to_handle(&self, data: &InodeData) -> io::Result<Option<OpenableFileHandle>> {
+        let file = match &data.file_or_handle {
+            FileOrHandle::File(file) => file,
+            FileOrHandle::Handle(_) => return Ok(None),
+        };
+
+        match FileHandle::from_fd(file)? {
+            Some(handle) => self.make_file_handle_openable(&handle).map(Some),
+            None => Ok(None),
+        }
+    }
+
     fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
-        let p = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let p = self.inodes.get(&parent).ok_or_else(ebadf)?;
 
         let p_file = p.get_file()?;
 
@@ -648,11 +1547,17 @@ impl PassthroughFs {
         };
 
         let st = statx(&path_fd, None)?;
+        let attr = self.override_stat(&path_fd, st.st)?;
 
         // Note that this will always be `None` if `cfg.inode_file_handles` is `Never`, but we only
         // really need the handle when we do not have an `O_PATH` fd open for every inode.  So if
-        // `cfg.inode_file_handles` is `Never`, we do not need it anyway.
-        let handle = self.get_file_handle_opt(&path_fd, &st)?;
+        // `cfg.inode_file_handles` is `Never`, we do not need it anyway.  The same goes for
+        // `Adaptive` mode until there are too many `O_PATH` fds.
+        let handle = if self.need_file_handles() {
+            self.get_file_handle_opt(&path_fd, &st)?
+        } else {
+            None
+        };
 
         let mut attr_flags: u32 = 0;
 
@@ -669,96 +1574,55 @@ impl PassthroughFs {
             mnt_id: st.mnt_id,
         };
 
-        let existing_inode = Self::claim_inode(&self.inodes.read().unwrap(), handle.as_ref(), &ids);
+        let existing_inode = self.inodes.claim(handle.as_ref(), &ids);
 
         let inode = if let Some(inode) = existing_inode {
+            // In `Adaptive` mode, switch the inode back to an `O_PATH` fd if we can afford it.
+            if self.cfg.inode_file_handles == InodeFileHandlesMode::Adaptive
+                && self.inodes.file_count() < self.fd_low_watermark()
+            {
+                if let Some(data) = self.inodes.get(&inode) {
+                    if matches!(data.file_or_handle, FileOrHandle::Handle(_)) {
+                        self.inodes
+                            .replace_file_or_handle(&data, FileOrHandle::File(path_fd));
+                    }
+                }
+            }
             inode
         } else {
-            let file_or_handle = if let Some(h) = handle.as_ref() {
-                FileOrHandle::Handle(self.make_file_handle_openable(h)?)
-            } else {
-                FileOrHandle::File(path_fd)
+            let file_or_handle = match handle.as_ref() {
+                Some(h) if self.use_file_handles() => {
+                    FileOrHandle::Handle(self.make_file_handle_openable(h)?)
+                }
+                _ => FileOrHandle::File(path_fd),
             };
 
             // There is a possible race here where two (or more) threads end up creating an inode
-            // ID.  However, only the one in the thread that locks `self.inodes` first will be used
-            // and the others are wasted.
+            // ID.  However, only the one in the thread that gets to insert it first will be used
+            // and the others are wasted (along with their `file_or_handle`).
             let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
-            let mut inodes = self.inodes.write().unwrap();
-
-            if let Some(inode) = Self::claim_inode(&inodes, handle.as_ref(), &ids) {
-                // An inode was added concurrently while we did not hold a lock on `self.inodes`, so
-                // we use that instead.  `file_or_handle` will be dropped.
-                inode
-            } else {
-                inodes.insert(Arc::new(InodeData {
+            self.inodes
+                .claim_or_insert(handle.as_ref(), &ids, || InodeData {
                     inode,
                     file_or_handle,
                     refcount: AtomicU64::new(1),
                     ids,
                     mode: st.st.st_mode,
-                }));
-
-                inode
-            }
+                });
+            self.relieve_fd_pressure();
+            inode
         };
 
         Ok(Entry {
             inode,
             generation: 0,
-            attr: st.st,
+            attr: self.guest_stat(attr),
             attr_flags,
             attr_timeout: self.cfg.attr_timeout,
             entry_timeout: self.cfg.entry_timeout,
         })
     }
 
-    /// Attempts to get an inode from `inodes` and increment its refcount.  Returns the inode
-    /// number on success and `None` on failure.  Reasons for failure can be that the inode isn't
-    /// in the map or that the refcount is zero.  This function will never increment a refcount
-    /// that's already zero.
-    fn claim_inode(
-        inodes: &InodeStore,
-        handle: Option<&FileHandle>,
-        ids: &InodeIds,
-    ) -> Option<Inode> {
-        let data = handle.and_then(|h| inodes.get_by_handle(h)).or_else(|| {
-            inodes.get_by_ids(ids).filter(|data| {
-                // When we have to fall back to looking up an inode by its inode ID, ensure
-                // that we hit an entry that has a valid file descriptor.  Having an FD
-                // open means that the inode cannot really be deleted until the FD is
-                // closed, so that the inode ID remains valid until we evict the
-                // `InodeData`.  With no FD open (and just a file handle), the inode can be
-                // deleted while we still have our `InodeData`, and so the inode ID may be
-                // reused by a completely different new inode.  Such inodes must be looked
-                // up by file handle, because this handle contains a generation ID to
-                // differentiate between the old and the new inode.
-                matches!(data.file_or_handle, FileOrHandle::File(_))
-            })
-        });
-        if let Some(data) = data {
-            // We use a CAS loop instead of `fetch_add()`, because we must never increment the
-            // refcount from zero to one.
-            let mut n = data.refcount.load(Ordering::Relaxed);
-            loop {
-                if n == 0 {
-                    return None;
-                }
-
-                match data.refcount.compare_exchange_weak(
-                    n,
-                    n + 1,
-                    Ordering::Relaxed,
-                    Ordering::Relaxed,
-                ) {
-                    Ok(_) => return Some(data.inode),
-                    Err(old) => n = old,
-                }
-            }
-        }
-        None
-    }
-
     fn do_open(
         &self,
         inode: Inode,
@@ -776,24 +1640,24 @@ impl PassthroughFs {
             flags &= !(libc::O_NOATIME as u32)
         }
 
-        let file = RwLock::new({
+        let (file, reader) = {
             let _killpriv_guard = if self.cfg.killpriv_v2 && kill_priv {
                 drop_effective_cap("FSETID")?
             } else {
                 None
             };
-            self.open_inode(inode, flags as i32)?
-        });
+            self.open_handle(inode, flags as i32)?
+        };
 
         if flags & (libc::O_TRUNC as u32) != 0 {
-            let file = file.read().expect("poisoned lock");
-            self.clear_file_capabilities(file.as_raw_fd(), false)?;
+            self.clear_file_capabilities(inode, file.as_raw_fd(), false)?;
         }
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-        let data = HandleData { inode, file };
+        let data = HandleData::new(inode, flags as i32, file, reader);
 
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
+        self.handles.insert(handle, Arc::new(data));
+        self.close_idle_handles();
 
         let mut opts = OpenOptions::empty();
         match self.cfg.cache_policy {
@@ -822,43 +1686,26 @@ impl PassthroughFs {
     }
 
     fn do_release(&self, inode: Inode, handle: Handle) -> io::Result<()> {
-        let mut handles = self.handles.write().unwrap();
-
-        if let btree_map::Entry::Occupied(e) = handles.entry(handle) {
-            if e.get().inode == inode {
-                // We don't need to close the file here because that will happen automatically when
-                // the last `Arc` is dropped.
-                e.remove();
-                return Ok(());
-            }
+        // We don't need to close the file here because that will happen automatically when the
+        // last `Arc` is dropped.
+        if self.handles.remove(handle, inode) {
+            Ok(())
+        } else {
+            Err(ebadf())
         }
-
-        Err(ebadf())
     }
 
     fn do_getattr(&self, inode: Inode) -> io::Result<(libc::stat64, Duration)> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
 
         let inode_file = data.get_file()?;
-        let st = statx(&inode_file, None)?.st;
+        let st = self.override_stat(&inode_file, statx(&inode_file, None)?.st)?;
 
-        Ok((st, self.cfg.attr_timeout))
+        Ok((self.guest_stat(st), self.cfg.attr_timeout))
     }
 
     fn do_unlink(&self, parent: Inode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
 
         let parent_file = data.get_file()?;
 
@@ -888,17 +1735,36 @@ impl PassthroughFs {
             return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
         }
 
-        match &self.cfg.xattrmap {
+        let name = match &self.cfg.xattrmap {
             Some(map) => match map.map_client_xattr(name).expect("unterminated mapping") {
-                AppliedRule::Deny => Err(io::Error::from_raw_os_error(libc::EPERM)),
-                AppliedRule::Unsupported => Err(io::Error::from_raw_os_error(libc::ENOTSUP)),
-                AppliedRule::Pass(new_name) => Ok(new_name),
+                AppliedRule::Deny => return Err(io::Error::from_raw_os_error(libc::EPERM)),
+                AppliedRule::Unsupported => {
+                    return Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+                }
+                AppliedRule::Pass(new_name) => new_name,
             },
-            None => Ok(Cow::Borrowed(name)),
+            None => Cow::Borrowed(name),
+        };
+
+        // The attributes recorded for `Config::override_stat` are private to the daemon.
+        if self.cfg.override_stat && *name == *override_stat::xattr_name() {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
         }
+        Ok(name)
     }
 
     fn map_server_xattrlist(&self, xattr_names: Vec<u8>) -> Vec<u8> {
+        let xattr_names = if self.cfg.override_stat {
+            let private = override_stat::xattr_name().to_bytes_with_nul();
+            xattr_names
+                .split_inclusive(|b| *b == 0)
+                .filter(|name| *name != private)
+                .flatten()
+                .copied()
+                .collect()
+        } else {
+            xattr_names
+        };
         let all_xattrs = match &self.cfg.xattrmap {
             Some(map) => map
                 .map_server_xattrlist(xattr_names)
@@ -924,19 +1790,31 @@ impl PassthroughFs {
 
     /// Clears file capabilities
     ///
+    /// * `inode` - The inode `fd` refers to
     /// * `fd` - A file descriptor
     /// * `o_path` - Must be `true` if the file referred to by `fd` was opened with the `O_PATH` flag
     ///
     /// If it is not clear whether `fd` was opened with `O_PATH` it is safe to set `o_path`
     /// to `true`.
-    fn clear_file_capabilities(&self, fd: RawFd, o_path: bool) -> io::Result<()> {
+    fn clear_file_capabilities(&self, inode: Inode, fd: RawFd, o_path: bool) -> io::Result<()> {
         match self.cfg.xattr_security_capability.as_ref() {
             // Unmapped, let the kernel take care of this.
-            None => Ok(()),
+            None => {
+                // Safe because this is a constant value and a valid C string.
+                let sec_xattr =
+                    unsafe { CStr::from_bytes_with_nul_unchecked(b"security.capability\0") };
+                self.xattr_cache.forget_value(inode, sec_xattr);
+                Ok(())
+            }
             // Otherwise we have to uphold the same semantics the kernel
             // would; which is to drop the "security.capability" xattr
             // on write
             Some(xattrname) => {
+                if self.xattr_cache.get(inode, xattrname) == Some(CachedXattr::Absent) {
+                    return Ok(());
+                }
+                let generation = self.xattr_cache.generation(inode);
+
                 let res = if o_path {
                     let proc_file_name = CString::new(format!("{fd}"))
                         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
@@ -950,11 +1828,16 @@ impl PassthroughFs {
                 };
 
                 if res == 0 {
+                    self.xattr_cache.invalidate(inode);
                     Ok(())
                 } else {
                     let eno = io::Error::last_os_error();
                     match eno.raw_os_error().unwrap() {
-                        libc::ENODATA | libc::ENOTSUP => Ok(()),
+                        libc::ENODATA => {
+                            self.xattr_cache.insert(inode, generation, xattrname, None);
+                            Ok(())
+                        }
+                        libc::ENOTSUP => Ok(()),
                         _ => Err(eno),
                     }
                 }
@@ -974,12 +1857,7 @@ impl PassthroughFs {
         extensions: Extensions,
     ) -> io::Result<RawFd> {
         let fd = {
-            let _credentials_guard = UnixCredentials::new(ctx.uid, ctx.gid)
-                .supplementary_gid(
-                    self.sup_group_extension.load(Ordering::Relaxed),
-                    extensions.sup_gid,
-                )
-                .set()?;
+            let _credentials_guard = self.credentials(ctx, extensions.sup_gid).set()?;
             let _umask_guard = self
                 .posix_acl
                 .load(Ordering::Relaxed)
@@ -991,7 +1869,7 @@ impl PassthroughFs {
                 parent_file,
                 name,
                 flags as i32 | libc::O_CREAT | libc::O_EXCL,
-                mode.into(),
+                self.host_mode(ctx, mode).into(),
             )?
         };
 
@@ -1025,6 +1903,12 @@ impl PassthroughFs {
                 return Err(io::Error::last_os_error());
             }
         }
+
+        if let Err(e) = self.record_new_file(ctx, parent_file, name, mode, 0, umask) {
+            // Safe because we own `fd` and do not use it afterwards.
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
         Ok(fd)
     }
 
@@ -1079,43 +1963,6 @@ impl PassthroughFs {
     }
 }
 
-fn forget_one(inodes: &mut InodeStore, inode: Inode, count: u64) {
-    if let Some(data) = inodes.get(&inode) {
-        // Acquiring the write lock on the inode map prevents new lookups from incrementing the
-        // refcount but there is the possibility that a previous lookup already acquired a
-        // reference to the inode data and is in the process of updating the refcount so we need
-        // to loop here until we can decrement successfully.
-        loop {
-            let refcount = data.refcount.load(Ordering::Relaxed);
-
-            // Saturating sub because it doesn't make sense for a refcount to go below zero and
-            // we don't want misbehaving clients to cause integer overflow.
-            let new_count = refcount.saturating_sub(count);
-
-            // We don't need any stronger ordering, because the refcount itself doesn't protect any
-            // data.  The `inodes` map is protected since we hold an exclusive reference (obtained
-            // from an `RwLock`).
-            if data.refcount.compare_exchange(
-                refcount,
-                new_count,
-                Ordering::Relaxed,
-                Ordering::Relaxed,
-            ) == Ok(refcount)
-            {
-                if new_count == 0 {
-                    // We just removed the last refcount for this inode. There's no need for an
-                    // acquire fence here because we hold a write lock on the inode map and any
-                    // thread that is waiting to do a forget on the same inode will have to wait
-                    // until we release the lock. So there's is no other release store for us to
-                    // synchronize with before deleting the entry.
-                    inodes.remove(&inode);
-                }
-                break;
-            }
-        }
-    }
-}
-
 impl FileSystem for PassthroughFs {
     type Inode = Inode;
     type Handle = Handle;
@@ -1136,16 +1983,15 @@ impl FileSystem for PassthroughFs {
         let st = statx(&path_fd, None)?;
         let handle = self.get_file_handle_opt(&path_fd, &st)?;
 
-        let file_or_handle = if let Some(h) = handle.as_ref() {
-            FileOrHandle::Handle(self.make_file_handle_openable(h)?)
-        } else {
-            FileOrHandle::File(path_fd)
+        let file_or_handle = match handle.as_ref() {
+            Some(h) if self.use_file_handles() => {
+                FileOrHandle::Handle(self.make_file_handle_openable(h)?)
+            }
+            _ => FileOrHandle::File(path_fd),
         };
 
-        let mut inodes = self.inodes.write().unwrap();
-
         // Not sure why the root inode gets a refcount of 2 but that's what libfuse does.
-        inodes.insert(Arc::new(InodeData {
+        self.inodes.insert(Arc::new(InodeData {
             inode: fuse::ROOT_ID,
             file_or_handle,
             refcount: AtomicU64::new(2),
@@ -1210,8 +2056,9 @@ impl FileSystem for PassthroughFs {
     }
 
     fn destroy(&self) {
-        self.handles.write().unwrap().clear();
-        self.inodes.write().unwrap().clear();
+        self.handles.clear();
+        self.inodes.clear();
+        self.xattr_cache.clear();
         self.writeback.store(false, Ordering::Relaxed);
         self.announce_submounts.store(false, Ordering::Relaxed);
         self.posix_acl.store(false, Ordering::Relaxed);
@@ -1219,13 +2066,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn statfs(&self, _ctx: Context, inode: Inode) -> io::Result<libc::statvfs64> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
 
         let inode_file = data.get_file()?;
         let mut out = MaybeUninit::<libc::statvfs64>::zeroed();
@@ -1241,20 +2082,32 @@ impl FileSystem for PassthroughFs {
     }
 
     fn lookup(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
-        self.do_lookup(parent, name)
+        match self.do_lookup(parent, name) {
+            Err(e)
+                if e.raw_os_error() == Some(libc::ENOENT)
+                    && !self.cfg.negative_timeout.is_zero() =>
+            {
+                Ok(Entry {
+                    inode: 0,
+                    generation: 0,
+                    // Safe because we are zero-initializing a struct with only POD fields.
+                    attr: unsafe { MaybeUninit::zeroed().assume_init() },
+                    attr_flags: 0,
+                    attr_timeout: Duration::ZERO,
+                    entry_timeout: self.cfg.negative_timeout,
+                })
+            }
+            res => res,
+        }
     }
 
     fn forget(&self, _ctx: Context, inode: Inode, count: u64) {
-        let mut inodes = self.inodes.write().unwrap();
-
-        forget_one(&mut inodes, inode, count)
+        self.inodes.forget(inode, count)
     }
 
     fn batch_forget(&self, _ctx: Context, requests: Vec<(Inode, u64)>) {
-        let mut inodes = self.inodes.write().unwrap();
-
         for (inode, count) in requests {
-            forget_one(&mut inodes, inode, count)
+            self.inodes.forget(inode, count)
         }
     }
 
@@ -1286,28 +2139,18 @@ impl FileSystem for PassthroughFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
 
         let parent_file = data.get_file()?;
 
         let res = {
-            let _credentials_guard = UnixCredentials::new(ctx.uid, ctx.gid)
-                .supplementary_gid(
-                    self.sup_group_extension.load(Ordering::Relaxed),
-                    extensions.sup_gid,
-                )
-                .set()?;
+            let _credentials_guard = self.credentials(&ctx, extensions.sup_gid).set()?;
             let _umask_guard = self
                 .posix_acl
                 .load(Ordering::Relaxed)
                 .then(|| oslib::ScopedUmask::new(umask));
 
+            let mode = self.host_mode(&ctx, libc::S_IFDIR | mode) & !libc::S_IFMT;
             // Safe because this doesn't modify any memory and we check the return value.
             unsafe { libc::mkdirat(parent_file.as_raw_fd(), name.as_ptr(), mode) }
         };
@@ -1324,6 +2167,7 @@ impl FileSystem for PassthroughFs {
                 return Err(e);
             }
         }
+        self.record_new_file(&ctx, &parent_file, name, libc::S_IFDIR | mode, 0, umask)?;
 
         self.do_lookup(parent, name)
     }
@@ -1350,7 +2194,7 @@ impl FileSystem for PassthroughFs {
         // Since we are going to work with the kernel offset, we have to acquire the file
         // lock for both the `lseek64` and `getdents64` syscalls to ensure that no other
         // thread changes the kernel offset while we are using it.
-        let dir = data.file.write().unwrap();
+        let dir = data.write_file();
 
         ReadDir::new(&*dir, offset as libc::off64_t, buf)
     }
@@ -1389,13 +2233,7 @@ impl FileSystem for PassthroughFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
 
         let parent_file = data.get_file()?;
 
@@ -1403,12 +2241,13 @@ impl FileSystem for PassthroughFs {
         // is later modified in the guest using `fcntl(F_SETFL)`. We do a per-write `O_APPEND`
         // check setting `RWF_APPEND` for non-mmapped writes, if necessary.
         let create_flags = flags & !(libc::O_APPEND as u32);
+        // Creating the file grants us both read and write access to it, regardless of its mode.
         let fd = self.do_create(
             &ctx,
             &parent_file,
             name,
             mode,
-            create_flags,
+            self.readable_flags(create_flags as i32) as u32,
             umask,
             extensions,
         );
@@ -1433,17 +2272,15 @@ impl FileSystem for PassthroughFs {
             }
             Ok(fd) => {
                 // Safe because we just opened this fd.
-                let file = RwLock::new(unsafe { File::from_raw_fd(fd) });
+                let file = unsafe { File::from_raw_fd(fd) };
 
                 let entry = self.do_lookup(parent, name)?;
 
                 let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-                let data = HandleData {
-                    inode: entry.inode,
-                    file,
-                };
+                let data = HandleData::new(entry.inode, create_flags as i32, file, None);
 
-                self.handles.write().unwrap().insert(handle, Arc::new(data));
+                self.handles.insert(handle, Arc::new(data));
+                self.close_idle_handles();
 
                 (entry, handle)
             }
@@ -1514,7 +2351,8 @@ impl FileSystem for PassthroughFs {
 
         // This is safe because write_from uses preadv64, so the underlying file descriptor
         // offset is not affected by this operation.
-        let f = data.file.read().unwrap();
+        let f = data.reader();
+        self.give_read_hints(&data, &*f, offset, size);
         w.write_from(&f, size as usize, offset)
     }
 
@@ -1535,7 +2373,7 @@ impl FileSystem for PassthroughFs {
 
         // This is safe because read_to uses `pwritev2(2)`, so the underlying file descriptor
         // offset is not affected by this operation.
-        let f = data.file.read().unwrap();
+        let f = data.read_file();
 
         {
             let _killpriv_guard = if self.cfg.killpriv_v2 && kill_priv {
@@ -1547,7 +2385,7 @@ impl FileSystem for PassthroughFs {
                 None
             };
 
-            self.clear_file_capabilities(f.as_raw_fd(), false)?;
+            self.clear_file_capabilities(inode, f.as_raw_fd(), false)?;
 
             // We don't set the `RWF_APPEND` (i.e., equivalent to `O_APPEND`) flag, if it's a
             // delayed write (i.e., using writeback mode or a mem mapped file) even if the file
@@ -1572,19 +2410,18 @@ impl FileSystem for PassthroughFs {
 
     fn setattr(
         &self,
-        _ctx: Context,
+        ctx: Context,
         inode: Inode,
         attr: libc::stat64,
         handle: Option<Handle>,
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
-        let inode_data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let inode_data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+
+        // Changing the mode updates the ACL, changing the owner or the size drops capabilities.
+        // Invalidate here in case we fail half-way, and again below in case someone repopulated
+        // the cache in between.
+        self.xattr_cache.invalidate(inode);
 
         // In this case, we need to open a new O_RDWR FD
         let rdwr_inode_file = handle.is_none() && valid.intersects(SetattrValid::SIZE);
@@ -1605,7 +2442,7 @@ impl FileSystem for PassthroughFs {
         let data = if let Some(handle) = handle {
             let hd = self.find_handle(handle, inode)?;
 
-            let fd = hd.file.write().unwrap().as_raw_fd();
+            let fd = hd.write_file().as_raw_fd();
             Data::Handle(hd, fd)
         } else {
             let pathname = CString::new(format!("{}", inode_file.as_raw_fd()))
@@ -1613,13 +2450,17 @@ impl FileSystem for PassthroughFs {
             Data::ProcPath(pathname)
         };
 
+        let valid = self.setattr_presented_owner(&attr, valid)?;
+        let valid = self.setattr_override_stat(&ctx, &inode_file, &attr, valid)?;
+
         if valid.contains(SetattrValid::MODE) {
+            let mode = self.squash_mode(&ctx, attr.st_mode);
             // Safe because this doesn't modify any memory and we check the return value.
             let res = unsafe {
                 match data {
-                    Data::Handle(_, fd) => libc::fchmod(fd, attr.st_mode),
+                    Data::Handle(_, fd) => libc::fchmod(fd, mode),
                     Data::ProcPath(ref p) => {
-                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), attr.st_mode, 0)
+                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), mode, 0)
                     }
                 }
             };
@@ -1629,20 +2470,31 @@ impl FileSystem for PassthroughFs {
         }
 
         if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
+            // Files cannot be given to squashed users (e.g. root) either, nor to ids without a host
+            // counterpart.
             let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
+                self.try_host_uid(attr.st_uid).ok_or_else(einval)?
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 u32::MAX
             };
             let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
+                self.try_host_gid(attr.st_gid).ok_or_else(einval)?
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 u32::MAX
             };
 
-            self.clear_file_capabilities(inode_file.as_raw_fd(), true)?;
+            self.clear_file_capabilities(inode, inode_file.as_raw_fd(), true)?;
+
+            // A squashed user must not be able to give files away either, so change the owner
+            // with its credentials rather than the daemon's.
+            let _credentials_guard = if self.cfg.squash.applies_to(ctx.uid) {
+                let ctx = self.host_context(&ctx);
+                UnixCredentials::new(ctx.uid, ctx.gid).set()?
+            } else {
+                None
+            };
 
             // Safe because this is a constant value and a valid C string.
             let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
@@ -1681,7 +2533,7 @@ impl FileSystem for PassthroughFs {
 
             // Safe because this doesn't modify any memory and we check the return value.
             let res = self
-                .clear_file_capabilities(fd, false)
+                .clear_file_capabilities(inode, fd, false)
                 .map(|_| unsafe { libc::ftruncate(fd, attr.st_size) })?;
             if res < 0 {
                 return Err(io::Error::last_os_error());
@@ -1726,6 +2578,7 @@ impl FileSystem for PassthroughFs {
             }
         }
 
+        self.xattr_cache.invalidate(inode);
         self.do_getattr(inode)
     }
 
@@ -1738,20 +2591,8 @@ impl FileSystem for PassthroughFs {
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
-        let old_inode = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&olddir)
-            .cloned()
-            .ok_or_else(ebadf)?;
-        let new_inode = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&newdir)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let old_inode = self.inodes.get(&olddir).ok_or_else(ebadf)?;
+        let new_inode = self.inodes.get(&newdir).ok_or_else(ebadf)?;
 
         let old_file = old_inode.get_file()?;
         let new_file = new_inode.get_file()?;
@@ -1786,23 +2627,12 @@ impl FileSystem for PassthroughFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
 
         let parent_file = data.get_file()?;
 
         let res = {
-            let _credentials_guard = UnixCredentials::new(ctx.uid, ctx.gid)
-                .supplementary_gid(
-                    self.sup_group_extension.load(Ordering::Relaxed),
-                    extensions.sup_gid,
-                )
-                .set()?;
+            let _credentials_guard = self.credentials(&ctx, extensions.sup_gid).set()?;
             let _umask_guard = self
                 .posix_acl
                 .load(Ordering::Relaxed)
@@ -1813,8 +2643,12 @@ impl FileSystem for PassthroughFs {
                 libc::mknodat(
                     parent_file.as_raw_fd(),
                     name.as_ptr(),
-                    mode as libc::mode_t,
-                    u64::from(rdev),
+                    self.host_mode(&ctx, mode) as libc::mode_t,
+                    if self.cfg.override_stat {
+                        0
+                    } else {
+                        u64::from(rdev)
+                    },
                 )
             }
         };
@@ -1832,6 +2666,7 @@ impl FileSystem for PassthroughFs {
                 return Err(e);
             }
         }
+        self.record_new_file(&ctx, &parent_file, name, mode, rdev, umask)?;
         self.do_lookup(parent, name)
     }
 
@@ -1842,20 +2677,8 @@ impl FileSystem for PassthroughFs {
         newparent: Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
-        let new_inode = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&newparent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+        let new_inode = self.inodes.get(&newparent).ok_or_else(ebadf)?;
 
         let inode_file = data.get_file()?;
         let newparent_file = new_inode.get_file()?;
@@ -1888,23 +2711,12 @@ impl FileSystem for PassthroughFs {
         name: &CStr,
         extensions: Extensions,
     ) -> io::Result<Entry> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
 
         let parent_file = data.get_file()?;
 
         let res = {
-            let _credentials_guard = UnixCredentials::new(ctx.uid, ctx.gid)
-                .supplementary_gid(
-                    self.sup_group_extension.load(Ordering::Relaxed),
-                    extensions.sup_gid,
-                )
-                .set()?;
+            let _credentials_guard = self.credentials(&ctx, extensions.sup_gid).set()?;
 
             // Safe because this doesn't modify any memory and we check the return value.
             unsafe { libc::symlinkat(linkname.as_ptr(), parent_file.as_raw_fd(), name.as_ptr()) }
@@ -1928,13 +2740,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn readlink(&self, _ctx: Context, inode: Inode) -> io::Result<Vec<u8>> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
 
         let inode_file = data.get_file()?;
 
@@ -1973,7 +2779,7 @@ impl FileSystem for PassthroughFs {
         // behavior by doing the same thing (dup-ing the fd and then immediately closing it). Safe
         // because this doesn't modify any memory and we check the return values.
         unsafe {
-            let newfd = libc::dup(data.file.write().unwrap().as_raw_fd());
+            let newfd = libc::dup(data.write_file().as_raw_fd());
             if newfd < 0 {
                 return Err(io::Error::last_os_error());
             }
@@ -1989,7 +2795,7 @@ impl FileSystem for PassthroughFs {
     fn fsync(&self, _ctx: Context, inode: Inode, datasync: bool, handle: Handle) -> io::Result<()> {
         let data = self.find_handle(handle, inode)?;
 
-        let fd = data.file.write().unwrap().as_raw_fd();
+        let fd = data.write_file().as_raw_fd();
 
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
@@ -2017,54 +2823,46 @@ impl FileSystem for PassthroughFs {
         self.fsync(ctx, inode, datasync, handle)
     }
 
-    fn access(&self, ctx: Context, inode: Inode, mask: u32) -> io::Result<()> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+    fn access(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        mask: u32,
+        extensions: Extensions,
+    ) -> io::Result<()> {
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
 
         let inode_file = data.get_file()?;
-        let st = statx(&inode_file, None)?.st;
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
-        if mode == libc::F_OK {
-            // The file exists since we were able to call `stat(2)` on it.
-            return Ok(());
-        }
-
-        if (mode & libc::R_OK) != 0
-            && ctx.uid != 0
-            && (st.st_uid != ctx.uid || st.st_mode & 0o400 == 0)
-            && (st.st_gid != ctx.gid || st.st_mode & 0o040 == 0)
-            && st.st_mode & 0o004 == 0
-        {
-            return Err(io::Error::from_raw_os_error(libc::EACCES));
-        }
-
-        if (mode & libc::W_OK) != 0
-            && ctx.uid != 0
-            && (st.st_uid != ctx.uid || st.st_mode & 0o200 == 0)
-            && (st.st_gid != ctx.gid || st.st_mode & 0o020 == 0)
-            && st.st_mode & 0o002 == 0
-        {
-            return Err(io::Error::from_raw_os_error(libc::EACCES));
-        }
-
-        // root can only execute something if it is executable by one of the owner, the group, or
-        // everyone.
-        if (mode & libc::X_OK) != 0
-            && (ctx.uid != 0 || st.st_mode & 0o111 == 0)
-            && (st.st_uid != ctx.uid || st.st_mode & 0o100 == 0)
-            && (st.st_gid != ctx.gid || st.st_mode & 0o010 == 0)
-            && st.st_mode & 0o001 == 0
-        {
-            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        // Let the host kernel decide, so ACLs, supplementary groups, capabilities and LSMs are
+        // taken into account, like they are when the file is actually opened.  Emulated
+        // attributes are unknown to the host kernel though, so those are checked by hand.
+        if self.os_facts.has_faccessat2 && !self.cfg.override_stat && !self.presents_ownership() {
+            let procname = CString::new(format!("{}", inode_file.as_raw_fd()))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let _working_dir_guard =
+                set_working_directory(self.proc_self_fd.as_raw_fd(), self.root_fd.as_raw_fd());
+            // Root keeps its capabilities, so it can read and write everything, but only execute
+            // files that are executable by someone, as usual.
+            match self
+                .credentials(&ctx, extensions.sup_gid)
+                .without_capability()
+                .set()
+            {
+                Ok(_credentials_guard) => {
+                    return oslib::faccessat2(libc::AT_FDCWD, &procname, mode)
+                }
+                // We may not be able to switch to the caller's credentials, e.g. if they are not
+                // mapped into our user namespace.  Fall back to the mode bits then.
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EPERM) | Some(libc::EINVAL)) => {
+                    debug!("Cannot check access with the caller's credentials: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
         }
 
-        Ok(())
+        self.access_by_mode(ctx, &inode_file, mode)
     }
 
     fn setxattr(
@@ -2080,16 +2878,21 @@ impl FileSystem for PassthroughFs {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
 
         let name = self.map_client_xattrname(name)?;
 
+        // Setting these takes privileges on the host that a squashed user does not have, and we
+        // set xattrs with the daemon's.
+        if self.cfg.squash.applies_to(ctx.uid) {
+            let host_name = name.to_bytes();
+            if host_name == b"security.capability" || host_name.starts_with(b"trusted.") {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+        }
+
+        let value = self.acl_to_host(&name, value)?;
+
         // If we are setting posix access acl and if SGID needs to be
         // cleared, then switch to caller's gid and drop CAP_FSETID
         // and that should make sure host kernel clears SGID.
@@ -2104,6 +2907,7 @@ impl FileSystem for PassthroughFs {
             && xattr_name.eq("system.posix_acl_access")
         {
             let cap_guard = drop_effective_cap("FSETID")?;
+            let ctx = self.host_context(&ctx);
             let credentials_guard = UnixCredentials::new(ctx.uid, ctx.gid).set()?;
 
             // If `UnixCredentials::set()` changes the effective user ID to non-zero, then the
@@ -2129,7 +2933,7 @@ impl FileSystem for PassthroughFs {
             // need to get a new fd.
             let file = self.open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)?;
 
-            self.clear_file_capabilities(file.as_raw_fd(), false)?;
+            self.clear_file_capabilities(inode, file.as_raw_fd(), false)?;
 
             // Safe because this doesn't modify any memory and we check the return value.
             unsafe {
@@ -2144,7 +2948,7 @@ impl FileSystem for PassthroughFs {
         } else {
             let file = data.get_file()?;
 
-            self.clear_file_capabilities(file.as_raw_fd(), true)?;
+            self.clear_file_capabilities(inode, file.as_raw_fd(), true)?;
 
             let procname = CString::new(format!("{}", file.as_raw_fd()))
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
@@ -2163,11 +2967,13 @@ impl FileSystem for PassthroughFs {
                 )
             }
         };
-        if res == 0 {
+        let res = if res == 0 {
             Ok(())
         } else {
             Err(io::Error::last_os_error())
-        }
+        };
+        self.xattr_cache.invalidate(inode);
+        res
     }
 
     fn getxattr(
@@ -2191,13 +2997,23 @@ impl FileSystem for PassthroughFs {
             }
         })?;
 
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+
+        if let Some(cached) = self.xattr_cache.get(inode, &name) {
+            return match cached {
+                CachedXattr::Absent => Err(io::Error::from_raw_os_error(libc::ENODATA)),
+                CachedXattr::Value(value) if size == 0 => {
+                    Ok(GetxattrReply::Count(value.len() as u32))
+                }
+                CachedXattr::Value(value) if value.len() > size as usize => {
+                    Err(io::Error::from_raw_os_error(libc::ERANGE))
+                }
+                CachedXattr::Value(value) => {
+                    Ok(GetxattrReply::Value(self.acl_to_guest(&name, value)?))
+                }
+            };
+        }
+        let generation = self.xattr_cache.generation(inode);
 
         let res = if is_safe_inode(data.mode) {
             // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
@@ -2233,14 +3049,20 @@ impl FileSystem for PassthroughFs {
             }
         };
         if res < 0 {
-            return Err(io::Error::last_os_error());
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENODATA) {
+                self.xattr_cache.insert(inode, generation, &name, None);
+            }
+            return Err(err);
         }
 
         if size == 0 {
             Ok(GetxattrReply::Count(res as u32))
         } else {
             buf.resize(res as usize, 0);
-            Ok(GetxattrReply::Value(buf))
+            self.xattr_cache
+                .insert(inode, generation, &name, Some(&buf));
+            Ok(GetxattrReply::Value(self.acl_to_guest(&name, buf)?))
         }
     }
 
@@ -2249,13 +3071,18 @@ impl FileSystem for PassthroughFs {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+
+        if let Some(list) = self.xattr_cache.get_list(inode) {
+            return if size == 0 {
+                Ok(ListxattrReply::Count(list.len() as u32))
+            } else if list.len() > size as usize {
+                Err(io::Error::from_raw_os_error(libc::ERANGE))
+            } else {
+                Ok(ListxattrReply::Names(self.map_server_xattrlist(list)))
+            };
+        }
+        let generation = self.xattr_cache.generation(inode);
 
         let mut buf = vec![0; size as usize];
 
@@ -2298,6 +3125,7 @@ impl FileSystem for PassthroughFs {
             Ok(ListxattrReply::Count(res as u32))
         } else {
             buf.resize(res as usize, 0);
+            self.xattr_cache.insert_list(inode, generation, &buf);
             let buf = self.map_server_xattrlist(buf);
             Ok(ListxattrReply::Names(buf))
         }
@@ -2308,13 +3136,7 @@ impl FileSystem for PassthroughFs {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
 
         let name = self.map_client_xattrname(name)?;
 
@@ -2338,11 +3160,56 @@ impl FileSystem for PassthroughFs {
             unsafe { libc::removexattr(procname.as_ptr(), name.as_ptr()) }
         };
 
-        if res == 0 {
+        let res = if res == 0 {
             Ok(())
         } else {
             Err(io::Error::last_os_error())
+        };
+        self.xattr_cache.invalidate(inode);
+        res
+    }
+
+    fn async_io(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        op: AsyncIoOp,
+    ) -> io::Result<AsyncIoTarget> {
+        let data = self.find_handle(handle, inode)?;
+
+        if let AsyncIoOp::Read { offset, size } = op {
+            self.give_read_hints(&data, &*data.reader(), offset, size);
+        }
+
+        let mut rw_flags = None;
+        if let AsyncIoOp::Write {
+            delayed_write,
+            kill_priv,
+            flags,
+        } = op
+        {
+            // Dropping FSETID only affects the current thread, not the kernel thread that may end
+            // up performing the write, so leave these to `write()`.
+            if self.cfg.killpriv_v2 && kill_priv {
+                return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+            }
+
+            self.clear_file_capabilities(inode, data.as_raw_fd(), false)?;
+
+            // See `write()` for why this is only needed for non-delayed writes.
+            let is_append = flags & libc::O_APPEND as u32 != 0;
+            rw_flags = (!delayed_write && is_append).then_some(oslib::WritevFlags::RWF_APPEND);
         }
+
+        let file: Arc<dyn AsRawFd + Send + Sync> = match op {
+            AsyncIoOp::Read { .. } => Arc::new(HandleReader(data)),
+            _ => data,
+        };
+        Ok(AsyncIoTarget {
+            file,
+            rw_flags: rw_flags.map_or(0, |f| f.bits()),
+        })
     }
 
     fn fallocate(
@@ -2356,7 +3223,7 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<()> {
         let data = self.find_handle(handle, inode)?;
 
-        let fd = data.file.write().unwrap().as_raw_fd();
+        let fd = data.write_file().as_raw_fd();
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
             libc::fallocate64(
@@ -2383,7 +3250,7 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<u64> {
         let data = self.find_handle(handle, inode)?;
 
-        let fd = data.file.write().unwrap().as_raw_fd();
+        let fd = data.write_file().as_raw_fd();
 
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::lseek(fd, offset as libc::off64_t, whence as libc::c_int) };
@@ -2409,12 +3276,12 @@ impl FileSystem for PassthroughFs {
         let data_in = self.find_handle(handle_in, inode_in)?;
 
         // Take just a read lock as we're not going to alter the file descriptor offset.
-        let fd_in = data_in.file.read().unwrap().as_raw_fd();
+        let fd_in = data_in.reader().as_raw_fd();
 
         let data_out = self.find_handle(handle_out, inode_out)?;
 
         // Take just a read lock as we're not going to alter the file descriptor offset.
-        let fd_out = data_out.file.read().unwrap().as_raw_fd();
+        let fd_out = data_out.read_file().as_raw_fd();
 
         // Safe because this will only modify `offset_in` and `offset_out` and we check
         // the return value.
@@ -2451,3 +3318,6 @@ impl FileSystem for PassthroughFs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
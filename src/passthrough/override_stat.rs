@@ -0,0 +1,162 @@
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+
+//! Ownership and mode emulation for an unprivileged daemon.
+//!
+//! An unprivileged daemon cannot give files to other users, or create device nodes.  Instead, the
+//! ownership, mode and device number the guest asked for are stored in the `XATTR_NAME` extended
+//! attribute of the host file, in the format used by fuse-overlayfs and containers/storage:
+//! `uid:gid:0mode[:type]`, where `type` is one of `file`, `dir`, `symlink`, `pipe`, `socket`,
+//! `block-MAJOR-MINOR` or `char-MAJOR-MINOR`.  The host file itself stays owned by the daemon.
+//!
+//! User extended attributes can only be set on regular files and directories, so device nodes,
+//! pipes and sockets are emulated by empty regular files on the host, and symlinks are reported
+//! as they are.
+
+use std::ffi::CStr;
+use std::str;
+
+/// The extended attribute holding the emulated attributes.
+pub const XATTR_NAME: &str = "user.containers.override_stat";
+
+/// Returns `XATTR_NAME` as a C string.
+pub fn xattr_name() -> &'static CStr {
+    // Safe because the literal is nul-terminated and has no interior nul bytes.
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"user.containers.override_stat\0") }
+}
+
+/// Returns whether attributes can be recorded for a host file of `mode`.
+pub fn supported(mode: u32) -> bool {
+    matches!(mode & libc::S_IFMT, libc::S_IFREG | libc::S_IFDIR)
+}
+
+/// The emulated attributes of a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OverrideStat {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    /// The file type and permission bits.
+    pub mode: u32,
+    pub rdev: u64,
+}
+
+impl OverrideStat {
+    /// Returns the attributes as they are on the host, to start from when none are stored yet.
+    pub fn from_stat(st: &libc::stat64) -> Self {
+        OverrideStat {
+            uid: st.st_uid,
+            gid: st.st_gid,
+            mode: st.st_mode,
+            rdev: st.st_rdev,
+        }
+    }
+
+    /// Parses the value of `XATTR_NAME`.  The file type defaults to the one in `host_mode` when
+    /// the value does not include it.  Returns `None` if the value is malformed.
+    pub fn parse(value: &[u8], host_mode: u32) -> Option<Self> {
+        let value = str::from_utf8(value).ok()?;
+        let mut fields = value.trim_end_matches('\0').split(':');
+
+        let uid = fields.next()?.parse().ok()?;
+        let gid = fields.next()?.parse().ok()?;
+        let mut mode = u32::from_str_radix(fields.next()?, 8).ok()?;
+        let mut rdev = 0;
+
+        let kind = match fields.next() {
+            None => host_mode & libc::S_IFMT,
+            Some("file") => libc::S_IFREG,
+            Some("dir") => libc::S_IFDIR,
+            Some("symlink") => libc::S_IFLNK,
+            Some("pipe") => libc::S_IFIFO,
+            Some("socket") => libc::S_IFSOCK,
+            Some(device) => {
+                let (kind, numbers) = device.split_once('-')?;
+                let (major, minor) = numbers.split_once('-')?;
+                rdev = libc::makedev(major.parse().ok()?, minor.parse().ok()?);
+                match kind {
+                    "block" => libc::S_IFBLK,
+                    "char" => libc::S_IFCHR,
+                    _ => return None,
+                }
+            }
+        };
+        // Some writers include the file type in the mode instead.
+        if mode & libc::S_IFMT == 0 {
+            mode |= kind;
+        }
+
+        if fields.next().is_some() {
+            return None;
+        }
+
+        Some(OverrideStat {
+            uid,
+            gid,
+            mode,
+            rdev,
+        })
+    }
+
+    /// Returns the value to store in `XATTR_NAME`.
+    pub fn to_xattr(self) -> String {
+        let kind = match self.mode & libc::S_IFMT {
+            libc::S_IFDIR => "dir".to_owned(),
+            libc::S_IFLNK => "symlink".to_owned(),
+            libc::S_IFIFO => "pipe".to_owned(),
+            libc::S_IFSOCK => "socket".to_owned(),
+            kind @ (libc::S_IFBLK | libc::S_IFCHR) => {
+                // Safe because these only extract bits from the device number.  They are not
+                // `unsafe` in all versions of libc.
+                #[allow(unused_unsafe)]
+                let (major, minor) = unsafe { (libc::major(self.rdev), libc::minor(self.rdev)) };
+                let kind = if kind == libc::S_IFBLK {
+                    "block"
+                } else {
+                    "char"
+                };
+                format!("{}-{}-{}", kind, major, minor)
+            }
+            _ => "file".to_owned(),
+        };
+        format!(
+            "{}:{}:0{:o}:{}",
+            self.uid,
+            self.gid,
+            self.mode & 0o7777,
+            kind
+        )
+    }
+
+    /// Replaces the attributes in `st` by the emulated ones.
+    pub fn apply(self, st: &mut libc::stat64) {
+        st.st_uid = self.uid;
+        st.st_gid = self.gid;
+        st.st_mode = self.mode;
+        st.st_rdev = self.rdev;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let ov = OverrideStat::parse(b"1000:100:0755", libc::S_IFDIR).unwrap();
+        assert_eq!(ov.mode, libc::S_IFDIR | 0o755);
+        assert_eq!((ov.uid, ov.gid, ov.rdev), (1000, 100, 0));
+
+        let ov = OverrideStat::parse(b"0:0:0660:char-1-3", libc::S_IFREG).unwrap();
+        assert_eq!(ov.mode, libc::S_IFCHR | 0o660);
+        assert_eq!(ov.rdev, libc::makedev(1, 3));
+        assert_eq!(ov.to_xattr(), "0:0:0660:char-1-3");
+
+        // A file type in the mode takes precedence.
+        let ov = OverrideStat::parse(b"0:0:100644", libc::S_IFDIR).unwrap();
+        assert_eq!(ov.mode, libc::S_IFREG | 0o644);
+
+        assert!(OverrideStat::parse(b"0:0", libc::S_IFREG).is_none());
+        assert!(OverrideStat::parse(b"0:0:0644:tape-1-2", libc::S_IFREG).is_none());
+        assert!(OverrideStat::parse(b"0:0:0644:file:x", libc::S_IFREG).is_none());
+    }
+}
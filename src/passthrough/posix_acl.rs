@@ -0,0 +1,104 @@
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+
+//! The ids in POSIX ACL extended attributes.
+//!
+//! The kernel stores ACLs in `system.posix_acl_access` and `system.posix_acl_default` as a
+//! little-endian version number (2), followed by entries of a 16-bit tag, 16-bit permissions and
+//! a 32-bit id, which is only meaningful for `ACL_USER` and `ACL_GROUP` entries.
+
+use std::convert::TryInto;
+use std::ffi::CStr;
+use std::io;
+
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP: u16 = 0x08;
+
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+
+/// Returns whether `name` is one of the extended attributes holding ACLs.
+pub fn is_acl(name: &CStr) -> bool {
+    matches!(
+        name.to_bytes(),
+        b"system.posix_acl_access" | b"system.posix_acl_default"
+    )
+}
+
+/// Returns `value` with the ids of its `ACL_USER` entries replaced by `uid(id)` and those of its
+/// `ACL_GROUP` entries replaced by `gid(id)`.  Fails with `EINVAL` if `value` is not a valid ACL,
+/// or if an id cannot be translated.
+// `is_multiple_of()` is newer than the Rust versions supported.
+#[allow(clippy::manual_is_multiple_of)]
+pub fn translate(
+    value: &[u8],
+    uid: impl Fn(u32) -> Option<u32>,
+    gid: impl Fn(u32) -> Option<u32>,
+) -> io::Result<Vec<u8>> {
+    let einval = || io::Error::from_raw_os_error(libc::EINVAL);
+
+    if value.len() < HEADER_SIZE || (value.len() - HEADER_SIZE) % ENTRY_SIZE != 0 {
+        return Err(einval());
+    }
+    let version = u32::from_le_bytes(value[..HEADER_SIZE].try_into().unwrap());
+    if version != ACL_XATTR_VERSION {
+        return Err(einval());
+    }
+
+    let mut translated = value.to_vec();
+    for entry in translated[HEADER_SIZE..].chunks_exact_mut(ENTRY_SIZE) {
+        let tag = u16::from_le_bytes(entry[..2].try_into().unwrap());
+        let id = u32::from_le_bytes(entry[4..].try_into().unwrap());
+        let id = match tag {
+            ACL_USER => uid(id).ok_or_else(einval)?,
+            ACL_GROUP => gid(id).ok_or_else(einval)?,
+            _ => continue,
+        };
+        entry[4..].copy_from_slice(&id.to_le_bytes());
+    }
+    Ok(translated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut value = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn translate_ids() {
+        const ACL_USER_OBJ: u16 = 0x01;
+        let uid = |id| (id < 100).then(|| id + 1000);
+        let gid = |id| (id < 100).then(|| id + 2000);
+
+        let value = acl(&[
+            (ACL_USER_OBJ, 6, u32::MAX),
+            (ACL_USER, 4, 5),
+            (ACL_GROUP, 4, 7),
+        ]);
+        assert_eq!(
+            translate(&value, uid, gid).unwrap(),
+            acl(&[
+                (ACL_USER_OBJ, 6, u32::MAX),
+                (ACL_USER, 4, 1005),
+                (ACL_GROUP, 4, 2007)
+            ])
+        );
+
+        let unmappable = acl(&[(ACL_USER, 4, 500)]);
+        assert_eq!(
+            translate(&unmappable, uid, gid).unwrap_err().raw_os_error(),
+            Some(libc::EINVAL)
+        );
+        assert!(translate(&value[..6], uid, gid).is_err());
+    }
+}
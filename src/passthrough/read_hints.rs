@@ -0,0 +1,168 @@
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+
+//! Hints to the host about how open files are read.
+//!
+//! The host kernel does its own readahead, but it only sees the reads we make, which may be
+//! spread over several threads and interleaved with other requests.  With `CachePolicy::Never`,
+//! that is all the prefetching there is, so we keep track of the reads on every handle and tell
+//! the host about the pattern: Sequential streams get `POSIX_FADV_SEQUENTIAL` and
+//! `POSIX_FADV_WILLNEED` for a window ahead of the current position, and random reads get
+//! `POSIX_FADV_RANDOM`.
+//!
+//! Data read ahead of a stream that has been abandoned is left in the page cache: It is shared
+//! with every other user of the file on the host, so `POSIX_FADV_DONTNEED` could drop pages that
+//! others are still using.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use crate::oslib;
+
+/// Settings for the heuristics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadHints {
+    /// How far ahead of a sequential stream to have the host read, in bytes.
+    pub window: u64,
+
+    /// Number of consecutive reads after which a handle is considered to be read sequentially.
+    pub sequential_threshold: u32,
+
+    /// Number of consecutive non-sequential reads after which a handle is considered to be read
+    /// randomly.
+    pub random_threshold: u32,
+}
+
+impl Default for ReadHints {
+    fn default() -> Self {
+        ReadHints {
+            window: 2 << 20,
+            sequential_threshold: 2,
+            random_threshold: 4,
+        }
+    }
+}
+
+/// A hint for the host, see `posix_fadvise(2)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hint {
+    Sequential,
+    Random,
+    WillNeed { offset: u64, len: u64 },
+}
+
+impl Hint {
+    /// Passes the hint on to the host for `file`.
+    pub fn apply(self, file: &impl AsRawFd) -> io::Result<()> {
+        let (offset, len, advice) = match self {
+            Hint::Sequential => (0, 0, libc::POSIX_FADV_SEQUENTIAL),
+            Hint::Random => (0, 0, libc::POSIX_FADV_RANDOM),
+            Hint::WillNeed { offset, len } => (offset, len, libc::POSIX_FADV_WILLNEED),
+        };
+        oslib::fadvise(file, offset as i64, len as i64, advice)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Pattern {
+    #[default]
+    Unknown,
+    Sequential,
+    Random,
+}
+
+/// The recent reads on a handle.
+#[derive(Debug, Default)]
+pub struct ReadPattern {
+    // Where the next read starts if the handle is read sequentially.
+    next_offset: u64,
+    // Number of consecutive sequential and non-sequential reads, respectively.
+    sequential: u32,
+    random: u32,
+    // How far the host has been asked to read ahead.
+    hinted_until: u64,
+    // The pattern the host has been told about.
+    pattern: Pattern,
+}
+
+impl ReadPattern {
+    /// Records a read of `size` bytes at `offset`, and returns the hints (at most two) to pass on
+    /// to the host as a result.
+    pub fn record(&mut self, cfg: &ReadHints, offset: u64, size: u32) -> Vec<Hint> {
+        let mut hints = Vec::new();
+        let end = offset.saturating_add(size.into());
+
+        if offset == self.next_offset {
+            self.sequential = self.sequential.saturating_add(1);
+            self.random = 0;
+        } else {
+            self.hinted_until = 0;
+            self.sequential = 1;
+            self.random = self.random.saturating_add(1);
+        }
+        self.next_offset = end;
+
+        if self.sequential >= cfg.sequential_threshold && cfg.window > 0 {
+            if self.pattern != Pattern::Sequential {
+                self.pattern = Pattern::Sequential;
+                hints.push(Hint::Sequential);
+            }
+
+            // Extend the window once half of it has been used up.
+            if self.hinted_until < end.saturating_add(cfg.window / 2) {
+                let start = std::cmp::max(end, self.hinted_until);
+                self.hinted_until = end.saturating_add(cfg.window);
+                hints.push(Hint::WillNeed {
+                    offset: start,
+                    len: self.hinted_until - start,
+                });
+            }
+        } else if self.random >= cfg.random_threshold && self.pattern != Pattern::Random {
+            self.pattern = Pattern::Random;
+            hints.push(Hint::Random);
+        }
+
+        hints
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequential() {
+        let cfg = ReadHints {
+            window: 1024,
+            sequential_threshold: 2,
+            random_threshold: 2,
+        };
+        let mut pattern = ReadPattern::default();
+
+        assert_eq!(pattern.record(&cfg, 0, 100), vec![]);
+        assert_eq!(
+            pattern.record(&cfg, 100, 100),
+            vec![
+                Hint::Sequential,
+                Hint::WillNeed {
+                    offset: 200,
+                    len: 1024
+                }
+            ]
+        );
+        // Less than half of the window has been used up.
+        assert_eq!(pattern.record(&cfg, 200, 300), vec![]);
+        assert_eq!(
+            pattern.record(&cfg, 500, 300),
+            vec![Hint::WillNeed {
+                offset: 1224,
+                len: 600
+            }]
+        );
+
+        // Abandoning the stream leaves what has been read ahead alone.
+        assert_eq!(pattern.record(&cfg, 10000, 100), vec![]);
+        assert_eq!(pattern.record(&cfg, 0, 100), vec![Hint::Random]);
+        assert_eq!(pattern.record(&cfg, 5000, 100), vec![]);
+    }
+}
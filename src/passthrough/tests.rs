@@ -0,0 +1,1186 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! End-to-end tests that drive `PassthroughFs` through `Server` with the in-process FUSE client
+//! from `test_client`, using a temporary directory as the shared directory.
+
+use super::*;
+use crate::fuse::{SetattrIn, WRITE_CACHE, WRITE_KILL_PRIV};
+use crate::server::{InitLimits, Server};
+use crate::test_client::{FuseClient, TempDir};
+use crate::uring::Uring;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+fn setup(cfg: Config, flags: FsOptions) -> (TempDir, FuseClient<PassthroughFs>) {
+    let dir = TempDir::new();
+    let cfg = Config {
+        root_dir: dir.path().to_str().unwrap().to_owned(),
+        ..cfg
+    };
+
+    let mut client = FuseClient::new(PassthroughFs::new(cfg).unwrap());
+    client.init(flags).unwrap();
+    (dir, client)
+}
+
+fn errno(e: io::Error) -> i32 {
+    e.raw_os_error().unwrap()
+}
+
+#[test]
+fn lookup_getattr() {
+    let (dir, mut client) = setup(Config::default(), FsOptions::empty());
+    fs::write(dir.path().join("file"), b"some data").unwrap();
+
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+    assert_ne!(entry.nodeid, 0);
+    assert_eq!(entry.attr.size, 9);
+    assert_eq!(entry.attr.mode & libc::S_IFMT, libc::S_IFREG);
+
+    let attr = client.getattr(entry.nodeid).unwrap();
+    assert_eq!(attr.attr.ino, entry.attr.ino);
+
+    // Looking up the same file again must return the same inode.
+    assert_eq!(
+        client.lookup(fuse::ROOT_ID, "file").unwrap().nodeid,
+        entry.nodeid
+    );
+
+    let err = client.lookup(fuse::ROOT_ID, "missing").unwrap_err();
+    assert_eq!(errno(err), libc::ENOENT);
+
+    client.forget(entry.nodeid, 2);
+    let err = client.getattr(entry.nodeid).unwrap_err();
+    assert_eq!(errno(err), libc::EBADF);
+}
+
+#[test]
+fn negative_entries() {
+    let cfg = Config {
+        negative_timeout: Duration::from_secs(10),
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(cfg, FsOptions::empty());
+
+    let entry = client.lookup(fuse::ROOT_ID, "missing").unwrap();
+    assert_eq!(entry.nodeid, 0);
+    assert_eq!(entry.entry_valid, 10);
+
+    // Other errors are still passed on.
+    fs::write(dir.path().join("file"), b"").unwrap();
+    let file = client.lookup(fuse::ROOT_ID, "file").unwrap();
+    let err = client.lookup(file.nodeid, "child").unwrap_err();
+    assert_eq!(errno(err), libc::ENOTDIR);
+}
+
+#[test]
+fn create_write_read() {
+    let (dir, mut client) = setup(Config::default(), FsOptions::empty());
+
+    let (entry, open) = client
+        .create(fuse::ROOT_ID, "file", 0o644, libc::O_RDWR as u32)
+        .unwrap();
+    assert_eq!(
+        client
+            .write(entry.nodeid, open.fh, 0, b"hello world", 0, 0)
+            .unwrap(),
+        11
+    );
+    assert_eq!(
+        client.read(entry.nodeid, open.fh, 6, 100).unwrap(),
+        b"world"
+    );
+    client.release(entry.nodeid, open.fh).unwrap();
+
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"hello world");
+    let mode = fs::metadata(dir.path().join("file"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o7777, 0o644);
+
+    let err = client
+        .create(
+            fuse::ROOT_ID,
+            "file",
+            0o644,
+            (libc::O_RDWR | libc::O_EXCL) as u32,
+        )
+        .unwrap_err();
+    assert_eq!(errno(err), libc::EEXIST);
+}
+
+#[test]
+fn write_append() {
+    let (dir, mut client) = setup(Config::default(), FsOptions::empty());
+    let path = dir.path().join("file");
+    fs::write(&path, b"hello").unwrap();
+
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+    let flags = (libc::O_WRONLY | libc::O_APPEND) as u32;
+    let open = client.open(entry.nodeid, flags).unwrap();
+
+    // The file may have been changed by someone else since the guest last saw it, so a write to a
+    // file opened with `O_APPEND` must go to the current end of the file, whatever offset the
+    // guest sends.
+    fs::write(&path, b"hello, ").unwrap();
+    client
+        .write(entry.nodeid, open.fh, 5, b"world", 0, flags)
+        .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"hello, world");
+
+    // Delayed writes (writeback cache, mmap) carry the correct offset.
+    client
+        .write(entry.nodeid, open.fh, 0, b"HELLO", WRITE_CACHE, flags)
+        .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"HELLO, world");
+}
+
+#[test]
+fn write_kill_priv() {
+    let cfg = Config {
+        killpriv_v2: true,
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(cfg, FsOptions::HANDLE_KILLPRIV_V2);
+    assert!(client.options().contains(FsOptions::HANDLE_KILLPRIV_V2));
+
+    let path = dir.path().join("file");
+    fs::write(&path, b"data").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o4755)).unwrap();
+
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+    let open = client.open(entry.nodeid, libc::O_WRONLY as u32).unwrap();
+
+    // Without CAP_FSETID, the host kernel clears the setuid bit on every write, so the write
+    // without `WRITE_KILL_PRIV` can only keep it if we are privileged.
+    // SAFETY: `geteuid()` has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } == 0 {
+        client.write(entry.nodeid, open.fh, 0, b"x", 0, 0).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o4755);
+    }
+
+    client
+        .write(entry.nodeid, open.fh, 0, b"y", WRITE_KILL_PRIV, 0)
+        .unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o7777, 0o755);
+    assert_eq!(fs::read(&path).unwrap(), b"yata");
+}
+
+#[test]
+fn writeback_write_only() {
+    // SAFETY: `geteuid()` has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let cfg = Config {
+        writeback: true,
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(cfg, FsOptions::WRITEBACK_CACHE);
+
+    let path = dir.path().join("file");
+    fs::write(&path, b"data").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o002)).unwrap();
+    std::os::unix::fs::chown(&path, Some(1000), Some(1000)).unwrap();
+
+    // Without these, we may write the file, but not read it.
+    let _dac_override = drop_effective_cap("DAC_OVERRIDE").unwrap();
+    let _dac_read_search = drop_effective_cap("DAC_READ_SEARCH").unwrap();
+
+    // The guest may read from write-only files to fill its cache.
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+    let open = client.open(entry.nodeid, libc::O_WRONLY as u32).unwrap();
+    client.write(entry.nodeid, open.fh, 0, b"D", 0, 0).unwrap();
+    assert_eq!(client.read(entry.nodeid, open.fh, 0, 4).unwrap(), b"Data");
+}
+
+#[test]
+fn io_uring() {
+    let (dir, mut client) = setup(Config::default(), FsOptions::empty());
+    match Uring::new(16) {
+        Ok(uring) => client.set_uring(uring),
+        Err(e) => {
+            eprintln!("Skipping test, io_uring is not available: {}", e);
+            return;
+        }
+    }
+
+    let (entry, open) = client
+        .create(fuse::ROOT_ID, "file", 0o644, libc::O_RDWR as u32)
+        .unwrap();
+    assert_eq!(
+        client
+            .write(entry.nodeid, open.fh, 0, b"hello world", 0, 0)
+            .unwrap(),
+        11
+    );
+    assert_eq!(
+        client.read(entry.nodeid, open.fh, 6, 100).unwrap(),
+        b"world"
+    );
+    assert_eq!(client.read(entry.nodeid, open.fh, 100, 100).unwrap(), b"");
+    client.fsync(entry.nodeid, open.fh, false).unwrap();
+    client.fsync(entry.nodeid, open.fh, true).unwrap();
+    assert_eq!(client.async_replies, 5);
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"hello world");
+
+    // Non-delayed writes to files opened with `O_APPEND` go to the end of the file.
+    let flags = (libc::O_WRONLY | libc::O_APPEND) as u32;
+    let append = client.open(entry.nodeid, flags).unwrap();
+    client
+        .write(entry.nodeid, append.fh, 0, b"!", 0, flags)
+        .unwrap();
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"hello world!");
+
+    // Errors from the host are passed on.
+    let err = client.read(entry.nodeid, append.fh, 0, 100).unwrap_err();
+    assert_eq!(errno(err), libc::EBADF);
+    assert_eq!(client.async_replies, 7);
+
+    // Requests that cannot be submitted are handled synchronously.
+    let err = client.read(entry.nodeid, 1234, 0, 100).unwrap_err();
+    assert_eq!(errno(err), libc::EBADF);
+    assert_eq!(client.async_replies, 7);
+}
+
+#[test]
+fn setattr_truncate() {
+    let (dir, mut client) = setup(Config::default(), FsOptions::empty());
+    let path = dir.path().join("file");
+    fs::write(&path, b"some data").unwrap();
+
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+    let setattr_in = SetattrIn {
+        valid: (SetattrValid::SIZE | SetattrValid::MODE).bits(),
+        size: 4,
+        mode: 0o600,
+        ..Default::default()
+    };
+    let attr = client.setattr(entry.nodeid, setattr_in).unwrap();
+    assert_eq!(attr.attr.size, 4);
+    assert_eq!(attr.attr.mode & 0o7777, 0o600);
+
+    assert_eq!(fs::read(&path).unwrap(), b"some");
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o7777, 0o600);
+}
+
+#[test]
+fn directories() {
+    let (dir, mut client) = setup(Config::default(), FsOptions::empty());
+
+    let subdir = client.mkdir(fuse::ROOT_ID, "dir", 0o755).unwrap();
+    assert_eq!(subdir.attr.mode & libc::S_IFMT, libc::S_IFDIR);
+    let (file, open) = client
+        .create(subdir.nodeid, "file", 0o644, libc::O_WRONLY as u32)
+        .unwrap();
+    client.release(file.nodeid, open.fh).unwrap();
+    assert!(dir.path().join("dir/file").is_file());
+
+    let open = client.opendir(subdir.nodeid).unwrap();
+    let mut names: Vec<_> = client
+        .readdir(subdir.nodeid, open.fh, 0)
+        .unwrap()
+        .into_iter()
+        .map(|e| e.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, [&b"."[..], b"..", b"file"]);
+    client.releasedir(subdir.nodeid, open.fh).unwrap();
+
+    let err = client.rmdir(fuse::ROOT_ID, "dir").unwrap_err();
+    assert_eq!(errno(err), libc::ENOTEMPTY);
+    client.unlink(subdir.nodeid, "file").unwrap();
+    client.rmdir(fuse::ROOT_ID, "dir").unwrap();
+    assert!(!dir.path().join("dir").exists());
+}
+
+#[test]
+fn rename_link_symlink() {
+    let (dir, mut client) = setup(Config::default(), FsOptions::empty());
+    fs::write(dir.path().join("a"), b"data").unwrap();
+
+    client
+        .rename(fuse::ROOT_ID, "a", fuse::ROOT_ID, "b", 0)
+        .unwrap();
+    assert!(!dir.path().join("a").exists());
+    assert_eq!(fs::read(dir.path().join("b")).unwrap(), b"data");
+
+    fs::write(dir.path().join("c"), b"other").unwrap();
+    let err = client
+        .rename(
+            fuse::ROOT_ID,
+            "b",
+            fuse::ROOT_ID,
+            "c",
+            libc::RENAME_NOREPLACE,
+        )
+        .unwrap_err();
+    assert_eq!(errno(err), libc::EEXIST);
+
+    let b = client.lookup(fuse::ROOT_ID, "b").unwrap();
+    let link = client.link(b.nodeid, fuse::ROOT_ID, "d").unwrap();
+    assert_eq!(link.nodeid, b.nodeid);
+    assert_eq!(link.attr.nlink, 2);
+
+    let symlink = client.symlink(fuse::ROOT_ID, "e", "b").unwrap();
+    assert_eq!(symlink.attr.mode & libc::S_IFMT, libc::S_IFLNK);
+    assert_eq!(client.readlink(symlink.nodeid).unwrap(), b"b");
+    assert_eq!(fs::read_link(dir.path().join("e")).unwrap(), Path::new("b"));
+}
+
+#[test]
+fn xattrs() {
+    let cfg = Config {
+        xattr: true,
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(cfg, FsOptions::empty());
+    fs::write(dir.path().join("file"), b"").unwrap();
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+
+    match client.setxattr(entry.nodeid, "user.test", b"value", 0) {
+        // No support for user xattrs on this host
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+        r => r.unwrap(),
+    }
+
+    assert_eq!(
+        client.getxattr(entry.nodeid, "user.test", 100).unwrap(),
+        b"value"
+    );
+    let err = client.getxattr(entry.nodeid, "user.test", 2).unwrap_err();
+    assert_eq!(errno(err), libc::ERANGE);
+    assert!(client
+        .listxattr(entry.nodeid, 100)
+        .unwrap()
+        .split(|c| *c == 0)
+        .any(|n| n == b"user.test"));
+
+    client.removexattr(entry.nodeid, "user.test").unwrap();
+    let err = client.getxattr(entry.nodeid, "user.test", 100).unwrap_err();
+    assert_eq!(errno(err), libc::ENODATA);
+}
+
+#[test]
+fn xattr_cache() {
+    let cfg = Config {
+        xattr: true,
+        xattr_cache_timeout: Duration::from_secs(60),
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(cfg, FsOptions::empty());
+    let path = dir.path().join("file");
+    fs::write(&path, b"").unwrap();
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+
+    let host_setxattr = |name: &str, value: &[u8]| {
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let name = CString::new(name).unwrap();
+        let res = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        assert_eq!(res, 0, "{}", io::Error::last_os_error());
+    };
+
+    match client.setxattr(entry.nodeid, "user.test", b"value", 0) {
+        // No support for user xattrs on this host
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+        r => r.unwrap(),
+    }
+    assert_eq!(
+        client.getxattr(entry.nodeid, "user.test", 100).unwrap(),
+        b"value"
+    );
+    let err = client
+        .getxattr(entry.nodeid, "user.other", 100)
+        .unwrap_err();
+    assert_eq!(errno(err), libc::ENODATA);
+
+    // Host-side changes are not visible until the cache expires...
+    host_setxattr("user.test", b"host");
+    host_setxattr("user.other", b"host");
+    assert_eq!(
+        client.getxattr(entry.nodeid, "user.test", 100).unwrap(),
+        b"value"
+    );
+    let err = client.getxattr(entry.nodeid, "user.test", 2).unwrap_err();
+    assert_eq!(errno(err), libc::ERANGE);
+    let err = client
+        .getxattr(entry.nodeid, "user.other", 100)
+        .unwrap_err();
+    assert_eq!(errno(err), libc::ENODATA);
+
+    // ...or the guest changes the inode.
+    let setattr_in = SetattrIn {
+        valid: SetattrValid::MODE.bits(),
+        mode: 0o600,
+        ..Default::default()
+    };
+    client.setattr(entry.nodeid, setattr_in).unwrap();
+    assert_eq!(
+        client.getxattr(entry.nodeid, "user.other", 100).unwrap(),
+        b"host"
+    );
+
+    client
+        .setxattr(entry.nodeid, "user.test", b"guest", 0)
+        .unwrap();
+    assert_eq!(
+        client.getxattr(entry.nodeid, "user.test", 100).unwrap(),
+        b"guest"
+    );
+    client.removexattr(entry.nodeid, "user.test").unwrap();
+    let err = client.getxattr(entry.nodeid, "user.test", 100).unwrap_err();
+    assert_eq!(errno(err), libc::ENODATA);
+}
+
+#[test]
+fn xattrs_disabled() {
+    let (dir, mut client) = setup(Config::default(), FsOptions::empty());
+    fs::write(dir.path().join("file"), b"").unwrap();
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+
+    let err = client
+        .setxattr(entry.nodeid, "user.test", b"value", 0)
+        .unwrap_err();
+    assert_eq!(errno(err), libc::ENOSYS);
+}
+
+#[test]
+fn parallel_lookup_open_forget() {
+    let dir = TempDir::new();
+    let names: Vec<CString> = (0..8)
+        .map(|i| {
+            fs::write(dir.path().join(format!("file{i}")), b"").unwrap();
+            CString::new(format!("file{i}")).unwrap()
+        })
+        .collect();
+
+    let cfg = Config {
+        root_dir: dir.path().to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+    let fs = PassthroughFs::new(cfg).unwrap();
+    fs.init(FsOptions::empty()).unwrap();
+    let ctx = Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+
+    // Threads racing to look up, open and forget the same files must always agree on the inode of
+    // each file while it is referenced, and must not lose any references.
+    let first = fs.lookup(ctx, fuse::ROOT_ID, &names[0]).unwrap().inode;
+    std::thread::scope(|s| {
+        for t in 0..8 {
+            let (fs, names) = (&fs, &names);
+            s.spawn(move || {
+                for i in 0..500 {
+                    let name = &names[(t + i) % names.len()];
+                    let inode = fs.lookup(ctx, fuse::ROOT_ID, name).unwrap().inode;
+                    if name == &names[0] {
+                        assert_eq!(inode, first);
+                    }
+
+                    let (handle, _) = fs.open(ctx, inode, false, libc::O_RDONLY as u32).unwrap();
+                    fs.getattr(ctx, inode, handle).unwrap();
+                    fs.release(ctx, inode, 0, handle.unwrap(), false, false, None)
+                        .unwrap();
+                    fs.forget(ctx, inode, 1);
+                }
+            });
+        }
+    });
+
+    fs.getattr(ctx, first, None).unwrap();
+    fs.forget(ctx, first, 1);
+    let err = fs.getattr(ctx, first, None).unwrap_err();
+    assert_eq!(errno(err), libc::EBADF);
+    assert_ne!(
+        fs.lookup(ctx, fuse::ROOT_ID, &names[0]).unwrap().inode,
+        first
+    );
+}
+
+#[test]
+fn adaptive_file_handles() {
+    let dir = TempDir::new();
+    let names: Vec<CString> = (0..16)
+        .map(|i| {
+            fs::write(dir.path().join(format!("file{i}")), b"data").unwrap();
+            CString::new(format!("file{i}")).unwrap()
+        })
+        .collect();
+
+    let cfg = Config {
+        root_dir: dir.path().to_str().unwrap().to_owned(),
+        inode_file_handles: InodeFileHandlesMode::Adaptive,
+        adaptive_fd_threshold: 8,
+        ..Default::default()
+    };
+    let fs = PassthroughFs::new(cfg).unwrap();
+    if fs.cfg.inode_file_handles != InodeFileHandlesMode::Adaptive {
+        // No support for file handles on this host
+        return;
+    }
+    fs.init(FsOptions::empty()).unwrap();
+    let ctx = Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+    let is_handle = |inode| {
+        matches!(
+            fs.inodes.get(&inode).unwrap().file_or_handle,
+            FileOrHandle::Handle(_)
+        )
+    };
+
+    // No file handles are generated until the threshold is reached.
+    let inode = fs.lookup(ctx, fuse::ROOT_ID, &names[0]).unwrap().inode;
+    assert!(!fs.fd_pressure_seen.load(Ordering::Relaxed));
+    fs.forget(ctx, inode, 1);
+
+    let inodes: Vec<Inode> = names
+        .iter()
+        .map(|name| fs.lookup(ctx, fuse::ROOT_ID, name).unwrap().inode)
+        .collect();
+    assert!(fs.fd_pressure_seen.load(Ordering::Relaxed));
+    assert!(fs.inodes.file_count() <= 8);
+    assert!(inodes.iter().any(|i| is_handle(*i)));
+
+    // Switched inodes keep working, and are found again by later lookups.
+    for (name, inode) in names.iter().zip(&inodes) {
+        let (handle, _) = fs.open(ctx, *inode, false, libc::O_RDONLY as u32).unwrap();
+        fs.release(ctx, *inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        assert_eq!(fs.lookup(ctx, fuse::ROOT_ID, name).unwrap().inode, *inode);
+    }
+
+    // Once most inodes are gone, lookups switch inodes back to O_PATH fds.
+    for inode in &inodes[..14] {
+        fs.forget(ctx, *inode, 2);
+    }
+    for (name, inode) in names[14..].iter().zip(&inodes[14..]) {
+        assert_eq!(fs.lookup(ctx, fuse::ROOT_ID, name).unwrap().inode, *inode);
+        assert!(!is_handle(*inode));
+    }
+    assert_eq!(fs.inodes.file_count(), 3);
+}
+
+#[test]
+fn idle_handles() {
+    let dir = TempDir::new();
+    fs::write(dir.path().join("file"), b"data").unwrap();
+    fs::write(dir.path().join("deleted"), b"data").unwrap();
+
+    let cfg = Config {
+        root_dir: dir.path().to_str().unwrap().to_owned(),
+        idle_handle_timeout: Duration::from_millis(1),
+        ..Default::default()
+    };
+    let fs = PassthroughFs::new(cfg).unwrap();
+    fs.init(FsOptions::empty()).unwrap();
+    let ctx = Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+    let is_open = |inode, handle| {
+        let data = fs.handles.get(handle, inode).unwrap();
+        let file = data.file.read().unwrap();
+        file.is_some()
+    };
+    let sweep = || {
+        std::thread::sleep(Duration::from_millis(2));
+        fs.close_idle_handles();
+    };
+
+    let file = fs
+        .lookup(ctx, fuse::ROOT_ID, &CString::new("file").unwrap())
+        .unwrap()
+        .inode;
+    let flags = (libc::O_RDWR | libc::O_TRUNC) as u32;
+    let fh = fs.open(ctx, file, false, flags).unwrap().0.unwrap();
+    let deleted = fs
+        .lookup(ctx, fuse::ROOT_ID, &CString::new("deleted").unwrap())
+        .unwrap()
+        .inode;
+    let flags = libc::O_RDONLY as u32;
+    let dh = fs.open(ctx, deleted, false, flags).unwrap().0.unwrap();
+    fs::remove_file(dir.path().join("deleted")).unwrap();
+
+    // The first sweep only notices that the handles are not used anymore.
+    sweep();
+    assert!(is_open(file, fh));
+    sweep();
+    assert!(!is_open(file, fh));
+    assert!(is_open(deleted, dh));
+
+    // Using the handle reopens the file, without truncating it again.
+    fs::write(dir.path().join("file"), b"data").unwrap();
+    fs.fsync(ctx, file, false, fh).unwrap();
+    assert!(is_open(file, fh));
+    let end = fs.lseek(ctx, file, fh, 0, libc::SEEK_END as u32).unwrap();
+    assert_eq!(end, 4);
+    sweep();
+    assert!(is_open(file, fh));
+
+    // Requests on other handles look for idle handles, too.
+    std::thread::sleep(Duration::from_millis(2));
+    fs.lseek(ctx, deleted, dh, 0, libc::SEEK_SET as u32)
+        .unwrap();
+    assert!(!is_open(file, fh));
+
+    fs.release(ctx, file, 0, fh, false, false, None).unwrap();
+    fs.release(ctx, deleted, 0, dh, false, false, None).unwrap();
+}
+
+#[test]
+fn init_limits() {
+    let default = InitLimits::default();
+    assert_eq!(default.validate(1024), Ok(()));
+    for invalid in [
+        InitLimits {
+            max_write: 1024,
+            ..default
+        },
+        InitLimits {
+            max_write: 16 << 20,
+            ..default
+        },
+        InitLimits {
+            congestion_threshold: 10,
+            max_background: 5,
+            ..default
+        },
+        InitLimits {
+            time_gran: 20,
+            ..default
+        },
+        InitLimits {
+            map_alignment: 22,
+            ..default
+        },
+    ] {
+        assert!(invalid.validate(1024).is_err(), "{:?}", invalid);
+    }
+
+    let dir = TempDir::new();
+    let cfg = Config {
+        root_dir: dir.path().to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+    let limits = InitLimits {
+        max_write: 2 << 20,
+        max_background: 64,
+        congestion_threshold: 48,
+        time_gran: 1000,
+        map_alignment: 0,
+    };
+    assert_eq!(limits.validate(1024), Ok(()));
+    assert_eq!(limits.fit_queue(1024), limits);
+    assert_eq!(limits.fit_queue(128).validate(128), Ok(()));
+    let mut server = Server::new(PassthroughFs::new(cfg).unwrap());
+    server.set_init_limits(limits);
+    let mut client = FuseClient::from_server(server);
+
+    let out = client.init(FsOptions::MAX_PAGES).unwrap();
+    assert_eq!(out.max_write, 2 << 20);
+    assert_eq!(u32::from(out.max_pages), limits.max_pages());
+    assert_eq!(out.max_background, 64);
+    assert_eq!(out.congestion_threshold, 48);
+    assert_eq!(out.time_gran, 1000);
+
+    // Writes larger than the default limit of 1 MiB go through.
+    let data = vec![0x5a; 2 << 20];
+    let (entry, open) = client
+        .create(fuse::ROOT_ID, "file", 0o644, libc::O_RDWR as u32)
+        .unwrap();
+    assert_eq!(
+        client.write(entry.nodeid, open.fh, 0, &data, 0, 0).unwrap(),
+        2 << 20
+    );
+    client.release(entry.nodeid, open.fh).unwrap();
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), data);
+
+    // The limits are lowered to what the negotiated queue size allows.
+    let cfg = Config {
+        root_dir: dir.path().to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+    let mut server = Server::new(PassthroughFs::new(cfg).unwrap());
+    server.set_init_limits(limits);
+    server.set_queue_size(128);
+    let mut client = FuseClient::from_server(server);
+    let out = client.init(FsOptions::MAX_PAGES).unwrap();
+    assert_eq!(out.max_write, limits.fit_queue(128).max_write);
+    assert!(u32::from(out.max_pages) <= 128 - 4);
+}
+
+#[test]
+fn read_hints() {
+    let cfg = Config {
+        read_hints: Some(Default::default()),
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(cfg, FsOptions::empty());
+    let data: Vec<u8> = (0..(1 << 20)).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.path().join("file"), &data).unwrap();
+
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+    let open = client.open(entry.nodeid, libc::O_RDONLY as u32).unwrap();
+
+    // A sequential stream, followed by random reads; the hints must not affect the data.
+    for offset in (0..(256 << 10)).step_by(64 << 10) {
+        let read = client
+            .read(entry.nodeid, open.fh, offset, 64 << 10)
+            .unwrap();
+        assert_eq!(read, &data[offset as usize..][..64 << 10]);
+    }
+    for offset in [900_000u64, 10, 500_000, 77_777, 1_000_000] {
+        let read = client.read(entry.nodeid, open.fh, offset, 4096).unwrap();
+        let end = std::cmp::min(offset as usize + 4096, data.len());
+        assert_eq!(read, &data[offset as usize..end]);
+    }
+    client.release(entry.nodeid, open.fh).unwrap();
+}
+
+#[test]
+fn squash_root() {
+    // Switching credentials requires root.
+    // SAFETY: `geteuid()` has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+
+    let cfg = Config {
+        squash: credentials::Squash::Root,
+        anon_uid: 12345,
+        anon_gid: 23456,
+        xattr: true,
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(cfg, FsOptions::empty());
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+
+    // Files created by root belong to the anonymous user, without setuid and setgid bits.
+    let (entry, open) = client
+        .create(
+            fuse::ROOT_ID,
+            "file",
+            libc::S_IFREG | 0o6755,
+            libc::O_RDWR as u32,
+        )
+        .unwrap();
+    client.release(entry.nodeid, open.fh).unwrap();
+    let metadata = fs::metadata(dir.path().join("file")).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (12345, 23456));
+    assert_eq!(metadata.mode() & 0o7777, 0o755);
+
+    // Neither can root set them later, nor give files to root.
+    let setattr_in = SetattrIn {
+        valid: (SetattrValid::MODE | SetattrValid::UID | SetattrValid::GID).bits(),
+        mode: libc::S_IFREG | 0o4700,
+        uid: 0,
+        gid: 0,
+        ..Default::default()
+    };
+    client.setattr(entry.nodeid, setattr_in).unwrap();
+    let metadata = fs::metadata(dir.path().join("file")).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (12345, 23456));
+    assert_eq!(metadata.mode() & 0o7777, 0o700);
+
+    // Nor give them to other users, as it only has the anonymous user's privileges.
+    let setattr_in = SetattrIn {
+        valid: SetattrValid::UID.bits(),
+        uid: 1000,
+        ..Default::default()
+    };
+    assert_eq!(
+        errno(client.setattr(entry.nodeid, setattr_in).unwrap_err()),
+        libc::EPERM
+    );
+    let metadata = fs::metadata(dir.path().join("file")).unwrap();
+    assert_eq!(metadata.uid(), 12345);
+
+    // Nor set xattrs that need privileges on the host.
+    for name in ["security.capability", "trusted.foo"] {
+        assert_eq!(
+            errno(client.setxattr(entry.nodeid, name, b"", 0).unwrap_err()),
+            libc::EPERM
+        );
+    }
+}
+
+#[test]
+fn translate_ids() {
+    // Switching credentials requires root.
+    // SAFETY: `geteuid()` has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+
+    let translation = |guest, host, count| {
+        IdTranslation::new(vec![crate::idmap::IdRange { guest, host, count }]).unwrap()
+    };
+    let cfg = Config {
+        uid_translation: Some(translation(1000, 201000, 10)),
+        gid_translation: Some(translation(1000, 301000, 10)),
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(cfg, FsOptions::empty());
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+
+    // Requests of guest uid 1000 are performed as host uid 201000.
+    client.uid = 1000;
+    client.gid = 1000;
+    let entry = client.mkdir(fuse::ROOT_ID, "dir", 0o755).unwrap();
+    let metadata = fs::metadata(dir.path().join("dir")).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (201000, 301000));
+    assert_eq!((entry.attr.uid, entry.attr.gid), (1000, 1000));
+
+    // Owners set with chown are translated, and so is the reply.
+    client.uid = 0;
+    client.gid = 0;
+    let setattr_in = SetattrIn {
+        valid: (SetattrValid::UID | SetattrValid::GID).bits(),
+        uid: 1005,
+        gid: 1001,
+        ..Default::default()
+    };
+    let attr = client.setattr(entry.nodeid, setattr_in).unwrap().attr;
+    assert_eq!((attr.uid, attr.gid), (1005, 1001));
+    let metadata = fs::metadata(dir.path().join("dir")).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (201005, 301001));
+
+    // Guest ids without a host counterpart cannot be set.
+    let setattr_in = SetattrIn {
+        valid: SetattrValid::UID.bits(),
+        uid: 5000,
+        ..Default::default()
+    };
+    assert_eq!(
+        errno(client.setattr(entry.nodeid, setattr_in).unwrap_err()),
+        libc::EINVAL
+    );
+
+    // ACLs with host ids without a guest counterpart cannot be read either.
+    std::os::unix::fs::chown(dir.path().join("dir"), Some(4242), Some(4242)).unwrap();
+    let attr = client.getattr(entry.nodeid).unwrap().attr;
+    assert_eq!((attr.uid, attr.gid), (65534, 65534));
+}
+
+#[test]
+fn override_stat() {
+    let cfg = Config {
+        override_stat: true,
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(cfg, FsOptions::empty());
+    let host = fs::metadata(dir.path()).unwrap();
+
+    // Device nodes are recorded on regular files owned by the daemon.
+    client.uid = 1000;
+    client.gid = 1000;
+    let dev = libc::makedev(1, 3) as u32;
+    let entry = client
+        .mknod(fuse::ROOT_ID, "null", libc::S_IFCHR | 0o666, dev)
+        .unwrap();
+    assert_eq!(entry.attr.mode, libc::S_IFCHR | 0o666);
+    assert_eq!((entry.attr.uid, entry.attr.gid), (1000, 1000));
+    assert_eq!(entry.attr.rdev, dev);
+    let metadata = fs::metadata(dir.path().join("null")).unwrap();
+    assert!(metadata.is_file());
+    assert_eq!((metadata.uid(), metadata.gid()), (host.uid(), host.gid()));
+
+    // Ownership and mode changes are recorded, including setuid bits.
+    let entry = client.mkdir(fuse::ROOT_ID, "dir", 0o755).unwrap();
+    let setattr_in = SetattrIn {
+        valid: (SetattrValid::MODE | SetattrValid::UID | SetattrValid::GID).bits(),
+        mode: libc::S_IFDIR | 0o2750,
+        uid: 123456,
+        gid: 654321,
+        ..Default::default()
+    };
+    client.setattr(entry.nodeid, setattr_in).unwrap();
+    let attr = client.getattr(entry.nodeid).unwrap().attr;
+    assert_eq!(attr.mode, libc::S_IFDIR | 0o2750);
+    assert_eq!((attr.uid, attr.gid), (123456, 654321));
+    let metadata = fs::metadata(dir.path().join("dir")).unwrap();
+    assert_eq!(metadata.mode() & 0o7777, 0o700);
+    assert_eq!((metadata.uid(), metadata.gid()), (host.uid(), host.gid()));
+
+    // `access` goes by the recorded attributes, whatever the host ones are.
+    client.uid = 1000;
+    assert_eq!(
+        client
+            .access(entry.nodeid, libc::W_OK as u32)
+            .map_err(errno),
+        Err(libc::EACCES)
+    );
+    client.uid = 123456;
+    client.access(entry.nodeid, libc::W_OK as u32).unwrap();
+
+    // An oversized record is ignored like a malformed one.
+    let path = dir.path().join("big");
+    fs::write(&path, b"").unwrap();
+    let cpath = CString::new(path.to_str().unwrap()).unwrap();
+    let value = [b'0'; 100];
+    // SAFETY: `setxattr()` only reads `value.len()` bytes from `value`.
+    let ret = unsafe {
+        libc::setxattr(
+            cpath.as_ptr(),
+            override_stat::xattr_name().as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+    let entry = client.lookup(fuse::ROOT_ID, "big").unwrap();
+    assert_eq!(entry.attr.mode, fs::metadata(&path).unwrap().mode());
+}
+
+#[test]
+fn present_ownership() {
+    let cfg = Config {
+        present_uid: Some(4321),
+        present_gid: Some(8765),
+        file_mode_mask: 0o022,
+        dir_mode_mask: 0o077,
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(cfg, FsOptions::empty());
+    let host = fs::metadata(dir.path()).unwrap();
+
+    // Files are created with the daemon's credentials, but presented as configured.
+    client.uid = 1000;
+    client.gid = 1000;
+    let (entry, open) = client
+        .create(
+            fuse::ROOT_ID,
+            "file",
+            libc::S_IFREG | 0o666,
+            libc::O_RDWR as u32,
+        )
+        .unwrap();
+    client.release(entry.nodeid, open.fh).unwrap();
+    assert_eq!((entry.attr.uid, entry.attr.gid), (4321, 8765));
+    assert_eq!(entry.attr.mode, libc::S_IFREG | 0o644);
+    let metadata = fs::metadata(dir.path().join("file")).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (host.uid(), host.gid()));
+    assert_eq!(metadata.mode() & 0o777, 0o666);
+
+    let attr = client.getattr(fuse::ROOT_ID).unwrap().attr;
+    assert_eq!(attr.mode & 0o077, 0);
+
+    // `access` goes by the presented attributes.
+    assert_eq!(
+        client
+            .access(entry.nodeid, libc::W_OK as u32)
+            .map_err(errno),
+        Err(libc::EACCES)
+    );
+    client.uid = 4321;
+    client.access(entry.nodeid, libc::W_OK as u32).unwrap();
+
+    // The presented ownership cannot be changed.
+    let mut setattr_in = SetattrIn {
+        valid: SetattrValid::UID.bits(),
+        uid: 1000,
+        ..Default::default()
+    };
+    assert_eq!(
+        client
+            .setattr(entry.nodeid, setattr_in)
+            .map(|_| ())
+            .map_err(errno),
+        Err(libc::EPERM)
+    );
+    setattr_in.uid = 4321;
+    client.setattr(entry.nodeid, setattr_in).unwrap();
+    let metadata = fs::metadata(dir.path().join("file")).unwrap();
+    assert_eq!(metadata.uid(), host.uid());
+}
+
+#[test]
+fn translate_acl_ids() {
+    let translation = |guest, host| {
+        IdTranslation::new(vec![crate::idmap::IdRange {
+            guest,
+            host,
+            count: 10,
+        }])
+        .unwrap()
+    };
+    let cfg = Config {
+        xattr: true,
+        posix_acl: true,
+        uid_translation: Some(translation(1000, 201000)),
+        gid_translation: Some(translation(1000, 301000)),
+        ..Default::default()
+    };
+    let (dir, mut client) = setup(
+        cfg,
+        FsOptions::POSIX_ACL | FsOptions::DONT_MASK | FsOptions::SETXATTR_EXT,
+    );
+    fs::write(dir.path().join("file"), b"").unwrap();
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+
+    // Version 2, then (tag, permissions, id) for the owner, user 1005, the owning group, group
+    // 1001, the mask and others.
+    let acl = |user: u32, group: u32| {
+        let mut value = 2u32.to_le_bytes().to_vec();
+        let entries = [
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 4, user),
+            (0x04, 4, u32::MAX),
+            (0x08, 4, group),
+            (0x10, 6, u32::MAX),
+            (0x20, 4, u32::MAX),
+        ];
+        for (tag, perm, id) in entries.iter() {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    };
+
+    match client.setxattr(entry.nodeid, "system.posix_acl_access", &acl(1005, 1001), 0) {
+        // No support for ACLs on this host
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+        r => r.unwrap(),
+    }
+
+    // The host has the translated ids, and the guest gets its own back.
+    let path = CString::new(dir.path().join("file").to_str().unwrap()).unwrap();
+    let name = CString::new("system.posix_acl_access").unwrap();
+    let mut host_value = vec![0u8; 100];
+    // SAFETY: `getxattr()` only writes up to `host_value.len()` bytes to `host_value`.
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            host_value.as_mut_ptr() as *mut libc::c_void,
+            host_value.len(),
+        )
+    };
+    host_value.truncate(len as usize);
+    assert_eq!(host_value, acl(201005, 301001));
+    assert_eq!(
+        client
+            .getxattr(entry.nodeid, "system.posix_acl_access", 100)
+            .unwrap(),
+        acl(1005, 1001)
+    );
+
+    // Ids outside of the translation are rejected.
+    let err = client
+        .setxattr(entry.nodeid, "system.posix_acl_access", &acl(5, 1001), 0)
+        .unwrap_err();
+    assert_eq!(errno(err), libc::EINVAL);
+
+    // ACLs with host ids without a guest counterpart cannot be read either.
+    let host_value = acl(4242, 301001);
+    // SAFETY: `setxattr()` only reads `host_value.len()` bytes from `host_value`.
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            host_value.as_ptr() as *const libc::c_void,
+            host_value.len(),
+            0,
+        )
+    };
+    assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+    let err = client
+        .getxattr(entry.nodeid, "system.posix_acl_access", 100)
+        .unwrap_err();
+    assert_eq!(errno(err), libc::EINVAL);
+}
+
+#[test]
+fn access_acl() {
+    // Switching credentials requires root.
+    // SAFETY: `geteuid()` has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+
+    let (dir, mut client) = setup(Config::default(), FsOptions::empty());
+    let path = dir.path().join("file");
+    fs::write(&path, b"").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+    let entry = client.lookup(fuse::ROOT_ID, "file").unwrap();
+
+    // The owner (root) and the mask may read and write, and so may user 1005.
+    let mut value = 2u32.to_le_bytes().to_vec();
+    let entries = [
+        (0x01u16, 6u16, u32::MAX),
+        (0x02, 6, 1005),
+        (0x04, 4, u32::MAX),
+        (0x10, 6, u32::MAX),
+        (0x20, 0, u32::MAX),
+    ];
+    for (tag, perm, id) in entries.iter() {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&perm.to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    let cpath = CString::new(path.to_str().unwrap()).unwrap();
+    let name = CString::new("system.posix_acl_access").unwrap();
+    // SAFETY: `setxattr()` only reads `value.len()` bytes from `value`.
+    let ret = unsafe {
+        libc::setxattr(
+            cpath.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        // No support for ACLs on this host
+        assert_eq!(
+            io::Error::last_os_error().raw_os_error(),
+            Some(libc::EOPNOTSUPP)
+        );
+        return;
+    }
+
+    let rw = (libc::R_OK | libc::W_OK) as u32;
+    client.uid = 1005;
+    client.gid = 1005;
+    client.access(entry.nodeid, rw).unwrap();
+    client.uid = 1006;
+    assert_eq!(
+        client.access(entry.nodeid, rw).map_err(errno),
+        Err(libc::EACCES)
+    );
+
+    // Root may read and write anything, but only execute what is executable by someone.
+    client.uid = 0;
+    client.gid = 0;
+    client.access(entry.nodeid, rw).unwrap();
+    assert_eq!(
+        client
+            .access(entry.nodeid, libc::X_OK as u32)
+            .map_err(errno),
+        Err(libc::EACCES)
+    );
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o650)).unwrap();
+    client.access(entry.nodeid, libc::X_OK as u32).unwrap();
+}
@@ -0,0 +1,292 @@
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+
+//! A cache for the extended attributes of inodes.
+//!
+//! With xattr support enabled, every write has to make sure "security.capability" is removed, and
+//! guests with ACL support look up "system.posix_acl_access" and friends all the time.  Most files
+//! have none of these, so caching the (mostly negative) results saves a lot of syscalls.
+//!
+//! Entries are keyed by the host's name of the attribute, i.e. after xattrmap translation.
+//! Changes made through the guest invalidate the cache, changes made on the host are picked up
+//! once the entries have expired.
+//!
+//! A lookup may race with a change of the same inode, so callers take a generation number before
+//! asking the host, and the result is only cached if no invalidation has happened since then.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::inode_store::Inode;
+
+/// Number of independently locked parts the cache is split into.
+const SHARDS: usize = 16;
+
+/// Maximum number of inodes cached per shard.
+const MAX_INODES_PER_SHARD: usize = 256;
+
+/// Maximum number of attributes cached per inode.
+const MAX_NAMES_PER_INODE: usize = 32;
+
+/// Values and name lists larger than this are not cached.
+const MAX_VALUE_SIZE: usize = 4096;
+
+struct InodeXattrs {
+    expires: Instant,
+    // `None` means the attribute does not exist.
+    values: HashMap<CString, Option<Vec<u8>>>,
+    // The raw (untranslated) result of listxattr().
+    list: Option<Vec<u8>>,
+}
+
+/// The result of a cache lookup for a single attribute.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CachedXattr {
+    /// The attribute exists and has this value.
+    Value(Vec<u8>),
+    /// The attribute does not exist.
+    Absent,
+}
+
+#[derive(Default)]
+struct Shard {
+    inodes: HashMap<Inode, InodeXattrs>,
+    // Incremented on every invalidation.
+    generation: u64,
+}
+
+pub struct XattrCache {
+    timeout: Duration,
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl XattrCache {
+    /// Creates a cache whose entries are valid for `timeout`.  A zero timeout disables the cache.
+    pub fn new(timeout: Duration) -> Self {
+        XattrCache {
+            timeout,
+            shards: (0..SHARDS).map(|_| Default::default()).collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.timeout.is_zero()
+    }
+
+    fn shard(&self, inode: Inode) -> &Mutex<Shard> {
+        &self.shards[inode as usize % SHARDS]
+    }
+
+    /// Returns the generation to pass to `insert()` or `insert_list()` after looking up an
+    /// attribute of `inode` on the host.
+    pub fn generation(&self, inode: Inode) -> u64 {
+        if !self.is_enabled() {
+            return 0;
+        }
+
+        self.shard(inode).lock().unwrap().generation
+    }
+
+    /// Runs `f` on the unexpired entry of `inode`, if any.
+    fn with_entry<T>(&self, inode: Inode, f: impl FnOnce(&InodeXattrs) -> Option<T>) -> Option<T> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut shard = self.shard(inode).lock().unwrap();
+        match shard.inodes.get(&inode) {
+            Some(entry) if entry.expires > Instant::now() => f(entry),
+            Some(_) => {
+                shard.inodes.remove(&inode);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Runs `f` on the entry of `inode`, creating it (and making room for it) if necessary.  Does
+    /// nothing if the shard of `inode` has been invalidated since `generation`.
+    fn with_entry_mut(&self, inode: Inode, generation: u64, f: impl FnOnce(&mut InodeXattrs)) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut shard = self.shard(inode).lock().unwrap();
+        if shard.generation != generation {
+            return;
+        }
+
+        let inodes = &mut shard.inodes;
+        if inodes.get(&inode).is_some_and(|e| e.expires <= now) {
+            inodes.remove(&inode);
+        }
+
+        if !inodes.contains_key(&inode) && inodes.len() >= MAX_INODES_PER_SHARD {
+            inodes.retain(|_, e| e.expires > now);
+            if inodes.len() >= MAX_INODES_PER_SHARD {
+                // All entries are still valid, evict the one that would expire first.
+                if let Some(oldest) = inodes
+                    .iter()
+                    .min_by_key(|(_, e)| e.expires)
+                    .map(|(i, _)| *i)
+                {
+                    inodes.remove(&oldest);
+                }
+            }
+        }
+
+        let entry = inodes.entry(inode).or_insert_with(|| InodeXattrs {
+            expires: now + self.timeout,
+            values: HashMap::new(),
+            list: None,
+        });
+        f(entry);
+    }
+
+    /// Returns what is known about the attribute `name` of `inode`, or `None` if nothing is.
+    pub fn get(&self, inode: Inode, name: &CStr) -> Option<CachedXattr> {
+        self.with_entry(inode, |e| {
+            e.values.get(name).map(|v| match v {
+                Some(value) => CachedXattr::Value(value.clone()),
+                None => CachedXattr::Absent,
+            })
+        })
+    }
+
+    /// Remembers the value of the attribute `name` of `inode`, or that it does not exist if
+    /// `value` is `None`.
+    pub fn insert(&self, inode: Inode, generation: u64, name: &CStr, value: Option<&[u8]>) {
+        if value.is_some_and(|v| v.len() > MAX_VALUE_SIZE) {
+            return;
+        }
+
+        self.with_entry_mut(inode, generation, |e| {
+            if e.values.len() >= MAX_NAMES_PER_INODE && !e.values.contains_key(name) {
+                return;
+            }
+            e.values.insert(name.into(), value.map(|v| v.to_vec()));
+        });
+    }
+
+    /// Forgets the value of the attribute `name` of `inode`, unless it is known not to exist.  Use
+    /// this after the attribute may have been removed behind our back.
+    pub fn forget_value(&self, inode: Inode, name: &CStr) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut shard = self.shard(inode).lock().unwrap();
+        let Shard { inodes, generation } = &mut *shard;
+        match inodes.get_mut(&inode) {
+            Some(entry) if entry.values.get(name) == Some(&None) => (),
+            Some(entry) => {
+                entry.values.remove(name);
+                entry.list = None;
+                *generation += 1;
+            }
+            // There may be a lookup in progress.
+            None => *generation += 1,
+        }
+    }
+
+    /// Returns the cached list of attribute names of `inode`.
+    pub fn get_list(&self, inode: Inode) -> Option<Vec<u8>> {
+        self.with_entry(inode, |e| e.list.clone())
+    }
+
+    /// Remembers the list of attribute names of `inode`.
+    pub fn insert_list(&self, inode: Inode, generation: u64, list: &[u8]) {
+        if list.len() > MAX_VALUE_SIZE {
+            return;
+        }
+
+        self.with_entry_mut(inode, generation, |e| e.list = Some(list.to_vec()));
+    }
+
+    /// Forgets everything about the attributes of `inode`.
+    pub fn invalidate(&self, inode: Inode) {
+        if self.is_enabled() {
+            let mut shard = self.shard(inode).lock().unwrap();
+            shard.inodes.remove(&inode);
+            shard.generation += 1;
+        }
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.inodes.clear();
+            shard.generation += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = XattrCache::new(Duration::ZERO);
+        cache.insert(1, 0, &name("user.a"), Some(b"x"));
+        assert_eq!(cache.get(1, &name("user.a")), None);
+    }
+
+    #[test]
+    fn values_and_invalidation() {
+        let cache = XattrCache::new(Duration::from_secs(60));
+        let a = name("user.a");
+        let cap = name("security.capability");
+
+        let generation = cache.generation(1);
+        cache.insert(1, generation, &a, Some(b"x"));
+        cache.insert(1, generation, &cap, None);
+        cache.insert_list(1, generation, b"user.a\0");
+        assert_eq!(cache.get(1, &a), Some(CachedXattr::Value(b"x".to_vec())));
+        assert_eq!(cache.get(1, &cap), Some(CachedXattr::Absent));
+        assert_eq!(cache.get(2, &a), None);
+
+        // Negative entries survive `forget_value()`, values do not.
+        cache.forget_value(1, &cap);
+        assert_eq!(cache.get(1, &cap), Some(CachedXattr::Absent));
+        cache.forget_value(1, &a);
+        assert_eq!(cache.get(1, &a), None);
+        assert_eq!(cache.get_list(1), None);
+
+        cache.invalidate(1);
+        assert_eq!(cache.get(1, &cap), None);
+
+        // Results of lookups that started before an invalidation are not cached.
+        cache.insert(1, generation, &a, Some(b"x"));
+        assert_eq!(cache.get(1, &a), None);
+    }
+
+    #[test]
+    fn expiry_and_bounds() {
+        let cache = XattrCache::new(Duration::from_millis(1));
+        cache.insert(1, 0, &name("user.a"), None);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.get(1, &name("user.a")), None);
+
+        let cache = XattrCache::new(Duration::from_secs(60));
+        cache.insert(1, 0, &name("user.big"), Some(&[0; MAX_VALUE_SIZE + 1]));
+        assert_eq!(cache.get(1, &name("user.big")), None);
+
+        for inode in 0..(SHARDS * MAX_INODES_PER_SHARD * 2) as Inode {
+            cache.insert(inode, 0, &name("user.a"), None);
+        }
+        let cached: usize = cache
+            .shards
+            .iter()
+            .map(|s| s.lock().unwrap().inodes.len())
+            .sum();
+        assert_eq!(cached, SHARDS * MAX_INODES_PER_SHARD);
+    }
+}
@@ -0,0 +1,600 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Recording of the FUSE requests handled by a `Server` and their replies, and replay of such a
+//! recording against a (possibly different) file system.
+//!
+//! A recording starts with a `FileHeader`, followed by a sequence of entries.  Each entry consists
+//! of an `EntryHeader` and `EntryHeader::len` bytes of data:
+//!
+//! - A request entry holds the raw request as read from the descriptor chain (i.e., the
+//!   `InHeader` followed by the body).  `EntryHeader::capacity` is the size of the writable part
+//!   of the chain, so that the request can be replayed with the same amount of room for the reply.
+//! - A reply entry holds the raw reply as written to the descriptor chain, and is associated with
+//!   its request through `EntryHeader::id`.  Requests that do not get a reply (e.g. `FORGET`)
+//!   have an empty reply entry.
+//! - A failure entry is written instead of a reply entry when `Server::handle_message()` returned
+//!   an error (e.g. because the request was malformed).
+//!
+//! All integers are stored in the host's byte order, so recordings can only be replayed on hosts
+//! with the same endianness.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use vhost::vhost_user::Backend;
+use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+use virtio_queue::{DescriptorChain, Queue, QueueOwnedT, QueueT};
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap, Le16, Le32, Le64};
+
+use crate::descriptor_utils::{Reader, Writer};
+use crate::filesystem::FileSystem;
+use crate::fuse::{InHeader, Opcode, OutHeader};
+use crate::server::{Server, FUSE_BUFFER_HEADER_SIZE};
+
+const MAGIC: [u8; 8] = *b"VFSDREC\0";
+const VERSION: u32 = 1;
+
+const DESC_TABLE_ADDR: u64 = 0;
+const AVAIL_RING_ADDR: u64 = 0x1000;
+const BUFFERS_ADDR: u64 = 0x1_0000;
+
+/// A split virtqueue descriptor, as laid out in guest memory.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct Descriptor {
+    addr: Le64,
+    len: Le32,
+    flags: Le16,
+    next: Le16,
+}
+unsafe impl ByteValued for Descriptor {}
+
+/// The available ring of a split virtqueue holding a single descriptor chain.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct AvailRing {
+    flags: Le16,
+    idx: Le16,
+    ring: [Le16; 1],
+}
+unsafe impl ByteValued for AvailRing {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct FileHeader {
+    magic: [u8; 8],
+    version: u32,
+    padding: u32,
+    /// Wall clock time at which the recording was started, in nanoseconds since the UNIX epoch.
+    start_time: u64,
+}
+unsafe impl ByteValued for FileHeader {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct EntryHeader {
+    /// Time since the start of the recording, in nanoseconds.
+    timestamp: u64,
+    /// Sequence number of the request this entry belongs to.
+    id: u64,
+    kind: u32,
+    /// Number of data bytes following this header.
+    len: u32,
+    /// Size of the writable part of the descriptor chain (only for requests).
+    capacity: u32,
+    padding: u32,
+}
+unsafe impl ByteValued for EntryHeader {}
+
+const KIND_REQUEST: u32 = 1;
+const KIND_REPLY: u32 = 2;
+const KIND_FAILURE: u32 = 3;
+
+/// The kind of an `Entry` in a recording.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A request that came with `capacity` bytes of room for the reply.
+    Request { capacity: u32 },
+    /// The reply to a request.
+    Reply,
+    /// `Server::handle_message()` failed to handle the request.
+    Failure,
+}
+
+/// A single entry of a recording.
+#[derive(Debug)]
+pub struct Entry {
+    /// Time since the start of the recording.
+    pub timestamp: Duration,
+    /// Sequence number of the request this entry belongs to.
+    pub id: u64,
+    pub kind: EntryKind,
+    /// The raw request or reply.
+    pub data: Vec<u8>,
+}
+
+struct RecorderState {
+    file: File,
+    next_id: u64,
+}
+
+/// Writes the requests handled by a `Server` and their replies to a file.
+pub struct Recorder {
+    state: Mutex<RecorderState>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Starts a new recording in `file`, which is expected to be empty.
+    pub fn new(mut file: File) -> io::Result<Recorder> {
+        let start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let header = FileHeader {
+            magic: MAGIC,
+            version: VERSION,
+            padding: 0,
+            start_time: start_time.as_nanos() as u64,
+        };
+        file.write_all(header.as_slice())?;
+
+        Ok(Recorder {
+            state: Mutex::new(RecorderState { file, next_id: 0 }),
+            start: Instant::now(),
+        })
+    }
+
+    /// Records `request`, and returns the id to pass to `record_reply()` for its reply.
+    pub(crate) fn record_request(&self, request: &[u8], capacity: usize) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+
+        let kind = EntryKind::Request {
+            capacity: u32::try_from(capacity).unwrap_or(u32::MAX),
+        };
+        self.write_entry(&mut state.file, id, kind, request);
+        id
+    }
+
+    /// Records the reply to request `id`, or its failure if `reply` is `None`.
+    pub(crate) fn record_reply(&self, id: u64, reply: Option<&[u8]>) {
+        let mut state = self.state.lock().unwrap();
+        match reply {
+            Some(reply) => self.write_entry(&mut state.file, id, EntryKind::Reply, reply),
+            None => self.write_entry(&mut state.file, id, EntryKind::Failure, &[]),
+        }
+    }
+
+    fn write_entry(&self, file: &mut File, id: u64, kind: EntryKind, data: &[u8]) {
+        let (kind, capacity) = match kind {
+            EntryKind::Request { capacity } => (KIND_REQUEST, capacity),
+            EntryKind::Reply => (KIND_REPLY, 0),
+            EntryKind::Failure => (KIND_FAILURE, 0),
+        };
+        let header = EntryHeader {
+            timestamp: self.start.elapsed().as_nanos() as u64,
+            id,
+            kind,
+            len: data.len() as u32,
+            capacity,
+            padding: 0,
+        };
+
+        // Write the entry with a single call, so that a crash leaves at most one incomplete entry
+        // at the end of the file.
+        let mut buf = Vec::with_capacity(size_of::<EntryHeader>() + data.len());
+        buf.extend_from_slice(header.as_slice());
+        buf.extend_from_slice(data);
+        if let Err(e) = file.write_all(&buf) {
+            warn!("Failed to write to the request recording: {}", e);
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads the entries of a recording written by a `Recorder`.
+pub struct RecordingReader<R: Read> {
+    inner: R,
+    /// Wall clock time at which the recording was started.
+    pub start_time: SystemTime,
+}
+
+impl<R: Read> RecordingReader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = FileHeader::default();
+        inner.read_exact(header.as_mut_slice())?;
+        if header.magic != MAGIC {
+            return Err(invalid_data("not a virtiofsd recording"));
+        }
+        if header.version != VERSION {
+            return Err(invalid_data("unsupported recording version"));
+        }
+
+        Ok(RecordingReader {
+            inner,
+            start_time: UNIX_EPOCH + Duration::from_nanos(header.start_time),
+        })
+    }
+
+    /// Returns the next entry, or `None` at the end of the recording.  An incomplete entry at the
+    /// end of the recording (e.g. because the daemon was killed) is ignored.
+    pub fn next_entry(&mut self) -> io::Result<Option<Entry>> {
+        let mut header = EntryHeader::default();
+        if !read_exact_or_eof(&mut self.inner, header.as_mut_slice())? {
+            return Ok(None);
+        }
+
+        let kind = match header.kind {
+            KIND_REQUEST => EntryKind::Request {
+                capacity: header.capacity,
+            },
+            KIND_REPLY => EntryKind::Reply,
+            KIND_FAILURE => EntryKind::Failure,
+            _ => return Err(invalid_data("unknown recording entry kind")),
+        };
+
+        let mut data = vec![0u8; header.len as usize];
+        if !read_exact_or_eof(&mut self.inner, &mut data)? {
+            return Ok(None);
+        }
+
+        Ok(Some(Entry {
+            timestamp: Duration::from_nanos(header.timestamp),
+            id: header.id,
+            kind,
+            data,
+        }))
+    }
+}
+
+/// Like `Read::read_exact()`, but returns `Ok(false)` if EOF is reached before `buf` is filled.
+fn read_exact_or_eof<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match r.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Summary of a replay.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// Number of requests that were replayed.
+    pub requests: usize,
+    /// Number of requests whose reply was compared with the recorded one (the recording may end
+    /// before the reply to a request was written).
+    pub compared: usize,
+    /// Number of replies that differ from the recorded ones.
+    pub mismatches: usize,
+}
+
+/// The outcome of handling a request, as recorded or replayed.
+#[derive(PartialEq, Eq)]
+enum Outcome {
+    Reply(Vec<u8>),
+    Failure,
+}
+
+struct RequestInfo(InHeader);
+
+impl fmt::Display for RequestInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let h = &self.0;
+        match Opcode::try_from(h.opcode) {
+            Ok(opcode) => write!(f, "{opcode:?}")?,
+            Err(_) => write!(f, "opcode {}", h.opcode)?,
+        }
+        write!(f, " (unique={}, nodeid={})", h.unique, h.nodeid)
+    }
+}
+
+fn reply_error(reply: &[u8]) -> Option<i32> {
+    reply
+        .get(..size_of::<OutHeader>())
+        .and_then(OutHeader::from_slice)
+        .map(|h| h.error)
+}
+
+/// Feeds all requests in `recording` to `server`, in the order in which they were recorded, and
+/// writes a description of every reply that differs from the recorded one to `out`.
+///
+/// Note that some replies are expected to differ unless the file system is in exactly the same
+/// state as when the recording was made, e.g. the timestamps in the attributes of a file.
+pub fn replay<F: FileSystem + Sync, R: Read, W: Write>(
+    server: &Server<F>,
+    recording: R,
+    out: &mut W,
+) -> io::Result<ReplayStats> {
+    let mut reader = RecordingReader::new(recording)?;
+
+    // Replies are recorded when they are sent, which with a thread pool is not necessarily in the
+    // order of the requests, so read the whole recording first.
+    let mut requests = Vec::new();
+    let mut outcomes = HashMap::new();
+    while let Some(entry) = reader.next_entry()? {
+        match entry.kind {
+            EntryKind::Request { capacity } => requests.push((entry.id, entry.data, capacity)),
+            EntryKind::Reply => {
+                outcomes.insert(entry.id, Outcome::Reply(entry.data));
+            }
+            EntryKind::Failure => {
+                outcomes.insert(entry.id, Outcome::Failure);
+            }
+        }
+    }
+
+    let mut stats = ReplayStats::default();
+    for (id, request, capacity) in requests {
+        let replayed = replay_request(server, &request, capacity)?;
+        stats.requests += 1;
+
+        let recorded = match outcomes.get(&id) {
+            Some(recorded) => recorded,
+            None => continue,
+        };
+        stats.compared += 1;
+        if *recorded == replayed {
+            continue;
+        }
+        stats.mismatches += 1;
+
+        let info = match request
+            .get(..size_of::<InHeader>())
+            .and_then(InHeader::from_slice)
+        {
+            Some(header) => RequestInfo(*header).to_string(),
+            None => "malformed request".to_owned(),
+        };
+        match (recorded, &replayed) {
+            (Outcome::Reply(recorded), Outcome::Reply(replayed)) => {
+                let (recorded_err, replayed_err) = (reply_error(recorded), reply_error(replayed));
+                if recorded_err != replayed_err {
+                    writeln!(
+                        out,
+                        "#{id} {info}: recorded error {recorded_err:?}, replayed error {replayed_err:?}"
+                    )?;
+                } else {
+                    let offset = recorded
+                        .iter()
+                        .zip(replayed.iter())
+                        .position(|(a, b)| a != b)
+                        .unwrap_or_else(|| recorded.len().min(replayed.len()));
+                    writeln!(
+                        out,
+                        "#{id} {info}: replies differ at offset {offset} (recorded {} bytes, replayed {} bytes)",
+                        recorded.len(),
+                        replayed.len()
+                    )?;
+                }
+            }
+            (Outcome::Reply(_), Outcome::Failure) => {
+                writeln!(out, "#{id} {info}: recorded a reply, replay failed")?
+            }
+            (Outcome::Failure, Outcome::Reply(_)) => {
+                writeln!(out, "#{id} {info}: recording failed, replayed a reply")?
+            }
+            (Outcome::Failure, Outcome::Failure) => unreachable!(),
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Passes `request` to `server` in a descriptor chain with `capacity` writable bytes.
+///
+/// Both are bounded by the largest message `server` accepts, so a damaged recording cannot make
+/// us allocate arbitrary amounts of memory for the chain.
+fn replay_request<F: FileSystem + Sync>(
+    server: &Server<F>,
+    request: &[u8],
+    capacity: u32,
+) -> io::Result<Outcome> {
+    let max_size = (server.max_buffer_size() + FUSE_BUFFER_HEADER_SIZE) as usize;
+    if request.len() > max_size || capacity as usize > max_size {
+        return Err(invalid_data(
+            "recorded request exceeds the maximum message size",
+        ));
+    }
+
+    let mem_size = BUFFERS_ADDR as usize + request.len() + capacity as usize;
+    let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), mem_size)])
+        .map_err(|e| io::Error::other(format!("failed to create guest memory: {e}")))?;
+    mem.write_slice(request, GuestAddress(BUFFERS_ADDR))
+        .map_err(|e| io::Error::other(format!("failed to write the request: {e}")))?;
+
+    let chain = match request_chain(&mem, request.len() as u32, capacity) {
+        Some(chain) => chain,
+        None => return Ok(Outcome::Failure),
+    };
+    let (reader, writer) = match (Reader::new(&mem, chain.clone()), Writer::new(&mem, chain)) {
+        (Ok(reader), Ok(writer)) => (reader, writer),
+        _ => return Ok(Outcome::Failure),
+    };
+
+    match server.handle_message(reader, writer, None::<&mut Backend>) {
+        Ok(len) => {
+            let mut reply = vec![0u8; len];
+            mem.read_slice(
+                &mut reply,
+                GuestAddress(BUFFERS_ADDR + request.len() as u64),
+            )
+            .map_err(|e| io::Error::other(format!("failed to read the reply: {e}")))?;
+            Ok(Outcome::Reply(reply))
+        }
+        Err(_) => Ok(Outcome::Failure),
+    }
+}
+
+/// Sets up a descriptor chain in `mem` with a readable descriptor of `len` bytes at
+/// `BUFFERS_ADDR`, followed by a writable one of `capacity` bytes, either of which is left out if
+/// empty.  Returns `None` if no chain could be set up.
+fn request_chain(
+    mem: &GuestMemoryMmap,
+    len: u32,
+    capacity: u32,
+) -> Option<DescriptorChain<&GuestMemoryMmap>> {
+    let mut descriptors = Vec::new();
+    if len > 0 {
+        descriptors.push((BUFFERS_ADDR, len, 0));
+    }
+    if capacity > 0 {
+        descriptors.push((
+            BUFFERS_ADDR + len as u64,
+            capacity,
+            VRING_DESC_F_WRITE as u16,
+        ));
+    }
+
+    if descriptors.is_empty() {
+        return None;
+    }
+
+    let count = descriptors.len();
+    for (index, (addr, len, mut flags)) in descriptors.into_iter().enumerate() {
+        if index + 1 < count {
+            flags |= VRING_DESC_F_NEXT as u16;
+        }
+        let desc = Descriptor {
+            addr: addr.into(),
+            len: len.into(),
+            flags: flags.into(),
+            next: (index as u16 + 1).into(),
+        };
+        let desc_addr = DESC_TABLE_ADDR + (index * size_of::<Descriptor>()) as u64;
+        mem.write_obj(desc, GuestAddress(desc_addr)).ok()?;
+    }
+    let avail = AvailRing {
+        flags: 0.into(),
+        idx: 1.into(),
+        ring: [0.into()],
+    };
+    mem.write_obj(avail, GuestAddress(AVAIL_RING_ADDR)).ok()?;
+
+    let mut queue = Queue::new(2).ok()?;
+    queue
+        .try_set_desc_table_address(GuestAddress(DESC_TABLE_ADDR))
+        .ok()?;
+    queue
+        .try_set_avail_ring_address(GuestAddress(AVAIL_RING_ADDR))
+        .ok()?;
+    queue.set_ready(true);
+    let mut iter = queue.iter(mem).ok()?;
+    iter.next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuse::{self, FsOptions};
+    use crate::passthrough::{self, PassthroughFs};
+    use crate::test_client::{FuseClient, TempDir};
+    use std::fs;
+
+    fn passthrough_fs(dir: &TempDir) -> PassthroughFs {
+        let cfg = passthrough::Config {
+            root_dir: dir.path().to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        PassthroughFs::new(cfg).unwrap()
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let dir = TempDir::new();
+        let path = dir.path().join("recording");
+
+        let recorder = Recorder::new(File::create(&path).unwrap()).unwrap();
+        let id = recorder.record_request(b"request", 42);
+        recorder.record_reply(id, Some(b"reply"));
+        let id = recorder.record_request(b"bad", 0);
+        recorder.record_reply(id, None);
+        drop(recorder);
+
+        // Simulate a daemon that was killed while writing an entry.
+        let mut data = fs::read(&path).unwrap();
+        data.extend_from_slice(&[0u8; 10]);
+
+        let mut reader = RecordingReader::new(&data[..]).unwrap();
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            entries.push((entry.id, entry.kind, entry.data));
+        }
+        assert_eq!(
+            entries,
+            [
+                (0, EntryKind::Request { capacity: 42 }, b"request".to_vec()),
+                (0, EntryKind::Reply, b"reply".to_vec()),
+                (1, EntryKind::Request { capacity: 0 }, b"bad".to_vec()),
+                (1, EntryKind::Failure, Vec::new()),
+            ]
+        );
+
+        assert!(RecordingReader::new(&b"not a recording at all"[..]).is_err());
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let recording = TempDir::new();
+        let path = recording.path().join("recording");
+
+        let dir = TempDir::new();
+        fs::write(dir.path().join("file"), b"").unwrap();
+
+        let mut server = Server::new(passthrough_fs(&dir));
+        server.set_recorder(Recorder::new(File::create(&path).unwrap()).unwrap());
+        let mut client = FuseClient::from_server(server);
+        client.init(FsOptions::empty()).unwrap();
+        client.lookup(fuse::ROOT_ID, "missing").unwrap_err();
+        client.lookup(fuse::ROOT_ID, "file").unwrap();
+        drop(client);
+
+        // Replaying against the same directory reproduces all replies.  The attributes returned
+        // by LOOKUP match, too, since the file has not been changed.
+        let server = Server::new(passthrough_fs(&dir));
+        let mut out = Vec::new();
+        let stats = replay(&server, File::open(&path).unwrap(), &mut out).unwrap();
+        assert_eq!(
+            stats,
+            ReplayStats {
+                requests: 3,
+                compared: 3,
+                mismatches: 0
+            }
+        );
+        assert!(out.is_empty());
+
+        // Against an empty directory, the lookup of "file" fails.
+        let empty = TempDir::new();
+        let server = Server::new(passthrough_fs(&empty));
+        let mut out = Vec::new();
+        let stats = replay(&server, File::open(&path).unwrap(), &mut out).unwrap();
+        assert_eq!(stats.mismatches, 1);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("#2 Lookup (unique=3, nodeid=1): recorded error Some(0)"));
+    }
+
+    #[test]
+    fn replay_oversized_request() {
+        let recording = TempDir::new();
+        let path = recording.path().join("recording");
+        let recorder = Recorder::new(File::create(&path).unwrap()).unwrap();
+        recorder.record_request(b"request", u32::MAX as usize);
+        drop(recorder);
+
+        // The request is rejected instead of setting up 4 GiB of guest memory for it.
+        let dir = TempDir::new();
+        let server = Server::new(passthrough_fs(&dir));
+        let err = replay(&server, File::open(&path).unwrap(), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
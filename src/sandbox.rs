@@ -29,6 +29,8 @@ pub enum Error {
     ChrootChdir(io::Error),
     /// Failed to clean the properties of the mount point.
     CleanMount(io::Error),
+    /// Failed to create the user namespace for the idmapped mount.
+    CreateIdmapUserns(io::Error),
     /// Failed to create a temporary directory.
     CreateTempDir(io::Error),
     /// Failed to drop supplemental groups.
@@ -37,6 +39,8 @@ pub enum Error {
     Fork(io::Error),
     /// Failed to get the number of supplemental groups.
     GetSupplementalGroups(io::Error),
+    /// Failed to idmap the clone of the shared directory.
+    IdmapMount(io::Error),
     /// Error bind-mounting a directory.
     MountBind(io::Error),
     /// Failed to mount old root.
@@ -47,6 +51,8 @@ pub enum Error {
     MountNewRoot(io::Error),
     /// Error mounting target directory.
     MountTarget(io::Error),
+    /// Failed to attach the idmapped clone of the shared directory.
+    MoveMount(io::Error),
     /// Failed to open `/proc/self/mountinfo`.
     OpenMountinfo(io::Error),
     /// Failed to open new root.
@@ -57,6 +63,8 @@ pub enum Error {
     OpenProcSelf(io::Error),
     /// Failed to open `/proc/self/fd`.
     OpenProcSelfFd(io::Error),
+    /// Failed to clone the mount tree of the shared directory.
+    OpenTree(io::Error),
     /// Error switching root directory.
     PivotRoot(io::Error),
     /// Failed to remove temporary directory.
@@ -75,6 +83,8 @@ pub enum Error {
     WriteUidMap(String),
     /// Sandbox mode unavailable for non-privileged users
     SandboxModeInvalidUID,
+    /// Idmap sandbox mode unavailable for non-privileged users
+    SandboxModeIdmapInvalidUID,
     /// Setting uid_map is only allowed inside a namespace for non-privileged users
     SandboxModeInvalidUidMap,
     /// Setting gid_map is only allowed inside a namespace for non-privileged users
@@ -86,8 +96,8 @@ impl error::Error for Error {}
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use self::Error::{
-            SandboxModeInvalidGidMap, SandboxModeInvalidUID, SandboxModeInvalidUidMap, WriteGidMap,
-            WriteUidMap,
+            SandboxModeIdmapInvalidUID, SandboxModeInvalidGidMap, SandboxModeInvalidUID,
+            SandboxModeInvalidUidMap, WriteGidMap, WriteUidMap,
         };
         match self {
             SandboxModeInvalidUID => {
@@ -97,18 +107,25 @@ impl fmt::Display for Error {
                     root (Use '--sandbox namespace' instead)"
                 )
             }
+            SandboxModeIdmapInvalidUID => {
+                write!(
+                    f,
+                    "sandbox mode 'idmap' can only be used by \
+                    root (Use '--sandbox namespace' instead)"
+                )
+            }
             SandboxModeInvalidUidMap => {
                 write!(
                     f,
-                    "uid_map can only be used by unprivileged user where sandbox mod is namespace \
-                    (Use '--sandbox namespace' instead)"
+                    "uid_map can only be used by unprivileged user where sandbox mod is namespace, \
+                    or by root where sandbox mode is idmap"
                 )
             }
             SandboxModeInvalidGidMap => {
                 write!(
                     f,
-                    "gid_map can only be used by unprivileged user where sandbox mod is namespace \
-                    (Use '--sandbox namespace' instead)"
+                    "gid_map can only be used by unprivileged user where sandbox mod is namespace, \
+                    or by root where sandbox mode is idmap"
                 )
             }
             WriteUidMap(msg) => write!(f, "write to uid map failed: {msg}"),
@@ -125,6 +142,9 @@ pub enum SandboxMode {
     Namespace,
     /// Create the sandbox using chroot.
     Chroot,
+    /// Create the sandbox using Linux namespaces, with an idmapped mount of the shared directory
+    /// translating ownership according to the uid/gid maps.
+    Idmap,
     /// Don't attempt to isolate the process inside a sandbox.
     None,
 }
@@ -135,6 +155,7 @@ impl FromStr for SandboxMode {
         match s.to_lowercase().as_str() {
             "namespace" => Ok(SandboxMode::Namespace),
             "chroot" => Ok(SandboxMode::Chroot),
+            "idmap" => Ok(SandboxMode::Idmap),
             "none" => Ok(SandboxMode::None),
             _ => Err("Unknown sandbox mode"),
         }
@@ -152,18 +173,20 @@ pub struct Sandbox {
     mountinfo_fd: Option<File>,
     /// Mechanism to be used for setting up the sandbox.
     sandbox_mode: SandboxMode,
-    /// UidMap to be used for `newuidmap(1)` command line arguments
-    uid_map: Option<UidMap>,
-    /// GidMap to be used for `newgidmap(1)` command line arguments
-    gid_map: Option<GidMap>,
+    /// UidMaps to be used for `newuidmap(1)` command line arguments
+    uid_map: Vec<UidMap>,
+    /// GidMaps to be used for `newgidmap(1)` command line arguments
+    gid_map: Vec<GidMap>,
+    /// The user namespace the shared directory is idmapped with in `SandboxMode::Idmap`.
+    idmap_userns: Option<File>,
 }
 
 impl Sandbox {
     pub fn new(
         shared_dir: String,
         sandbox_mode: SandboxMode,
-        uid_map: Option<UidMap>,
-        gid_map: Option<GidMap>,
+        uid_map: Vec<UidMap>,
+        gid_map: Vec<GidMap>,
     ) -> io::Result<Self> {
         let shared_dir_rp = fs::canonicalize(shared_dir)?;
         let shared_dir_rp_str = shared_dir_rp
@@ -177,6 +200,7 @@ impl Sandbox {
             sandbox_mode,
             uid_map,
             gid_map,
+            idmap_userns: None,
         })
     }
 
@@ -230,14 +254,23 @@ impl Sandbox {
         // Safe because we just opened this fd.
         self.proc_self_fd = Some(unsafe { File::from_raw_fd(proc_self_fd) });
 
-        // Bind-mount `self.shared_dir` on itself so we can use as new root on `pivot_root` syscall.
-        oslib::mount(
-            self.shared_dir.as_str().into(),
-            self.shared_dir.as_str(),
-            None,
-            libc::MS_BIND | libc::MS_REC,
-        )
-        .map_err(Error::BindMountSharedDir)?;
+        if let Some(userns) = self.idmap_userns.take() {
+            // Attach an idmapped clone of `self.shared_dir` on itself instead of a plain bind
+            // mount, so the ownership of all files is translated by the kernel.
+            let tree = oslib::open_tree_clone(&self.shared_dir).map_err(Error::OpenTree)?;
+            oslib::mount_setattr_idmap(&tree, &userns).map_err(Error::IdmapMount)?;
+            oslib::move_mount(&tree, &self.shared_dir).map_err(Error::MoveMount)?;
+        } else {
+            // Bind-mount `self.shared_dir` on itself so we can use as new root on `pivot_root`
+            // syscall.
+            oslib::mount(
+                self.shared_dir.as_str().into(),
+                self.shared_dir.as_str(),
+                None,
+                libc::MS_BIND | libc::MS_REC,
+            )
+            .map_err(Error::BindMountSharedDir)?;
+        }
 
         // Get a file descriptor to our old root so we can reference it after switching root.
         let c_root_dir = CString::new("/").unwrap();
@@ -308,89 +341,180 @@ impl Sandbox {
         Ok(())
     }
 
-    /// Sets mappings for the given uid and gid.
+    /// Sets mappings for the given uids and gids.
     fn setup_id_mappings(
         &self,
-        uid_map: Option<UidMap>,
-        gid_map: Option<GidMap>,
+        uid_map: &[UidMap],
+        gid_map: &[GidMap],
         pid: i32,
     ) -> Result<(), Error> {
         let current_uid = unsafe { libc::geteuid() };
         let current_gid = unsafe { libc::getegid() };
-        // Take uid map or set up a 1-to-1 mapping for our current euid.
-        let uid_map = uid_map.unwrap_or(UidMap {
+        // Take uid maps or set up a 1-to-1 mapping for our current euid.
+        let default_uid_map = [UidMap {
             outside_uid: current_uid,
             inside_uid: current_uid,
             count: 1,
-        });
+        }];
+        let uid_map = if uid_map.is_empty() {
+            &default_uid_map
+        } else {
+            uid_map
+        };
 
-        // Take gid map or set up a 1-to-1 mapping for our current gid.
-        let gid_map = gid_map.unwrap_or(GidMap {
+        // Take gid maps or set up a 1-to-1 mapping for our current gid.
+        let default_gid_map = [GidMap {
             outside_gid: current_gid,
             inside_gid: current_gid,
             count: 1,
-        });
+        }];
+        let gid_map = if gid_map.is_empty() {
+            &default_gid_map
+        } else {
+            gid_map
+        };
 
         // Unprivileged user can not set any mapping without any restriction.
         // Therefore, newuidmap/newgidmap is used instead of writing directly
         // into proc/[pid]/{uid,gid}_map if a potentially privileged action is
-        // requested (outside {u,g}id != e{u,g}id or count > 1).
-        if uid_map.outside_uid != current_uid || uid_map.count > 1 {
-            let mut newuidmap = Command::new("newuidmap");
-            newuidmap.arg(pid.to_string());
-            newuidmap.arg(uid_map.inside_uid.to_string());
-            newuidmap.arg(uid_map.outside_uid.to_string());
-            newuidmap.arg(uid_map.count.to_string());
-            let output = newuidmap.output().map_err(|_| {
-                Error::WriteUidMap(format!(
-                    "failed to execute newuidmap: {}",
-                    io::Error::last_os_error()
-                ))
-            })?;
-            if !output.status.success() {
-                return Err(Error::WriteUidMap(
-                    String::from_utf8_lossy(&output.stderr).to_string(),
-                ));
+        // requested (several ranges, outside {u,g}id != e{u,g}id or count > 1).
+        match uid_map {
+            [m] if m.outside_uid == current_uid && m.count == 1 => {
+                // Unprivileged part, we can driectly write to /proc/[pid]/uid_map.
+                std::fs::write(
+                    format!("/proc/{pid}/uid_map"),
+                    format!("{} {} 1", m.inside_uid, m.outside_uid),
+                )
+                .map_err(|e| Error::WriteUidMap(e.to_string()))?;
+            }
+            _ => {
+                let mut newuidmap = Command::new("newuidmap");
+                newuidmap.arg(pid.to_string());
+                for m in uid_map {
+                    newuidmap.arg(m.inside_uid.to_string());
+                    newuidmap.arg(m.outside_uid.to_string());
+                    newuidmap.arg(m.count.to_string());
+                }
+                let output = newuidmap.output().map_err(|_| {
+                    Error::WriteUidMap(format!(
+                        "failed to execute newuidmap: {}",
+                        io::Error::last_os_error()
+                    ))
+                })?;
+                if !output.status.success() {
+                    return Err(Error::WriteUidMap(
+                        String::from_utf8_lossy(&output.stderr).to_string(),
+                    ));
+                }
             }
-        } else {
-            // Unprivileged part, we can driectly write to /proc/[pid]/uid_map.
-            std::fs::write(
-                format!("/proc/{pid}/uid_map"),
-                format!("{} {} 1", uid_map.inside_uid, uid_map.outside_uid),
-            )
-            .map_err(|e| Error::WriteUidMap(e.to_string()))?;
         }
 
-        if gid_map.outside_gid != current_gid || gid_map.count > 1 {
-            let mut newgidmap = Command::new("newgidmap");
-            newgidmap.arg(pid.to_string());
-            newgidmap.arg(gid_map.inside_gid.to_string());
-            newgidmap.arg(gid_map.outside_gid.to_string());
-            newgidmap.arg(gid_map.count.to_string());
-            let output = newgidmap.output().map_err(|_| {
-                Error::WriteGidMap(format!(
-                    "failed to execute newgidmap: {}",
-                    io::Error::last_os_error()
-                ))
-            })?;
-            if !output.status.success() {
-                return Err(Error::WriteGidMap(
-                    String::from_utf8_lossy(&output.stderr).to_string(),
-                ));
-            }
-        } else {
-            // Unprivileged part, we can driectly write to /proc/[pid]/gid_map.
-            std::fs::write(format!("/proc/{pid}/setgroups"), b"deny")
+        match gid_map {
+            [m] if m.outside_gid == current_gid && m.count == 1 => {
+                // Unprivileged part, we can driectly write to /proc/[pid]/gid_map.
+                std::fs::write(format!("/proc/{pid}/setgroups"), b"deny")
+                    .map_err(|e| Error::WriteGidMap(e.to_string()))?;
+                std::fs::write(
+                    format!("/proc/{pid}/gid_map"),
+                    format!("{} {} 1", m.inside_gid, m.outside_gid),
+                )
                 .map_err(|e| Error::WriteGidMap(e.to_string()))?;
-            std::fs::write(
-                format!("/proc/{pid}/gid_map"),
-                format!("{} {} 1", gid_map.inside_gid, gid_map.outside_gid),
-            )
-            .map_err(|e| Error::WriteGidMap(e.to_string()))?;
+            }
+            _ => {
+                let mut newgidmap = Command::new("newgidmap");
+                newgidmap.arg(pid.to_string());
+                for m in gid_map {
+                    newgidmap.arg(m.inside_gid.to_string());
+                    newgidmap.arg(m.outside_gid.to_string());
+                    newgidmap.arg(m.count.to_string());
+                }
+                let output = newgidmap.output().map_err(|_| {
+                    Error::WriteGidMap(format!(
+                        "failed to execute newgidmap: {}",
+                        io::Error::last_os_error()
+                    ))
+                })?;
+                if !output.status.success() {
+                    return Err(Error::WriteGidMap(
+                        String::from_utf8_lossy(&output.stderr).to_string(),
+                    ));
+                }
+            }
         }
         Ok(())
     }
 
+    /// Creates the user namespace for the idmapped mount of `SandboxMode::Idmap`.
+    ///
+    /// Ids inside the namespace are the ids on the host, and ids outside of it are the ids the
+    /// guest sees, so that the mount presents host id `outside_uid` of a `UidMap` as
+    /// `inside_uid`, just like `SandboxMode::Namespace` does.  Without maps, ids are not
+    /// translated.
+    fn create_idmap_userns(&self) -> Result<File, Error> {
+        let uid_map: String = self
+            .uid_map
+            .iter()
+            .map(|m| format!("{} {} {}\n", m.outside_uid, m.inside_uid, m.count))
+            .collect();
+        let gid_map: String = self
+            .gid_map
+            .iter()
+            .map(|m| format!("{} {} {}\n", m.outside_gid, m.inside_gid, m.count))
+            .collect();
+        let identity = "0 0 4294967295\n".to_string();
+
+        let (mut x_reader, mut x_writer) = oslib::pipe().map_err(Error::CreateIdmapUserns)?;
+        let (mut y_reader, y_writer) = oslib::pipe().map_err(Error::CreateIdmapUserns)?;
+
+        let pid = util::sfork().map_err(Error::Fork)?;
+        if pid == 0 {
+            // The child only creates the namespace, and keeps it alive until the parent has
+            // opened it, i.e. closed its end of the second pipe.
+            drop(x_reader);
+            drop(y_writer);
+            let ret = unsafe { libc::unshare(libc::CLONE_NEWUSER) };
+            let _ = x_writer.write_all(&[(ret == 0) as u8]);
+            let _ = y_reader.read(&mut [0]);
+            process::exit(0);
+        }
+        drop(x_writer);
+        drop(y_reader);
+
+        let userns = (|| {
+            let mut output = [0];
+            x_reader.read_exact(&mut output)?;
+            if output[0] != 1 {
+                return Err(io::Error::other("cannot create a user namespace"));
+            }
+
+            fs::write(
+                format!("/proc/{pid}/uid_map"),
+                if uid_map.is_empty() {
+                    &identity
+                } else {
+                    &uid_map
+                },
+            )?;
+            fs::write(
+                format!("/proc/{pid}/gid_map"),
+                if gid_map.is_empty() {
+                    &identity
+                } else {
+                    &gid_map
+                },
+            )?;
+            File::open(format!("/proc/{pid}/ns/user"))
+        })()
+        .map_err(Error::CreateIdmapUserns);
+
+        // Let the child terminate.
+        drop(y_writer);
+        let mut status = 0_i32;
+        let _ = unsafe { libc::waitpid(pid, &mut status, 0) };
+
+        userns
+    }
+
     pub fn enter_namespace(&mut self) -> Result<(), Error> {
         let uid = unsafe { libc::geteuid() };
 
@@ -424,9 +548,7 @@ impl Sandbox {
             // Setup uid/gid mappings
             if uid != 0 {
                 let ppid = unsafe { libc::getppid() };
-                if let Err(error) =
-                    self.setup_id_mappings(self.uid_map.clone(), self.gid_map.clone(), ppid)
-                {
+                if let Err(error) = self.setup_id_mappings(&self.uid_map, &self.gid_map, ppid) {
                     // We don't really need to close the pipes here, since the OS will close the FDs
                     // after the process exits. But let's do it explicitly to signal an error to the
                     // other end of the pipe.
@@ -579,11 +701,22 @@ impl Sandbox {
             return Err(Error::SandboxModeInvalidUID);
         }
 
-        if self.uid_map.is_some() && (uid == 0 || self.sandbox_mode != SandboxMode::Namespace) {
+        if uid != 0 && self.sandbox_mode == SandboxMode::Idmap {
+            return Err(Error::SandboxModeIdmapInvalidUID);
+        }
+
+        // Id maps apply to the user namespace of unprivileged users, or to the idmapped mount.
+        let id_maps_allowed = match self.sandbox_mode {
+            SandboxMode::Namespace => uid != 0,
+            SandboxMode::Idmap => true,
+            SandboxMode::Chroot | SandboxMode::None => false,
+        };
+
+        if !self.uid_map.is_empty() && !id_maps_allowed {
             return Err(Error::SandboxModeInvalidUidMap);
         }
 
-        if self.gid_map.is_some() && (uid == 0 || self.sandbox_mode != SandboxMode::Namespace) {
+        if !self.gid_map.is_empty() && !id_maps_allowed {
             return Err(Error::SandboxModeInvalidGidMap);
         }
 
@@ -612,6 +745,12 @@ impl Sandbox {
 
         match self.sandbox_mode {
             SandboxMode::Namespace => self.enter_namespace(),
+            SandboxMode::Idmap => {
+                // The namespace has to be created from the initial user namespace, before
+                // entering the sandbox.
+                self.idmap_userns = Some(self.create_idmap_userns()?);
+                self.enter_namespace()
+            }
             SandboxMode::Chroot => self.enter_chroot(),
             SandboxMode::None => Ok(()),
         }
@@ -627,7 +766,7 @@ impl Sandbox {
 
     pub fn get_root_dir(&self) -> String {
         match self.sandbox_mode {
-            SandboxMode::Namespace | SandboxMode::Chroot => "/".to_string(),
+            SandboxMode::Namespace | SandboxMode::Idmap | SandboxMode::Chroot => "/".to_string(),
             SandboxMode::None => self.shared_dir.clone(),
         }
     }
@@ -636,7 +775,7 @@ impl Sandbox {
     /// accessible in our sandbox
     pub fn get_mountinfo_prefix(&self) -> Option<String> {
         match self.sandbox_mode {
-            SandboxMode::Namespace | SandboxMode::None => None,
+            SandboxMode::Namespace | SandboxMode::Idmap | SandboxMode::None => None,
             SandboxMode::Chroot => Some(self.shared_dir.clone()),
         }
     }
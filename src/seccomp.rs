@@ -69,6 +69,7 @@ pub fn enable_seccomp(action: SeccompAction, allow_remote_logging: bool) -> Resu
     allow_syscall!(ctx, libc::SYS_capget); // For CAP_FSETID
     allow_syscall!(ctx, libc::SYS_capset);
     allow_syscall!(ctx, libc::SYS_clock_gettime);
+    allow_syscall!(ctx, libc::SYS_clock_nanosleep); // For fault injection delays
     allow_syscall!(ctx, libc::SYS_clone);
     allow_syscall!(ctx, libc::SYS_clone3);
     allow_syscall!(ctx, libc::SYS_close);
@@ -92,6 +93,8 @@ pub fn enable_seccomp(action: SeccompAction, allow_remote_logging: bool) -> Resu
     allow_syscall!(ctx, libc::SYS_eventfd2);
     allow_syscall!(ctx, libc::SYS_exit);
     allow_syscall!(ctx, libc::SYS_exit_group);
+    allow_syscall!(ctx, libc::SYS_faccessat2);
+    allow_syscall!(ctx, libc::SYS_fadvise64);
     allow_syscall!(ctx, libc::SYS_fallocate);
     allow_syscall!(ctx, libc::SYS_fchdir);
     allow_syscall!(ctx, libc::SYS_fchmod);
@@ -126,6 +129,9 @@ pub fn enable_seccomp(action: SeccompAction, allow_remote_logging: bool) -> Resu
     allow_syscall!(ctx, libc::SYS_gettid);
     allow_syscall!(ctx, libc::SYS_gettimeofday);
     allow_syscall!(ctx, libc::SYS_getxattr);
+    allow_syscall!(ctx, libc::SYS_io_uring_enter); // For --io-uring
+    allow_syscall!(ctx, libc::SYS_io_uring_register);
+    allow_syscall!(ctx, libc::SYS_io_uring_setup);
     allow_syscall!(ctx, libc::SYS_linkat);
     allow_syscall!(ctx, libc::SYS_listxattr);
     allow_syscall!(ctx, libc::SYS_lseek);
@@ -138,6 +144,7 @@ pub fn enable_seccomp(action: SeccompAction, allow_remote_logging: bool) -> Resu
     allow_syscall!(ctx, libc::SYS_mremap);
     allow_syscall!(ctx, libc::SYS_munmap);
     allow_syscall!(ctx, libc::SYS_name_to_handle_at);
+    allow_syscall!(ctx, libc::SYS_nanosleep); // For fault injection delays
     #[cfg(not(target_arch = "loongarch64"))]
     allow_syscall!(ctx, libc::SYS_newfstatat);
     #[cfg(target_arch = "powerpc64")]
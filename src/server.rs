@@ -5,23 +5,31 @@
 use super::fs_cache_req_handler::FsCacheReqHandler;
 use crate::descriptor_utils::{Reader, Writer};
 use crate::filesystem::{
-    Context, DirEntry, DirectoryIterator, Entry, Extensions, FileSystem, GetxattrReply,
+    AsyncIoOp, Context, DirEntry, DirectoryIterator, Entry, Extensions, FileSystem, GetxattrReply,
     ListxattrReply, SecContext, ZeroCopyReader, ZeroCopyWriter,
 };
 use crate::fuse::*;
 use crate::passthrough::util::einval;
+use crate::record::Recorder;
+use crate::uring::{GuestIovecs, IoOp, Uring};
 use crate::{oslib, Error, Result};
 use std::convert::{TryFrom, TryInto};
 use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::mem::{size_of, MaybeUninit};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::time::Duration;
-use vm_memory::ByteValued;
+use virtio_queue::DescriptorChain;
+use vm_memory::{ByteValued, Bytes, GuestMemory};
 
-const FUSE_BUFFER_HEADER_SIZE: u32 = 0x1000;
+pub(crate) const FUSE_BUFFER_HEADER_SIZE: u32 = 0x1000;
 const MAX_BUFFER_SIZE: u32 = 1 << 20;
+
+// Descriptors a request needs besides the ones for its data: The `InHeader`, the `OutHeader` and
+// the other arguments (see `FUSE_HEADER_OVERHEAD` in the guest's virtio_fs.c).
+const DESCRIPTOR_OVERHEAD: usize = 4;
 const DIRENT_PADDING: [u8; 8] = [0; 8];
 
 const CURRENT_DIR_CSTR: &[u8] = b".";
@@ -65,9 +73,161 @@ impl<'a> io::Write for ZcWriter<'a> {
     }
 }
 
+/// Limits the server announces to the guest in its reply to INIT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InitLimits {
+    /// Maximum size of the data of a single READ or WRITE request, in bytes.  This also determines
+    /// `max_pages`, and the guest may lower it further to what its `max_pages` limit allows.
+    pub max_write: u32,
+
+    /// Maximum number of pending background requests (e.g. readahead and asynchronous direct I/O)
+    /// the guest may have.
+    pub max_background: u16,
+
+    /// Number of pending background requests at which the guest considers the file system
+    /// congested.
+    pub congestion_threshold: u16,
+
+    /// Granularity of timestamps, in nanoseconds.  Must be a power of 10 between 1 and 10^9.
+    pub time_gran: u32,
+
+    /// Log2 of the alignment, in bytes, of the file and memory offsets of DAX mappings.  0 means
+    /// no particular alignment is needed.
+    pub map_alignment: u16,
+}
+
+impl Default for InitLimits {
+    fn default() -> Self {
+        InitLimits {
+            max_write: MAX_BUFFER_SIZE,
+            max_background: u16::MAX,
+            congestion_threshold: (u16::MAX / 4) * 3,
+            time_gran: 1,
+            map_alignment: 0,
+        }
+    }
+}
+
+impl InitLimits {
+    fn page_size() -> u32 {
+        // Safe because sysconf() has no side effects.
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE).try_into().unwrap() }
+    }
+
+    /// Returns the number of pages needed for `max_write` bytes.
+    pub fn max_pages(&self) -> u32 {
+        ((self.max_write - 1) / Self::page_size()) + 1
+    }
+
+    /// Returns the limits with `max_write` lowered to what fits into a descriptor chain of a queue
+    /// with `queue_size` entries, see `validate()`.
+    pub fn fit_queue(&self, queue_size: usize) -> InitLimits {
+        let max_pages = queue_size
+            .saturating_sub(DESCRIPTOR_OVERHEAD)
+            .min(u16::MAX.into()) as u32;
+        InitLimits {
+            max_write: self
+                .max_write
+                .min(max_pages.saturating_mul(Self::page_size()))
+                .max(4096),
+            ..*self
+        }
+    }
+
+    /// Checks that the limits make sense, and that a request with `max_write` bytes of data fits
+    /// into a descriptor chain of a queue with `queue_size` entries, even with one page per
+    /// descriptor.
+    pub fn validate(&self, queue_size: usize) -> std::result::Result<(), String> {
+        if self.max_write < 4096 {
+            return Err(format!(
+                "max_write ({}) must be at least 4096",
+                self.max_write
+            ));
+        }
+        let max_pages = self.max_pages();
+        if max_pages > u16::MAX.into() || max_pages as usize + DESCRIPTOR_OVERHEAD > queue_size {
+            return Err(format!(
+                "max_write ({}) needs {} pages, but the queue size of {} only allows for {}",
+                self.max_write,
+                max_pages,
+                queue_size,
+                queue_size.saturating_sub(DESCRIPTOR_OVERHEAD),
+            ));
+        }
+        if self.max_background == 0 {
+            return Err("max_background must not be 0".to_string());
+        }
+        if self.congestion_threshold > self.max_background {
+            return Err(format!(
+                "congestion_threshold ({}) must not exceed max_background ({})",
+                self.congestion_threshold, self.max_background
+            ));
+        }
+        if !(0..=9).any(|e| self.time_gran == 10u32.pow(e)) {
+            return Err(format!(
+                "time_gran ({}) must be a power of 10 between 1 and 1000000000",
+                self.time_gran
+            ));
+        }
+        // The guest refuses larger alignments than its DAX range size of 2 MiB.
+        if self.map_alignment > 21 {
+            return Err(format!(
+                "map_alignment ({}) must be at most 21",
+                self.map_alignment
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What a request mostly spends its time on, so that slow requests can be kept from delaying quick
+/// ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestKind {
+    /// Looks at or changes metadata, which is usually quick.
+    Metadata,
+    /// Transfers or synchronizes file data, which may take arbitrarily long.
+    Data,
+}
+
+impl RequestKind {
+    /// Determines the kind of the request in `chain` from its header, without building a
+    /// `Reader` for it.  Requests whose header is not in the first descriptor, or that cannot be
+    /// parsed, are treated as metadata requests, handling them fails quickly.
+    pub fn of<M>(chain: &DescriptorChain<M>) -> RequestKind
+    where
+        M: Clone + Deref,
+        M::Target: GuestMemory,
+    {
+        let opcode = chain
+            .clone()
+            .next()
+            .filter(|desc| !desc.is_write_only() && desc.len() as usize >= size_of::<InHeader>())
+            .and_then(|desc| chain.memory().read_obj::<InHeader>(desc.addr()).ok())
+            .and_then(|in_header| Opcode::try_from(in_header.opcode).ok());
+
+        match opcode {
+            Some(
+                Opcode::Read
+                | Opcode::Write
+                | Opcode::Flush
+                | Opcode::Fsync
+                | Opcode::Fsyncdir
+                | Opcode::Fallocate
+                | Opcode::CopyFileRange,
+            ) => RequestKind::Data,
+            _ => RequestKind::Metadata,
+        }
+    }
+}
+
 pub struct Server<F: FileSystem + Sync> {
     fs: F,
     options: AtomicU64,
+    recorder: Option<Recorder>,
+    limits: InitLimits,
+    // The size of the request queue, 0 while unknown.
+    queue_size: AtomicUsize,
 }
 
 impl<F: FileSystem + Sync> Server<F> {
@@ -75,11 +235,203 @@ impl<F: FileSystem + Sync> Server<F> {
         Server {
             fs,
             options: AtomicU64::new(FsOptions::empty().bits()),
+            recorder: None,
+            limits: InitLimits::default(),
+            queue_size: AtomicUsize::new(0),
         }
     }
 
-    #[allow(clippy::cognitive_complexity)]
+    /// Announces `limits` instead of the defaults to the guest.  They must have been validated.
+    pub fn set_init_limits(&mut self, limits: InitLimits) {
+        self.limits = limits;
+    }
+
+    /// Sets the size of the request queue the guest negotiated.  `max_write` is lowered in the
+    /// reply to INIT if a request of that size would not fit into the queue.
+    pub fn set_queue_size(&self, queue_size: usize) {
+        self.queue_size.store(queue_size, Ordering::Relaxed);
+    }
+
+    /// The largest buffer we accept for any request or reply.
+    pub(crate) fn max_buffer_size(&self) -> u32 {
+        std::cmp::max(MAX_BUFFER_SIZE, self.limits.max_write)
+    }
+
+    /// Records all requests handled from now on, and their replies, with `recorder`.
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
     pub fn handle_message<T: FsCacheReqHandler>(
+        &self,
+        r: Reader,
+        w: Writer,
+        vu_req: Option<&mut T>,
+    ) -> Result<usize> {
+        let recorder = match &self.recorder {
+            Some(recorder) => recorder,
+            None => return self.process_message(r, w, vu_req),
+        };
+
+        // Reading from or writing to a clone does not affect the original, so use clones to
+        // capture the request before it is processed and the reply afterwards.
+        let mut request = Vec::with_capacity(r.available_bytes());
+        let _ = r.clone().read_to_end(&mut request);
+        let id = recorder.record_request(&request, w.available_bytes());
+
+        let reply = w.clone();
+        let res = self.process_message(r, w, vu_req);
+        match &res {
+            Ok(len) => recorder.record_reply(id, Some(&reply.read_back(*len))),
+            Err(_) => recorder.record_reply(id, None),
+        }
+        res
+    }
+
+    /// Like `handle_message`, but submits READ, WRITE and FSYNC requests to `uring` if the file
+    /// system supports it (see `FileSystem::async_io`).  Returns `None` for requests that have
+    /// been submitted: their reply is written once the I/O has completed, after which
+    /// `uring.complete()` returns `ctx` along with the length of the reply.
+    ///
+    /// While recording, all requests are handled synchronously.
+    ///
+    /// # Safety
+    ///
+    /// The guest memory that `r` and `w` refer to must stay mapped until the request has
+    /// completed, e.g. by keeping a reference to it in `ctx`.
+    pub unsafe fn handle_message_async<T: FsCacheReqHandler, C>(
+        &self,
+        r: Reader,
+        w: Writer,
+        vu_req: Option<&mut T>,
+        uring: &Uring<C>,
+        ctx: C,
+    ) -> Result<Option<usize>> {
+        if self.recorder.is_none() && self.submit_async(&r, &w, uring, ctx) {
+            return Ok(None);
+        }
+
+        self.handle_message(r, w, vu_req).map(Some)
+    }
+
+    /// Submits the request to `uring` if possible, and returns whether it did.  The original
+    /// `r` and `w` are left untouched so that the request can still be handled synchronously
+    /// otherwise.
+    unsafe fn submit_async<C>(&self, r: &Reader, w: &Writer, uring: &Uring<C>, ctx: C) -> bool {
+        let mut r = r.clone();
+        let mut w = w.clone();
+
+        let in_header: InHeader = match r.read_obj() {
+            Ok(in_header) => in_header,
+            Err(_) => return false,
+        };
+        if in_header.len > (self.max_buffer_size() + FUSE_BUFFER_HEADER_SIZE) {
+            return false;
+        }
+
+        let (fh, op, io_op, iovecs, reply_len) = match Opcode::try_from(in_header.opcode) {
+            Ok(Opcode::Read) => {
+                let ReadIn {
+                    fh, offset, size, ..
+                } = match r.read_obj() {
+                    Ok(read_in) => read_in,
+                    Err(_) => return false,
+                };
+                // Let the synchronous path reject this.
+                if size > self.max_buffer_size() {
+                    return false;
+                }
+                // Split the writer into 2 pieces: one for the `OutHeader` and the rest for the
+                // data.
+                let data = match w.split_at(size_of::<OutHeader>()) {
+                    Ok(data) => data,
+                    Err(_) => return false,
+                };
+                (
+                    fh,
+                    AsyncIoOp::Read { offset, size },
+                    IoOp::Read { offset },
+                    data.iovecs(size as usize),
+                    size_of::<OutHeader>(),
+                )
+            }
+            Ok(Opcode::Write) => {
+                let WriteIn {
+                    fh,
+                    offset,
+                    size,
+                    write_flags,
+                    flags,
+                    ..
+                } = match r.read_obj() {
+                    Ok(write_in) => write_in,
+                    Err(_) => return false,
+                };
+                let op = AsyncIoOp::Write {
+                    delayed_write: write_flags & WRITE_CACHE != 0,
+                    kill_priv: write_flags & WRITE_KILL_PRIV != 0,
+                    flags,
+                };
+                (
+                    fh,
+                    op,
+                    IoOp::Write { offset },
+                    r.iovecs(size as usize),
+                    size_of::<OutHeader>() + size_of::<WriteOut>(),
+                )
+            }
+            Ok(Opcode::Fsync) => {
+                let FsyncIn {
+                    fh, fsync_flags, ..
+                } = match r.read_obj() {
+                    Ok(fsync_in) => fsync_in,
+                    Err(_) => return false,
+                };
+                let datasync = fsync_flags & 0x1 != 0;
+                (
+                    fh,
+                    AsyncIoOp::Fsync { datasync },
+                    IoOp::Fsync { datasync },
+                    GuestIovecs::default(),
+                    size_of::<OutHeader>(),
+                )
+            }
+            _ => return false,
+        };
+
+        let reply = w.iovecs(reply_len);
+        if reply.len() < reply_len {
+            return false;
+        }
+
+        let target = match self.fs.async_io(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            op,
+        ) {
+            Ok(target) => target,
+            Err(_) => return false,
+        };
+
+        let unique = in_header.unique;
+        let reply_fn = Box::new(move |res: i32| {
+            let (data, len) = async_reply(io_op, unique, res);
+            reply.copy_from(&data);
+            len
+        });
+
+        match uring.submit(target, io_op, iovecs, reply_fn, ctx) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Failed to submit request to io_uring: {}", e);
+                false
+            }
+        }
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    fn process_message<T: FsCacheReqHandler>(
         &self,
         mut r: Reader,
         w: Writer,
@@ -87,7 +439,7 @@ impl<F: FileSystem + Sync> Server<F> {
     ) -> Result<usize> {
         let in_header: InHeader = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        if in_header.len > (MAX_BUFFER_SIZE + FUSE_BUFFER_HEADER_SIZE) {
+        if in_header.len > (self.max_buffer_size() + FUSE_BUFFER_HEADER_SIZE) {
             return reply_error(
                 io::Error::from_raw_os_error(libc::ENOMEM),
                 in_header.unique,
@@ -213,7 +565,7 @@ impl<F: FileSystem + Sync> Server<F> {
             let RemovemappingIn { count } = r.read_obj().map_err(Error::DecodeMessage)?;
 
             if let Some(size) = (count as usize).checked_mul(size_of::<RemovemappingOne>()) {
-                if size > MAX_BUFFER_SIZE as usize {
+                if size > self.max_buffer_size() as usize {
                     return reply_error(
                         io::Error::from_raw_os_error(libc::ENOMEM),
                         in_header.unique,
@@ -624,7 +976,10 @@ impl<F: FileSystem + Sync> Server<F> {
         };
 
         // Split the writer into 2 pieces: one for the `OutHeader` and the rest for the data.
-        let data_writer = ZcWriter(w.split_at(size_of::<OutHeader>()).unwrap());
+        let data_writer = ZcWriter(
+            w.split_at(size_of::<OutHeader>())
+                .map_err(|_| Error::EncodeMessage(einval()))?,
+        );
 
         match self.fs.read(
             Context::from(in_header),
@@ -830,7 +1185,7 @@ impl<F: FileSystem + Sync> Server<F> {
 
         r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
 
-        if size > MAX_BUFFER_SIZE {
+        if size > self.max_buffer_size() {
             return reply_error(
                 io::Error::from_raw_os_error(libc::ENOMEM),
                 in_header.unique,
@@ -860,7 +1215,7 @@ impl<F: FileSystem + Sync> Server<F> {
     fn listxattr(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
         let GetxattrIn { size, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        if size > MAX_BUFFER_SIZE {
+        if size > self.max_buffer_size() {
             return reply_error(
                 io::Error::from_raw_os_error(libc::ENOMEM),
                 in_header.unique,
@@ -968,7 +1323,7 @@ impl<F: FileSystem + Sync> Server<F> {
         }
 
         // These fuse features are supported by this server by default.
-        let supported = FsOptions::ASYNC_READ
+        let mut supported = FsOptions::ASYNC_READ
             | FsOptions::PARALLEL_DIROPS
             | FsOptions::BIG_WRITES
             | FsOptions::AUTO_INVAL_DATA
@@ -983,8 +1338,19 @@ impl<F: FileSystem + Sync> Server<F> {
         let flags_64 = ((flags2 as u64) << 32) | (flags as u64);
         let capable = FsOptions::from_bits_truncate(flags_64);
 
-        let page_size: u32 = unsafe { libc::sysconf(libc::_SC_PAGESIZE).try_into().unwrap() };
-        let max_pages = ((MAX_BUFFER_SIZE - 1) / page_size) + 1;
+        let limits = match self.queue_size.load(Ordering::Relaxed) {
+            0 => self.limits,
+            queue_size => self.limits.fit_queue(queue_size),
+        };
+        if limits.max_write < self.limits.max_write {
+            warn!(
+                "Lowering max_write from {} to {} to fit into the queue",
+                self.limits.max_write, limits.max_write
+            );
+        }
+        if limits.map_alignment != 0 {
+            supported |= FsOptions::MAP_ALIGNMENT;
+        }
 
         match self.fs.init(capable) {
             Ok(want) => {
@@ -996,12 +1362,12 @@ impl<F: FileSystem + Sync> Server<F> {
                     minor: KERNEL_MINOR_VERSION,
                     max_readahead,
                     flags: enabled as u32,
-                    max_background: u16::MAX,
-                    congestion_threshold: (u16::MAX / 4) * 3,
-                    max_write: MAX_BUFFER_SIZE,
-                    time_gran: 1, // nanoseconds
-                    max_pages: max_pages.try_into().unwrap(),
-                    map_alignment: 0,
+                    max_background: limits.max_background,
+                    congestion_threshold: limits.congestion_threshold,
+                    max_write: limits.max_write,
+                    time_gran: limits.time_gran,
+                    max_pages: limits.max_pages().try_into().unwrap(),
+                    map_alignment: limits.map_alignment,
                     flags2: (enabled >> 32) as u32,
                     ..Default::default()
                 };
@@ -1037,7 +1403,7 @@ impl<F: FileSystem + Sync> Server<F> {
             fh, offset, size, ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        if size > MAX_BUFFER_SIZE {
+        if size > self.max_buffer_size() {
             return reply_error(
                 io::Error::from_raw_os_error(libc::ENOMEM),
                 in_header.unique,
@@ -1056,7 +1422,9 @@ impl<F: FileSystem + Sync> Server<F> {
 
         // Skip over enough bytes for the header.
         let unique = in_header.unique;
-        let mut cursor = w.split_at(size_of::<OutHeader>()).unwrap();
+        let mut cursor = w
+            .split_at(size_of::<OutHeader>())
+            .map_err(|_| Error::EncodeMessage(einval()))?;
         let result = match self.fs.readdir(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -1132,7 +1500,7 @@ impl<F: FileSystem + Sync> Server<F> {
             fh, offset, size, ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        if size > MAX_BUFFER_SIZE {
+        if size > self.max_buffer_size() {
             return reply_error(
                 io::Error::from_raw_os_error(libc::ENOMEM),
                 in_header.unique,
@@ -1151,7 +1519,9 @@ impl<F: FileSystem + Sync> Server<F> {
 
         // Skip over enough bytes for the header.
         let unique = in_header.unique;
-        let mut cursor = w.split_at(size_of::<OutHeader>()).unwrap();
+        let mut cursor = w
+            .split_at(size_of::<OutHeader>())
+            .map_err(|_| Error::EncodeMessage(einval()))?;
         let result = match self.fs.readdir(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -1274,10 +1644,24 @@ impl<F: FileSystem + Sync> Server<F> {
     fn access(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
         let AccessIn { mask, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        match self
-            .fs
-            .access(Context::from(in_header), in_header.nodeid.into(), mask)
-        {
+        let remaining_len = (in_header.len as usize)
+            .checked_sub(size_of::<InHeader>())
+            .and_then(|l| l.checked_sub(size_of::<AccessIn>()))
+            .ok_or(Error::InvalidHeaderLength)?;
+        let mut buf = vec![0; remaining_len];
+
+        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+
+        let options = FsOptions::from_bits_truncate(self.options.load(Ordering::Relaxed));
+
+        let extensions = get_extensions(options, 0, buf.as_slice())?;
+
+        match self.fs.access(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            mask,
+            extensions,
+        ) {
             Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
             Err(e) => reply_error(e, in_header.unique, w),
         }
@@ -1396,7 +1780,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let BatchForgetIn { count, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
         if let Some(size) = (count as usize).checked_mul(size_of::<ForgetOne>()) {
-            if size > MAX_BUFFER_SIZE as usize {
+            if size > self.max_buffer_size() as usize {
                 return reply_error(
                     io::Error::from_raw_os_error(libc::ENOMEM),
                     in_header.unique,
@@ -1576,6 +1960,60 @@ fn reply_ok<T: ByteValued>(
     Ok(w.bytes_written())
 }
 
+/// Builds the reply to a READ, WRITE or FSYNC request that has been performed asynchronously with
+/// result `res`, and returns it along with the total length of the reply, which for reads includes
+/// the data that has been transferred already.
+fn async_reply(op: IoOp, unique: u64, res: i32) -> (Vec<u8>, usize) {
+    if res < 0 {
+        let header = OutHeader {
+            len: size_of::<OutHeader>() as u32,
+            error: res,
+            unique,
+        };
+
+        debug!(
+            "Replying ERROR, header: OutHeader {{ error: {} ({}), unique: {}, len: {} }}",
+            header.error,
+            strerror(-header.error),
+            header.unique,
+            header.len
+        );
+        return (header.as_slice().to_vec(), header.len as usize);
+    }
+
+    let count = res as usize;
+    let (out, data_len) = match op {
+        IoOp::Read { .. } => (None, count),
+        IoOp::Write { .. } => (
+            Some(WriteOut {
+                size: count as u32,
+                ..Default::default()
+            }),
+            0,
+        ),
+        IoOp::Fsync { .. } => (None, 0),
+    };
+
+    let out_len = if out.is_some() {
+        size_of::<WriteOut>()
+    } else {
+        0
+    };
+    let header = OutHeader {
+        len: (size_of::<OutHeader>() + out_len + data_len) as u32,
+        error: 0,
+        unique,
+    };
+
+    debug!("Replying OK, header: {:?}", header);
+
+    let mut reply = header.as_slice().to_vec();
+    if let Some(out) = out {
+        reply.extend_from_slice(out.as_slice());
+    }
+    (reply, header.len as usize)
+}
+
 fn strerror(error: i32) -> String {
     let mut err_desc: Vec<u8> = vec![0; 256];
     let buf_ptr = err_desc.as_mut_ptr() as *mut libc::c_char;
@@ -1769,6 +2207,10 @@ fn get_extensions(options: FsOptions, skip: usize, request_bytes: &[u8]) -> Resu
             .checked_sub(size_of::<ExtHeader>())
             .ok_or(Error::InvalidHeaderLength)?;
 
+        if remaining_bytes.len() < extension_size {
+            return Err(Error::InvalidHeaderLength);
+        }
+
         let (current_extension_bytes, next_extension_bytes) =
             remaining_bytes.split_at(extension_size);
 
@@ -1807,3 +2249,40 @@ fn get_extensions(options: FsOptions, skip: usize, request_bytes: &[u8]) -> Resu
 
     Ok(extensions)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::descriptor_utils::{create_descriptor_chain, DescriptorType};
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    fn kind_of(opcode: Opcode) -> RequestKind {
+        let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0x0), 0x10000)]).unwrap();
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![
+                (DescriptorType::Readable, size_of::<InHeader>() as u32),
+                (DescriptorType::Writable, size_of::<OutHeader>() as u32),
+            ],
+            0,
+        )
+        .unwrap();
+        let in_header = InHeader {
+            len: size_of::<InHeader>() as u32,
+            opcode: opcode as u32,
+            ..Default::default()
+        };
+        memory.write_obj(in_header, GuestAddress(0x100)).unwrap();
+
+        RequestKind::of(&chain)
+    }
+
+    #[test]
+    fn request_kind() {
+        assert_eq!(kind_of(Opcode::Fsync), RequestKind::Data);
+        assert_eq!(kind_of(Opcode::Write), RequestKind::Data);
+        assert_eq!(kind_of(Opcode::Getattr), RequestKind::Metadata);
+    }
+}
@@ -0,0 +1,686 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! A minimal FUSE client that talks to a `Server` the way the guest kernel would, but without a
+//! VM: requests are encoded into guest memory, handed to `Server::handle_message()` through a
+//! descriptor chain, and the replies are decoded back into the structures from `fuse`.  With
+//! `set_uring()`, requests go through `Server::handle_message_async()` instead.
+//!
+//! This is only meant for tests, so protocol violations by the server (e.g. a reply that does not
+//! match the request) cause a panic, while errors returned by the file system are passed on as
+//! `io::Error`s.
+
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
+
+use crate::descriptor_utils::{create_descriptor_chain, DescriptorType, Reader, Writer};
+use crate::filesystem::FileSystem;
+use crate::fs_cache_req_handler::FsCacheReqHandler;
+use crate::fuse::*;
+use crate::server::Server;
+use crate::uring::Uring;
+
+const DESC_TABLE_ADDR: u64 = 0;
+const BUFFERS_ADDR: u64 = 0x1_0000;
+
+/// Default size of the writable part of the descriptor chain, large enough for any reply that
+/// does not carry file data.
+const DEFAULT_REPLY_SIZE: u32 = 0x1000;
+
+/// Temporary directory that is removed (including its contents) when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let template = std::env::temp_dir().join("virtiofsd-test-XXXXXX");
+        let template = CString::new(template.as_os_str().as_bytes()).unwrap();
+        let mut template = template.into_bytes_with_nul();
+
+        // SAFETY: `template` is a nul-terminated, writable buffer that outlives the call.
+        let ret = unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) };
+        assert!(!ret.is_null(), "mkdtemp: {}", io::Error::last_os_error());
+
+        template.pop();
+        TempDir {
+            path: PathBuf::from(OsStr::from_bytes(&template)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Default for TempDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// DAX is not supported by the test client, so this is never actually called.
+struct NoCacheReqHandler;
+
+impl FsCacheReqHandler for NoCacheReqHandler {
+    fn map(
+        &mut self,
+        _foffset: u64,
+        _moffset: u64,
+        _len: u64,
+        _flags: u64,
+        _fd: RawFd,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    fn unmap(&mut self, _requests: Vec<RemovemappingOne>) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
+
+/// A directory entry as returned by `FuseClient::readdir()`.
+#[derive(Debug)]
+pub struct DirEntryOut {
+    pub ino: u64,
+    pub offset: u64,
+    pub type_: u32,
+    pub name: Vec<u8>,
+}
+
+pub struct FuseClient<F: FileSystem + Sync> {
+    server: Server<F>,
+    uring: Option<Uring<()>>,
+    unique: u64,
+    options: FsOptions,
+
+    /// Number of requests that were completed by the io_uring.
+    pub async_replies: u64,
+
+    /// Credentials sent in the header of every request.
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+/// Waits for the single request in flight on `uring` to complete and returns its reply length.
+fn wait_for_completion(uring: &Uring<()>) -> usize {
+    loop {
+        if let Some(((), len)) = uring.complete().pop() {
+            return len;
+        }
+
+        let mut pollfd = libc::pollfd {
+            fd: uring.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `pollfd` is a valid array of one element.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 10_000) };
+        assert!(ret > 0, "timed out waiting for io_uring completion");
+    }
+}
+
+fn name_bytes(name: &str) -> Vec<u8> {
+    CString::new(name).unwrap().into_bytes_with_nul()
+}
+
+fn parse<T: ByteValued>(reply: &[u8]) -> T {
+    assert!(reply.len() >= size_of::<T>(), "reply too short");
+    T::from_slice(&reply[..size_of::<T>()]).copied().unwrap()
+}
+
+impl<F: FileSystem + Sync> FuseClient<F> {
+    pub fn new(fs: F) -> Self {
+        Self::from_server(Server::new(fs))
+    }
+
+    pub fn from_server(server: Server<F>) -> Self {
+        FuseClient {
+            server,
+            uring: None,
+            unique: 0,
+            options: FsOptions::empty(),
+            async_replies: 0,
+            // SAFETY: These calls have no preconditions and cannot fail.
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            pid: std::process::id(),
+        }
+    }
+
+    /// Sends all further requests through `Server::handle_message_async()` with `uring`.
+    pub fn set_uring(&mut self, uring: Uring<()>) {
+        self.uring = Some(uring);
+    }
+
+    /// Sends a request consisting of `args` to the server and returns the payload of the reply
+    /// (i.e., without the `OutHeader`). `reply_size` is the size of the writable buffer passed
+    /// along with the request, including the room for the `OutHeader`.
+    pub fn call(
+        &mut self,
+        opcode: Opcode,
+        nodeid: u64,
+        args: &[&[u8]],
+        reply_size: u32,
+    ) -> io::Result<Vec<u8>> {
+        self.unique += 1;
+        let len = size_of::<InHeader>() + args.iter().map(|a| a.len()).sum::<usize>();
+        let header = InHeader {
+            len: len as u32,
+            opcode: opcode as u32,
+            unique: self.unique,
+            nodeid,
+            uid: self.uid,
+            gid: self.gid,
+            pid: self.pid,
+            total_extlen: 0,
+            padding: 0,
+        };
+
+        let mut request = header.as_slice().to_vec();
+        for arg in args {
+            request.extend_from_slice(arg);
+        }
+
+        let reply = self.send(&request, reply_size);
+        if reply.is_empty() {
+            return Ok(reply);
+        }
+
+        let out: OutHeader = parse(&reply);
+        assert_eq!(out.len as usize, reply.len(), "OutHeader.len mismatch");
+        assert_eq!(out.unique, self.unique, "reply for the wrong request");
+        if out.error != 0 {
+            assert!(out.error < 0, "positive error value {}", out.error);
+            return Err(io::Error::from_raw_os_error(-out.error));
+        }
+
+        Ok(reply[size_of::<OutHeader>()..].to_vec())
+    }
+
+    /// Passes the raw `request` to `Server::handle_message()` and returns the raw reply.
+    fn send(&mut self, request: &[u8], reply_size: u32) -> Vec<u8> {
+        let mem_size = BUFFERS_ADDR as usize + request.len() + reply_size as usize;
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), mem_size)]).unwrap();
+        mem.write_slice(request, GuestAddress(BUFFERS_ADDR))
+            .unwrap();
+
+        let mut descriptors = vec![(DescriptorType::Readable, request.len() as u32)];
+        if reply_size > 0 {
+            descriptors.push((DescriptorType::Writable, reply_size));
+        }
+        let chain = create_descriptor_chain(
+            &mem,
+            GuestAddress(DESC_TABLE_ADDR),
+            GuestAddress(BUFFERS_ADDR),
+            descriptors,
+            0,
+        )
+        .unwrap();
+        let reader = Reader::new(&mem, chain.clone()).unwrap();
+        let writer = Writer::new(&mem, chain).unwrap();
+
+        let len = match &self.uring {
+            // SAFETY: `mem` outlives the request since we wait for it to complete below.
+            Some(uring) => unsafe {
+                self.server.handle_message_async(
+                    reader,
+                    writer,
+                    None::<&mut NoCacheReqHandler>,
+                    uring,
+                    (),
+                )
+            },
+            None => self
+                .server
+                .handle_message(reader, writer, None::<&mut NoCacheReqHandler>)
+                .map(Some),
+        }
+        .unwrap_or_else(|e| panic!("failed to handle request: {}", e));
+        let len = match len {
+            Some(len) => len,
+            None => {
+                self.async_replies += 1;
+                wait_for_completion(self.uring.as_ref().unwrap())
+            }
+        };
+        assert!(len <= reply_size as usize, "reply overflows the buffer");
+        if len == 0 {
+            return Vec::new();
+        }
+
+        let mut reply = vec![0u8; len];
+        mem.read_slice(
+            &mut reply,
+            GuestAddress(BUFFERS_ADDR + request.len() as u64),
+        )
+        .unwrap();
+        reply
+    }
+
+    pub fn init(&mut self, flags: FsOptions) -> io::Result<InitOut> {
+        let init_in = InitInCompat {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            max_readahead: 0x20000,
+            flags: flags.bits() as u32,
+        };
+        let init_in_ext = InitInExt {
+            flags2: (flags.bits() >> 32) as u32,
+            ..Default::default()
+        };
+
+        let reply = self.call(
+            Opcode::Init,
+            0,
+            &[init_in.as_slice(), init_in_ext.as_slice()],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        let out: InitOut = parse(&reply);
+        self.options =
+            FsOptions::from_bits_truncate(((out.flags2 as u64) << 32) | out.flags as u64);
+        Ok(out)
+    }
+
+    /// The options negotiated during `init()`.
+    pub fn options(&self) -> FsOptions {
+        self.options
+    }
+
+    pub fn lookup(&mut self, parent: u64, name: &str) -> io::Result<EntryOut> {
+        let reply = self.call(
+            Opcode::Lookup,
+            parent,
+            &[&name_bytes(name)],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok(parse(&reply))
+    }
+
+    pub fn forget(&mut self, nodeid: u64, nlookup: u64) {
+        let forget_in = ForgetIn { nlookup };
+        let reply = self
+            .call(Opcode::Forget, nodeid, &[forget_in.as_slice()], 0)
+            .unwrap();
+        assert!(reply.is_empty(), "FORGET must not be answered");
+    }
+
+    pub fn getattr(&mut self, nodeid: u64) -> io::Result<AttrOut> {
+        let getattr_in = GetattrIn::default();
+        let reply = self.call(
+            Opcode::Getattr,
+            nodeid,
+            &[getattr_in.as_slice()],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok(parse(&reply))
+    }
+
+    pub fn setattr(&mut self, nodeid: u64, setattr_in: SetattrIn) -> io::Result<AttrOut> {
+        let reply = self.call(
+            Opcode::Setattr,
+            nodeid,
+            &[setattr_in.as_slice()],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok(parse(&reply))
+    }
+
+    pub fn readlink(&mut self, nodeid: u64) -> io::Result<Vec<u8>> {
+        self.call(Opcode::Readlink, nodeid, &[], DEFAULT_REPLY_SIZE)
+    }
+
+    pub fn symlink(&mut self, parent: u64, name: &str, target: &str) -> io::Result<EntryOut> {
+        let reply = self.call(
+            Opcode::Symlink,
+            parent,
+            &[&name_bytes(name), &name_bytes(target)],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok(parse(&reply))
+    }
+
+    pub fn mknod(&mut self, parent: u64, name: &str, mode: u32, rdev: u32) -> io::Result<EntryOut> {
+        let mknod_in = MknodIn {
+            mode,
+            rdev,
+            umask: 0,
+            padding: 0,
+        };
+        let reply = self.call(
+            Opcode::Mknod,
+            parent,
+            &[mknod_in.as_slice(), &name_bytes(name)],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok(parse(&reply))
+    }
+
+    pub fn mkdir(&mut self, parent: u64, name: &str, mode: u32) -> io::Result<EntryOut> {
+        let mkdir_in = MkdirIn { mode, umask: 0 };
+        let reply = self.call(
+            Opcode::Mkdir,
+            parent,
+            &[mkdir_in.as_slice(), &name_bytes(name)],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok(parse(&reply))
+    }
+
+    pub fn unlink(&mut self, parent: u64, name: &str) -> io::Result<()> {
+        self.call(
+            Opcode::Unlink,
+            parent,
+            &[&name_bytes(name)],
+            DEFAULT_REPLY_SIZE,
+        )
+        .map(|_| ())
+    }
+
+    pub fn rmdir(&mut self, parent: u64, name: &str) -> io::Result<()> {
+        self.call(
+            Opcode::Rmdir,
+            parent,
+            &[&name_bytes(name)],
+            DEFAULT_REPLY_SIZE,
+        )
+        .map(|_| ())
+    }
+
+    pub fn rename(
+        &mut self,
+        olddir: u64,
+        oldname: &str,
+        newdir: u64,
+        newname: &str,
+        flags: u32,
+    ) -> io::Result<()> {
+        let rename_in = Rename2In {
+            newdir,
+            flags,
+            padding: 0,
+        };
+        self.call(
+            Opcode::Rename2,
+            olddir,
+            &[
+                rename_in.as_slice(),
+                &name_bytes(oldname),
+                &name_bytes(newname),
+            ],
+            DEFAULT_REPLY_SIZE,
+        )
+        .map(|_| ())
+    }
+
+    pub fn link(&mut self, nodeid: u64, newparent: u64, newname: &str) -> io::Result<EntryOut> {
+        let link_in = LinkIn { oldnodeid: nodeid };
+        let reply = self.call(
+            Opcode::Link,
+            newparent,
+            &[link_in.as_slice(), &name_bytes(newname)],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok(parse(&reply))
+    }
+
+    pub fn open(&mut self, nodeid: u64, flags: u32) -> io::Result<OpenOut> {
+        let open_in = OpenIn {
+            flags,
+            open_flags: 0,
+        };
+        let reply = self.call(
+            Opcode::Open,
+            nodeid,
+            &[open_in.as_slice()],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok(parse(&reply))
+    }
+
+    pub fn create(
+        &mut self,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        flags: u32,
+    ) -> io::Result<(EntryOut, OpenOut)> {
+        let create_in = CreateIn {
+            flags,
+            mode,
+            umask: 0,
+            open_flags: 0,
+        };
+        let reply = self.call(
+            Opcode::Create,
+            parent,
+            &[create_in.as_slice(), &name_bytes(name)],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok((parse(&reply), parse(&reply[size_of::<EntryOut>()..])))
+    }
+
+    pub fn read(&mut self, nodeid: u64, fh: u64, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let read_in = ReadIn {
+            fh,
+            offset,
+            size,
+            ..Default::default()
+        };
+        self.call(
+            Opcode::Read,
+            nodeid,
+            &[read_in.as_slice()],
+            size_of::<OutHeader>() as u32 + size,
+        )
+    }
+
+    /// Writes `data` at `offset`. `write_flags` are the `WRITE_*` flags of the FUSE protocol,
+    /// `flags` are the open flags of the file as seen by the guest.
+    pub fn write(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        write_flags: u32,
+        flags: u32,
+    ) -> io::Result<u32> {
+        let write_in = WriteIn {
+            fh,
+            offset,
+            size: data.len() as u32,
+            write_flags,
+            flags,
+            ..Default::default()
+        };
+        let reply = self.call(
+            Opcode::Write,
+            nodeid,
+            &[write_in.as_slice(), data],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok(parse::<WriteOut>(&reply).size)
+    }
+
+    pub fn release(&mut self, nodeid: u64, fh: u64) -> io::Result<()> {
+        let release_in = ReleaseIn {
+            fh,
+            ..Default::default()
+        };
+        self.call(
+            Opcode::Release,
+            nodeid,
+            &[release_in.as_slice()],
+            DEFAULT_REPLY_SIZE,
+        )
+        .map(|_| ())
+    }
+
+    pub fn statfs(&mut self, nodeid: u64) -> io::Result<Kstatfs> {
+        let reply = self.call(Opcode::Statfs, nodeid, &[], DEFAULT_REPLY_SIZE)?;
+        Ok(parse(&reply))
+    }
+
+    pub fn setxattr(
+        &mut self,
+        nodeid: u64,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> io::Result<()> {
+        let size = value.len() as u32;
+        let setxattr_in = SetxattrIn {
+            size,
+            flags,
+            setxattr_flags: 0,
+            padding: 0,
+        };
+        let setxattr_in_compat = SetxattrInCompat { size, flags };
+        let setxattr_in = if self.options.contains(FsOptions::SETXATTR_EXT) {
+            setxattr_in.as_slice()
+        } else {
+            setxattr_in_compat.as_slice()
+        };
+
+        self.call(
+            Opcode::Setxattr,
+            nodeid,
+            &[setxattr_in, &name_bytes(name), value],
+            DEFAULT_REPLY_SIZE,
+        )
+        .map(|_| ())
+    }
+
+    /// Returns the value of the extended attribute `name`, which is expected to be at most `size`
+    /// bytes long.
+    pub fn getxattr(&mut self, nodeid: u64, name: &str, size: u32) -> io::Result<Vec<u8>> {
+        let getxattr_in = GetxattrIn { size, padding: 0 };
+        self.call(
+            Opcode::Getxattr,
+            nodeid,
+            &[getxattr_in.as_slice(), &name_bytes(name)],
+            size_of::<OutHeader>() as u32 + size.max(size_of::<GetxattrOut>() as u32),
+        )
+    }
+
+    /// Returns the nul-separated list of extended attribute names.
+    pub fn listxattr(&mut self, nodeid: u64, size: u32) -> io::Result<Vec<u8>> {
+        let getxattr_in = GetxattrIn { size, padding: 0 };
+        self.call(
+            Opcode::Listxattr,
+            nodeid,
+            &[getxattr_in.as_slice()],
+            size_of::<OutHeader>() as u32 + size.max(size_of::<GetxattrOut>() as u32),
+        )
+    }
+
+    pub fn removexattr(&mut self, nodeid: u64, name: &str) -> io::Result<()> {
+        self.call(
+            Opcode::Removexattr,
+            nodeid,
+            &[&name_bytes(name)],
+            DEFAULT_REPLY_SIZE,
+        )
+        .map(|_| ())
+    }
+
+    pub fn opendir(&mut self, nodeid: u64) -> io::Result<OpenOut> {
+        let open_in = OpenIn {
+            flags: libc::O_RDONLY as u32 | libc::O_DIRECTORY as u32,
+            open_flags: 0,
+        };
+        let reply = self.call(
+            Opcode::Opendir,
+            nodeid,
+            &[open_in.as_slice()],
+            DEFAULT_REPLY_SIZE,
+        )?;
+        Ok(parse(&reply))
+    }
+
+    pub fn readdir(&mut self, nodeid: u64, fh: u64, offset: u64) -> io::Result<Vec<DirEntryOut>> {
+        let read_in = ReadIn {
+            fh,
+            offset,
+            size: DEFAULT_REPLY_SIZE,
+            ..Default::default()
+        };
+        let reply = self.call(
+            Opcode::Readdir,
+            nodeid,
+            &[read_in.as_slice()],
+            size_of::<OutHeader>() as u32 + DEFAULT_REPLY_SIZE,
+        )?;
+
+        let mut entries = Vec::new();
+        let mut rem = &reply[..];
+        while !rem.is_empty() {
+            let dirent: Dirent = parse(rem);
+            let name_start = size_of::<Dirent>();
+            let name_end = name_start + dirent.namelen as usize;
+            entries.push(DirEntryOut {
+                ino: dirent.ino,
+                offset: dirent.off,
+                type_: dirent.type_,
+                name: rem[name_start..name_end].to_vec(),
+            });
+            // Entries are padded to 8 bytes.
+            rem = &rem[((name_end + 7) & !7).min(rem.len())..];
+        }
+        Ok(entries)
+    }
+
+    pub fn releasedir(&mut self, nodeid: u64, fh: u64) -> io::Result<()> {
+        let release_in = ReleaseIn {
+            fh,
+            ..Default::default()
+        };
+        self.call(
+            Opcode::Releasedir,
+            nodeid,
+            &[release_in.as_slice()],
+            DEFAULT_REPLY_SIZE,
+        )
+        .map(|_| ())
+    }
+
+    pub fn fsync(&mut self, nodeid: u64, fh: u64, datasync: bool) -> io::Result<()> {
+        let fsync_in = FsyncIn {
+            fh,
+            fsync_flags: datasync as u32,
+            padding: 0,
+        };
+        self.call(
+            Opcode::Fsync,
+            nodeid,
+            &[fsync_in.as_slice()],
+            DEFAULT_REPLY_SIZE,
+        )
+        .map(|_| ())
+    }
+
+    pub fn access(&mut self, nodeid: u64, mask: u32) -> io::Result<()> {
+        let access_in = AccessIn { mask, padding: 0 };
+        self.call(
+            Opcode::Access,
+            nodeid,
+            &[access_in.as_slice()],
+            DEFAULT_REPLY_SIZE,
+        )
+        .map(|_| ())
+    }
+}
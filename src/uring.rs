@@ -0,0 +1,289 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Asynchronous data transfer using io_uring.
+//!
+//! `Server::handle_message_async()` submits READ, WRITE and FSYNC requests to a `Uring` instead of
+//! performing them on the calling thread.  The kernel transfers the data directly between the host
+//! file and the guest buffers of the descriptor chain, and the reply is written into the chain
+//! once the operation has completed.  The ring's eventfd becomes readable when that happens, after
+//! which `Uring::complete()` returns the completed requests so that their descriptors can be
+//! returned to the guest.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::Mutex;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use vm_memory::volatile_memory::{PtrGuard, PtrGuardMut};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use crate::filesystem::AsyncIoTarget;
+
+/// Guest buffers that are handed to the kernel, or that a reply is written into.
+#[derive(Default)]
+pub struct GuestIovecs {
+    iovecs: Vec<libc::iovec>,
+    // Keep the buffers mapped for as long as their pointers may be used.
+    _read_guards: Vec<PtrGuard>,
+    _write_guards: Vec<PtrGuardMut>,
+    // Marks the first bytes of the corresponding writable buffer dirty.
+    mark_dirty: Vec<Box<dyn Fn(usize) + Send>>,
+}
+
+// Safe because the buffers are guest memory, which does not belong to any particular thread.
+unsafe impl Send for GuestIovecs {}
+
+impl GuestIovecs {
+    /// Adds a buffer that the kernel only reads from.
+    ///
+    /// # Safety
+    ///
+    /// The guest memory that `guard` points to must stay mapped for as long as the returned
+    /// object is used.
+    pub(crate) unsafe fn push_readable(&mut self, guard: PtrGuard, len: usize) {
+        self.iovecs.push(libc::iovec {
+            iov_base: guard.as_ptr() as *mut libc::c_void,
+            iov_len: len,
+        });
+        self._read_guards.push(guard);
+    }
+
+    /// Adds a buffer that is written to, calling `mark_dirty` with the number of bytes at its
+    /// front that have been written.
+    ///
+    /// # Safety
+    ///
+    /// The guest memory that `guard` points to must stay mapped for as long as the returned
+    /// object is used.
+    pub(crate) unsafe fn push_writable(
+        &mut self,
+        guard: PtrGuardMut,
+        len: usize,
+        mark_dirty: Box<dyn Fn(usize) + Send>,
+    ) {
+        self.iovecs.push(libc::iovec {
+            iov_base: guard.as_ptr() as *mut libc::c_void,
+            iov_len: len,
+        });
+        self._write_guards.push(guard);
+        self.mark_dirty.push(mark_dirty);
+    }
+
+    /// Returns the total length of the buffers.
+    pub fn len(&self) -> usize {
+        self.iovecs.iter().map(|iov| iov.iov_len).sum()
+    }
+
+    /// Returns true if there are no buffers, or they are all empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Marks the first `count` bytes of the buffers dirty in the guest memory's bitmap, after
+    /// they have been written to without going through a `Writer`.
+    pub fn mark_dirty(&self, count: usize) {
+        let mut rem = count;
+        for (iov, mark_dirty) in self.iovecs.iter().zip(&self.mark_dirty) {
+            if rem == 0 {
+                break;
+            }
+
+            let len = std::cmp::min(iov.iov_len, rem);
+            mark_dirty(len);
+            rem -= len;
+        }
+    }
+
+    /// Copies as much of `data` into the buffers as fits, returning the number of bytes copied.
+    /// Only valid for writable buffers.
+    pub fn copy_from(&self, data: &[u8]) -> usize {
+        let mut pos = 0;
+        for iov in &self.iovecs {
+            if pos == data.len() {
+                break;
+            }
+
+            let len = std::cmp::min(iov.iov_len, data.len() - pos);
+            // Safe because the buffer is mapped and writable (see `push_writable()`) and we copy
+            // at most `iov_len` bytes.
+            unsafe { ptr::copy_nonoverlapping(data[pos..].as_ptr(), iov.iov_base as *mut u8, len) };
+            pos += len;
+        }
+        self.mark_dirty(pos);
+        pos
+    }
+}
+
+/// An operation to perform on an `AsyncIoTarget`.
+#[derive(Clone, Copy, Debug)]
+pub enum IoOp {
+    /// Read from the file at `offset` into the buffers.
+    Read { offset: u64 },
+    /// Write the buffers to the file at `offset`.
+    Write { offset: u64 },
+    /// Synchronize the file contents, only the data if `datasync` is true.
+    Fsync { datasync: bool },
+}
+
+/// Writes the reply for a completed operation, given its result (a byte count or a negative
+/// errno), and returns the length of the reply.
+pub type ReplyFn = Box<dyn FnOnce(i32) -> usize + Send>;
+
+struct Pending<C> {
+    ctx: C,
+    reply: ReplyFn,
+    // The kernel accesses these until the operation has completed.
+    _file: AsyncIoTarget,
+    iovecs: GuestIovecs,
+}
+
+struct State<C> {
+    ring: IoUring,
+    pending: HashMap<u64, Pending<C>>,
+    next_id: u64,
+}
+
+/// An io_uring instance for asynchronous I/O, along with the requests in flight on it.  `C` is
+/// whatever the caller needs to finish a request once it has completed, e.g. the index of its
+/// descriptor chain.
+pub struct Uring<C> {
+    state: Mutex<State<C>>,
+    eventfd: EventFd,
+}
+
+impl<C> Uring<C> {
+    /// Creates a ring with room for `entries` submissions.
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let ring = IoUring::new(entries)?;
+        let eventfd = EventFd::new(EFD_NONBLOCK)?;
+        ring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+
+        Ok(Uring {
+            state: Mutex::new(State {
+                ring,
+                pending: HashMap::new(),
+                next_id: 0,
+            }),
+            eventfd,
+        })
+    }
+
+    /// Submits `op` on `target`, transferring data from or to `iovecs`.  Once the operation has
+    /// completed, `reply` is called with its result and the next call to `complete()` returns
+    /// `ctx` along with the length of the reply.
+    pub fn submit(
+        &self,
+        target: AsyncIoTarget,
+        op: IoOp,
+        iovecs: GuestIovecs,
+        reply: ReplyFn,
+        ctx: C,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+
+        let fd = types::Fd(target.file.as_raw_fd());
+        let entry = match op {
+            IoOp::Read { offset } => {
+                opcode::Readv::new(fd, iovecs.iovecs.as_ptr(), iovecs.iovecs.len() as u32)
+                    .offset(offset)
+                    .rw_flags(target.rw_flags)
+                    .build()
+            }
+            IoOp::Write { offset } => {
+                opcode::Writev::new(fd, iovecs.iovecs.as_ptr(), iovecs.iovecs.len() as u32)
+                    .offset(offset)
+                    .rw_flags(target.rw_flags)
+                    .build()
+            }
+            IoOp::Fsync { datasync } => {
+                let flags = if datasync {
+                    types::FsyncFlags::DATASYNC
+                } else {
+                    types::FsyncFlags::empty()
+                };
+                opcode::Fsync::new(fd).flags(flags).build()
+            }
+        }
+        .user_data(id);
+
+        Self::push(&mut state.ring, &entry)?;
+
+        state.next_id += 1;
+        state.pending.insert(
+            id,
+            Pending {
+                ctx,
+                reply,
+                _file: target,
+                iovecs,
+            },
+        );
+
+        // If this fails, the entry stays queued and is submitted along with the next one, or by
+        // `complete()`.
+        if let Err(e) = state.ring.submit() {
+            debug!("Failed to submit to io_uring: {}", e);
+        }
+
+        Ok(())
+    }
+
+    fn push(ring: &mut IoUring, entry: &squeue::Entry) -> io::Result<()> {
+        // Safe because the caller keeps the file and the buffers alive until the entry completes.
+        if unsafe { ring.submission().push(entry) }.is_ok() {
+            return Ok(());
+        }
+
+        // The submission queue is full, make room and try again.
+        ring.submit()?;
+        unsafe { ring.submission().push(entry) }
+            .map_err(|_| io::Error::from_raw_os_error(libc::EBUSY))
+    }
+
+    /// Writes the replies of all completed operations and returns their `ctx` along with the
+    /// length of the reply.
+    pub fn complete(&self) -> Vec<(C, usize)> {
+        // The eventfd only wakes up the caller, the completion queue has the actual events.
+        let _ = self.eventfd.read();
+
+        let completed: Vec<(Pending<C>, i32)> = {
+            let mut state = self.state.lock().unwrap();
+            if !state.ring.submission().is_empty() {
+                if let Err(e) = state.ring.submit() {
+                    warn!("Failed to submit to io_uring: {}", e);
+                }
+            }
+
+            let cqes: Vec<(u64, i32)> = state
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            cqes.into_iter()
+                .filter_map(|(id, res)| state.pending.remove(&id).map(|p| (p, res)))
+                .collect()
+        };
+
+        completed
+            .into_iter()
+            .map(|(pending, res)| {
+                // The kernel wrote to the guest buffers directly, so let the bitmap know.  This
+                // does nothing for buffers that were only read from.
+                if res > 0 {
+                    pending.iovecs.mark_dirty(res as usize);
+                }
+                (pending.ctx, (pending.reply)(res))
+            })
+            .collect()
+    }
+}
+
+impl<C> AsRawFd for Uring<C> {
+    /// Returns the eventfd that becomes readable when operations have completed.
+    fn as_raw_fd(&self) -> RawFd {
+        self.eventfd.as_raw_fd()
+    }
+}
@@ -3,7 +3,7 @@
 // found in the LICENSE file.
 
 use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Write};
+use std::io::{Error, Write};
 use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::Path;
@@ -99,10 +99,7 @@ pub fn sfork() -> io::Result<i32> {
         }
         if num_fds != 0 {
             // The original parent died
-            return Err(Error::new(
-                ErrorKind::Other,
-                "Parent process died unexpectedly",
-            ));
+            return Err(Error::other("Parent process died unexpectedly"));
         }
     }
     Ok(child_pid)
@@ -142,7 +139,7 @@ pub fn wait_for_child(pid: i32) -> ! {
 /// # Errors
 /// An error variant will be returned:
 /// - if the input string does not match the name, without the 'CAP_' prefix,
-/// of any of the capability defined in `linux/capabiliy.h`.
+///   of any of the capability defined in `linux/capabiliy.h`.
 /// - if `capng::get_caps_process()` cannot get  the  capabilities  and bounding set of the process.
 /// - if `capng::update()` fails to update the internal posix capabilities settings.
 /// - if `capng::apply()` fails to transfer the specified internal posix capabilities